@@ -0,0 +1,94 @@
+//! Compares `LSMTree::multi_get` with a loop of `LSMTree::get` calls
+//!
+//! Run with `cargo bench --bench multi_get`. The tree is compacted into many
+//! tables on the deeper levels, reads go through a block cache too small to
+//! hold them, so most lookups read a block.
+
+use std::hint::black_box;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+use lsm_tree_kv::{BlockCache, LSMTree, Options};
+
+const KEYS: u64 = 200_000;
+const VALUE_SIZE: usize = 100;
+const ROUNDS: usize = 50;
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key{i:010}").into_bytes()
+}
+
+/// Keys spread over the whole tree, the same on every run
+fn random_keys(count: usize, seed: &mut u64) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|_| {
+            // 64-bit LCG, the high bits are the most random
+            *seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            key((*seed >> 33) % KEYS)
+        })
+        .collect()
+}
+
+fn open_tree(path: &PathBuf) -> LSMTree {
+    let _ = fs::remove_dir_all(path);
+    let options = Options {
+        write_buffer_size: 4 * 1024 * 1024,
+        target_file_size: 256 * 1024,
+        max_bytes_for_level_base: 4 * 1024 * 1024,
+        block_cache: Some(Arc::new(BlockCache::new(256 * 1024))),
+        ..Options::default()
+    };
+    let tree = LSMTree::open_with_options(path, options).unwrap();
+
+    let value = vec![b'v'; VALUE_SIZE];
+    for i in 0..KEYS {
+        tree.put(key(i), value.clone()).unwrap();
+    }
+    tree.flush().unwrap();
+    tree.compact_range(&key(0), &key(KEYS)).unwrap();
+    tree
+}
+
+/// Average time per key of `lookup` over `ROUNDS` batches of `batch_size` keys
+fn measure(batch_size: usize, mut lookup: impl FnMut(&[&[u8]])) -> Duration {
+    let mut seed = 42;
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let keys = random_keys(batch_size, &mut seed);
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+
+        let start = Instant::now();
+        lookup(&keys);
+        elapsed += start.elapsed();
+    }
+    elapsed / (ROUNDS * batch_size) as u32
+}
+
+fn main() {
+    let path = env::temp_dir().join("lsm-tree-kv-bench").join("multi_get");
+    let tree = open_tree(&path);
+
+    // tables of a level are read in parallel only with several CPUs
+    let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    println!("{cpus} CPUs");
+    println!("{:>10} {:>12} {:>12}", "batch", "get", "multi_get");
+    for batch_size in [8, 64, 256, 1024, 4096] {
+        let get = measure(batch_size, |keys| {
+            for key in keys {
+                black_box(tree.get(key).unwrap());
+            }
+        });
+        let multi_get = measure(batch_size, |keys| {
+            black_box(tree.multi_get(keys).unwrap());
+        });
+        println!("{batch_size:>10} {get:>12.2?} {multi_get:>12.2?}");
+    }
+
+    drop(tree);
+    let _ = fs::remove_dir_all(&path);
+}
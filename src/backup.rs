@@ -0,0 +1,593 @@
+//! Backup engine: incremental backups of a tree with restore and retention
+//!
+//! Backups live in a directory of their own:
+//!
+//! ```text
+//! shared/<number>_<crc32>_<size>.sst   // SSTables, stored once for all backups
+//! private/<id>/...                     // manifests and logs of one backup
+//! meta/<id>                            // metadata of one backup
+//! ```
+//!
+//! A backup takes a checkpoint of the tree inside the backup directory, then copies
+//! its tables to `shared` unless an earlier backup already stored them. Tables
+//! never change once written, so a backup only adds the tables written since the
+//! previous one.
+//!
+//! The metadata is a text file, written last, so an interrupted backup doesn't
+//! show up. Its files are removed by the next delete or purge.
+//!
+//! ```text
+//! lsm-tree-kv backup 1
+//! timestamp <seconds since the Unix epoch>
+//! sequence <sequence number of the newest write in the backup>
+//! file <crc32> <size> <path in the backup directory> <path in the tree>
+//! ...
+//! ```
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::checkpoint;
+use crate::crc32;
+use crate::lsm::LSMTree;
+use crate::manifest;
+use crate::{Error, Result};
+
+/// Header line identifying the metadata format
+const META_HEADER: &str = "lsm-tree-kv backup 1";
+
+/// Directory holding the tables of all backups
+const SHARED_DIR: &str = "shared";
+
+/// Directory holding the other files, one subdirectory per backup
+const PRIVATE_DIR: &str = "private";
+
+/// Directory holding the metadata files
+const META_DIR: &str = "meta";
+
+/// Checkpoint a backup is taken from
+const CHECKPOINT_DIR: &str = "checkpoint";
+
+/// Summary of a backup, see `BackupEngine::backups`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u32,
+    /// Seconds since the Unix epoch when the backup was taken
+    pub timestamp: u64,
+    /// Sequence number of the newest write in the backup
+    pub sequence: u64,
+    /// Total size of the files of the backup, shared ones included
+    pub size: u64,
+    pub num_files: usize,
+}
+
+/// A file of a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BackupFile {
+    /// CRC-32 of the whole file
+    checksum: u32,
+    size: u64,
+    /// Path relative to the backup directory
+    stored: PathBuf,
+    /// Path relative to the data directory of the tree
+    path: PathBuf,
+}
+
+/// Contents of a metadata file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BackupMeta {
+    timestamp: u64,
+    sequence: u64,
+    files: Vec<BackupFile>,
+}
+
+impl BackupMeta {
+    fn info(&self, id: u32) -> BackupInfo {
+        BackupInfo {
+            id,
+            timestamp: self.timestamp,
+            sequence: self.sequence,
+            size: self.files.iter().map(|file| file.size).sum(),
+            num_files: self.files.len(),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents.lines();
+        if lines.next() != Some(META_HEADER) {
+            return Err(Error::Corruption(format!(
+                "Invalid backup metadata header in {path:?}"
+            )));
+        }
+
+        let mut meta = Self {
+            timestamp: 0,
+            sequence: 0,
+            files: Vec::new(),
+        };
+        for line in lines.filter(|line| !line.is_empty()) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["timestamp", timestamp] => meta.timestamp = parse_field(timestamp)?,
+                ["sequence", sequence] => meta.sequence = parse_field(sequence)?,
+                ["file", checksum, size, stored, path] => meta.files.push(BackupFile {
+                    checksum: u32::from_str_radix(checksum, 16).map_err(|_| {
+                        Error::Corruption(format!("Invalid backup checksum: {checksum}"))
+                    })?,
+                    size: parse_field(size)?,
+                    stored: PathBuf::from(stored),
+                    path: PathBuf::from(path),
+                }),
+                _ => {
+                    return Err(Error::Corruption(format!(
+                        "Invalid backup metadata record: {line}"
+                    )));
+                }
+            }
+        }
+
+        Ok(meta)
+    }
+
+    /// Atomically writes the metadata to `path`
+    fn write(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(writer, "{META_HEADER}")?;
+            writeln!(writer, "timestamp {}", self.timestamp)?;
+            writeln!(writer, "sequence {}", self.sequence)?;
+            for file in &self.files {
+                writeln!(
+                    writer,
+                    "file {:08x} {} {} {}",
+                    file.checksum,
+                    file.size,
+                    file.stored.display(),
+                    file.path.display()
+                )?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Takes, verifies, restores and purges backups stored in one directory
+///
+/// Methods changing the backups take `&mut self`, a backup directory must only
+/// be used by one engine at a time.
+#[derive(Debug)]
+pub struct BackupEngine {
+    dir: PathBuf,
+}
+
+impl BackupEngine {
+    /// Opens the backups stored at the given path, creates the directory if it
+    /// doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let dir = path.as_ref().to_path_buf();
+        for sub in [SHARED_DIR, PRIVATE_DIR, META_DIR] {
+            fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(Self { dir })
+    }
+
+    /// Backs up the current state of a tree, returns the id of the new backup
+    ///
+    /// Flushes the memtables of the tree first, see `LSMTree::checkpoint`.
+    pub fn create_backup(&mut self, tree: &LSMTree) -> Result<u32> {
+        let id = self.ids()?.last().map_or(1, |id| id + 1);
+
+        let checkpoint_dir = self.dir.join(CHECKPOINT_DIR);
+        if checkpoint_dir.exists() {
+            fs::remove_dir_all(&checkpoint_dir)?;
+        }
+        let private_dir = self.private_dir(id);
+        if private_dir.exists() {
+            fs::remove_dir_all(&private_dir)?;
+        }
+
+        let sequence = tree.create_checkpoint(&checkpoint_dir)?;
+        let result = self.store_checkpoint(id, &checkpoint_dir, sequence);
+        let _ = fs::remove_dir_all(&checkpoint_dir);
+        result?;
+
+        Ok(id)
+    }
+
+    /// Get a summary of every backup, oldest first
+    pub fn backups(&self) -> Result<Vec<BackupInfo>> {
+        self.ids()?
+            .into_iter()
+            .map(|id| Ok(self.read_meta(id)?.info(id)))
+            .collect()
+    }
+
+    /// Checks that every file of a backup is present with the recorded size and
+    /// checksum
+    pub fn verify(&self, id: u32) -> Result<()> {
+        for file in self.read_meta(id)?.files {
+            let path = self.dir.join(&file.stored);
+            let (checksum, size) = file_checksum(&path)?;
+            check_file(id, &file, checksum, size)?;
+        }
+        Ok(())
+    }
+
+    /// Restores a backup into `target`, which must not exist yet
+    ///
+    /// Files are checked against their checksums while they are copied. The
+    /// restored directory can be opened with `LSMTree::open`.
+    pub fn restore<P: AsRef<Path>>(&self, id: u32, target: P) -> Result<()> {
+        let target = target.as_ref();
+        let meta = self.read_meta(id)?;
+        checkpoint::validate_dest(target)?;
+
+        checkpoint::write_atomically(target, |dir| {
+            for file in &meta.files {
+                let dest = dir.join(&file.path);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let (checksum, size) = copy_file(&self.dir.join(&file.stored), &dest)?;
+                check_file(id, file, checksum, size)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Deletes a backup and the tables no other backup holds
+    pub fn delete_backup(&mut self, id: u32) -> Result<()> {
+        match fs::remove_file(self.meta_path(id)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => return Err(no_backup(id)),
+            Err(err) => return Err(err.into()),
+        }
+        self.collect_garbage()
+    }
+
+    /// Deletes all but the newest `keep` backups
+    pub fn purge_old_backups(&mut self, keep: usize) -> Result<()> {
+        let ids = self.ids()?;
+        let purged = ids.len().saturating_sub(keep);
+        for &id in &ids[..purged] {
+            fs::remove_file(self.meta_path(id))?;
+        }
+        self.collect_garbage()
+    }
+
+    /// Deletes the backups taken more than `age` ago
+    pub fn purge_backups_older_than(&mut self, age: Duration) -> Result<()> {
+        let cutoff = now_secs().saturating_sub(age.as_secs());
+        for info in self.backups()? {
+            if info.timestamp < cutoff {
+                fs::remove_file(self.meta_path(info.id))?;
+            }
+        }
+        self.collect_garbage()
+    }
+
+    /// Stores the files of a checkpoint in the backup directory and records them
+    ///
+    /// Tables are copied, the checkpoint links them to the files of the tree.
+    fn store_checkpoint(&self, id: u32, checkpoint_dir: &Path, sequence: u64) -> Result<()> {
+        let mut files = Vec::new();
+        for path in list_files(checkpoint_dir, Path::new(""))? {
+            let src = checkpoint_dir.join(&path);
+            let (checksum, size) = file_checksum(&src)?;
+
+            let stored = if let Some(number) = manifest::sst_number(&path) {
+                let stored =
+                    Path::new(SHARED_DIR).join(format!("{number:08}_{checksum:08x}_{size}.sst"));
+                let dest = self.dir.join(&stored);
+                // an earlier backup holds the same table
+                if !dest.exists() {
+                    // the checkpoint links the table of the tree, the backup keeps a copy
+                    let tmp_path = dest.with_extension("tmp");
+                    if copy_file(&src, &tmp_path)? != (checksum, size) {
+                        let _ = fs::remove_file(&tmp_path);
+                        return Err(Error::Corruption(format!(
+                            "{path:?} changed while it was backed up"
+                        )));
+                    }
+                    fs::rename(&tmp_path, dest)?;
+                }
+                stored
+            } else {
+                let stored = Path::new(PRIVATE_DIR).join(id.to_string()).join(&path);
+                let dest = self.dir.join(&stored);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(&src, dest)?;
+                stored
+            };
+
+            files.push(BackupFile {
+                checksum,
+                size,
+                stored,
+                path,
+            });
+        }
+
+        let meta = BackupMeta {
+            timestamp: now_secs(),
+            sequence,
+            files,
+        };
+        meta.write(&self.meta_path(id))
+    }
+
+    /// Removes the files no backup refers to anymore
+    fn collect_garbage(&self) -> Result<()> {
+        let ids = self.ids()?;
+        let mut referenced = HashSet::new();
+        for &id in &ids {
+            referenced.extend(self.read_meta(id)?.files.into_iter().map(|f| f.stored));
+        }
+
+        for path in list_files(&self.dir.join(SHARED_DIR), Path::new(SHARED_DIR))? {
+            if !referenced.contains(&path) {
+                fs::remove_file(self.dir.join(path))?;
+            }
+        }
+
+        for entry in fs::read_dir(self.dir.join(PRIVATE_DIR))? {
+            let path = entry?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str()?.parse().ok());
+            if id.is_none_or(|id| !ids.contains(&id)) {
+                fs::remove_dir_all(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ids of all backups, ascending
+    fn ids(&self) -> Result<Vec<u32>> {
+        let mut ids: Vec<u32> = fs::read_dir(self.dir.join(META_DIR))?
+            .filter_map(std::result::Result::ok)
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn read_meta(&self, id: u32) -> Result<BackupMeta> {
+        let path = self.meta_path(id);
+        if !path.exists() {
+            return Err(no_backup(id));
+        }
+        BackupMeta::read(&path)
+    }
+
+    fn meta_path(&self, id: u32) -> PathBuf {
+        self.dir.join(META_DIR).join(id.to_string())
+    }
+
+    fn private_dir(&self, id: u32) -> PathBuf {
+        self.dir.join(PRIVATE_DIR).join(id.to_string())
+    }
+}
+
+/// Lists the files below `dir` recursively, as paths starting with `prefix`
+fn list_files(dir: &Path, prefix: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files.extend(list_files(&entry.path(), &path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Computes the checksum and size of a file
+fn file_checksum(path: &Path) -> Result<(u32, u64)> {
+    checksum_reader(File::open(path)?, |_| Ok(()))
+}
+
+/// Copies a file, returns the checksum and size of the copied data
+fn copy_file(src: &Path, dest: &Path) -> Result<(u32, u64)> {
+    let mut writer = File::create(dest)?;
+    let result = checksum_reader(File::open(src)?, |chunk| Ok(writer.write_all(chunk)?))?;
+    writer.sync_all()?;
+    Ok(result)
+}
+
+/// Reads `reader` to the end, passing every chunk to `chunk`
+fn checksum_reader(
+    mut reader: impl Read,
+    mut chunk: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<(u32, u64)> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut checksum = 0;
+    let mut size = 0;
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok((checksum, size));
+        }
+        checksum = crc32::update(checksum, &buf[..len]);
+        size += len as u64;
+        chunk(&buf[..len])?;
+    }
+}
+
+fn check_file(id: u32, file: &BackupFile, checksum: u32, size: u64) -> Result<()> {
+    if size != file.size || checksum != file.checksum {
+        return Err(Error::Corruption(format!(
+            "Backup {id}: {:?} doesn't match its recorded size or checksum",
+            file.stored
+        )));
+    }
+    Ok(())
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str) -> Result<T> {
+    field
+        .parse()
+        .map_err(|_| Error::Corruption(format!("Invalid backup metadata field: {field}")))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn no_backup(id: u32) -> Error {
+    Error::InvalidArgument(format!("No backup with id {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn shared_files(dir: &Path) -> usize {
+        fs::read_dir(dir.join(SHARED_DIR)).unwrap().count()
+    }
+
+    #[test]
+    fn test_incremental_backup_and_restore() {
+        let dir = temp_dir("backup", "incremental");
+        let tree = LSMTree::open(dir.join("db")).unwrap();
+        let mut engine = BackupEngine::open(dir.join("backups")).unwrap();
+
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let first = engine.create_backup(&tree).unwrap();
+        assert_eq!(shared_files(&dir.join("backups")), 1);
+
+        // the table of the first backup is stored once
+        tree.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        tree.put(b"b".to_vec(), b"3".to_vec()).unwrap();
+        let second = engine.create_backup(&tree).unwrap();
+        assert_eq!(shared_files(&dir.join("backups")), 2);
+        drop(tree);
+
+        let backups = engine.backups().unwrap();
+        assert_eq!(
+            backups.iter().map(|b| b.id).collect::<Vec<_>>(),
+            [first, second]
+        );
+        assert_eq!(backups[0].sequence, 1);
+        assert_eq!(backups[1].sequence, 3);
+        engine.verify(first).unwrap();
+        engine.verify(second).unwrap();
+
+        engine.restore(first, dir.join("first")).unwrap();
+        engine.restore(second, dir.join("second")).unwrap();
+        assert!(engine.restore(second, dir.join("second")).is_err());
+
+        let restored = LSMTree::open(dir.join("first")).unwrap();
+        assert_eq!(restored.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(restored.get(b"b").unwrap(), None);
+
+        let restored = LSMTree::open(dir.join("second")).unwrap();
+        assert_eq!(restored.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(restored.get(b"b").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn test_verify_detects_corruption() {
+        let dir = temp_dir("backup", "corruption");
+        let tree = LSMTree::open(dir.join("db")).unwrap();
+        let mut engine = BackupEngine::open(dir.join("backups")).unwrap();
+
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let id = engine.create_backup(&tree).unwrap();
+
+        let shared = dir.join("backups").join(SHARED_DIR);
+        let table = fs::read_dir(&shared)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut data = fs::read(&table).unwrap();
+        data[0] ^= 0xFF;
+        fs::write(&table, data).unwrap();
+
+        assert!(matches!(engine.verify(id), Err(Error::Corruption(_))));
+        assert!(matches!(
+            engine.restore(id, dir.join("restored")),
+            Err(Error::Corruption(_))
+        ));
+        assert!(!dir.join("restored").exists());
+        assert!(!dir.join("restored.tmp").exists());
+    }
+
+    #[test]
+    fn test_backup_keeps_own_copy_of_tables() {
+        let dir = temp_dir("backup", "own_copy");
+        let tree = LSMTree::open(dir.join("db")).unwrap();
+        let mut engine = BackupEngine::open(dir.join("backups")).unwrap();
+
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let id = engine.create_backup(&tree).unwrap();
+        drop(tree);
+
+        // damage the table of the tree, the one of the backup is another file
+        let table = list_files(&dir.join("db"), Path::new(""))
+            .unwrap()
+            .into_iter()
+            .find(|path| manifest::sst_number(path).is_some())
+            .unwrap();
+        let table = dir.join("db").join(table);
+        let mut data = fs::read(&table).unwrap();
+        data[0] ^= 0xFF;
+        fs::write(&table, data).unwrap();
+
+        engine.verify(id).unwrap();
+    }
+
+    #[test]
+    fn test_purge_old_backups() {
+        let dir = temp_dir("backup", "purge");
+        let tree = LSMTree::open(dir.join("db")).unwrap();
+        let backups_dir = dir.join("backups");
+        let mut engine = BackupEngine::open(&backups_dir).unwrap();
+
+        for i in 0..3u8 {
+            tree.put(vec![i], vec![i]).unwrap();
+            engine.create_backup(&tree).unwrap();
+        }
+        assert_eq!(shared_files(&backups_dir), 3);
+
+        engine
+            .purge_backups_older_than(Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(engine.backups().unwrap().len(), 3);
+
+        engine.purge_old_backups(1).unwrap();
+        let backups = engine.backups().unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].id, 3);
+        assert_eq!(shared_files(&backups_dir), 3);
+        assert_eq!(
+            fs::read_dir(backups_dir.join(PRIVATE_DIR)).unwrap().count(),
+            1
+        );
+
+        engine.delete_backup(3).unwrap();
+        assert!(engine.delete_backup(3).is_err());
+        assert_eq!(shared_files(&backups_dir), 0);
+    }
+}
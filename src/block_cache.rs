@@ -0,0 +1,119 @@
+//! Shared cache of `SSTable` blocks
+//!
+//! Blocks are keyed by the id of the table they belong to and their offset in
+//! the file. The cache is bounded by the total size of the blocks it holds and
+//! evicts the least recently used ones. A single cache can be shared by several
+//! trees through `Options::block_cache`.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::lru::LruCache;
+
+/// Blocks by (table id, offset in the file), charged by their size
+type Blocks = LruCache<(u64, u64), Arc<Vec<u8>>>;
+
+/// Size-bounded LRU cache of raw blocks
+pub struct BlockCache {
+    blocks: Mutex<Blocks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// Creates a cache holding up to `capacity` bytes of blocks
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            blocks: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Looks up a block, counting a hit or a miss
+    pub(crate) fn get(&self, table_id: u64, offset: u64) -> Option<Arc<Vec<u8>>> {
+        let block = self.blocks.lock().unwrap().get(&(table_id, offset));
+
+        let counter = if block.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        block
+    }
+
+    /// Adds a block, evicting the least recently used blocks until it fits
+    ///
+    /// Blocks larger than the whole cache are not cached.
+    pub(crate) fn insert(&self, table_id: u64, offset: u64, block: Arc<Vec<u8>>) {
+        let charge = block.len();
+        self.blocks
+            .lock()
+            .unwrap()
+            .insert((table_id, offset), block, charge);
+    }
+
+    /// Get the maximum total size of the cached blocks in bytes
+    pub fn capacity(&self) -> usize {
+        self.blocks.lock().unwrap().capacity()
+    }
+
+    /// Get the total size of the cached blocks in bytes
+    pub fn usage(&self) -> usize {
+        self.blocks.lock().unwrap().usage()
+    }
+
+    /// Get the number of lookups which found their block
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of lookups which had to read their block from the file
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity())
+            .field("usage", &self.usage())
+            .field("hits", &self.hits())
+            .field("misses", &self.misses())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; len])
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let cache = BlockCache::new(100);
+        cache.insert(1, 0, block(40));
+        cache.insert(1, 40, block(40));
+
+        // block 0 was used last, so block 40 is evicted
+        assert!(cache.get(1, 0).is_some());
+        cache.insert(2, 0, block(40));
+        assert!(cache.get(1, 40).is_none());
+        assert!(cache.get(2, 0).is_some());
+        assert_eq!(cache.usage(), 80);
+
+        // too large to be cached at all
+        cache.insert(3, 0, block(101));
+        assert!(cache.get(3, 0).is_none());
+
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(cache.misses(), 2);
+    }
+}
@@ -0,0 +1,102 @@
+//! Bloom filters over the keys of an `SSTable`
+//!
+//! A filter answers whether a table may contain a key without reading its index
+//! or data blocks. It never misses a key that was added, but reports keys which
+//! were not added with a probability depending on the bits spent per key.
+//!
+//! # Format
+//!
+//! ```text
+//! bits:       [u8; n]
+//! num_probes: u8 (1 byte)
+//! ```
+
+/// Collects key hashes and builds the filter
+pub struct FilterBuilder {
+    bits_per_key: usize,
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    pub const fn new(bits_per_key: usize) -> Self {
+        Self {
+            bits_per_key,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        self.hashes.push(hash(key));
+    }
+
+    /// Encodes the filter of all added keys
+    pub fn finish(&self) -> Vec<u8> {
+        // ln(2) * bits per key minimizes the false positive rate
+        let num_probes = (self.bits_per_key * 69 / 100).clamp(1, 30);
+        let num_bits = (self.hashes.len() * self.bits_per_key).max(64);
+
+        let mut filter = vec![0u8; num_bits.div_ceil(8)];
+        let num_bits = filter.len() as u64 * 8;
+        for &hash in &self.hashes {
+            for bit in probes(hash, num_probes, num_bits) {
+                filter[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+
+        filter.push(num_probes as u8);
+        filter
+    }
+}
+
+/// Checks whether a key may have been added to an encoded filter
+///
+/// Empty or malformed filters match every key.
+pub fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    let Some((&num_probes, bits)) = filter.split_last() else {
+        return true;
+    };
+    if bits.is_empty() || num_probes == 0 {
+        return true;
+    }
+
+    let num_bits = bits.len() as u64 * 8;
+    probes(hash(key), usize::from(num_probes), num_bits)
+        .all(|bit| bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+}
+
+/// Bit positions of a key, derived from its hash by double hashing
+fn probes(hash: u64, num_probes: usize, num_bits: u64) -> impl Iterator<Item = u64> {
+    let delta = hash.rotate_left(32) | 1;
+    (0..num_probes as u64).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % num_bits)
+}
+
+/// 64-bit FNV-1a
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut builder = FilterBuilder::new(10);
+        for i in 0..1000u32 {
+            builder.add(&i.to_le_bytes());
+        }
+        let filter = builder.finish();
+
+        assert!((0..1000u32).all(|i| may_contain(&filter, &i.to_le_bytes())));
+
+        // about 1% false positives at 10 bits per key
+        let false_positives = (1000..11000u32)
+            .filter(|i| may_contain(&filter, &i.to_le_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        assert!(may_contain(&[], b"key"));
+    }
+}
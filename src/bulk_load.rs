@@ -0,0 +1,259 @@
+//! Bulk loading of sorted data without going through the memtable
+//!
+//! A `BulkLoader` writes the pairs it is given straight to `SSTables` in the
+//! directory of its column family, starting a new table whenever one reaches
+//! `Options::target_file_size`. `finish` adds all tables to the tree in one step,
+//! the way `LSMTree::ingest_external_files` does, so readers see either none or
+//! all of the loaded pairs.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::ingest::IngestedFile;
+use crate::lsm::LSMTree;
+use crate::memtable::Value;
+use crate::options::Options;
+use crate::sstable::SSTableBuilder;
+use crate::write_batch;
+use crate::{Error, Result};
+
+/// Table being written by a loader
+struct OpenTable {
+    builder: SSTableBuilder,
+    path: PathBuf,
+    /// First key of the table
+    smallest: Vec<u8>,
+}
+
+/// Loads sorted pairs into a tree, see `LSMTree::bulk_loader`
+///
+/// Dropping a loader without calling `finish` deletes the tables written so far.
+pub struct BulkLoader<'a> {
+    tree: &'a LSMTree,
+    /// Id of the family the tables go to
+    family: u32,
+    options: Arc<Options>,
+    current: Option<OpenTable>,
+    /// Finished tables
+    files: Vec<IngestedFile>,
+    /// Last key added
+    last_key: Option<Vec<u8>>,
+}
+
+impl<'a> BulkLoader<'a> {
+    pub(crate) fn new(tree: &'a LSMTree, family: u32) -> Result<Self> {
+        Ok(Self {
+            tree,
+            family,
+            options: tree.family_options(family)?,
+            current: None,
+            files: Vec::new(),
+            last_key: None,
+        })
+    }
+
+    /// Adds a pair, keys must be added in strictly increasing order
+    ///
+    /// A key out of order fails with `Error::InvalidArgument` and is not added.
+    /// The pair expires after `Options::default_ttl` if set, like a `put`.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let value = self.options.default_ttl.map_or_else(
+            || Value::Some(value.to_vec()),
+            |ttl| Value::Expiring {
+                value: value.to_vec(),
+                expires_at: write_batch::expires_at(ttl),
+            },
+        );
+        self.add_value(key, &value)
+    }
+
+    /// Adds a pair expiring at `expires_at`, in milliseconds since the UNIX
+    /// epoch, instead of after `Options::default_ttl`
+    pub(crate) fn add_expiring(&mut self, key: &[u8], value: &[u8], expires_at: u64) -> Result<()> {
+        let value = Value::Expiring {
+            value: value.to_vec(),
+            expires_at,
+        };
+        self.add_value(key, &value)
+    }
+
+    fn add_value(&mut self, key: &[u8], value: &Value) -> Result<()> {
+        if let Some(last_key) = &self.last_key {
+            if self.options.comparator.compare(last_key, key) != Ordering::Less {
+                return Err(Error::InvalidArgument(format!(
+                    "Bulk loaded key {:?} is not greater than the previous key {:?}",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(last_key)
+                )));
+            }
+        }
+
+        if self.current.is_none() {
+            let path = self.tree.new_table_path(self.family)?;
+            let builder = SSTableBuilder::with_options(path.clone(), &self.options)?;
+            self.current = Some(OpenTable {
+                builder,
+                path,
+                smallest: key.to_vec(),
+            });
+        }
+
+        let table = self.current.as_mut().unwrap();
+        table.builder.add(key, value)?;
+        let full = table.builder.data_size() >= self.options.target_file_size;
+        self.last_key = Some(key.to_vec());
+
+        if full {
+            self.finish_table()?;
+        }
+        Ok(())
+    }
+
+    /// Get the name of the order keys must be added in
+    pub(crate) fn comparator_name(&self) -> &str {
+        self.options.comparator.name()
+    }
+
+    /// Get the number of tables written so far
+    pub fn num_tables(&self) -> usize {
+        self.files.len() + usize::from(self.current.is_some())
+    }
+
+    /// Adds all loaded pairs to the tree at once
+    ///
+    /// The loaded values replace the values already stored for their keys.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_table()?;
+        let files = mem::take(&mut self.files);
+        if files.is_empty() {
+            return Ok(());
+        }
+        self.tree.add_tables(self.family, files)
+    }
+
+    /// Finishes the table being written, if any
+    fn finish_table(&mut self) -> Result<()> {
+        let Some(table) = self.current.take() else {
+            return Ok(());
+        };
+
+        if let Err(err) = table.builder.finish() {
+            let _ = fs::remove_file(&table.path);
+            return Err(err);
+        }
+
+        self.files.push(IngestedFile {
+            path: table.path,
+            smallest: table.smallest,
+            largest: self.last_key.clone().unwrap(),
+        });
+        Ok(())
+    }
+}
+
+impl Drop for BulkLoader<'_> {
+    fn drop(&mut self) {
+        // best effort, files outside the manifest are deleted on the next open
+        if let Some(table) = self.current.take() {
+            drop(table.builder);
+            let _ = fs::remove_file(&table.path);
+        }
+        for file in &self.files {
+            let _ = fs::remove_file(&file.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest;
+    use crate::test_util::temp_dir;
+    use std::path::Path;
+
+    fn table_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| manifest::sst_number(&entry.as_ref().unwrap().path()).is_some())
+            .count()
+    }
+
+    #[test]
+    fn test_bulk_load_splits_tables() {
+        let path = temp_dir("bulk_load", "splits");
+        let options = Options {
+            target_file_size: 1024,
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(&path, options.clone()).unwrap();
+        tree.put(b"k0050".to_vec(), b"old".to_vec()).unwrap();
+
+        let mut loader = tree.bulk_loader().unwrap();
+        for i in 0..200u8 {
+            let key = format!("k{i:04}");
+            loader.add(key.as_bytes(), &[i; 100]).unwrap();
+        }
+        assert!(loader.num_tables() > 10);
+
+        // nothing is visible before the loader finishes
+        assert_eq!(tree.get(b"k0100").unwrap(), None);
+        loader.finish().unwrap();
+
+        assert_eq!(tree.get(b"k0050").unwrap(), Some(vec![50; 100]));
+        assert_eq!(tree.get(b"k0199").unwrap(), Some(vec![199; 100]));
+        drop(tree);
+
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+        assert_eq!(tree.scan(b"k", b"l").unwrap().len(), 200);
+    }
+
+    #[test]
+    fn test_keys_out_of_order() {
+        let path = temp_dir("bulk_load", "out_of_order");
+        let tree = LSMTree::open(&path).unwrap();
+
+        let mut loader = tree.bulk_loader().unwrap();
+        loader.add(b"b", b"1").unwrap();
+        assert!(matches!(
+            loader.add(b"a", b"2"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            loader.add(b"b", b"3"),
+            Err(Error::InvalidArgument(_))
+        ));
+        loader.add(b"c", b"4").unwrap();
+        loader.finish().unwrap();
+
+        assert_eq!(
+            tree.scan(b"a", b"z").unwrap(),
+            vec![
+                (b"b".to_vec(), b"1".to_vec()),
+                (b"c".to_vec(), b"4".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dropped_loader_deletes_tables() {
+        let path = temp_dir("bulk_load", "dropped");
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+
+        let mut loader = tree.bulk_loader_cf(&users).unwrap();
+        for i in 0..100u32 {
+            loader.add(&i.to_be_bytes(), &[0; 500]).unwrap();
+        }
+        let dir = path.join("families").join("users");
+        assert!(table_files(&dir) > 0);
+
+        drop(loader);
+        assert_eq!(table_files(&dir), 0);
+        assert_eq!(tree.get_cf(&users, &0u32.to_be_bytes()).unwrap(), None);
+    }
+}
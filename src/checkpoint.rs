@@ -0,0 +1,159 @@
+//! Checkpoints: copies of a tree which can be opened on their own
+//!
+//! A checkpoint holds a manifest per column family, the live `SSTables` and the
+//! write-ahead logs. Tables never change once written, so they are hard-linked
+//! into the checkpoint and only copied when it is on another file system. Logs
+//! are still appended to, so they are copied up to their length at the time the
+//! checkpoint was taken.
+//!
+//! The checkpoint is assembled in a temporary directory next to its destination
+//! and renamed into place once complete, see `write_atomically`.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::column_family::{self, DEFAULT_COLUMN_FAMILY};
+use crate::manifest::{self, Manifest};
+use crate::version::Version;
+use crate::{Error, Result};
+
+/// Files of a column family taken into a checkpoint
+pub struct FamilySnapshot {
+    pub name: String,
+    /// Keeps the tables from being deleted until they are linked
+    pub version: Arc<Version>,
+    /// Sequence number of the newest write flushed to the tables
+    pub flushed_sequence: u64,
+}
+
+/// A log taken into a checkpoint
+pub struct LogSnapshot {
+    /// Name of the log file
+    pub name: PathBuf,
+    /// Open handle, so the log can be read even if it is deleted meanwhile
+    pub file: File,
+    /// Length of the log when the checkpoint was taken
+    pub len: u64,
+}
+
+impl LogSnapshot {
+    /// Opens a log and remembers its current length
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let name = path
+            .file_name()
+            .map(PathBuf::from)
+            .ok_or_else(|| Error::InvalidArgument(format!("Invalid log path {path:?}")))?;
+        Ok(Self { name, file, len })
+    }
+}
+
+/// Checks that a checkpoint can be created at `dest`
+pub fn validate_dest(dest: &Path) -> Result<()> {
+    if dest.exists() {
+        return Err(Error::InvalidArgument(format!(
+            "Directory {dest:?} already exists"
+        )));
+    }
+    if dest.file_name().is_none() {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid directory {dest:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the captured families and logs to `dest`
+pub fn write(dest: &Path, families: &[FamilySnapshot], logs: Vec<LogSnapshot>) -> Result<()> {
+    write_atomically(dest, |dir| write_files(dir, families, logs))
+}
+
+/// Fills a temporary directory with `fill` and renames it to `dest` once done
+///
+/// `dest` must have been checked with `validate_dest`. The temporary directory
+/// is removed if `fill` fails.
+pub fn write_atomically(dest: &Path, fill: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let mut tmp_name = dest.file_name().unwrap().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_dir = dest.with_file_name(tmp_name);
+
+    // leftover of an interrupted attempt
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    fs::create_dir_all(&tmp_dir)?;
+
+    let result = fill(&tmp_dir).and_then(|()| {
+        fs::rename(&tmp_dir, dest)?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&tmp_dir);
+    }
+    result
+}
+
+fn write_files(dir: &Path, families: &[FamilySnapshot], logs: Vec<LogSnapshot>) -> Result<()> {
+    for family in families {
+        let family_dir = if family.name == DEFAULT_COLUMN_FAMILY {
+            dir.to_path_buf()
+        } else {
+            column_family::family_dir(dir, &family.name)
+        };
+        fs::create_dir_all(&family_dir)?;
+
+        for sst in family.version.levels.iter().flatten() {
+            let name = sst.path().file_name().unwrap();
+            link_or_copy(sst.path(), &family_dir.join(name))?;
+        }
+
+        let manifest = Manifest {
+            files: family.version.manifest_entries(),
+            flushed_sequence: family.flushed_sequence,
+        };
+        manifest::write(&family_dir, &manifest)?;
+    }
+
+    for log in logs {
+        let mut dest = File::create(dir.join(&log.name))?;
+        io::copy(&mut log.file.take(log.len), &mut dest)?;
+        dest.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Hard-links `src` to `dest`, copies it if the two are on different file systems
+pub fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::io::Write;
+
+    #[test]
+    fn test_log_copied_up_to_snapshot() {
+        let dir = temp_dir("checkpoint", "log_prefix");
+        let log_path = dir.join("00000001.log");
+        fs::write(&log_path, b"before").unwrap();
+
+        let log = LogSnapshot::open(&log_path).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(b" after").unwrap();
+
+        let dest = dir.join("dest");
+        write(&dest, &[], vec![log]).unwrap();
+        assert_eq!(fs::read(dest.join("00000001.log")).unwrap(), b"before");
+        assert!(!dir.join("dest.tmp").exists());
+        assert!(validate_dest(&dest).is_err());
+    }
+}
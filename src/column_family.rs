@@ -0,0 +1,220 @@
+//! Column families: independent keyspaces sharing one tree
+//!
+//! Every family has its own memtables, `SSTables`, levels and options. The default
+//! family lives in the data directory itself, other families in
+//! `families/<name>` below it, each with its own manifest. All families share the
+//! write-ahead log and the sequence numbers, so a `WriteBatch` spanning several
+//! families is applied atomically.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::manifest::{self, Manifest};
+use crate::memtable::Memtable;
+use crate::options::Options;
+use crate::sstable::SSTable;
+use crate::table_cache::TableCache;
+use crate::version::Version;
+use crate::write_controller::{self, WriteStallCondition};
+use crate::{Error, Result};
+
+/// Name of the family every tree has
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Id of the default family
+pub const DEFAULT_FAMILY_ID: u32 = 0;
+
+/// Directory below the data directory holding the non-default families
+pub const FAMILIES_DIR: &str = "families";
+
+/// Handle to a column family, see `LSMTree::create_column_family`
+///
+/// Handles of dropped families are rejected by all operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnFamily {
+    id: u32,
+    name: String,
+}
+
+impl ColumnFamily {
+    pub(crate) const fn new(id: u32, name: String) -> Self {
+        Self { id, name }
+    }
+
+    /// Get the name of the family
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Id of the family while the tree is open, 0 is the default family
+    pub(crate) const fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// State of an open column family, guarded by the tree mutex
+pub struct Family {
+    pub name: String,
+    /// Directory holding the manifest and `SSTables` of the family
+    pub dir: PathBuf,
+    pub options: Arc<Options>,
+    /// Active in-memory table, written and read outside the mutex through a clone
+    pub memtable: Arc<Memtable>,
+    /// Full memtables waiting to be flushed, newest first
+    pub immutables: VecDeque<Arc<Memtable>>,
+    /// Current set of `SSTables`
+    pub version: Arc<Version>,
+    /// Largest key of the last compaction per level, to pick files round-robin
+    pub compact_pointers: Vec<Vec<u8>>,
+    /// Sequence number of the newest write flushed to the `SSTables`
+    pub flushed_sequence: u64,
+}
+
+impl Family {
+    /// Opens the family stored in `dir`, deleting files left over by interrupted
+    /// flushes or compactions
+    pub fn open(
+        name: String,
+        dir: PathBuf,
+        options: Options,
+        table_cache: &Arc<TableCache>,
+    ) -> Result<Self> {
+        options.validate()?;
+
+        // find all existing sstbales, sorted -> creation order
+        let mut sst_paths = sst_paths(&dir)?;
+        sst_paths.sort();
+
+        let comparator = Arc::clone(&options.comparator);
+        let mut version = Version::new(options.num_levels, Arc::clone(&comparator));
+        let mut flushed_sequence = 0;
+
+        if let Some(manifest) = manifest::read(&dir)? {
+            for entry in &manifest.files {
+                if entry.level >= options.num_levels {
+                    return Err(Error::InvalidArgument(format!(
+                        "Manifest has files in level {} but num_levels is {}",
+                        entry.level, options.num_levels
+                    )));
+                }
+
+                let path = manifest::sst_path(&dir, entry.number);
+                let sst = SSTable::open_cached(path, &options, table_cache)?;
+                version.levels[entry.level].push(Arc::new(sst));
+            }
+
+            // files not in the manifest are leftovers of interrupted flushes or compactions
+            for path in &sst_paths {
+                let number = manifest::sst_number(path);
+                if !manifest
+                    .files
+                    .iter()
+                    .any(|entry| Some(entry.number) == number)
+                {
+                    fs::remove_file(path)?;
+                }
+            }
+
+            flushed_sequence = manifest.flushed_sequence;
+        } else {
+            // no manifest yet, every table is an L0 table
+            for path in sst_paths {
+                let sst = SSTable::open_cached(path, &options, table_cache)?;
+                version.levels[0].push(Arc::new(sst));
+            }
+        }
+
+        version.sort_levels();
+        let flushed_sequence = flushed_sequence.max(version.largest_sequence());
+
+        let family = Self {
+            name,
+            dir,
+            compact_pointers: vec![Vec::new(); options.num_levels],
+            memtable: Arc::new(Memtable::with_rep(comparator, options.memtable_rep)),
+            options: Arc::new(options),
+            immutables: VecDeque::new(),
+            version: Arc::new(version),
+            flushed_sequence,
+        };
+        family.write_manifest(&family.version)?;
+
+        Ok(family)
+    }
+
+    /// Persists a version of the family together with its flushed sequence number
+    pub fn write_manifest(&self, version: &Version) -> Result<()> {
+        let manifest = Manifest {
+            files: version.manifest_entries(),
+            flushed_sequence: self.flushed_sequence,
+        };
+        manifest::write(&self.dir, &manifest)
+    }
+
+    /// Turns the active memtable immutable and starts a new one
+    ///
+    /// Waits for the writes still being inserted into the active memtable, an
+    /// immutable memtable doesn't change anymore.
+    pub fn switch_memtable(&mut self) {
+        self.memtable.wait_for_writers();
+        let comparator = Arc::clone(&self.options.comparator);
+        let memtable = Arc::new(Memtable::with_rep(comparator, self.options.memtable_rep));
+        let memtable = mem::replace(&mut self.memtable, memtable);
+        self.immutables.push_front(memtable);
+    }
+
+    /// Check whether the family holds writes which are not flushed yet
+    ///
+    /// Waits for the writes still being inserted into the active memtable, they
+    /// are numbered and logged already.
+    pub fn has_unflushed_data(&self) -> bool {
+        self.memtable.wait_for_writers();
+        !self.memtable.is_empty() || !self.immutables.is_empty()
+    }
+
+    /// Get the throttling state the family asks for
+    pub fn stall_condition(&self) -> WriteStallCondition {
+        write_controller::stall_condition(&self.options, self.immutables.len(), &self.version)
+    }
+
+    /// Largest `SSTable` number in the family directory
+    pub fn max_file_number(&self) -> Result<usize> {
+        Ok(sst_paths(&self.dir)?
+            .iter()
+            .filter_map(|path| manifest::sst_number(path))
+            .max()
+            .unwrap_or(0))
+    }
+}
+
+/// Directory of a non-default family
+pub fn family_dir(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(FAMILIES_DIR).join(name)
+}
+
+/// Checks that a family name can be used as a directory name
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if name.is_empty() || name.len() > 255 || !valid_chars {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid column family name {name:?}, use 1-255 characters out of [A-Za-z0-9_-]"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Paths of the `SSTables` in a directory, in no particular order
+pub fn sst_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(std::result::Result::ok)
+        .map(|entry| entry.path())
+        .filter(|p| manifest::sst_number(p).is_some())
+        .collect())
+}
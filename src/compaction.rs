@@ -0,0 +1,626 @@
+//! Leveled compaction
+//!
+//! L0 holds overlapping tables straight from memtable flushes. Once it has
+//! `level0_file_num_compaction_trigger` files all of them are merged with the
+//! overlapping L1 tables. Every deeper level has a target size, a level above its
+//! target pushes one table (picked round-robin) into the next level.
+
+use std::cmp::{self, Ordering};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::compaction_filter::{CompactionFilterContext, Decision};
+use crate::comparator::{self, Comparator};
+use crate::memtable::{Value, now_millis};
+use crate::merge_operator::{self, MergeOperator};
+use crate::options::Options;
+use crate::sstable::{SSTable, SSTableBuilder};
+use crate::table_cache::TableCache;
+use crate::version::Version;
+use crate::{Error, Result};
+
+/// Stream of sorted key-value pairs
+pub type EntryIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Value)>> + 'a>;
+
+/// A compaction picked from a version
+pub struct Compaction {
+    /// Level the compaction reads from
+    pub level: usize,
+    /// Input tables of `level`, newest first
+    pub inputs: Vec<Arc<SSTable>>,
+    /// Overlapping input tables of `level + 1`
+    pub next_inputs: Vec<Arc<SSTable>>,
+    /// Whether no deeper level holds data for the compacted key range,
+    /// in that case tombstones can be dropped
+    pub bottommost: bool,
+    /// Whether the compaction was requested through `LSMTree::compact_range`
+    pub manual: bool,
+    /// Whether the outputs replace the inputs in `level` instead of going one
+    /// level down, used to rewrite the last level of a range
+    pub in_place: bool,
+    /// Order of the keys
+    pub comparator: Arc<dyn Comparator>,
+}
+
+impl Compaction {
+    /// Level the compaction writes to
+    pub const fn output_level(&self) -> usize {
+        if self.in_place {
+            self.level
+        } else {
+            self.level + 1
+        }
+    }
+
+    /// Largest key of the input tables
+    pub fn largest_key(&self) -> Vec<u8> {
+        key_range(self.all_inputs()).1
+    }
+
+    /// All input tables, newest first
+    pub fn all_inputs(&self) -> impl Iterator<Item = &Arc<SSTable>> {
+        self.inputs.iter().chain(&self.next_inputs)
+    }
+
+    /// Builds a compaction from the given level inputs
+    fn new(version: &Version, level: usize, inputs: Vec<Arc<SSTable>>) -> Self {
+        let (start, end) = key_range(&inputs);
+        let next_inputs = version.overlapping_files(level + 1, &start, &end);
+
+        // the key range grows if the next level files stick out
+        let (start, end) = key_range(inputs.iter().chain(&next_inputs));
+        let bottommost = (level + 2..version.levels.len())
+            .all(|l| version.overlapping_files(l, &start, &end).is_empty());
+
+        Self {
+            level,
+            inputs,
+            next_inputs,
+            bottommost,
+            manual: false,
+            in_place: false,
+            comparator: Arc::clone(&version.comparator),
+        }
+    }
+
+    /// Context passed to the compaction filter
+    const fn filter_context(&self) -> CompactionFilterContext {
+        CompactionFilterContext {
+            level: self.level,
+            output_level: self.output_level(),
+            is_bottommost: self.bottommost,
+            is_manual: self.manual,
+        }
+    }
+}
+
+/// Picks the most urgent compaction, if any level exceeds its target
+///
+/// `compact_pointers` holds, per level, the largest key of the last compaction of
+/// that level so tables are picked round-robin.
+pub fn pick_compaction(
+    version: &Version,
+    options: &Options,
+    compact_pointers: &[Vec<u8>],
+) -> Option<Compaction> {
+    let (level, score) = compaction_scores(version, options)
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    if score < 1.0 {
+        return None;
+    }
+
+    if level == 0 {
+        return Some(Compaction::new(version, 0, version.levels[0].clone()));
+    }
+
+    // first table after the compact pointer, wrapping around
+    let files = &version.levels[level];
+    let pointer = compact_pointers[level].as_slice();
+    let file = files
+        .iter()
+        .find(|sst| {
+            sst.smallest_key()
+                .is_some_and(|k| version.comparator.compare(k, pointer) == Ordering::Greater)
+        })
+        .unwrap_or(&files[0]);
+
+    Some(Compaction::new(version, level, vec![Arc::clone(file)]))
+}
+
+/// Builds a compaction of the tables of `level` overlapping `[start, end]`
+///
+/// L0 tables overlap each other, so all of them are compacted as soon as one of
+/// them overlaps the range.
+pub fn range_compaction(
+    version: &Version,
+    level: usize,
+    start: &[u8],
+    end: &[u8],
+) -> Option<Compaction> {
+    let mut inputs = version.overlapping_files(level, start, end);
+    if inputs.is_empty() {
+        return None;
+    }
+
+    if level == 0 {
+        inputs.clone_from(&version.levels[0]);
+    }
+
+    let mut compaction = Compaction::new(version, level, inputs);
+    compaction.manual = true;
+    Some(compaction)
+}
+
+/// Builds a compaction rewriting the tables of `level` overlapping `[start, end]`
+/// into `level` itself
+///
+/// Used for the deepest level holding data of the range, where no compaction from
+/// the level above reaches the tables which only overlap each other.
+pub fn in_place_compaction(
+    version: &Version,
+    level: usize,
+    start: &[u8],
+    end: &[u8],
+) -> Option<Compaction> {
+    let inputs = version.overlapping_files(level, start, end);
+    if level == 0 || inputs.is_empty() {
+        return None;
+    }
+
+    let (start, end) = key_range(&inputs);
+    let bottommost = (level + 1..version.levels.len())
+        .all(|l| version.overlapping_files(l, &start, &end).is_empty());
+    Some(Compaction {
+        level,
+        inputs,
+        next_inputs: Vec::new(),
+        bottommost,
+        manual: true,
+        in_place: true,
+        comparator: Arc::clone(&version.comparator),
+    })
+}
+
+/// Estimates how many bytes compactions need to rewrite to bring every level
+/// back under its target
+pub fn pending_compaction_bytes(version: &Version, options: &Options) -> u64 {
+    let mut pending = 0;
+
+    if version.levels[0].len() >= options.level0_file_num_compaction_trigger {
+        pending += version.level_bytes(0);
+    }
+
+    // the last level has nowhere to go
+    for level in 1..version.levels.len() - 1 {
+        let bytes = version.level_bytes(level);
+        pending += bytes.saturating_sub(options.max_bytes_for_level(level));
+    }
+
+    pending
+}
+
+/// Score per level, a level with a score of at least 1 needs a compaction
+#[allow(clippy::cast_precision_loss)]
+fn compaction_scores(version: &Version, options: &Options) -> Vec<(usize, f64)> {
+    let mut scores = vec![(
+        0,
+        version.levels[0].len() as f64 / options.level0_file_num_compaction_trigger as f64,
+    )];
+
+    for level in 1..version.levels.len() - 1 {
+        let score = version.level_bytes(level) as f64 / options.max_bytes_for_level(level) as f64;
+        scores.push((level, score));
+    }
+
+    scores
+}
+
+/// Merges the inputs of a compaction into new tables
+///
+/// `new_table_path` is called for every output file.
+pub fn run(
+    compaction: &Compaction,
+    options: &Options,
+    table_cache: &Arc<TableCache>,
+    new_table_path: &mut dyn FnMut() -> PathBuf,
+) -> Result<Vec<SSTable>> {
+    let sources: Vec<EntryIter> = compaction
+        .all_inputs()
+        .map(|sst| Box::new(sst.iter()) as EntryIter)
+        .collect();
+
+    // outputs can't tell which input an entry came from, they inherit the newest
+    let largest_sequence = compaction
+        .all_inputs()
+        .map(|sst| sst.largest_sequence())
+        .max()
+        .unwrap_or(0);
+
+    let now = now_millis();
+    let mut outputs = Vec::new();
+    let mut builder: Option<(PathBuf, SSTableBuilder)> = None;
+
+    let entries = MergingIterator::new(sources)
+        .with_comparator(Arc::clone(&compaction.comparator))
+        .with_merge_operator(options.merge_operator.clone(), compaction.bottommost);
+
+    for entry in entries {
+        let (key, value) = entry?;
+
+        let Some(value) = apply_filter(compaction, options, &key, value, now) else {
+            continue;
+        };
+
+        // nothing older can be shadowed by the tombstone anymore
+        if compaction.bottommost && value == Value::Tombstone {
+            continue;
+        }
+
+        if builder.is_none() {
+            let path = new_table_path();
+            let mut new_builder = SSTableBuilder::with_options(path.clone(), options)?;
+            new_builder.set_largest_sequence(largest_sequence);
+            builder = Some((path, new_builder));
+        }
+        let (_, current) = builder.as_mut().unwrap();
+        current.add(&key, &value)?;
+
+        if current.data_size() >= options.target_file_size {
+            let (path, finished) = builder.take().unwrap();
+            finished.finish()?;
+            outputs.push(SSTable::open_cached(path, options, table_cache)?);
+        }
+    }
+
+    if let Some((path, finished)) = builder {
+        finished.finish()?;
+        outputs.push(SSTable::open_cached(path, options, table_cache)?);
+    }
+
+    Ok(outputs)
+}
+
+/// Drops expired values and passes live ones through the compaction filter,
+/// `None` drops the entry
+fn apply_filter(
+    compaction: &Compaction,
+    options: &Options,
+    key: &[u8],
+    value: Value,
+    now: u64,
+) -> Option<Value> {
+    let decision = match (&value, &options.compaction_filter) {
+        (Value::Tombstone | Value::Merge(_), _) => Decision::Keep,
+        (value, _) if value.is_expired(now) => Decision::Remove,
+        (Value::Some(val) | Value::Expiring { value: val, .. }, Some(filter)) => {
+            filter.filter(&compaction.filter_context(), key, val)
+        }
+        (_, None) => Decision::Keep,
+    };
+
+    match decision {
+        Decision::Keep => Some(value),
+        Decision::ChangeValue(new_value) => match value {
+            // a rewritten value keeps its expiration time
+            Value::Expiring { expires_at, .. } => Some(Value::Expiring {
+                value: new_value,
+                expires_at,
+            }),
+            _ => Some(Value::Some(new_value)),
+        },
+        // older versions in deeper levels must stay hidden
+        Decision::Remove if compaction.bottommost => None,
+        Decision::Remove => Some(Value::Tombstone),
+    }
+}
+
+/// Key range covered by a set of tables
+fn key_range<'a>(tables: impl IntoIterator<Item = &'a Arc<SSTable>>) -> (Vec<u8>, Vec<u8>) {
+    let mut start: Option<&[u8]> = None;
+    let mut end: Option<&[u8]> = None;
+
+    for sst in tables {
+        let comparator = sst.comparator();
+        if let Some(smallest) = sst.smallest_key() {
+            start = Some(start.map_or(smallest, |s| {
+                cmp::min_by(s, smallest, |a, b| comparator.compare(a, b))
+            }));
+        }
+        if let Some(largest) = sst.largest_key() {
+            end = Some(end.map_or(largest, |e| {
+                cmp::max_by(e, largest, |a, b| comparator.compare(a, b))
+            }));
+        }
+    }
+
+    (
+        start.unwrap_or_default().to_vec(),
+        end.unwrap_or_default().to_vec(),
+    )
+}
+
+/// Merges several sorted streams into one
+///
+/// Sources are ordered newest first: for keys present in more than one source only
+/// the entry of the newest source is returned. Merge operands of the newest source
+/// are combined with the older entries of the key, see `with_merge_operator`.
+pub struct MergingIterator<'a> {
+    sources: Vec<EntryIter<'a>>,
+    /// Current entry of each source, `None` once exhausted
+    heads: Vec<Option<(Vec<u8>, Value)>>,
+    /// Error hit while advancing a source, returned on the next call
+    error: Option<Error>,
+    /// Operator folding merge operands onto their base value
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Whether the sources hold all data of their keys, so operands without a base
+    /// value can be merged completely
+    complete: bool,
+    /// Time used to check expiration of base values
+    now: u64,
+    /// Order of the keys in the sources
+    comparator: Arc<dyn Comparator>,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub fn new(sources: Vec<EntryIter<'a>>) -> Self {
+        let mut iter = Self {
+            heads: Vec::with_capacity(sources.len()),
+            sources,
+            error: None,
+            merge_operator: None,
+            complete: false,
+            now: now_millis(),
+            comparator: comparator::bytewise(),
+        };
+
+        for idx in 0..iter.sources.len() {
+            iter.heads.push(None);
+            iter.advance(idx);
+        }
+
+        iter
+    }
+
+    /// Sets the order of the keys, bytewise by default
+    #[must_use]
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    /// Sets the operator used to resolve merge operands
+    ///
+    /// `complete` tells whether no older data of the keys exists beyond the sources.
+    #[must_use]
+    pub fn with_merge_operator(
+        mut self,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        complete: bool,
+    ) -> Self {
+        self.merge_operator = merge_operator;
+        self.complete = complete;
+        self
+    }
+
+    /// Collects the operands and base value of `key` from the sources older than
+    /// `min_idx` and combines them with `operands`
+    fn merge_older(&mut self, min_idx: usize, key: &[u8], operands: Vec<Vec<u8>>) -> Result<Value> {
+        let mut operands = operands;
+        let mut base = None;
+
+        for idx in min_idx + 1..self.heads.len() {
+            if !self.heads[idx]
+                .as_ref()
+                .is_some_and(|(k, _)| self.same_key(k, key))
+            {
+                continue;
+            }
+
+            // anything older than the base value is shadowed
+            if base.is_none() {
+                match self.heads[idx].take().unwrap().1 {
+                    Value::Merge(mut older) => {
+                        older.append(&mut operands);
+                        operands = older;
+                    }
+                    value => base = Some(value),
+                }
+            }
+            self.advance(idx);
+        }
+
+        match (&self.merge_operator, base) {
+            (Some(operator), Some(base)) => {
+                merge_operator::fold(operator.as_ref(), key, Some(&base), &operands, self.now)
+            }
+            (Some(operator), None) if self.complete => {
+                merge_operator::fold(operator.as_ref(), key, None, &operands, self.now)
+            }
+            (Some(operator), None) => {
+                // keep the operands, but collapse them if possible
+                let collapsed = (operands.len() > 1)
+                    .then(|| operator.partial_merge(key, &operands))
+                    .flatten();
+                Ok(Value::Merge(collapsed.map_or(operands, |op| vec![op])))
+            }
+            (None, None) if !self.complete => Ok(Value::Merge(operands)),
+            (None, _) => Err(Error::InvalidArgument(
+                "Found merge operands but no merge operator is configured".to_string(),
+            )),
+        }
+    }
+
+    fn same_key(&self, a: &[u8], b: &[u8]) -> bool {
+        self.comparator.compare(a, b) == Ordering::Equal
+    }
+
+    /// Moves a source to its next entry
+    fn advance(&mut self, idx: usize) {
+        self.heads[idx] = match self.sources[idx].next() {
+            Some(Ok(entry)) => Some(entry),
+            Some(Err(err)) => {
+                self.error.get_or_insert(err);
+                None
+            }
+            None => None,
+        };
+    }
+}
+
+impl Iterator for MergingIterator<'_> {
+    type Item = Result<(Vec<u8>, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+
+        // smallest key wins, on ties the newest (lowest index) source
+        let mut min_idx: Option<usize> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                let min_key = |m: usize| &self.heads[m].as_ref().unwrap().0;
+                if min_idx
+                    .is_none_or(|m| self.comparator.compare(key, min_key(m)) == Ordering::Less)
+                {
+                    min_idx = Some(idx);
+                }
+            }
+        }
+        let min_idx = min_idx?;
+        let (key, value) = self.heads[min_idx].take().unwrap();
+
+        let value = if let Value::Merge(operands) = value {
+            self.merge_older(min_idx, &key, operands)
+        } else {
+            // skip shadowed entries of older sources
+            for idx in min_idx + 1..self.heads.len() {
+                if self.heads[idx]
+                    .as_ref()
+                    .is_some_and(|(k, _)| self.same_key(k, &key))
+                {
+                    self.advance(idx);
+                }
+            }
+            Ok(value)
+        };
+        self.advance(min_idx);
+
+        Some(value.map(|value| (key, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entries: &'static [(&'static str, Option<&'static str>)]) -> EntryIter<'static> {
+        Box::new(entries.iter().map(|(k, v)| {
+            let value = v.map_or(Value::Tombstone, |v| Value::Some(v.as_bytes().to_vec()));
+            Ok((k.as_bytes().to_vec(), value))
+        }))
+    }
+
+    #[test]
+    fn test_merging_iterator_newest_wins() {
+        let newer = source(&[("a", Some("new")), ("c", None)]);
+        let older = source(&[("a", Some("old")), ("b", Some("b")), ("c", Some("c"))]);
+
+        let merged: Vec<_> = MergingIterator::new(vec![newer, older])
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            merged,
+            vec![
+                (b"a".to_vec(), Value::Some(b"new".to_vec())),
+                (b"b".to_vec(), Value::Some(b"b".to_vec())),
+                (b"c".to_vec(), Value::Tombstone),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merging_iterator_empty_sources() {
+        let mut merged = MergingIterator::new(vec![source(&[]), source(&[])]);
+        assert!(merged.next().is_none());
+    }
+
+    #[test]
+    fn test_max_bytes_for_level() {
+        let options = Options::default();
+        assert_eq!(
+            options.max_bytes_for_level(1),
+            options.max_bytes_for_level_base
+        );
+        assert_eq!(
+            options.max_bytes_for_level(3),
+            options.max_bytes_for_level_base * 100
+        );
+    }
+
+    #[test]
+    fn test_no_compaction_for_empty_version() {
+        let options = Options::default();
+        let version = Version::new(options.num_levels, comparator::bytewise());
+        let pointers = vec![Vec::new(); options.num_levels];
+
+        assert!(pick_compaction(&version, &options, &pointers).is_none());
+        assert_eq!(pending_compaction_bytes(&version, &options), 0);
+    }
+
+    fn merge_source(entries: Vec<(&'static str, Value)>) -> EntryIter<'static> {
+        Box::new(
+            entries
+                .into_iter()
+                .map(|(k, v)| Ok((k.as_bytes().to_vec(), v))),
+        )
+    }
+
+    fn operands(ops: &[&str]) -> Value {
+        Value::Merge(ops.iter().map(|op| op.as_bytes().to_vec()).collect())
+    }
+
+    #[test]
+    fn test_merging_iterator_folds_operands() {
+        let operator: Arc<dyn MergeOperator> = Arc::new(crate::AppendOperator::new(b","));
+        let sources = || {
+            vec![
+                merge_source(vec![("a", operands(&["3"])), ("b", operands(&["y"]))]),
+                merge_source(vec![("a", operands(&["1", "2"]))]),
+                merge_source(vec![
+                    ("a", Value::Some(b"0".to_vec())),
+                    ("b", Value::Tombstone),
+                ]),
+            ]
+        };
+
+        let merged: Vec<_> = MergingIterator::new(sources())
+            .with_merge_operator(Some(operator.clone()), false)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            merged,
+            vec![
+                (b"a".to_vec(), Value::Some(b"0,1,2,3".to_vec())),
+                (b"b".to_vec(), Value::Some(b"y".to_vec())),
+            ]
+        );
+
+        // without a base value operands are only collapsed, unless the sources are complete
+        let newer_only = || vec![sources().remove(0), sources().remove(1)];
+        let partial: Vec<_> = MergingIterator::new(newer_only())
+            .with_merge_operator(Some(operator.clone()), false)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(partial[0], (b"a".to_vec(), operands(&["1,2,3"])));
+
+        let complete: Vec<_> = MergingIterator::new(newer_only())
+            .with_merge_operator(Some(operator), true)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(complete[0], (b"a".to_vec(), Value::Some(b"1,2,3".to_vec())));
+    }
+}
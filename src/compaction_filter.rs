@@ -0,0 +1,47 @@
+//! Hook to drop or rewrite values while they are compacted
+//!
+//! A filter registered through `Options::compaction_filter` sees every live value
+//! read by a compaction. Tombstones are not passed to the filter.
+
+use std::fmt;
+
+/// What a compaction does with a value after asking the filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Write the value unchanged
+    Keep,
+    /// Drop the value, the key reads as deleted afterwards
+    Remove,
+    /// Replace the value
+    ChangeValue(Vec<u8>),
+}
+
+/// Describes the compaction a filter is called from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionFilterContext {
+    /// Level the compaction reads from
+    pub level: usize,
+    /// Level the compaction writes to
+    pub output_level: usize,
+    /// Whether no deeper level holds data of the compacted key range
+    pub is_bottommost: bool,
+    /// Whether the compaction was requested through `LSMTree::compact_range`
+    pub is_manual: bool,
+}
+
+/// Decides per key-value pair what a compaction keeps
+///
+/// Called from the compaction thread, so implementations must be thread-safe.
+pub trait CompactionFilter: Send + Sync {
+    /// Name of the filter, used in debug output
+    fn name(&self) -> &str;
+
+    /// Decides what happens to `value` stored under `key`
+    fn filter(&self, context: &CompactionFilterContext, key: &[u8], value: &[u8]) -> Decision;
+}
+
+impl fmt::Debug for dyn CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CompactionFilter({})", self.name())
+    }
+}
@@ -0,0 +1,115 @@
+//! Key ordering
+//!
+//! A `Comparator` defines the order of keys in memtables, `SSTables`, scans and
+//! compactions. Its name is stored in every `SSTable`, opening a table with a
+//! comparator of a different name fails, as the table would be read in the wrong
+//! order.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+
+/// Total order over keys
+///
+/// Keys comparing as `Equal` are the same key, even if their bytes differ.
+/// Called from reads and from the compaction thread, so implementations must be
+/// thread-safe.
+pub trait Comparator: Send + Sync {
+    /// Name of the ordering, persisted in every `SSTable`
+    ///
+    /// Must change whenever the ordering changes.
+    fn name(&self) -> &str;
+
+    /// Compares two keys
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+impl fmt::Debug for dyn Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Comparator({})", self.name())
+    }
+}
+
+/// Lexicographic order of the key bytes, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &'static str {
+        "lsm-tree-kv.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Reversed lexicographic order of the key bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &'static str {
+        "lsm-tree-kv.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// The comparator used unless `Options::comparator` says otherwise
+pub fn bytewise() -> Arc<dyn Comparator> {
+    Arc::new(BytewiseComparator)
+}
+
+/// Key of a sorted map, ordered by a comparator instead of its bytes
+#[derive(Clone)]
+pub struct OrderedKey {
+    pub key: Vec<u8>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl OrderedKey {
+    pub const fn new(key: Vec<u8>, comparator: Arc<dyn Comparator>) -> Self {
+        Self { key, comparator }
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator.compare(&self.key, &other.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_ordered_key_uses_comparator() {
+        let reverse: Arc<dyn Comparator> = Arc::new(ReverseBytewiseComparator);
+        let keys: BTreeSet<OrderedKey> = [b"a", b"c", b"b"]
+            .into_iter()
+            .map(|key| OrderedKey::new(key.to_vec(), Arc::clone(&reverse)))
+            .collect();
+
+        let sorted: Vec<&[u8]> = keys.iter().map(|key| key.key.as_slice()).collect();
+        assert_eq!(sorted, vec![b"c", b"b", b"a"]);
+    }
+}
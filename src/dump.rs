@@ -0,0 +1,313 @@
+//! Portable dump format for `LSMTree::export` and `LSMTree::import`
+//!
+//! A dump only holds the live pairs in key order, independent of how the tree
+//! stores them, so it can be loaded by any later version of the engine.
+//!
+//! # Format
+//!
+//! ```text
+//! magic:          [u8; 8]   // "LSMKVDMP"
+//! version:        u32       // 1
+//! comparator_len: u32
+//! comparator:     [u8; comparator_len]  // name of the order of the keys
+//! checksum:       u32       // CRC-32 of version, comparator_len and comparator
+//! for each pair:
+//!   tag:          u8        // 1, or 2 for a pair with an expiration time
+//!   key_len:      u32
+//!   key:          [u8; key_len]
+//!   value_len:    u32
+//!   value:        [u8; value_len]
+//!   expires_at:   u64       // only for tag 2, milliseconds since the UNIX epoch
+//!   checksum:     u32       // CRC-32 of tag up to the field before
+//! trailer:
+//!   tag:          u8        // 0
+//!   count:        u64       // number of pairs
+//!   checksum:     u32       // CRC-32 of tag and count
+//! ```
+//!
+//! All integers are little endian. A dump without trailer was cut off.
+
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+use crate::crc32;
+use crate::{Error, Result};
+
+/// Identifies a dump
+const MAGIC: &[u8; 8] = b"LSMKVDMP";
+
+/// Version of the format written
+const VERSION: u32 = 1;
+
+const TRAILER_TAG: u8 = 0;
+const PAIR_TAG: u8 = 1;
+const EXPIRING_PAIR_TAG: u8 = 2;
+
+/// Key, value and expiration time of a pair read from a dump
+pub type Pair = (Vec<u8>, Vec<u8>, Option<u64>);
+
+/// Writes pairs to a dump
+pub struct DumpWriter<W: Write> {
+    writer: BufWriter<W>,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Writes the header of a dump of keys sorted by `comparator`
+    pub fn new(writer: W, comparator: &str) -> Result<Self> {
+        let mut header = VERSION.to_le_bytes().to_vec();
+        put_bytes(&mut header, comparator.as_bytes());
+
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&header)?;
+        writer.write_all(&crc32::crc32(&header).to_le_bytes())?;
+        Ok(Self { writer, count: 0 })
+    }
+
+    /// Appends a pair which expires at `expires_at`, if set, pairs must be added
+    /// in key order
+    pub fn add(&mut self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let mut record = vec![if expires_at.is_some() {
+            EXPIRING_PAIR_TAG
+        } else {
+            PAIR_TAG
+        }];
+        put_bytes(&mut record, key);
+        put_bytes(&mut record, value);
+        if let Some(expires_at) = expires_at {
+            record.extend_from_slice(&expires_at.to_le_bytes());
+        }
+
+        self.writer.write_all(&record)?;
+        self.writer
+            .write_all(&crc32::crc32(&record).to_le_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Writes the trailer, returns the number of pairs written
+    pub fn finish(mut self) -> Result<u64> {
+        let mut trailer = vec![TRAILER_TAG];
+        trailer.extend_from_slice(&self.count.to_le_bytes());
+
+        self.writer.write_all(&trailer)?;
+        self.writer
+            .write_all(&crc32::crc32(&trailer).to_le_bytes())?;
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Reads the pairs of a dump, checking every checksum
+pub struct DumpReader<R: Read> {
+    reader: BufReader<R>,
+    /// Name of the order of the keys
+    comparator: String,
+    /// Number of pairs read so far
+    count: u64,
+    /// Set once the trailer was read
+    done: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Reads the header of a dump
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+
+        let mut magic = [0; 8];
+        read_exact(&mut reader, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Corruption("Not a dump, invalid magic".to_string()));
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            return Err(Error::InvalidArgument(format!(
+                "Unsupported dump version {version}, expected {VERSION}"
+            )));
+        }
+        let comparator = read_bytes(&mut reader)?;
+
+        let mut header = version.to_le_bytes().to_vec();
+        put_bytes(&mut header, &comparator);
+        check(&mut reader, &header, "header")?;
+
+        let comparator = String::from_utf8(comparator)
+            .map_err(|_| Error::Corruption("Invalid comparator name in dump".to_string()))?;
+        Ok(Self {
+            reader,
+            comparator,
+            count: 0,
+            done: false,
+        })
+    }
+
+    /// Get the name of the comparator the keys are sorted by
+    pub fn comparator(&self) -> &str {
+        &self.comparator
+    }
+
+    /// Reads the next pair, `None` once the trailer was reached
+    pub fn next_pair(&mut self) -> Result<Option<Pair>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut tag = [0];
+        read_exact(&mut self.reader, &mut tag)?;
+        match tag[0] {
+            tag @ (PAIR_TAG | EXPIRING_PAIR_TAG) => {
+                let key = read_bytes(&mut self.reader)?;
+                let value = read_bytes(&mut self.reader)?;
+                let expires_at = if tag == EXPIRING_PAIR_TAG {
+                    let mut buf = [0; 8];
+                    read_exact(&mut self.reader, &mut buf)?;
+                    Some(u64::from_le_bytes(buf))
+                } else {
+                    None
+                };
+
+                let mut record = vec![tag];
+                put_bytes(&mut record, &key);
+                put_bytes(&mut record, &value);
+                if let Some(expires_at) = expires_at {
+                    record.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                check(&mut self.reader, &record, "pair")?;
+
+                self.count += 1;
+                Ok(Some((key, value, expires_at)))
+            }
+            TRAILER_TAG => {
+                let mut count = [0; 8];
+                read_exact(&mut self.reader, &mut count)?;
+
+                let mut trailer = tag.to_vec();
+                trailer.extend_from_slice(&count);
+                check(&mut self.reader, &trailer, "trailer")?;
+
+                let count = u64::from_le_bytes(count);
+                if count != self.count {
+                    return Err(Error::Corruption(format!(
+                        "Dump has {} pairs, its trailer says {count}",
+                        self.count
+                    )));
+                }
+                self.done = true;
+                Ok(None)
+            }
+            tag => Err(Error::Corruption(format!("Invalid dump record tag {tag}"))),
+        }
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Reads a checksum and compares it with the one of `data`
+fn check(reader: &mut impl Read, data: &[u8], what: &str) -> Result<()> {
+    if read_u32(reader)? != crc32::crc32(data) {
+        return Err(Error::Corruption(format!("Dump {what} checksum mismatch")));
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(truncated());
+    }
+    Ok(bytes)
+}
+
+/// Like `Read::read_exact`, but reports a cut off dump as corruption
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|err| {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            truncated()
+        } else {
+            err.into()
+        }
+    })
+}
+
+fn truncated() -> Error {
+    Error::Corruption("Dump is truncated".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(pairs: &[(&[u8], &[u8], Option<u64>)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut writer = DumpWriter::new(&mut buf, "test.Comparator").unwrap();
+        for (key, value, expires_at) in pairs {
+            writer.add(key, value, *expires_at).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), pairs.len() as u64);
+        buf
+    }
+
+    fn read_all(buf: &[u8]) -> Result<Vec<Pair>> {
+        let mut reader = DumpReader::new(buf)?;
+        let mut pairs = Vec::new();
+        while let Some(pair) = reader.next_pair()? {
+            pairs.push(pair);
+        }
+        Ok(pairs)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let buf = dump(&[(b"a", b"1", None), (b"b", b"", Some(42))]);
+
+        let reader = DumpReader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.comparator(), "test.Comparator");
+        assert_eq!(
+            read_all(&buf).unwrap(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec(), None),
+                (b"b".to_vec(), Vec::new(), Some(42))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_corruption_detected() {
+        let buf = dump(&[(b"a", b"1", None), (b"b", b"2", None)]);
+
+        // flipped value byte
+        let mut flipped = buf.clone();
+        let pos = flipped.len() - 13 - 5;
+        flipped[pos] ^= 1;
+        assert!(matches!(read_all(&flipped), Err(Error::Corruption(_))));
+
+        // flipped expiration time
+        let mut flipped = dump(&[(b"a", b"1", Some(42))]);
+        let pos = flipped.len() - 13 - 5;
+        flipped[pos] ^= 1;
+        assert!(matches!(read_all(&flipped), Err(Error::Corruption(_))));
+
+        // cut off before the trailer
+        let cut = &buf[..buf.len() - 13];
+        assert!(matches!(read_all(cut), Err(Error::Corruption(_))));
+
+        let mut newer = buf;
+        newer[8] = 2;
+        assert!(matches!(
+            DumpReader::new(newer.as_slice()),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
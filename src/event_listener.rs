@@ -0,0 +1,111 @@
+//! Callbacks on flushes, compactions, table files and write stalls
+//!
+//! Listeners are registered through `Options::listeners`. Callbacks run on the
+//! thread doing the work, some of them while the tree is locked, so they should
+//! return quickly and must not call back into the tree.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::Error;
+use crate::write_controller::WriteStallCondition;
+
+/// A table file written by a flush or compaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableFileInfo {
+    /// Name of the column family the table belongs to
+    pub column_family: String,
+    pub path: PathBuf,
+    /// Level the table was added to
+    pub level: usize,
+    /// Size of the file in bytes
+    pub file_size: u64,
+    pub num_entries: u32,
+}
+
+/// A table file deleted after it was compacted away or its family was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableFileDeletionInfo {
+    pub path: PathBuf,
+    /// Size of the file in bytes
+    pub file_size: u64,
+}
+
+/// A flush of one immutable memtable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushJobInfo {
+    /// Name of the column family the memtable belongs to
+    pub column_family: String,
+    /// Path of the L0 table the memtable is written to
+    pub path: PathBuf,
+    /// Number of keys in the memtable
+    pub num_entries: usize,
+    /// Sequence number of the newest write in the memtable
+    pub largest_sequence: u64,
+}
+
+/// A finished compaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionJobInfo {
+    /// Name of the compacted column family
+    pub column_family: String,
+    /// Level the compaction read from
+    pub level: usize,
+    /// Level the compaction wrote to
+    pub output_level: usize,
+    /// Whether the compaction was requested through `LSMTree::compact_range`
+    pub is_manual: bool,
+    /// Tables read, at `level` or `output_level`
+    pub inputs: Vec<TableFileInfo>,
+    /// Tables written to `output_level`
+    pub outputs: Vec<TableFileInfo>,
+    pub elapsed: Duration,
+}
+
+/// Background work which failed and stopped all further writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundErrorReason {
+    Flush,
+    Compaction,
+    /// Appending to the write-ahead log
+    WriteAheadLog,
+}
+
+/// A change of the throttling state of the write path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStallInfo {
+    pub previous: WriteStallCondition,
+    pub current: WriteStallCondition,
+}
+
+/// Receives events of a tree, every callback does nothing by default
+pub trait EventListener: Send + Sync {
+    /// Called before a memtable is written to `info.path`
+    fn on_flush_begin(&self, _info: &FlushJobInfo) {}
+
+    /// Called once a flushed table is part of the tree
+    fn on_flush_completed(&self, _info: &FlushJobInfo, _table: &TableFileInfo) {}
+
+    /// Called once the outputs of a compaction replaced its inputs
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo) {}
+
+    /// Called for every table file added to the tree, by flushes and compactions
+    fn on_table_file_created(&self, _info: &TableFileInfo) {}
+
+    /// Called once an obsolete table file was deleted, which happens when the
+    /// last reader lets go of it
+    fn on_table_file_deleted(&self, _info: &TableFileDeletionInfo) {}
+
+    /// Called when background work failed, writes fail from then on
+    fn on_background_error(&self, _reason: BackgroundErrorReason, _error: &Error) {}
+
+    /// Called when writes start or stop being delayed or stopped
+    fn on_stall_conditions_changed(&self, _info: &WriteStallInfo) {}
+}
+
+impl fmt::Debug for dyn EventListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventListener")
+    }
+}
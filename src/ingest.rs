@@ -0,0 +1,141 @@
+//! Ingestion of `SSTables` built outside the tree, see `LSMTree::ingest_external_files`
+//!
+//! Files are copied into the family directory first and checked there: their
+//! keys must be sorted by the comparator of the family and the files must not
+//! overlap each other. The tree then gives all of them one new sequence number,
+//! so they are newer than every write before, and adds each file to the deepest
+//! level where neither that level nor any level above holds an overlapping
+//! table. A file overlapping L0 stays in L0.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::comparator::Comparator;
+use crate::options::Options;
+use crate::sstable::SSTable;
+use crate::version::Version;
+use crate::{Error, Result};
+
+/// A checked table inside the family directory waiting to be added to the tree
+pub struct IngestedFile {
+    /// Path inside the family directory
+    pub path: PathBuf,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+}
+
+/// Copies an external file to `dest` and checks its keys
+pub fn prepare(src: &Path, dest: PathBuf, options: &Options) -> Result<IngestedFile> {
+    fs::copy(src, &dest)?;
+
+    match key_range(src, &dest, options) {
+        Ok((smallest, largest)) => Ok(IngestedFile {
+            path: dest,
+            smallest,
+            largest,
+        }),
+        Err(err) => {
+            let _ = fs::remove_file(&dest);
+            Err(err)
+        }
+    }
+}
+
+/// Reads all keys of a table, returns its smallest and largest key
+fn key_range(src: &Path, path: &Path, options: &Options) -> Result<(Vec<u8>, Vec<u8>)> {
+    let table = SSTable::open_with_options(path.to_path_buf(), options)?;
+
+    let mut previous: Option<Vec<u8>> = None;
+    for entry in &table {
+        let (key, _) = entry?;
+        if let Some(previous) = &previous {
+            if options.comparator.compare(previous, &key) != Ordering::Less {
+                return Err(Error::InvalidArgument(format!(
+                    "{src:?} is not sorted by comparator {:?}",
+                    options.comparator.name()
+                )));
+            }
+        }
+        previous = Some(key);
+    }
+
+    match (table.smallest_key(), table.largest_key()) {
+        (Some(smallest), Some(largest)) => Ok((smallest.to_vec(), largest.to_vec())),
+        _ => Err(Error::InvalidArgument(format!("{src:?} has no entries"))),
+    }
+}
+
+/// Sorts the files by key and checks that they don't overlap each other
+pub fn check_overlaps(files: &mut [IngestedFile], comparator: &dyn Comparator) -> Result<()> {
+    files.sort_by(|a, b| comparator.compare(&a.smallest, &b.smallest));
+
+    for pair in files.windows(2) {
+        if comparator.compare(&pair[0].largest, &pair[1].smallest) != Ordering::Less {
+            return Err(Error::InvalidArgument(
+                "Ingested files overlap each other".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Deepest level a file can go to without hiding newer data or overlapping a
+/// table of that level
+pub fn target_level(version: &Version, smallest: &[u8], largest: &[u8]) -> usize {
+    let mut target = 0;
+    for (level, tables) in version.levels.iter().enumerate() {
+        if tables.iter().any(|sst| sst.overlaps(smallest, largest)) {
+            break;
+        }
+        target = level;
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+    use crate::memtable::Value;
+    use crate::sstable::SSTableBuilder;
+    use crate::test_util::temp_dir;
+    use std::sync::Arc;
+
+    /// Claims to be the bytewise order but sorts in reverse
+    struct Impostor;
+
+    impl Comparator for Impostor {
+        fn name(&self) -> &str {
+            BytewiseComparator.name()
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn test_prepare_rejects_unsorted_file() {
+        let dir = temp_dir("ingest", "unsorted");
+        let src = dir.join("src.sst");
+        let options = Options {
+            comparator: Arc::new(Impostor),
+            ..Options::default()
+        };
+        let mut builder = SSTableBuilder::with_options(src.clone(), &options).unwrap();
+        for key in [b"c", b"b", b"a"] {
+            builder.add(key, &Value::Some(b"v".to_vec())).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let dest = dir.join("00000001.sst");
+        let result = prepare(&src, dest.clone(), &Options::default());
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert!(!dest.exists());
+
+        let file = prepare(&src, dest, &options).unwrap();
+        assert_eq!(file.smallest, b"c");
+        assert_eq!(file.largest, b"a");
+    }
+}
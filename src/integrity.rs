@@ -0,0 +1,78 @@
+//! Integrity check of the live tables, see `LSMTree::verify_integrity`
+//!
+//! Every table is read from disk end to end with `sstable::verify_table`,
+//! bypassing the block cache, and the tables of L1 and deeper are checked not to
+//! overlap. Damage is collected per table instead of ending the check.
+
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::sstable;
+use crate::version::Version;
+
+/// What `LSMTree::verify_integrity` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of live tables read
+    pub tables_checked: usize,
+    /// Number of entries read from them
+    pub entries_checked: u64,
+    /// Tables with damage, in the order they were checked
+    pub damaged: Vec<DamagedTable>,
+}
+
+impl IntegrityReport {
+    /// Check whether no damage was found
+    pub fn is_ok(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+/// A live table with damage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedTable {
+    /// Column family the table belongs to
+    pub family: String,
+    pub level: usize,
+    pub path: PathBuf,
+    /// Descriptions of the damage found
+    pub problems: Vec<String>,
+}
+
+/// Checks the tables of the given column families
+pub fn verify(families: &[(String, Arc<Version>)]) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+
+    for (family, version) in families {
+        let comparator = version.comparator.as_ref();
+        for (level, tables) in version.levels.iter().enumerate() {
+            for (i, sst) in tables.iter().enumerate() {
+                let check = sstable::verify_table(sst.path(), comparator);
+                let mut problems = check.problems;
+
+                // deeper levels are sorted by key and don't overlap
+                if level > 0 && i > 0 {
+                    let previous = tables[i - 1].largest_key().unwrap_or_default();
+                    let smallest = sst.smallest_key().unwrap_or_default();
+                    if comparator.compare(previous, smallest) != Ordering::Less {
+                        problems.push(format!("Overlaps the previous table of level {level}"));
+                    }
+                }
+
+                report.tables_checked += 1;
+                report.entries_checked += check.entries;
+                if !problems.is_empty() {
+                    report.damaged.push(DamagedTable {
+                        family: family.clone(),
+                        level,
+                        path: sst.path().clone(),
+                        problems,
+                    });
+                }
+            }
+        }
+    }
+
+    report
+}
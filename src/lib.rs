@@ -1,10 +1,66 @@
+mod backup;
+mod block_cache;
+mod bloom;
+mod bulk_load;
+mod checkpoint;
+mod column_family;
+mod compaction;
+mod compaction_filter;
+mod comparator;
+mod crc32;
+mod dump;
+mod event_listener;
+mod ingest;
+mod integrity;
+mod lock_manager;
+mod lru;
 mod lsm;
+mod manifest;
 mod memtable;
+mod memtable_rep;
+mod merge_operator;
+mod mmap;
+mod multi_get;
+mod options;
+mod pinned;
+mod repair;
+mod skiplist;
 mod sstable;
+mod statistics;
+mod table_cache;
+#[cfg(test)]
+mod test_util;
+mod transaction;
+mod version;
+mod wal;
+mod write_batch;
+mod write_buffer_manager;
+mod write_controller;
 
+pub use backup::{BackupEngine, BackupInfo};
+pub use block_cache::BlockCache;
+pub use bulk_load::BulkLoader;
+pub use column_family::ColumnFamily;
+pub use compaction_filter::{CompactionFilter, CompactionFilterContext, Decision};
+pub use comparator::{BytewiseComparator, Comparator, ReverseBytewiseComparator};
+pub use event_listener::{
+    BackgroundErrorReason, CompactionJobInfo, EventListener, FlushJobInfo, TableFileDeletionInfo,
+    TableFileInfo, WriteStallInfo,
+};
+pub use integrity::{DamagedTable, IntegrityReport};
 pub use lsm::LSMTree;
-pub use memtable::{Memtable, Value};
-pub use sstable::{SSTable, SSTableBuilder};
+pub use memtable::{Memtable, MemtableIter, Value};
+pub use memtable_rep::MemtableRepKind;
+pub use merge_operator::{AppendOperator, MergeOperator, U64AddOperator};
+pub use options::{BLOCK_CACHE_CAPACITY, MEMTABLE_SIZE_THRESHOLD, Options};
+pub use pinned::PinnedValue;
+pub use repair::RepairReport;
+pub use sstable::{SSTable, SSTableBuilder, SSTableIter};
+pub use statistics::{Histogram, HistogramData, MAX_TRACKED_LEVELS, Statistics, Ticker};
+pub use transaction::Transaction;
+pub use write_batch::WriteBatch;
+pub use write_buffer_manager::WriteBufferManager;
+pub use write_controller::{StallStats, WriteStallCondition};
 
 use std::io;
 
@@ -17,6 +73,12 @@ pub enum Error {
     Corruption(String),
     // invalid operation or argument
     InvalidArgument(String),
+    // transaction conflicts with a write committed after it started
+    Conflict(String),
+    // waiting for a lock would deadlock
+    Busy(String),
+    // lock not acquired within the timeout
+    TimedOut(String),
 }
 
 impl std::fmt::Display for Error {
@@ -25,6 +87,9 @@ impl std::fmt::Display for Error {
             Self::Io(err) => write!(f, "I/O error: {err}"),
             Self::Corruption(msg) => write!(f, "Corruption: {msg}"),
             Self::InvalidArgument(msg) => write!(f, "Invalid argument: {msg}"),
+            Self::Conflict(msg) => write!(f, "Conflict: {msg}"),
+            Self::Busy(msg) => write!(f, "Busy: {msg}"),
+            Self::TimedOut(msg) => write!(f, "Timed out: {msg}"),
         }
     }
 }
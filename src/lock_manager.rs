@@ -0,0 +1,174 @@
+//! Per-key locks for pessimistic transactions
+//!
+//! Every key is held by at most one transaction. A transaction that has to wait
+//! records which transaction it waits for. Each waiting transaction waits for a
+//! single holder, so a deadlock shows up as the holder chain leading back to the
+//! transaction that is about to wait.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+
+/// Hands out key locks to transactions, identified by an id
+pub struct LockManager {
+    /// Source of transaction ids
+    next_id: AtomicU64,
+    table: Mutex<LockTable>,
+    /// Signalled whenever locks are released
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockTable {
+    /// Key -> id of the transaction holding it
+    holders: HashMap<Vec<u8>, u64>,
+    /// Id of a waiting transaction -> id of the transaction it waits for
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    /// Check whether `waiter` waiting for `holder` closes a cycle
+    fn would_deadlock(&self, waiter: u64, holder: u64) -> bool {
+        let mut current = holder;
+        // the chain is at most as long as the number of waiting transactions
+        for _ in 0..=self.waits_for.len() {
+            if current == waiter {
+                return true;
+            }
+            match self.waits_for.get(&current) {
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Get a new transaction id
+    pub fn new_transaction_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Locks `key` for transaction `id`, waiting up to `timeout` for its holder
+    ///
+    /// Fails with `Error::Busy` if waiting would deadlock and with `Error::TimedOut`
+    /// once the timeout has passed. Locking a key twice is a no-op.
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn lock(&self, id: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock().unwrap();
+
+        loop {
+            let holder = match table.holders.get(key) {
+                None => {
+                    table.holders.insert(key.to_vec(), id);
+                    table.waits_for.remove(&id);
+                    return Ok(());
+                }
+                Some(&holder) if holder == id => return Ok(()),
+                Some(&holder) => holder,
+            };
+
+            if table.would_deadlock(id, holder) {
+                table.waits_for.remove(&id);
+                return Err(Error::Busy(format!(
+                    "deadlock detected while locking key {:?}",
+                    String::from_utf8_lossy(key)
+                )));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits_for.remove(&id);
+                return Err(Error::TimedOut(format!(
+                    "timed out waiting for the lock on key {:?}",
+                    String::from_utf8_lossy(key)
+                )));
+            }
+
+            table.waits_for.insert(id, holder);
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Releases the locks transaction `id` holds on `keys`
+    pub fn unlock<'a>(&self, id: u64, keys: impl IntoIterator<Item = &'a Vec<u8>>) {
+        let mut table = self.table.lock().unwrap();
+        for key in keys {
+            if table.holders.get(key) == Some(&id) {
+                table.holders.remove(key);
+            }
+        }
+        drop(table);
+
+        self.released.notify_all();
+    }
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn test_lock_timeout() {
+        let locks = LockManager::new();
+        let (a, b) = (locks.new_transaction_id(), locks.new_transaction_id());
+
+        locks.lock(a, b"key", TIMEOUT).unwrap();
+        // reentrant
+        locks.lock(a, b"key", TIMEOUT).unwrap();
+
+        assert!(matches!(
+            locks.lock(b, b"key", TIMEOUT),
+            Err(Error::TimedOut(_))
+        ));
+
+        locks.unlock(a, [&b"key".to_vec()]);
+        locks.lock(b, b"key", TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let locks = LockManager::new();
+        let (a, b) = (locks.new_transaction_id(), locks.new_transaction_id());
+        locks.lock(a, b"x", TIMEOUT).unwrap();
+        locks.lock(b, b"y", TIMEOUT).unwrap();
+
+        std::thread::scope(|scope| {
+            // a waits for b ...
+            let waiter = scope.spawn(|| locks.lock(a, b"y", Duration::from_secs(10)));
+            while !locks.table.lock().unwrap().waits_for.contains_key(&a) {
+                std::thread::yield_now();
+            }
+
+            // ... so b waiting for a would never finish
+            assert!(matches!(
+                locks.lock(b, b"x", Duration::from_secs(10)),
+                Err(Error::Busy(_))
+            ));
+
+            locks.unlock(b, [&b"y".to_vec()]);
+            waiter.join().unwrap().unwrap();
+        });
+    }
+}
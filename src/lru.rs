@@ -0,0 +1,111 @@
+//! Least-recently-used map bounded by the total charge of its entries
+//!
+//! Not thread-safe on its own, the caches built on it wrap it in a mutex.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+pub struct LruCache<K, V> {
+    /// Maximum total charge of the entries
+    capacity: usize,
+    /// Entries with their charge and the tick of their last use
+    entries: HashMap<K, (V, usize, u64)>,
+    /// Tick of the last use -> key, the first entry is evicted first
+    order: BTreeMap<u64, K>,
+    /// Incremented on every use
+    tick: u64,
+    /// Total charge of the entries
+    usage: usize,
+}
+
+impl<K: Hash + Eq + Copy, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            usage: 0,
+        }
+    }
+
+    /// Looks up an entry and marks it as used right now
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (value, _, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        *last_used = tick;
+        self.order.insert(tick, *key);
+        Some(value.clone())
+    }
+
+    /// Adds an entry unless the key is cached already, evicting the least
+    /// recently used entries until it fits
+    ///
+    /// Entries charging more than the whole capacity are not cached.
+    pub fn insert(&mut self, key: K, value: V, charge: usize) {
+        if charge > self.capacity || self.get(&key).is_some() {
+            return;
+        }
+
+        while self.usage + charge > self.capacity {
+            let Some((_, evicted)) = self.order.pop_first() else {
+                break;
+            };
+            let (_, evicted_charge, _) = self.entries.remove(&evicted).unwrap();
+            self.usage -= evicted_charge;
+        }
+
+        let tick = self.tick;
+        self.usage += charge;
+        self.order.insert(tick, key);
+        self.entries.insert(key, (value, charge, tick));
+    }
+
+    /// Removes an entry
+    pub fn remove(&mut self, key: &K) {
+        if let Some((_, charge, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.usage -= charge;
+        }
+    }
+
+    /// Get the total charge of the entries
+    pub const fn usage(&self) -> usize {
+        self.usage
+    }
+
+    /// Get the maximum total charge of the entries
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut lru = LruCache::new(100);
+        lru.insert(1, "a", 40);
+        lru.insert(2, "b", 40);
+
+        // 1 was used last, so 2 is evicted
+        assert_eq!(lru.get(&1), Some("a"));
+        lru.insert(3, "c", 40);
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some("a"));
+        assert_eq!(lru.get(&3), Some("c"));
+        assert_eq!(lru.usage(), 80);
+
+        // too large to be cached at all
+        lru.insert(4, "d", 101);
+        assert_eq!(lru.get(&4), None);
+
+        lru.remove(&1);
+        assert_eq!(lru.usage(), 40);
+    }
+}
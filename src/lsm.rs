@@ -1,307 +1,3894 @@
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
+use std::io::{Read, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::Result;
-use crate::memtable::{Memtable, Value};
-use crate::sstable::{SSTable, SSTableBuilder};
-
-const MEMTABLE_SIZE_THRESHOLD: usize = 4096; // 4KB
+use crate::bulk_load::BulkLoader;
+use crate::checkpoint::{self, FamilySnapshot, LogSnapshot};
+use crate::column_family::{
+    self, ColumnFamily, DEFAULT_COLUMN_FAMILY, DEFAULT_FAMILY_ID, FAMILIES_DIR, Family,
+};
+use crate::compaction::{self, Compaction, EntryIter, MergingIterator};
+use crate::dump::{DumpReader, DumpWriter};
+use crate::event_listener::{
+    BackgroundErrorReason, CompactionJobInfo, EventListener, FlushJobInfo, TableFileInfo,
+    WriteStallInfo,
+};
+use crate::ingest::{self, IngestedFile};
+use crate::integrity::{self, IntegrityReport};
+use crate::lock_manager::LockManager;
+use crate::manifest::{self, MANIFEST_FILE, Manifest};
+use crate::memtable::{Memtable, Value, now_millis};
+use crate::merge_operator::{self, MergeOperator};
+use crate::multi_get::{self, KeyLookup};
+use crate::options::Options;
+use crate::pinned::{Lookup, PinnedValue};
+use crate::repair::{self, RepairReport};
+use crate::sstable::{self, SSTable, SSTableBuilder};
+use crate::statistics::{Histogram, Statistics, StopWatch, Ticker};
+use crate::table_cache::TableCache;
+use crate::transaction::{FlushedWrites, Transaction};
+use crate::version::Version;
+use crate::wal::{self, Wal};
+use crate::write_batch::WriteBatch;
+use crate::write_buffer_manager::MemoryUsage;
+use crate::write_controller::{self, StallStats, WriteStallCondition};
+use crate::{Error, Result};
 
 /// The main LSM-Tree structure
+///
+/// All methods take `&self`, the tree can be shared between threads.
 pub struct LSMTree {
-    /// Active in-memory table
-    memtable: Memtable,
-    /// Queue of flushed `SSTables` (L0)
-    l0_sstables: VecDeque<SSTable>,
+    /// State shared with the compaction thread
+    inner: Arc<Inner>,
+    /// Background thread running compactions
+    compaction_thread: Option<JoinHandle<()>>,
+}
+
+/// Shared part of the tree
+struct Inner {
     /// Path to the data directory
     data_dir: PathBuf,
-    /// A counter to generate unique sstable file names
-    sst_counter: AtomicUsize,
+    /// Options the tree was opened with, also used by the default column family
+    options: Options,
+    /// A counter to generate unique sstable and log file names
+    file_counter: AtomicUsize,
+    /// Mutable state, see `State`
+    state: Mutex<State>,
+    /// Signalled when there may be compaction work, or on shutdown
+    compaction_cv: Condvar,
+    /// Signalled whenever a flush or compaction finished
+    bg_work_done: Condvar,
+    /// Time writes spent stalled
+    stall_stats: Mutex<StallStats>,
+    /// Key locks of pessimistic transactions
+    lock_manager: LockManager,
+    /// Open `SSTable` files of all column families
+    table_cache: Arc<TableCache>,
+}
+
+/// State guarded by the tree mutex
+struct State {
+    /// Open column families by id
+    families: BTreeMap<u32, Family>,
+    /// Id of the next created column family
+    next_family_id: u32,
+    /// Whether some thread is currently flushing the immutable memtables
+    flushing: bool,
+    /// Whether the compaction thread is currently running a compaction
+    compacting: bool,
+    /// First error hit by background work, fails all subsequent writes
+    bg_error: Option<String>,
+    /// Set when the tree is dropped, stops the compaction thread
+    shutting_down: bool,
+    /// Sequence number of the last applied write batch
+    last_sequence: u64,
+    /// Write-ahead log new writes go to
+    wal: Wal,
+    /// Logs replaced by a newer one, with the sequence number of their last write
+    old_logs: Vec<(usize, u64)>,
+    /// Memory of the memtables last reported to the write buffer manager
+    reported_memory: MemoryUsage,
+    /// Write stall condition the event listeners were last told about
+    stall_condition: WriteStallCondition,
+    /// Writes flushed or ingested while optimistic transactions are open
+    flushed_writes: FlushedWrites,
+}
+
+/// Memtables and tables of a family taken under the mutex, see `Inner::read_view`
+struct ReadView {
+    active: Arc<Memtable>,
+    /// Newest first
+    immutables: VecDeque<Arc<Memtable>>,
+    version: Arc<Version>,
+    operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl State {
+    fn family(&self, id: u32) -> Result<&Family> {
+        self.families.get(&id).ok_or_else(dropped_family)
+    }
+
+    fn default_family(&self) -> &Family {
+        &self.families[&DEFAULT_FAMILY_ID]
+    }
 }
 
 impl LSMTree {
-    /// Opens LSM-Tree at the given path.
+    /// Opens LSM-Tree at the given path with default options.
     ///
     /// creates the directory if it doesn't exist and
     /// recovers the state from any existing `SSTable` files.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, Options::default())
+    }
+
+    /// Opens LSM-Tree at the given path with the given options.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        Self::open_with_column_families(path, options, Vec::new())
+    }
+
+    /// Opens LSM-Tree at the given path with options per column family.
+    ///
+    /// Listed families which don't exist yet are created. Existing families which
+    /// are not listed use `options`, like the default family.
+    pub fn open_with_column_families<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+        column_families: Vec<(String, Options)>,
+    ) -> Result<Self> {
+        options.validate()?;
+
         let data_dir = path.as_ref().to_path_buf();
         fs::create_dir_all(&data_dir)?;
 
-        // find all existing sstbales
-        let mut sst_paths: Vec<PathBuf> = fs::read_dir(&data_dir)?
+        let table_cache = Arc::new(TableCache::new(options.max_open_files));
+        let mut families = open_families(&data_dir, &options, &column_families, &table_cache)?;
+
+        // logs are replayed in creation order
+        let mut log_paths: Vec<PathBuf> = fs::read_dir(&data_dir)?
             .filter_map(std::result::Result::ok)
             .map(|entry| entry.path())
-            .filter(|p| p.extension().is_some_and(|ext| ext == "sst"))
+            .filter(|p| wal::log_number(p).is_some())
             .collect();
+        log_paths.sort();
 
-        // sort -> creation order
-        sst_paths.sort();
+        let mut max_file_num = log_paths
+            .iter()
+            .filter_map(|p| wal::log_number(p))
+            .max()
+            .unwrap_or(0);
+        for family in families.values() {
+            max_file_num = max_file_num.max(family.max_file_number()?);
+        }
 
-        // Deque is used here since pushing elements to the front has a O(1) complexity while Vec has O(n)
-        let mut l0_sstables = VecDeque::new();
-        let mut max_sst_num = 0;
+        let last_sequence = replay_logs(&mut families, &log_paths)?;
+        let wal = Wal::create(&data_dir, max_file_num + 1)?;
 
-        for path in sst_paths {
-            let sst = SSTable::open(path)?;
-            if let Some(num_str) = sst.path().file_stem().and_then(|s| s.to_str()) {
-                if let Ok(num) = num_str.parse::<usize>() {
-                    if num > max_sst_num {
-                        max_sst_num = num;
-                    }
-                }
+        // the replayed writes are flushed right away, which makes the old logs obsolete
+        let old_logs = log_paths
+            .iter()
+            .filter_map(|p| wal::log_number(p))
+            .map(|number| (number, last_sequence))
+            .collect();
+        for family in families.values_mut() {
+            if !family.memtable.is_empty() {
+                family.switch_memtable();
             }
-            // O(1)
-            l0_sstables.push_front(sst);
         }
 
-        Ok(Self {
-            memtable: Memtable::new(),
-            l0_sstables,
+        let next_family_id = u32::try_from(families.len()).unwrap();
+        let inner = Arc::new(Inner {
             data_dir,
-            sst_counter: AtomicUsize::new(max_sst_num + 1),
-        })
-    }
+            file_counter: AtomicUsize::new(max_file_num + 2),
+            state: Mutex::new(State {
+                families,
+                next_family_id,
+                flushing: false,
+                compacting: false,
+                bg_error: None,
+                shutting_down: false,
+                last_sequence,
+                wal,
+                old_logs,
+                reported_memory: MemoryUsage::default(),
+                stall_condition: WriteStallCondition::Normal,
+                flushed_writes: FlushedWrites::default(),
+            }),
+            options,
+            compaction_cv: Condvar::new(),
+            bg_work_done: Condvar::new(),
+            stall_stats: Mutex::new(StallStats::default()),
+            lock_manager: LockManager::new(),
+            table_cache,
+        });
 
-    /// Retrieves a value for a given key.
-    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        // 1. check active memtable
-        if let Some(value) = self.memtable.get(key) {
-            return match value {
-                Value::Some(v) => Ok(Some(v.clone())),
-                Value::Tombstone => Ok(None),
-            };
-        }
+        inner.flush_immutables(inner.lock_state())?;
+        inner.delete_obsolete_logs(&mut inner.lock_state());
+
+        let compaction_thread = {
+            let inner = Arc::clone(&inner);
+            thread::Builder::new()
+                .name("lsm-compaction".to_string())
+                .spawn(move || inner.compaction_loop())?
+        };
 
-        // 2. check L0 SSTables from newest to oldest
-        for sstable in &mut self.l0_sstables {
-            match sstable.get(key)? {
-                Some(Value::Some(value)) => return Ok(Some(value)),
-                Some(Value::Tombstone) => return Ok(None),
-                None => continue,
+        let tree = Self {
+            inner,
+            compaction_thread: Some(compaction_thread),
+        };
+
+        for (name, options) in column_families {
+            if tree.column_family(&name).is_none() {
+                tree.create_column_family(&name, options)?;
             }
         }
 
-        // 3. nothing found :(
-        Ok(None)
+        Ok(tree)
+    }
+
+    /// Rebuilds the tree at the given path from damaged files, using default options.
+    ///
+    /// The tree must not be open. See `repair_with_options`.
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<RepairReport> {
+        Self::repair_with_options(path, Options::default())
+    }
+
+    /// Rebuilds the tree at the given path from damaged files.
+    ///
+    /// Readable entries of damaged `SSTables` are kept in new tables, the
+    /// damaged files are moved to a `lost` directory next to them and the
+    /// manifests are written anew. Every column family is read with `options`.
+    /// The tree must not be open.
+    pub fn repair_with_options<P: AsRef<Path>>(path: P, options: Options) -> Result<RepairReport> {
+        repair::repair(path.as_ref(), &options)
+    }
+
+    /// Retrieves a value for a given key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _timer = self.timer(Histogram::GetMicros);
+        self.inner.get(DEFAULT_FAMILY_ID, key)
+    }
+
+    /// Retrieves a value for a given key from a column family.
+    pub fn get_cf(&self, family: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let _timer = self.timer(Histogram::GetMicros);
+        self.inner.get(family.id(), key)
+    }
+
+    /// Retrieves the values of many keys at once, in the order of `keys`.
+    ///
+    /// Every table is read at most once for the whole batch and keys sharing a
+    /// block read it once, which makes large batches faster than calling `get`
+    /// for each key, see `benches/multi_get.rs`.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let _timer = self.timer(Histogram::MultiGetMicros);
+        self.inner.multi_get(DEFAULT_FAMILY_ID, keys)
+    }
+
+    /// Retrieves the values of many keys of a column family, see `multi_get`.
+    pub fn multi_get_cf(
+        &self,
+        family: &ColumnFamily,
+        keys: &[&[u8]],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        let _timer = self.timer(Histogram::MultiGetMicros);
+        self.inner.multi_get(family.id(), keys)
+    }
+
+    /// Retrieves a value without copying it out of the immutable memtable, block
+    /// or memory map holding it.
+    ///
+    /// Values of the active memtable and values computed from merge operands are
+    /// copied, see `PinnedValue::is_pinned`.
+    pub fn get_pinned(&self, key: &[u8]) -> Result<Option<PinnedValue>> {
+        let _timer = self.timer(Histogram::GetMicros);
+        self.inner.get_pinned(DEFAULT_FAMILY_ID, key)
+    }
+
+    /// Retrieves a value from a column family without copying it, see `get_pinned`.
+    pub fn get_pinned_cf(&self, family: &ColumnFamily, key: &[u8]) -> Result<Option<PinnedValue>> {
+        let _timer = self.timer(Histogram::GetMicros);
+        self.inner.get_pinned(family.id(), key)
+    }
+
+    /// Retrieves a value into `buf`, reusing its allocation. Returns whether the
+    /// key was found, `buf` is cleared either way.
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+        let _timer = self.timer(Histogram::GetMicros);
+        self.inner.get_into(DEFAULT_FAMILY_ID, key, buf)
+    }
+
+    /// Retrieves a value from a column family into `buf`, see `get_into`.
+    pub fn get_into_cf(
+        &self,
+        family: &ColumnFamily,
+        key: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<bool> {
+        let _timer = self.timer(Histogram::GetMicros);
+        self.inner.get_into(family.id(), key, buf)
+    }
+
+    /// Returns all live key-value pairs with keys in `[start, end]`, in key order.
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.scan(DEFAULT_FAMILY_ID, start, end)
+    }
+
+    /// Returns all live key-value pairs of a column family with keys in `[start, end]`.
+    pub fn scan_cf(
+        &self,
+        family: &ColumnFamily,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.scan(family.id(), start, end)
     }
 
     /// Inserts a key-value pair.
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.memtable.put(key, value);
+    ///
+    /// The pair expires after `Options::default_ttl` if set.
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _timer = self.timer(Histogram::PutMicros);
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.inner.write(batch, |_| Ok(()))
+    }
 
-        if self.memtable.size_bytes() >= MEMTABLE_SIZE_THRESHOLD {
-            self.flush_memtable()?;
-        }
+    /// Inserts a key-value pair into a column family.
+    pub fn put_cf(&self, family: &ColumnFamily, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _timer = self.timer(Histogram::PutMicros);
+        let mut batch = WriteBatch::new();
+        batch.put_cf(family, key, value);
+        self.inner.write(batch, |_| Ok(()))
+    }
 
-        Ok(())
+    /// Inserts a key-value pair which reads as deleted once `ttl` has passed.
+    ///
+    /// Expired pairs are physically removed by compactions.
+    pub fn put_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _timer = self.timer(Histogram::PutMicros);
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(key, value, ttl);
+        self.inner.write(batch, |_| Ok(()))
+    }
+
+    /// Adds a merge operand for a key.
+    ///
+    /// `Options::merge_operator` combines the operands with the existing value when
+    /// the key is read or compacted, so no read is needed to update it.
+    pub fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.write(batch)
+    }
+
+    /// Adds a merge operand for a key in a column family.
+    pub fn merge_cf(&self, family: &ColumnFamily, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge_cf(family, key, operand);
+        self.write(batch)
     }
 
     /// Deletes a key.
-    pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
-        self.memtable.delete(key);
+    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
+        let _timer = self.timer(Histogram::DeleteMicros);
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.inner.write(batch, |_| Ok(()))
+    }
 
-        if self.memtable.size_bytes() >= MEMTABLE_SIZE_THRESHOLD {
-            self.flush_memtable()?;
+    /// Deletes a key from a column family.
+    pub fn delete_cf(&self, family: &ColumnFamily, key: Vec<u8>) -> Result<()> {
+        let _timer = self.timer(Histogram::DeleteMicros);
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(family, key);
+        self.inner.write(batch, |_| Ok(()))
+    }
+
+    /// Applies all writes of a batch atomically, even across column families.
+    ///
+    /// Plain puts expire after `Options::default_ttl` of their family if set.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let _timer = self.timer(Histogram::WriteMicros);
+        self.inner.write(batch, |_| Ok(()))
+    }
+
+    /// Creates a column family, an independent keyspace with its own options.
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn create_column_family(&self, name: &str, options: Options) -> Result<ColumnFamily> {
+        column_family::validate_name(name)?;
+        options.validate()?;
+
+        let mut state = self.inner.lock_state();
+        if state.families.values().any(|family| family.name == name) {
+            return Err(Error::InvalidArgument(format!(
+                "Column family {name:?} already exists"
+            )));
         }
 
-        Ok(())
+        // writes to an earlier family of the same name are still in the logs, the
+        // sequence number makes the replay skip them
+        let dir = column_family::family_dir(&self.inner.data_dir, name);
+        fs::create_dir_all(&dir)?;
+        let manifest = Manifest {
+            files: Vec::new(),
+            flushed_sequence: state.last_sequence,
+        };
+        manifest::write(&dir, &manifest)?;
+
+        let options = with_tree_options(&self.inner.options, options);
+        let family = Family::open(name.to_string(), dir, options, &self.inner.table_cache)?;
+        let id = state.next_family_id;
+        state.next_family_id += 1;
+        state.families.insert(id, family);
+
+        Ok(ColumnFamily::new(id, name.to_string()))
     }
 
-    /// Flushes the current memtable to a new L0 `SSTable`.
-    fn flush_memtable(&mut self) -> Result<()> {
-        if self.memtable.is_empty() {
-            return Ok(());
+    /// Drops a column family and deletes its data.
+    ///
+    /// Handles of the family are rejected afterwards.
+    pub fn drop_column_family(&self, family: &ColumnFamily) -> Result<()> {
+        if family.id() == DEFAULT_FAMILY_ID {
+            return Err(Error::InvalidArgument(
+                "The default column family can't be dropped".to_string(),
+            ));
         }
 
-        let sst_num = self.sst_counter.fetch_add(1, Ordering::SeqCst);
-        let sst_path = self.data_dir.join(format!("{sst_num:08}.sst"));
+        let mut state = self.inner.lock_state();
+        let dir = state.family(family.id())?.dir.clone();
 
-        // flush memtable to new SSTable
-        let mut builder = SSTableBuilder::new(sst_path.clone())?;
-        for (key, value) in &self.memtable {
-            builder.add(key, value)?;
+        // without a manifest the directory is removed on the next open
+        fs::remove_file(dir.join(MANIFEST_FILE))?;
+        let dropped = state.families.remove(&family.id()).unwrap();
+
+        // deleted once no reader uses them anymore
+        for sst in dropped.version.levels.iter().flatten() {
+            sst.mark_obsolete();
         }
-        builder.finish()?;
+        drop(dropped);
+        self.inner.account_memory(&mut state);
+        self.inner.check_stall_condition(&mut state);
 
-        // add new SSTable to L0 list
-        let new_sstable = SSTable::open(sst_path)?;
-        self.l0_sstables.push_front(new_sstable);
+        // best effort, fails while readers still hold files
+        let _ = fs::remove_dir(&dir);
 
-        // clear memtable
-        self.memtable = Memtable::new();
+        self.inner.delete_obsolete_logs(&mut state);
+        drop(state);
 
+        // writes may have been waiting for the family's flushes
+        self.inner.bg_work_done.notify_all();
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
+    /// Get a handle to the column family with the given name
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamily> {
+        let state = self.inner.lock_state();
+        state
+            .families
+            .iter()
+            .find(|(_, family)| family.name == name)
+            .map(|(id, family)| ColumnFamily::new(*id, family.name.clone()))
+    }
 
-    fn temp_dir(name: &str) -> PathBuf {
-        let dir = std::env::temp_dir().join("lsm-tree-kv-test").join(name);
+    /// Get handles to all column families, the default family first
+    pub fn column_families(&self) -> Vec<ColumnFamily> {
+        let state = self.inner.lock_state();
+        state
+            .families
+            .iter()
+            .map(|(id, family)| ColumnFamily::new(*id, family.name.clone()))
+            .collect()
+    }
 
-        // clean up before test
-        if dir.exists() {
-            fs::remove_dir_all(&dir).unwrap();
-        }
+    /// Starts an optimistic transaction.
+    ///
+    /// Writes are buffered in the transaction until `Transaction::commit`, which
+    /// fails with `Error::Conflict` if another write got in the way.
+    pub fn begin_transaction(&self) -> Transaction<'_> {
+        let mut state = self.inner.lock_state();
+        let snapshot = Inner::visible_sequence(&state);
+        state.flushed_writes.open_snapshot(snapshot);
+        drop(state);
+        Transaction::new(self, snapshot, None)
+    }
 
-        fs::create_dir_all(&dir).unwrap();
-        dir
+    /// Starts a pessimistic transaction.
+    ///
+    /// The transaction locks every key it writes or reads through
+    /// `Transaction::get_for_update`, other transactions wait for the locks until
+    /// it commits or rolls back.
+    pub fn begin_pessimistic_transaction(&self) -> Transaction<'_> {
+        let snapshot = Inner::visible_sequence(&self.inner.lock_state());
+        let id = self.inner.lock_manager.new_transaction_id();
+        Transaction::new(self, snapshot, Some(id))
     }
 
-    #[test]
-    fn test_open_creates_dir() {
-        let path = temp_dir("open_creates_dir");
-        LSMTree::open(&path).unwrap();
-        assert!(path.exists());
+    /// Starts loading sorted pairs without going through the memtable.
+    ///
+    /// The pairs are written to new `SSTables` and added to the tree at once by
+    /// `BulkLoader::finish`.
+    pub fn bulk_loader(&self) -> Result<BulkLoader<'_>> {
+        BulkLoader::new(self, DEFAULT_FAMILY_ID)
     }
 
-    #[test]
-    fn test_put_and_get_memtable_only() {
-        let path = temp_dir("put_get_memtable");
-        let mut tree = LSMTree::open(path).unwrap();
+    /// Starts loading sorted pairs into a column family, see `bulk_loader`.
+    pub fn bulk_loader_cf(&self, family: &ColumnFamily) -> Result<BulkLoader<'_>> {
+        BulkLoader::new(self, family.id())
+    }
 
-        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
-        let val = tree.get(b"key1").unwrap();
-        assert_eq!(val, Some(b"value1".to_vec()));
+    pub(crate) fn options(&self) -> &Options {
+        &self.inner.options
     }
 
-    #[test]
-    fn test_memtable_flush() {
-        let path = temp_dir("memtable_flush");
-        let mut tree = LSMTree::open(path.clone()).unwrap();
+    pub(crate) fn lock_manager(&self) -> &LockManager {
+        &self.inner.lock_manager
+    }
 
-        // small put, does not trigger a flush
-        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
-        assert_eq!(tree.l0_sstables.len(), 0);
+    /// Commits the writes of a transaction started at sequence number `snapshot`,
+    /// unless one of `keys` was written after it
+    pub(crate) fn commit_transaction<'a>(
+        &self,
+        batch: WriteBatch,
+        snapshot: u64,
+        keys: impl Iterator<Item = &'a [u8]>,
+    ) -> Result<()> {
+        self.inner.write(batch, |state| {
+            // writes are numbered before they are inserted, the check has to see them
+            state.default_family().memtable.wait_for_writers();
+            for key in keys {
+                let sequence = key_sequence(state, key)?;
+                if sequence.is_some_and(|sequence| sequence > snapshot) {
+                    return Err(Error::Conflict(format!(
+                        "key {:?} was written after the transaction started",
+                        String::from_utf8_lossy(key)
+                    )));
+                }
+            }
+            Ok(())
+        })
+    }
 
-        // large put to trigger a flush
-        let big_value = vec![0u8; MEMTABLE_SIZE_THRESHOLD];
-        tree.put(b"key2".to_vec(), big_value).unwrap();
+    /// Ends an optimistic transaction started at sequence number `snapshot`
+    pub(crate) fn release_snapshot(&self, snapshot: u64) {
+        self.inner
+            .lock_state()
+            .flushed_writes
+            .release_snapshot(snapshot);
+    }
 
-        // Memtable should be flushed and a new one created
-        assert_eq!(tree.l0_sstables.len(), 1);
-        assert!(tree.memtable.is_empty());
+    /// Get the options of a family
+    pub(crate) fn family_options(&self, family: u32) -> Result<Arc<Options>> {
+        Ok(self.inner.family_files(family)?.1)
+    }
 
-        // SSTable file should exist
-        assert_eq!(
-            fs::read_dir(&path)
-                .unwrap()
-                .filter_map(std::result::Result::ok)
-                .filter(|e| e.path().extension().is_some_and(|ext| ext == "sst"))
-                .count(),
-            1
-        );
+    /// Get a path for a new table in the directory of a family
+    pub(crate) fn new_table_path(&self, family: u32) -> Result<PathBuf> {
+        let (dir, _) = self.inner.family_files(family)?;
+        Ok(self.inner.new_sst_path(&dir))
     }
 
-    #[test]
-    fn test_get_after_flush() {
-        let path = temp_dir("get_after_flush");
-        let mut tree = LSMTree::open(path).unwrap();
+    /// Adds tables written to the family directory to the family in one step
+    pub(crate) fn add_tables(&self, family: u32, files: Vec<IngestedFile>) -> Result<()> {
+        self.inner.add_ingested(family, files)
+    }
 
-        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
-        // flush
-        tree.put(b"key2".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
-            .unwrap();
+    /// Flushes the active memtables of all column families to new L0 `SSTables`,
+    /// even if they are not full.
+    ///
+    /// Returns once all memtables written before the call are on disk.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.flush_memtables()
+    }
 
-        // key1 should now be in an SSTable
-        assert!(tree.memtable.is_empty());
-        let val = tree.get(b"key1").unwrap();
-        assert_eq!(val, Some(b"value1".to_vec()));
+    /// Compacts all `SSTables` overlapping `[start, end]` down to the bottommost level.
+    ///
+    /// Flushes the memtable first. Tombstones and overwritten values in the range
+    /// are physically dropped, which reclaims their space right away.
+    pub fn compact_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.inner.compact_range(DEFAULT_FAMILY_ID, start, end)
     }
 
-    #[test]
-    fn test_multiple_flushes() {
-        let path = temp_dir("multiple_flushes");
-        let mut tree = LSMTree::open(path).unwrap();
+    /// Compacts all `SSTables` of a column family overlapping `[start, end]` down
+    /// to the bottommost level, see `compact_range`.
+    pub fn compact_range_cf(&self, family: &ColumnFamily, start: &[u8], end: &[u8]) -> Result<()> {
+        self.inner.compact_range(family.id(), start, end)
+    }
 
-        // flush
-        tree.put(b"key1".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
-            .unwrap();
-        assert_eq!(tree.l0_sstables.len(), 1);
+    /// Adds `SSTables` built with `SSTableBuilder` to the tree.
+    ///
+    /// The keys of each file must be sorted by the comparator of the tree and the
+    /// files must not overlap each other. They are copied, the originals are left
+    /// untouched. Unflushed writes are flushed first, the ingested values replace
+    /// the values already stored for their keys. Each file goes to the deepest
+    /// level where no table of that level or above overlaps it.
+    pub fn ingest_external_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<()> {
+        self.inner.ingest(DEFAULT_FAMILY_ID, paths)
+    }
 
-        // flushq again
-        tree.put(b"key2".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
-            .unwrap();
-        assert_eq!(tree.l0_sstables.len(), 2);
+    /// Adds `SSTables` to a column family, see `ingest_external_files`.
+    pub fn ingest_external_files_cf<P: AsRef<Path>>(
+        &self,
+        family: &ColumnFamily,
+        paths: &[P],
+    ) -> Result<()> {
+        self.inner.ingest(family.id(), paths)
+    }
 
-        // check values from both SSTables
-        let val1 = tree.get(b"key1").unwrap();
-        assert_eq!(val1, Some(vec![0u8; MEMTABLE_SIZE_THRESHOLD]));
-        let val2 = tree.get(b"key2").unwrap();
-        assert_eq!(val2, Some(vec![0u8; MEMTABLE_SIZE_THRESHOLD]));
+    /// Writes all live pairs to `writer` in the portable dump format.
+    ///
+    /// The dump reflects the tree at the time of the call, writes made while it
+    /// is being written are not part of it. The memtable is flushed first. Values written with a TTL keep their
+    /// expiration time. Returns the number of pairs written.
+    pub fn export<W: Write>(&self, writer: W) -> Result<u64> {
+        self.inner.export(DEFAULT_FAMILY_ID, None, writer)
     }
 
-    #[test]
-    fn test_read_priority_memtable_over_sstable() {
-        let path = temp_dir("read_priority");
-        let mut tree = LSMTree::open(path).unwrap();
+    /// Writes the live pairs with keys in `[start, end]` to a dump, see `export`.
+    pub fn export_range<W: Write>(&self, start: &[u8], end: &[u8], writer: W) -> Result<u64> {
+        self.inner
+            .export(DEFAULT_FAMILY_ID, Some((start, end)), writer)
+    }
 
-        // put initial value and flush it
-        tree.put(b"key1".to_vec(), b"old_value".to_vec()).unwrap();
-        tree.put(b"filler".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
-            .unwrap();
-        assert_eq!(tree.l0_sstables.len(), 1);
+    /// Writes all live pairs of a column family to a dump, see `export`.
+    pub fn export_cf<W: Write>(&self, family: &ColumnFamily, writer: W) -> Result<u64> {
+        self.inner.export(family.id(), None, writer)
+    }
 
-        // put new value in memtable
-        tree.put(b"key1".to_vec(), b"new_value".to_vec()).unwrap();
+    /// Writes the live pairs of a column family with keys in `[start, end]` to a
+    /// dump, see `export`.
+    pub fn export_range_cf<W: Write>(
+        &self,
+        family: &ColumnFamily,
+        start: &[u8],
+        end: &[u8],
+        writer: W,
+    ) -> Result<u64> {
+        self.inner.export(family.id(), Some((start, end)), writer)
+    }
 
-        // should return new value from memtable
-        let val = tree.get(b"key1").unwrap();
-        assert_eq!(val, Some(b"new_value".to_vec()));
+    /// Loads a dump written by `export` through a `BulkLoader`.
+    ///
+    /// The dump must be sorted by the comparator of the tree. Its pairs replace
+    /// the values already stored for their keys and become visible at once. Pairs
+    /// keep the expiration time they were exported with, those expired meanwhile
+    /// are skipped. Returns the number of pairs loaded.
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64> {
+        self.import_into(DEFAULT_FAMILY_ID, reader)
     }
 
-    #[test]
-    fn test_tombstone_in_memtable_masks_sstable() {
-        let path = temp_dir("tombstone_mask");
-        let mut tree = LSMTree::open(path).unwrap();
+    /// Loads a dump into a column family, see `import`.
+    pub fn import_cf<R: Read>(&self, family: &ColumnFamily, reader: R) -> Result<u64> {
+        self.import_into(family.id(), reader)
+    }
 
-        // put value and flush it
-        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
-        tree.put(b"filler".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
-            .unwrap();
-        assert_eq!(tree.l0_sstables.len(), 1);
+    fn import_into<R: Read>(&self, family: u32, reader: R) -> Result<u64> {
+        let mut dump = DumpReader::new(reader)?;
+        let mut loader = BulkLoader::new(self, family)?;
 
-        // delete it (places tombstone in memtable)
-        tree.delete(b"key1".to_vec()).unwrap();
+        let comparator = loader.comparator_name();
+        if dump.comparator() != comparator {
+            return Err(Error::InvalidArgument(format!(
+                "Dump is sorted by comparator {:?}, not {comparator:?}",
+                dump.comparator()
+            )));
+        }
 
-        // get should return None because of the tombstone
-        let val = tree.get(b"key1").unwrap();
-        assert_eq!(val, None);
+        let now = now_millis();
+        let mut count = 0;
+        while let Some((key, value, expires_at)) = dump.next_pair()? {
+            match expires_at {
+                Some(expires_at) if expires_at <= now => continue,
+                Some(expires_at) => loader.add_expiring(&key, &value, expires_at)?,
+                None => loader.add(&key, &value)?,
+            }
+            count += 1;
+        }
+        loader.finish()?;
+        Ok(count)
     }
 
-    #[test]
-    fn test_restart_recovers_sstables() {
-        let path = temp_dir("restart_recovery");
+    /// Writes a copy of the tree to `dest`, which can be opened like any other tree.
+    ///
+    /// Flushes the memtables first, then hard-links the live `SSTables` into
+    /// `dest` and copies the write-ahead logs, so writes only wait for the flush.
+    /// Tables are copied when `dest` is on another file system. `dest` must not
+    /// exist yet.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        self.create_checkpoint(dest.as_ref()).map(|_| ())
+    }
 
-        // create LSMT, write some data, flush
-        {
-            let mut tree = LSMTree::open(&path).unwrap();
-            tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
-            tree.put(b"key2".to_vec(), b"value2".to_vec()).unwrap();
-            tree.put(b"filler".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
-                .unwrap();
-            assert_eq!(tree.l0_sstables.len(), 1);
+    /// Writes a checkpoint to `dest`, returns the sequence number of the newest
+    /// write it holds
+    pub(crate) fn create_checkpoint(&self, dest: &Path) -> Result<u64> {
+        checkpoint::validate_dest(dest)?;
 
-            // tree is dropped here since it goes out of scope
+        self.inner.flush_memtables()?;
+        let (families, logs, sequence) = self.inner.capture_files()?;
+        checkpoint::write(dest, &families, logs)?;
+        Ok(sequence)
+    }
+
+    /// Reads every live `SSTable` end to end and reports the damage found.
+    ///
+    /// Unlike reads, the check doesn't stop at the first damaged table, see
+    /// `IntegrityReport`. Tables flushed or compacted away meanwhile are still
+    /// checked to the end.
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn verify_integrity(&self) -> IntegrityReport {
+        let families: Vec<(String, Arc<Version>)> = {
+            let state = self.inner.lock_state();
+            state
+                .families
+                .values()
+                .map(|family| (family.name.clone(), Arc::clone(&family.version)))
+                .collect()
+        };
+        integrity::verify(&families)
+    }
+
+    /// Get the number of `SSTables` in a level
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn num_files_at_level(&self, level: usize) -> usize {
+        let state = self.inner.lock_state();
+        let levels = &state.default_family().version.levels;
+        levels.get(level).map_or(0, Vec::len)
+    }
+
+    /// Get the current throttling state of the write path
+    pub fn write_stall_condition(&self) -> WriteStallCondition {
+        let state = self.inner.lock_state();
+        stall_condition(&state)
+    }
+
+    /// Get the time writes spent delayed or stopped so far
+    pub fn stall_stats(&self) -> StallStats {
+        *self.inner.stall_stats.lock().unwrap()
+    }
+
+    /// Get the statistics the tree records into, see `Options::statistics`
+    pub fn statistics(&self) -> Option<&Arc<Statistics>> {
+        self.inner.options.statistics.as_ref()
+    }
+
+    /// Get a property of the tree as text, `None` for unknown properties
+    ///
+    /// - `stats`: every counter and histogram of `Options::statistics`
+    /// - `num-files-at-level<N>`: number of tables at level N of the default family
+    /// - `num-immutable-mem-table`: memtables of all families waiting for a flush
+    /// - `cur-size-all-mem-tables`: bytes allocated by all memtables
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn property(&self, name: &str) -> Option<String> {
+        if let Some(level) = name.strip_prefix("num-files-at-level") {
+            let level: usize = level.parse().ok()?;
+            return Some(self.num_files_at_level(level).to_string());
         }
 
-        // re-open LSMT, should recover SSTables
-        {
-            let mut tree = LSMTree::open(&path).unwrap();
-            assert_eq!(tree.l0_sstables.len(), 1);
-            assert!(tree.memtable.is_empty());
+        match name {
+            "stats" => self.statistics().map(ToString::to_string),
+            "num-immutable-mem-table" => {
+                let state = self.inner.lock_state();
+                let count: usize = state.families.values().map(|f| f.immutables.len()).sum();
+                Some(count.to_string())
+            }
+            "cur-size-all-mem-tables" => {
+                let state = self.inner.lock_state();
+                let bytes: usize = state
+                    .families
+                    .values()
+                    .map(|f| {
+                        let immutable: usize = f.immutables.iter().map(|m| m.memory_usage()).sum();
+                        f.memtable.memory_usage() + immutable
+                    })
+                    .sum();
+                Some(bytes.to_string())
+            }
+            _ => None,
+        }
+    }
 
-            // data should be accessible
-            let val1 = tree.get(b"key1").unwrap();
-            assert_eq!(val1, Some(b"value1".to_vec()));
-            let val2 = tree.get(b"key2").unwrap();
-            assert_eq!(val2, Some(b"value2".to_vec()));
+    /// Times an operation into a histogram of the statistics, if any
+    fn timer(&self, histogram: Histogram) -> StopWatch<'_> {
+        StopWatch::start(self.inner.statistics(), histogram)
+    }
+}
+
+impl Drop for LSMTree {
+    fn drop(&mut self) {
+        let mut state = self.inner.lock_state();
+        state.shutting_down = true;
+        if let Some(manager) = &self.inner.options.write_buffer_manager {
+            manager.update(state.reported_memory, MemoryUsage::default());
+        }
+        drop(state);
+        self.inner.compaction_cv.notify_all();
+
+        if let Some(handle) = self.compaction_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Inner {
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn statistics(&self) -> Option<&Statistics> {
+        self.options.statistics.as_deref()
+    }
+
+    /// Calls every event listener
+    fn notify(&self, event: impl Fn(&dyn EventListener)) {
+        for listener in &self.options.listeners {
+            event(listener.as_ref());
+        }
+    }
+
+    /// Tells the event listeners if the write stall condition changed
+    fn check_stall_condition(&self, state: &mut State) {
+        let current = stall_condition(state);
+        if current == state.stall_condition {
+            return;
+        }
+
+        let info = WriteStallInfo {
+            previous: state.stall_condition,
+            current,
+        };
+        state.stall_condition = current;
+        self.notify(|listener| listener.on_stall_conditions_changed(&info));
+    }
+
+    fn record(&self, ticker: Ticker, count: u64) {
+        if let Some(statistics) = self.statistics() {
+            statistics.record(ticker, count);
         }
     }
+
+    /// Records a point lookup, `memtable_hit` if no table had to be read
+    fn record_read(&self, memtable_hit: bool, value: Option<&[u8]>) {
+        let memtable = if memtable_hit {
+            Ticker::MemtableHit
+        } else {
+            Ticker::MemtableMiss
+        };
+        self.record(memtable, 1);
+        self.record(Ticker::KeysRead, 1);
+        self.record(Ticker::BytesRead, value.map_or(0, <[u8]>::len) as u64);
+    }
+
+    /// Sequence number of the last write to the default family, which every read
+    /// started after the call sees
+    fn visible_sequence(state: &State) -> u64 {
+        // writes are numbered before they are inserted
+        state.default_family().memtable.wait_for_writers();
+        state.last_sequence
+    }
+
+    /// Memtables and tables of a family, to be read without holding the mutex
+    ///
+    /// Waits for the writes still being inserted into the active memtable, so
+    /// every batch numbered before the call is seen in full.
+    fn read_view(&self, family: u32) -> Result<ReadView> {
+        Ok(self.read_view_with(family, |_| ())?.0)
+    }
+
+    /// Like `read_view`, also passing the active memtable to `f` before any
+    /// further batch is inserted into it
+    ///
+    /// Reads of several keys copy what they need from the active memtable in `f`,
+    /// a batch inserted while they read the rest would be seen in part.
+    #[allow(clippy::significant_drop_tightening)]
+    fn read_view_with<R>(
+        &self,
+        family: u32,
+        f: impl FnOnce(&Memtable) -> R,
+    ) -> Result<(ReadView, R)> {
+        let state = self.lock_state();
+        let family = state.family(family)?;
+
+        // batches are numbered under the mutex and inserted after it is released
+        family.memtable.wait_for_writers();
+        let active = f(&family.memtable);
+
+        let view = ReadView {
+            active: Arc::clone(&family.memtable),
+            immutables: family.immutables.clone(),
+            version: Arc::clone(&family.version),
+            operator: family.options.merge_operator.clone(),
+        };
+        Ok((view, active))
+    }
+
+    /// Point lookup in a column family
+    fn get(&self, family: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = now_millis();
+        let view = self.read_view(family)?;
+
+        // 1. check active memtable
+        let active = view.active.get(key);
+        if let Some(value) = &active {
+            if !matches!(value, Value::Merge(_)) {
+                let value = value_to_option(value.clone(), now);
+                self.record_read(true, value.as_deref());
+                return Ok(value);
+            }
+        }
+
+        // 2. check memtables waiting for a flush, newest to oldest
+        let is_base = |value: Option<&Value>| value.is_some_and(|v| !matches!(v, Value::Merge(_)));
+        let mut memtable_values: Vec<Value> = active.into_iter().collect();
+        for memtable in &view.immutables {
+            if is_base(memtable_values.last()) {
+                break;
+            }
+            memtable_values.extend(memtable.get(key));
+        }
+        let memtable_hit = is_base(memtable_values.last());
+
+        // 3. check SSTables from newest to oldest
+        let values = memtable_values
+            .into_iter()
+            .map(Ok)
+            .chain(view.version.values(key, self.statistics()));
+
+        let value = resolve_value(view.operator.as_deref(), key, values, now)?;
+        self.record_read(memtable_hit, value.as_deref());
+        Ok(value)
+    }
+
+    /// Batched point lookups, see `LSMTree::multi_get`
+    fn multi_get(&self, family: u32, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let now = now_millis();
+        let (view, active) = self.read_view_with(family, |active| {
+            keys.iter().map(|key| active.get(key)).collect::<Vec<_>>()
+        })?;
+        let ReadView {
+            immutables,
+            version,
+            operator,
+            ..
+        } = view;
+
+        // distinct keys in key order
+        let comparator = &version.comparator;
+        let mut sorted: Vec<(&[u8], Option<Value>)> = keys.iter().copied().zip(active).collect();
+        sorted.sort_by(|a, b| comparator.compare(a.0, b.0));
+        sorted.dedup_by(|a, b| comparator.compare(a.0, b.0) == cmp::Ordering::Equal);
+
+        let mut lookups: Vec<KeyLookup> = Vec::with_capacity(sorted.len());
+        for (key, active) in sorted {
+            let mut lookup = KeyLookup::new(key);
+            if let Some(value) = active {
+                lookup.push(value);
+            }
+            lookups.push(lookup);
+        }
+
+        for memtable in &immutables {
+            for lookup in lookups.iter_mut().filter(|l| !l.done) {
+                if let Some(value) = memtable.get(lookup.key) {
+                    lookup.push(value);
+                }
+            }
+        }
+        let memtable_hits = lookups.iter().filter(|l| l.done).count();
+        self.record(Ticker::MemtableHit, memtable_hits as u64);
+        self.record(Ticker::MemtableMiss, (lookups.len() - memtable_hits) as u64);
+        multi_get::read_tables(&version, &mut lookups, self.statistics())?;
+
+        let distinct: Vec<&[u8]> = lookups.iter().map(|lookup| lookup.key).collect();
+        let values = lookups
+            .into_iter()
+            .map(|lookup| {
+                let values = lookup.values.into_iter().map(Ok);
+                resolve_value(operator.as_deref(), lookup.key, values, now)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // back to the order of the input
+        let values: Vec<Option<Vec<u8>>> = keys
+            .iter()
+            .map(|key| {
+                distinct
+                    .binary_search_by(|k| comparator.compare(k, key))
+                    .ok()
+                    .and_then(|idx| values[idx].clone())
+            })
+            .collect();
+
+        self.record(Ticker::KeysRead, keys.len() as u64);
+        let bytes: usize = values.iter().flatten().map(Vec::len).sum();
+        self.record(Ticker::BytesRead, bytes as u64);
+        Ok(values)
+    }
+
+    /// Point lookup borrowing the value, see `LSMTree::get_pinned`
+    fn get_pinned(&self, family: u32, key: &[u8]) -> Result<Option<PinnedValue>> {
+        let (lookup, memtable_hit) = self.lookup_pinned(family, key, now_millis())?;
+        match lookup {
+            Lookup::Value(value) => {
+                self.record_read(memtable_hit, value.as_deref());
+                Ok(value)
+            }
+            // operands are resolved against older values like in `get`
+            Lookup::Merge => Ok(self.get(family, key)?.map(PinnedValue::owned)),
+        }
+    }
+
+    /// Newest entry of a key, borrowed from where it is stored, and whether it
+    /// was found in a memtable
+    fn lookup_pinned(&self, family: u32, key: &[u8], now: u64) -> Result<(Lookup, bool)> {
+        let view = self.read_view(family)?;
+
+        // the active memtable changes while it is read, its values are copied
+        let active = view.active.get_with(key, |value| match value {
+            Value::Merge(_) => Lookup::Merge,
+            value => Lookup::Value(
+                value
+                    .live_value(now)
+                    .map(|bytes| PinnedValue::owned(bytes.to_vec())),
+            ),
+        });
+        if let Some(lookup) = active {
+            return Ok((lookup, true));
+        }
+
+        for memtable in &view.immutables {
+            let is_merge = memtable.get_with(key, |value| matches!(value, Value::Merge(_)));
+            if let Some(is_merge) = is_merge {
+                let lookup = if is_merge {
+                    Lookup::Merge
+                } else {
+                    Lookup::Value(PinnedValue::memtable(memtable, key, now))
+                };
+                return Ok((lookup, true));
+            }
+        }
+
+        for (level, sstable) in view.version.tables_for(key) {
+            if let Some(statistics) = self.statistics() {
+                statistics.record_probes(level, 1);
+            }
+            if let Some(lookup) = sstable.get_pinned(key, now)? {
+                return Ok((lookup, false));
+            }
+        }
+
+        Ok((Lookup::Value(None), false))
+    }
+
+    /// Point lookup into a caller buffer, see `LSMTree::get_into`
+    fn get_into(&self, family: u32, key: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+        buf.clear();
+        let Some(value) = self.get_pinned(family, key)? else {
+            return Ok(false);
+        };
+        buf.extend_from_slice(&value);
+        Ok(true)
+    }
+
+    /// Range scan over a column family, see `LSMTree::scan`
+    fn scan(&self, family: u32, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut results = Vec::new();
+        self.scan_with(family, Some((start, end)), |key, value, _| {
+            results.push((key, value));
+            Ok(())
+        })?;
+        Ok(results)
+    }
+
+    /// Passes the live pairs of a family with keys in `range`, or all of them, to
+    /// `f` in key order, together with their expiration time if they have one
+    ///
+    /// The pairs are read from the state of the family when the call started.
+    fn scan_with(
+        &self,
+        family: u32,
+        range: Option<(&[u8], &[u8])>,
+        f: impl FnMut(Vec<u8>, Vec<u8>, Option<u64>) -> Result<()>,
+    ) -> Result<()> {
+        let (view, active) = self.read_view_with(family, |active| {
+            memtable_range(active, range).collect::<Vec<_>>()
+        })?;
+        Self::scan_view(&view, active, range, f)
+    }
+
+    /// Passes the live pairs of `view` with keys in `range` to `f`, see
+    /// `scan_with`
+    ///
+    /// `active` holds the entries read from the active memtable of the view, the
+    /// memtable itself is not read.
+    fn scan_view(
+        view: &ReadView,
+        active: Vec<(Vec<u8>, Value)>,
+        range: Option<(&[u8], &[u8])>,
+        mut f: impl FnMut(Vec<u8>, Vec<u8>, Option<u64>) -> Result<()>,
+    ) -> Result<()> {
+        let now = now_millis();
+        let comparator = Arc::clone(&view.version.comparator);
+        if let Some((start, end)) = range {
+            if comparator.compare(start, end) == cmp::Ordering::Greater {
+                return Err(Error::InvalidArgument(
+                    "scan start is greater than end".to_string(),
+                ));
+            }
+        }
+
+        // newest source first
+        let mut sources: Vec<EntryIter> = vec![Box::new(active.into_iter().map(Ok))];
+        for memtable in &view.immutables {
+            sources.push(Box::new(memtable_range(memtable, range).map(Ok)));
+        }
+        sources.extend(view.version.range_sources(range.map(|(start, _)| start)));
+
+        let entries = MergingIterator::new(sources)
+            .with_comparator(Arc::clone(&comparator))
+            .with_merge_operator(view.operator.clone(), true);
+
+        for entry in entries {
+            let (key, value) = entry?;
+
+            // tables are only bounded at the start
+            if range.is_some_and(|(_, end)| comparator.compare(&key, end) == cmp::Ordering::Greater)
+            {
+                break;
+            }
+
+            let expires_at = match &value {
+                Value::Expiring { expires_at, .. } => Some(*expires_at),
+                _ => None,
+            };
+            if let Some(value) = value_to_option(value, now) {
+                f(key, value, expires_at)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs a write batch and applies it to the active memtables, flushing the
+    /// ones which are full
+    ///
+    /// `validate` runs with the state locked right before the batch is applied
+    /// and can reject it.
+    fn write(
+        &self,
+        mut batch: WriteBatch,
+        validate: impl FnOnce(&State) -> Result<()>,
+    ) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.make_room_for_write(batch.size_bytes())?;
+        validate(&state)?;
+
+        let family_ids = batch.families();
+        for &id in &family_ids {
+            let family = state.family(id)?;
+            if family.options.merge_operator.is_none() && batch.has_merges(id) {
+                return Err(Error::InvalidArgument(
+                    "merge requires a merge operator".to_string(),
+                ));
+            }
+            if let Some(ttl) = family.options.default_ttl {
+                batch.expire_puts(id, ttl);
+            }
+        }
+
+        // resolve first, so a failing merge leaves every family unchanged
+        let mut resolved = Vec::new();
+        for &id in &family_ids {
+            let family = state.family(id)?;
+            if batch.has_merges(id) {
+                // operands are folded onto the newest value, earlier writes have to be in
+                family.memtable.wait_for_writers();
+            }
+            let operator = family.options.merge_operator.as_deref();
+            resolved.push((
+                id,
+                family
+                    .memtable
+                    .resolve(batch.family_entries(id), operator)?,
+            ));
+        }
+
+        let sequence = state.last_sequence + 1;
+        self.log_batch(&mut state, sequence, &batch)?;
+
+        // inserted without the mutex, other writers and readers go on meanwhile
+        let writes: Vec<_> = resolved
+            .into_iter()
+            .map(|(id, entries)| (state.families[&id].memtable.begin_write(), entries))
+            .collect();
+        state.last_sequence = sequence;
+        drop(state);
+
+        for (write, entries) in writes {
+            write.insert(entries, sequence);
+        }
+        self.record(Ticker::KeysWritten, batch.len() as u64);
+        self.record(Ticker::BytesWritten, batch.size_bytes() as u64);
+
+        let mut state = self.lock_state();
+        self.account_memory(&mut state);
+        let mut full: Vec<u32> = family_ids
+            .into_iter()
+            .filter(|id| {
+                // the family may have been dropped meanwhile
+                state.families.get(id).is_some_and(|family| {
+                    family.memtable.used_memory() >= family.options.write_buffer_size
+                })
+            })
+            .collect();
+
+        // over the shared memory budget the largest memtable of this tree goes
+        let over_budget = self
+            .options
+            .write_buffer_manager
+            .as_ref()
+            .is_some_and(|manager| manager.should_flush());
+        if full.is_empty() && over_budget {
+            full.extend(
+                state
+                    .families
+                    .iter()
+                    .filter(|(_, family)| !family.memtable.is_empty())
+                    .max_by_key(|(_, family)| family.memtable.memory_usage())
+                    .map(|(id, _)| *id),
+            );
+        }
+
+        if full.is_empty() {
+            drop(state);
+            return Ok(());
+        }
+
+        self.switch_memtables(&mut state, &full)?;
+        self.flush_immutables(state)
+    }
+
+    /// Appends a batch to the write-ahead log, syncing it if the options ask to
+    ///
+    /// A failure stops all further writes, a partially written record would hide
+    /// everything logged after it.
+    fn log_batch(&self, state: &mut State, sequence: u64, batch: &WriteBatch) -> Result<()> {
+        let entries = batch.entries().iter().map(|(id, key, value)| {
+            let name = state.families[id].name.as_str();
+            (name, key.as_slice(), value)
+        });
+
+        let result = state.wal.add_batch(sequence, entries).and_then(|bytes| {
+            if self.options.sync_writes {
+                state.wal.sync()?;
+                self.record(Ticker::WalSyncs, 1);
+            }
+            Ok(bytes)
+        });
+        match result {
+            Ok(bytes) => {
+                self.record(Ticker::WalWrites, 1);
+                self.record(Ticker::WalBytes, bytes as u64);
+                Ok(())
+            }
+            Err(err) => {
+                state.bg_error = Some(err.to_string());
+                self.notify(|listener| {
+                    listener.on_background_error(BackgroundErrorReason::WriteAheadLog, &err);
+                });
+                Err(err)
+            }
+        }
+    }
+
+    /// Applies the write stall policy before a write of `bytes`
+    ///
+    /// Delays the write once when over a soft limit and blocks while over a hard
+    /// limit. Returns with the state locked and the write allowed to proceed.
+    fn make_room_for_write(&self, bytes: usize) -> Result<MutexGuard<'_, State>> {
+        let mut state = self.lock_state();
+        let mut delayed = false;
+
+        loop {
+            if let Some(err) = &state.bg_error {
+                return Err(background_error(err));
+            }
+
+            match stall_condition(&state) {
+                WriteStallCondition::Normal => return Ok(state),
+                WriteStallCondition::Delayed if delayed => return Ok(state),
+                WriteStallCondition::Delayed => {
+                    drop(state);
+
+                    let delay = write_controller::write_delay(&self.options, bytes);
+                    thread::sleep(delay);
+                    delayed = true;
+
+                    let mut stall_stats = self.stall_stats.lock().unwrap();
+                    stall_stats.delayed_writes += 1;
+                    stall_stats.delayed_time += delay;
+                    self.record(Ticker::StallMicros, delay.as_micros() as u64);
+                    drop(stall_stats);
+
+                    state = self.lock_state();
+                }
+                WriteStallCondition::Stopped => {
+                    let start = Instant::now();
+                    while state.bg_error.is_none()
+                        && stall_condition(&state) == WriteStallCondition::Stopped
+                    {
+                        state = self.bg_work_done.wait(state).unwrap();
+                    }
+
+                    let mut stall_stats = self.stall_stats.lock().unwrap();
+                    stall_stats.stopped_writes += 1;
+                    stall_stats.stopped_time += start.elapsed();
+                    self.record(Ticker::StallMicros, start.elapsed().as_micros() as u64);
+                }
+            }
+        }
+    }
+
+    /// Turns the active memtables of the given families immutable and starts a new
+    /// log for the writes that follow
+    fn switch_memtables(&self, state: &mut State, family_ids: &[u32]) -> Result<()> {
+        let number = self.file_counter.fetch_add(1, Ordering::SeqCst);
+        let wal = Wal::create(&self.data_dir, number)?;
+        let old = mem::replace(&mut state.wal, wal);
+        state.old_logs.push((old.number(), state.last_sequence));
+
+        for id in family_ids {
+            state.families.get_mut(id).unwrap().switch_memtable();
+        }
+        self.account_memory(state);
+        self.check_stall_condition(state);
+
+        Ok(())
+    }
+
+    /// Reports the memory of the memtables to the write buffer manager, if any
+    fn account_memory(&self, state: &mut State) {
+        let Some(manager) = &self.options.write_buffer_manager else {
+            return;
+        };
+
+        let mut usage = MemoryUsage::default();
+        for family in state.families.values() {
+            let mutable = family.memtable.memory_usage();
+            let immutable: usize = family.immutables.iter().map(|m| m.memory_usage()).sum();
+            usage.mutable += mutable;
+            usage.total += mutable + immutable;
+        }
+
+        manager.update(state.reported_memory, usage);
+        state.reported_memory = usage;
+    }
+
+    /// Turns all non-empty active memtables immutable and waits until every
+    /// immutable memtable is flushed
+    #[allow(clippy::significant_drop_tightening)]
+    fn flush_memtables(&self) -> Result<()> {
+        let mut state = self.lock_state();
+        let non_empty: Vec<u32> = state
+            .families
+            .iter()
+            .filter(|(_, family)| !family.memtable.is_empty())
+            .map(|(id, _)| *id)
+            .collect();
+        if !non_empty.is_empty() {
+            self.switch_memtables(&mut state, &non_empty)?;
+        }
+        self.flush_immutables(state)?;
+
+        // another thread may still be flushing
+        let mut state = self.lock_state();
+        while state
+            .families
+            .values()
+            .any(|family| !family.immutables.is_empty())
+        {
+            if let Some(err) = &state.bg_error {
+                return Err(background_error(err));
+            }
+            state = self.bg_work_done.wait(state).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Writes the live pairs of a family with keys in `range` to a dump
+    fn export<W: Write>(
+        &self,
+        family: u32,
+        range: Option<(&[u8], &[u8])>,
+        writer: W,
+    ) -> Result<u64> {
+        let (_, options) = self.family_files(family)?;
+        let mut dump = DumpWriter::new(writer, options.comparator.name())?;
+        let view = self.frozen_view(family)?;
+        Self::scan_view(&view, Vec::new(), range, |key, value, expires_at| {
+            dump.add(&key, &value, expires_at)
+        })?;
+        dump.finish()
+    }
+
+    /// Memtables and tables of a family holding exactly the writes made before the
+    /// call, see `export`
+    ///
+    /// Turns the active memtable immutable, so later writes go to a new one, and
+    /// flushes it. The active memtable of the returned view is that new one and
+    /// is not to be read.
+    #[allow(clippy::significant_drop_tightening)]
+    fn frozen_view(&self, family: u32) -> Result<ReadView> {
+        let mut state = self.lock_state();
+        let memtable = &state.family(family)?.memtable;
+        memtable.wait_for_writers();
+        if !memtable.is_empty() {
+            self.switch_memtables(&mut state, &[family])?;
+        }
+
+        let family = state.family(family)?;
+        let view = ReadView {
+            active: Arc::clone(&family.memtable),
+            immutables: family.immutables.clone(),
+            version: Arc::clone(&family.version),
+            operator: family.options.merge_operator.clone(),
+        };
+        self.flush_immutables(state)?;
+        Ok(view)
+    }
+
+    /// Copies external `SSTables` into a family and adds them to its tree
+    fn ingest<P: AsRef<Path>>(&self, id: u32, paths: &[P]) -> Result<()> {
+        let (dir, options) = self.family_files(id)?;
+
+        let mut files = Vec::new();
+        for path in paths {
+            let dest = self.new_sst_path(&dir);
+            match ingest::prepare(path.as_ref(), dest, &options) {
+                Ok(file) => files.push(file),
+                Err(err) => {
+                    remove_files(&files);
+                    return Err(err);
+                }
+            }
+        }
+
+        self.add_ingested(id, files)
+    }
+
+    /// Adds checked files inside the family directory to the family in one step
+    ///
+    /// The files are deleted if they can't be added.
+    fn add_ingested(&self, id: u32, mut files: Vec<IngestedFile>) -> Result<()> {
+        let result = self.family_files(id).and_then(|(_, options)| {
+            ingest::check_overlaps(&mut files, options.comparator.as_ref())?;
+            self.install_ingested(id, &options, &mut files)
+        });
+
+        let tables = match result {
+            Ok(tables) => tables,
+            Err(err) => {
+                remove_files(&files);
+                return Err(err);
+            }
+        };
+
+        self.notify(|listener| {
+            for table in &tables {
+                listener.on_table_file_created(table);
+            }
+        });
+        Ok(())
+    }
+
+    /// Get the directory and options of a family
+    #[allow(clippy::significant_drop_tightening)]
+    fn family_files(&self, id: u32) -> Result<(PathBuf, Arc<Options>)> {
+        let state = self.lock_state();
+        let family = state.family(id)?;
+        Ok((family.dir.clone(), Arc::clone(&family.options)))
+    }
+
+    /// Adds ingested files to a family once it has no unflushed writes left
+    ///
+    /// The files are renamed to new file numbers, which keeps them ahead of the
+    /// tables flushed meanwhile in L0.
+    #[allow(clippy::significant_drop_tightening)]
+    fn install_ingested(
+        &self,
+        id: u32,
+        options: &Options,
+        files: &mut [IngestedFile],
+    ) -> Result<Vec<TableFileInfo>> {
+        let mut state = self.lock_state();
+        loop {
+            if let Some(err) = &state.bg_error {
+                return Err(background_error(err));
+            }
+
+            let family = state.family(id)?;
+            if family.has_unflushed_data() {
+                // the family would count older unflushed writes as flushed
+                if !family.memtable.is_empty() {
+                    self.switch_memtables(&mut state, &[id])?;
+                }
+                self.flush_immutables(state)?;
+                state = self.lock_state();
+                if state.flushing {
+                    state = self.bg_work_done.wait(state).unwrap();
+                }
+            } else if state.compacting {
+                // the compaction could write to the levels the files go to
+                state = self.bg_work_done.wait(state).unwrap();
+            } else {
+                break;
+            }
+        }
+
+        let sequence = state.last_sequence + 1;
+        let family = state.families.get_mut(&id).unwrap();
+        let mut version = (*family.version).clone();
+        let mut tables = Vec::new();
+        let mut ingested = Vec::new();
+        for file in files.iter_mut() {
+            let path = self.new_sst_path(&family.dir);
+            fs::rename(&file.path, &path)?;
+            file.path = path;
+
+            sstable::write_largest_sequence(&file.path, sequence)?;
+            let sstable = SSTable::open_cached(file.path.clone(), options, &self.table_cache)?;
+            let level = ingest::target_level(&version, &file.smallest, &file.largest);
+            tables.push(table_info(&family.name, level, &sstable));
+            let sstable = Arc::new(sstable);
+            if id == DEFAULT_FAMILY_ID {
+                ingested.push(Arc::clone(&sstable));
+            }
+            version.levels[level].push(sstable);
+        }
+        version.sort_levels();
+
+        let flushed_sequence = mem::replace(&mut family.flushed_sequence, sequence);
+        if let Err(err) = install_version(family, version) {
+            family.flushed_sequence = flushed_sequence;
+            return Err(err);
+        }
+        state.last_sequence = sequence;
+        for table in ingested {
+            state.flushed_writes.add_table(table, sequence);
+        }
+
+        self.check_stall_condition(&mut state);
+        self.compaction_cv.notify_one();
+        Ok(tables)
+    }
+
+    /// Takes the current tables of every family and the live logs for a checkpoint
+    ///
+    /// Writes logged after the last flush of a family are replayed from the logs
+    /// when the checkpoint is opened. Also returns the sequence number of the
+    /// newest logged write.
+    #[allow(clippy::significant_drop_tightening)]
+    fn capture_files(&self) -> Result<(Vec<FamilySnapshot>, Vec<LogSnapshot>, u64)> {
+        let state = self.lock_state();
+        let families = state
+            .families
+            .values()
+            .map(|family| FamilySnapshot {
+                name: family.name.clone(),
+                version: Arc::clone(&family.version),
+                flushed_sequence: family.flushed_sequence,
+            })
+            .collect();
+
+        let numbers = state.old_logs.iter().map(|&(number, _)| number);
+        let logs = numbers
+            .chain(std::iter::once(state.wal.number()))
+            .map(|number| LogSnapshot::open(&wal::log_path(&self.data_dir, number)))
+            .collect::<Result<_>>()?;
+
+        Ok((families, logs, state.last_sequence))
+    }
+
+    /// Flushes the immutable memtables to L0, oldest first per column family
+    ///
+    /// Only one thread flushes at a time, others leave their memtable in the queue
+    /// for the flushing thread to pick up.
+    fn flush_immutables<'a>(&'a self, mut state: MutexGuard<'a, State>) -> Result<()> {
+        if state.flushing {
+            return Ok(());
+        }
+        state.flushing = true;
+
+        loop {
+            let next = state.families.iter().find_map(|(id, family)| {
+                let memtable = family.immutables.back()?;
+                let options = Arc::clone(&family.options);
+                let name = family.name.clone();
+                Some((*id, Arc::clone(memtable), family.dir.clone(), options, name))
+            });
+            let Some((id, memtable, dir, options, name)) = next else {
+                break;
+            };
+
+            drop(state);
+            let job = FlushJobInfo {
+                column_family: name,
+                path: self.new_sst_path(&dir),
+                num_entries: memtable.len(),
+                largest_sequence: memtable.largest_sequence(),
+            };
+            self.notify(|listener| listener.on_flush_begin(&job));
+
+            let timer = StopWatch::start(self.statistics(), Histogram::FlushMicros);
+            let result = self.write_level0_table(job.path.clone(), &options, &memtable);
+            drop(timer);
+            state = self.lock_state();
+
+            let result = result.and_then(|sstable| {
+                let table = table_info(&job.column_family, 0, &sstable);
+                Self::install_flush(&mut state, id, sstable, memtable.largest_sequence())?;
+                if id == DEFAULT_FAMILY_ID {
+                    state.flushed_writes.add_memtable(&memtable);
+                }
+                self.record(Ticker::FlushCount, 1);
+                self.record(Ticker::FlushBytes, table.file_size);
+                Ok(table)
+            });
+
+            let table = match result {
+                Ok(table) => table,
+                Err(err) => {
+                    state.flushing = false;
+                    state.bg_error = Some(err.to_string());
+                    self.bg_work_done.notify_all();
+                    drop(state);
+
+                    self.notify(|listener| {
+                        listener.on_background_error(BackgroundErrorReason::Flush, &err);
+                    });
+                    return Err(err);
+                }
+            };
+
+            self.account_memory(&mut state);
+            self.check_stall_condition(&mut state);
+
+            // the flushing flag keeps other threads from flushing meanwhile
+            drop(state);
+            self.notify(|listener| {
+                listener.on_table_file_created(&table);
+                listener.on_flush_completed(&job, &table);
+            });
+            state = self.lock_state();
+
+            self.delete_obsolete_logs(&mut state);
+            self.bg_work_done.notify_all();
+            self.compaction_cv.notify_one();
+        }
+
+        state.flushing = false;
+        Ok(())
+    }
+
+    /// Writes a memtable to a new L0 `SSTable` at `sst_path`.
+    fn write_level0_table(
+        &self,
+        sst_path: PathBuf,
+        options: &Options,
+        memtable: &Memtable,
+    ) -> Result<SSTable> {
+        // flush memtable to new SSTable
+        let mut builder = SSTableBuilder::with_options(sst_path.clone(), options)?;
+        builder.set_largest_sequence(memtable.largest_sequence());
+        for (key, value) in memtable {
+            builder.add(&key, &value)?;
+        }
+        builder.finish()?;
+
+        SSTable::open_cached(sst_path, options, &self.table_cache)
+    }
+
+    /// Adds a flushed table to L0 of its family and removes the flushed memtable
+    fn install_flush(state: &mut State, id: u32, sstable: SSTable, sequence: u64) -> Result<()> {
+        let Some(family) = state.families.get_mut(&id) else {
+            // dropped while flushing
+            sstable.mark_obsolete();
+            return Ok(());
+        };
+
+        let mut version = (*family.version).clone();
+        version.levels[0].insert(0, Arc::new(sstable));
+        family.flushed_sequence = family.flushed_sequence.max(sequence);
+        install_version(family, version)?;
+
+        family.immutables.pop_back();
+        Ok(())
+    }
+
+    /// Deletes the logs holding only writes which are flushed in every family
+    fn delete_obsolete_logs(&self, state: &mut State) {
+        let needed_after = state
+            .families
+            .values()
+            .filter(|family| family.has_unflushed_data())
+            .map(|family| family.flushed_sequence)
+            .min();
+
+        state.old_logs.retain(|&(number, last_sequence)| {
+            if needed_after.is_some_and(|sequence| last_sequence > sequence) {
+                return true;
+            }
+
+            // best effort, leftover logs are replayed and deleted on the next open
+            let _ = fs::remove_file(wal::log_path(&self.data_dir, number));
+            false
+        });
+    }
+
+    fn new_sst_path(&self, dir: &Path) -> PathBuf {
+        let sst_num = self.file_counter.fetch_add(1, Ordering::SeqCst);
+        manifest::sst_path(dir, sst_num)
+    }
+
+    /// Body of the compaction thread
+    #[allow(clippy::significant_drop_tightening)]
+    fn compaction_loop(&self) {
+        let mut state = self.lock_state();
+
+        loop {
+            if state.shutting_down {
+                return;
+            }
+
+            // a manual compaction keeps the flag set while it runs
+            let picked = if state.bg_error.is_none() && !state.compacting {
+                state.families.iter().find_map(|(id, family)| {
+                    let compaction = compaction::pick_compaction(
+                        &family.version,
+                        &family.options,
+                        &family.compact_pointers,
+                    )?;
+                    Some((*id, compaction))
+                })
+            } else {
+                None
+            };
+
+            let Some((id, compaction)) = picked else {
+                state = self.compaction_cv.wait(state).unwrap();
+                continue;
+            };
+
+            let family = state.families.get_mut(&id).unwrap();
+            family.compact_pointers[compaction.level] = compaction.largest_key();
+            let options = Arc::clone(&family.options);
+            let dir = family.dir.clone();
+            state.compacting = true;
+            drop(state);
+
+            let result = self.run_compaction(id, &options, &dir, &compaction);
+            if let Err(err) = &result {
+                self.notify(|listener| {
+                    listener.on_background_error(BackgroundErrorReason::Compaction, err);
+                });
+            }
+
+            state = self.lock_state();
+            if let Err(err) = result {
+                state.bg_error = Some(err.to_string());
+            }
+
+            state.compacting = false;
+            self.bg_work_done.notify_all();
+        }
+    }
+
+    /// Runs a manual compaction of `[start, end]` of a family, see `LSMTree::compact_range`
+    fn compact_range(&self, id: u32, start: &[u8], end: &[u8]) -> Result<()> {
+        let (dir, options) = self.family_files(id)?;
+        if options.comparator.compare(start, end) == cmp::Ordering::Greater {
+            return Err(Error::InvalidArgument(
+                "compact_range start is greater than end".to_string(),
+            ));
+        }
+        self.flush_memtables()?;
+
+        // wait for the running compaction, then keep the compaction thread out
+        let mut state = self.lock_state();
+        while state.compacting {
+            state = self.bg_work_done.wait(state).unwrap();
+        }
+        state.compacting = true;
+        drop(state);
+
+        let result = self.run_range_compactions(id, &options, &dir, start, end);
+
+        self.lock_state().compacting = false;
+        self.compaction_cv.notify_one();
+        self.bg_work_done.notify_all();
+        result
+    }
+
+    /// Pushes the tables of a family overlapping `[start, end]` level by level down
+    /// to the deepest level holding data of the range, then rewrites the tables of
+    /// that level in place
+    fn run_range_compactions(
+        &self,
+        id: u32,
+        options: &Options,
+        dir: &Path,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<()> {
+        let current_version = || {
+            self.lock_state()
+                .family(id)
+                .map(|family| Arc::clone(&family.version))
+        };
+
+        let version = current_version()?;
+        let Some(last_level) = (0..version.levels.len())
+            .rev()
+            .find(|&level| !version.overlapping_files(level, start, end).is_empty())
+        else {
+            return Ok(());
+        };
+
+        // L0 can't be the bottommost level, its data goes at least to L1
+        for level in 0..last_level.max(1) {
+            let version = current_version()?;
+            if let Some(compaction) = compaction::range_compaction(&version, level, start, end) {
+                self.run_compaction(id, options, dir, &compaction)?;
+            }
+        }
+
+        // tables of the last level only overlapping each other aren't reached from above
+        if last_level > 0 {
+            let version = current_version()?;
+            if let Some(compaction) =
+                compaction::in_place_compaction(&version, last_level, start, end)
+            {
+                self.run_compaction(id, options, dir, &compaction)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the outputs of a compaction of a family and installs them
+    fn run_compaction(
+        &self,
+        id: u32,
+        options: &Options,
+        dir: &Path,
+        compaction: &Compaction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let timer = StopWatch::start(self.statistics(), Histogram::CompactionMicros);
+        let outputs = compaction::run(compaction, options, &self.table_cache, &mut || {
+            self.new_sst_path(dir)
+        })?;
+        drop(timer);
+
+        let mut state = self.lock_state();
+        let Some(name) = state.families.get(&id).map(|family| family.name.clone()) else {
+            // dropped while compacting, the outputs are discarded
+            return install_compaction(&mut state, id, compaction, outputs);
+        };
+
+        let output_level = compaction.output_level();
+        let info = CompactionJobInfo {
+            inputs: compaction
+                .inputs
+                .iter()
+                .map(|sst| (compaction.level, sst))
+                .chain(compaction.next_inputs.iter().map(|sst| (output_level, sst)))
+                .map(|(level, sst)| table_info(&name, level, sst))
+                .collect(),
+            outputs: outputs
+                .iter()
+                .map(|sst| table_info(&name, output_level, sst))
+                .collect(),
+            column_family: name,
+            level: compaction.level,
+            output_level,
+            is_manual: compaction.manual,
+            elapsed: start.elapsed(),
+        };
+
+        install_compaction(&mut state, id, compaction, outputs)?;
+        self.check_stall_condition(&mut state);
+        drop(state);
+
+        let bytes_read: u64 = info.inputs.iter().map(|table| table.file_size).sum();
+        let bytes_written: u64 = info.outputs.iter().map(|table| table.file_size).sum();
+        self.record(Ticker::CompactionCount, 1);
+        self.record(Ticker::CompactionBytesRead, bytes_read);
+        self.record(Ticker::CompactionBytesWritten, bytes_written);
+
+        self.notify(|listener| {
+            for table in &info.outputs {
+                listener.on_table_file_created(table);
+            }
+            listener.on_compaction_completed(&info);
+        });
+        Ok(())
+    }
+}
+
+/// Entries of a memtable with keys in `range`, or all of them
+fn memtable_range<'a>(
+    memtable: &'a Memtable,
+    range: Option<(&[u8], &[u8])>,
+) -> Box<dyn Iterator<Item = (Vec<u8>, Value)> + 'a> {
+    match range {
+        Some((start, end)) => Box::new(memtable.range(start, end)),
+        None => Box::new(memtable.iter()),
+    }
+}
+
+/// Deletes files which didn't make it into the tree
+fn remove_files(files: &[IngestedFile]) {
+    // best effort, files outside the manifest are deleted on the next open
+    for file in files {
+        let _ = fs::remove_file(&file.path);
+    }
+}
+
+/// Describes a table of a family for the event listeners
+fn table_info(column_family: &str, level: usize, sstable: &SSTable) -> TableFileInfo {
+    TableFileInfo {
+        column_family: column_family.to_string(),
+        path: sstable.path().clone(),
+        level,
+        file_size: sstable.file_size(),
+        num_entries: sstable.num_entries(),
+    }
+}
+
+/// Replaces the inputs of a finished compaction with its outputs
+fn install_compaction(
+    state: &mut State,
+    id: u32,
+    compaction: &Compaction,
+    outputs: Vec<SSTable>,
+) -> Result<()> {
+    let Some(family) = state.families.get_mut(&id) else {
+        // dropped while compacting
+        for sst in &outputs {
+            sst.mark_obsolete();
+        }
+        return Ok(());
+    };
+
+    let mut version = (*family.version).clone();
+    let is_input = |sst: &Arc<SSTable>| compaction.all_inputs().any(|i| Arc::ptr_eq(i, sst));
+
+    version.levels[compaction.level].retain(|sst| !is_input(sst));
+    let output_level = &mut version.levels[compaction.output_level()];
+    output_level.retain(|sst| !is_input(sst));
+    output_level.extend(outputs.into_iter().map(Arc::new));
+    version.sort_levels();
+
+    install_version(family, version)?;
+
+    // deleted once no reader uses them anymore
+    for sst in compaction.all_inputs() {
+        sst.mark_obsolete();
+    }
+
+    Ok(())
+}
+
+/// Persists a new version of a family in its manifest and makes it current
+fn install_version(family: &mut Family, version: Version) -> Result<()> {
+    family.write_manifest(&version)?;
+    family.version = Arc::new(version);
+    Ok(())
+}
+
+/// Options of a column family, with the settings shared by the whole tree
+fn with_tree_options(tree: &Options, mut family: Options) -> Options {
+    family.statistics.clone_from(&tree.statistics);
+    family.listeners.clone_from(&tree.listeners);
+    family
+}
+
+/// Opens the default family and every family found below the data directory
+///
+/// Families without options in `column_families` use `options`.
+fn open_families(
+    data_dir: &Path,
+    options: &Options,
+    column_families: &[(String, Options)],
+    table_cache: &Arc<TableCache>,
+) -> Result<BTreeMap<u32, Family>> {
+    let mut family_options: HashMap<String, Options> = HashMap::new();
+    for (name, family) in column_families {
+        column_family::validate_name(name)?;
+        family_options.insert(name.clone(), with_tree_options(options, family.clone()));
+    }
+
+    let mut families = BTreeMap::new();
+    families.insert(
+        DEFAULT_FAMILY_ID,
+        Family::open(
+            DEFAULT_COLUMN_FAMILY.to_string(),
+            data_dir.to_path_buf(),
+            options.clone(),
+            table_cache,
+        )?,
+    );
+
+    let families_dir = data_dir.join(FAMILIES_DIR);
+    if families_dir.exists() {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(&families_dir)?
+            .filter_map(std::result::Result::ok)
+            .map(|entry| entry.path())
+            .collect();
+        dirs.sort();
+
+        for dir in dirs {
+            // leftover of an interrupted create or drop
+            if !dir.join(MANIFEST_FILE).exists() {
+                fs::remove_dir_all(&dir)?;
+                continue;
+            }
+
+            let name = dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    Error::Corruption(format!("Invalid column family directory {dir:?}"))
+                })?;
+            let options = family_options
+                .remove(&name)
+                .unwrap_or_else(|| options.clone());
+
+            let id = u32::try_from(families.len()).unwrap();
+            families.insert(id, Family::open(name, dir, options, table_cache)?);
+        }
+    }
+
+    Ok(families)
+}
+
+/// Applies the logged writes newer than what each family has flushed
+///
+/// Returns the sequence number of the newest write found.
+fn replay_logs(families: &mut BTreeMap<u32, Family>, log_paths: &[PathBuf]) -> Result<u64> {
+    let mut last_sequence = families
+        .values()
+        .map(|family| family.flushed_sequence)
+        .max()
+        .unwrap_or(0);
+
+    for path in log_paths {
+        for record in wal::read(path)? {
+            last_sequence = last_sequence.max(record.sequence);
+
+            for family in families.values_mut() {
+                if record.sequence <= family.flushed_sequence {
+                    continue;
+                }
+
+                let entries: Vec<(&[u8], &Value)> = record
+                    .entries
+                    .iter()
+                    .filter(|(name, _, _)| *name == family.name)
+                    .map(|(_, key, value)| (key.as_slice(), value))
+                    .collect();
+                if entries.is_empty() {
+                    continue;
+                }
+
+                let operator = family.options.merge_operator.as_deref();
+                family.memtable.apply(entries, record.sequence, operator)?;
+            }
+        }
+    }
+
+    Ok(last_sequence)
+}
+
+/// The most restrictive throttling state any family asks for
+fn stall_condition(state: &State) -> WriteStallCondition {
+    state
+        .families
+        .values()
+        .map(Family::stall_condition)
+        .max()
+        .unwrap_or(WriteStallCondition::Normal)
+}
+
+/// Computes the result of a point lookup from the values of the key, newest first
+///
+/// Merge operands are collected until a base value shows up, then folded onto it.
+fn resolve_value(
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    values: impl Iterator<Item = Result<Value>>,
+    now: u64,
+) -> Result<Option<Vec<u8>>> {
+    // oldest first
+    let mut operands: Vec<Vec<u8>> = Vec::new();
+    let mut base = None;
+
+    for value in values {
+        match value? {
+            Value::Merge(mut older) => {
+                older.append(&mut operands);
+                operands = older;
+            }
+            value => {
+                base = Some(value);
+                break;
+            }
+        }
+    }
+
+    if operands.is_empty() {
+        return Ok(base.and_then(|value| value_to_option(value, now)));
+    }
+
+    let operator = operator.ok_or_else(|| {
+        Error::InvalidArgument(
+            "Found merge operands but no merge operator is configured".to_string(),
+        )
+    })?;
+    let merged = merge_operator::fold(operator, key, base.as_ref(), &operands, now)?;
+    Ok(value_to_option(merged, now))
+}
+
+/// Sequence number of the newest write to a key, `None` if it was never written
+///
+/// Writes which left the memtables before the oldest open optimistic transaction
+/// started are not known and count as never written.
+fn key_sequence(state: &State, key: &[u8]) -> Result<Option<u64>> {
+    let family = state.default_family();
+    let memtable_sequence = family
+        .immutables
+        .iter()
+        .find_map(|memtable| memtable.sequence_of(key));
+
+    if let Some(sequence) = family.memtable.sequence_of(key).or(memtable_sequence) {
+        return Ok(Some(sequence));
+    }
+    state
+        .flushed_writes
+        .key_sequence(key, &family.options.comparator)
+}
+
+/// Converts an internal value into what `get` returns
+fn value_to_option(value: Value, now: u64) -> Option<Vec<u8>> {
+    match value {
+        Value::Some(v) => Some(v),
+        Value::Expiring { value, expires_at } if expires_at > now => Some(value),
+        // operands are resolved before, see `resolve_value`
+        Value::Expiring { .. } | Value::Tombstone | Value::Merge(_) => None,
+    }
+}
+
+fn background_error(msg: &str) -> Error {
+    Error::Io(std::io::Error::other(format!("background error: {msg}")))
+}
+
+fn dropped_family() -> Error {
+    Error::InvalidArgument("Column family was dropped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_listener::TableFileDeletionInfo;
+    use crate::statistics::{Histogram, Statistics, Ticker};
+    use crate::test_util::temp_dir;
+    use crate::{
+        CompactionFilter, CompactionFilterContext, Decision, MEMTABLE_SIZE_THRESHOLD,
+        MemtableRepKind, WriteBufferManager,
+    };
+    use std::fs;
+    use std::sync::atomic::AtomicBool;
+
+    fn l0_len(tree: &LSMTree) -> usize {
+        tree.num_files_at_level(0)
+    }
+
+    fn memtable_is_empty(tree: &LSMTree) -> bool {
+        tree.inner.lock_state().default_family().memtable.is_empty()
+    }
+
+    /// Blocks until the compaction thread has nothing left to do
+    #[allow(clippy::significant_drop_tightening)]
+    fn wait_for_compactions(tree: &LSMTree) {
+        let inner = &tree.inner;
+        let mut state = inner.lock_state();
+        while state.compacting
+            || state.families.values().any(|family| {
+                compaction::pick_compaction(
+                    &family.version,
+                    &family.options,
+                    &family.compact_pointers,
+                )
+                .is_some()
+            })
+        {
+            state = inner.bg_work_done.wait(state).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_open_creates_dir() {
+        let path = temp_dir("lsm", "open_creates_dir");
+        LSMTree::open(&path).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_put_and_get_memtable_only() {
+        let path = temp_dir("lsm", "put_get_memtable");
+        let tree = LSMTree::open(path).unwrap();
+
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        let val = tree.get(b"key1").unwrap();
+        assert_eq!(val, Some(b"value1".to_vec()));
+    }
+
+    #[test]
+    fn test_memtable_flush() {
+        let path = temp_dir("lsm", "memtable_flush");
+        let tree = LSMTree::open(path.clone()).unwrap();
+
+        // small put, does not trigger a flush
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        assert_eq!(l0_len(&tree), 0);
+
+        // large put to trigger a flush
+        let big_value = vec![0u8; MEMTABLE_SIZE_THRESHOLD];
+        tree.put(b"key2".to_vec(), big_value).unwrap();
+
+        // Memtable should be flushed and a new one created
+        assert_eq!(l0_len(&tree), 1);
+        assert!(memtable_is_empty(&tree));
+
+        // SSTable file should exist
+        assert_eq!(
+            fs::read_dir(&path)
+                .unwrap()
+                .filter_map(std::result::Result::ok)
+                .filter(|e| e.path().extension().is_some_and(|ext| ext == "sst"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_memtable_flush_counts_overhead() {
+        let path = temp_dir("lsm", "memtable_flush_overhead");
+        let tree = LSMTree::open(path).unwrap();
+
+        // 200 bytes of keys and values, but each entry takes a node slot too
+        for i in 0..100u8 {
+            tree.put(vec![i], vec![i]).unwrap();
+        }
+        assert!(l0_len(&tree) >= 1);
+    }
+
+    #[test]
+    fn test_get_after_flush() {
+        let path = temp_dir("lsm", "get_after_flush");
+        let tree = LSMTree::open(path).unwrap();
+
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        // flush
+        tree.put(b"key2".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
+            .unwrap();
+
+        // key1 should now be in an SSTable
+        assert!(memtable_is_empty(&tree));
+        let val = tree.get(b"key1").unwrap();
+        assert_eq!(val, Some(b"value1".to_vec()));
+    }
+
+    #[test]
+    fn test_multiple_flushes() {
+        let path = temp_dir("lsm", "multiple_flushes");
+        let tree = LSMTree::open(path).unwrap();
+
+        // flush
+        tree.put(b"key1".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
+            .unwrap();
+        assert_eq!(l0_len(&tree), 1);
+
+        // flushq again
+        tree.put(b"key2".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
+            .unwrap();
+        assert_eq!(l0_len(&tree), 2);
+
+        // check values from both SSTables
+        let val1 = tree.get(b"key1").unwrap();
+        assert_eq!(val1, Some(vec![0u8; MEMTABLE_SIZE_THRESHOLD]));
+        let val2 = tree.get(b"key2").unwrap();
+        assert_eq!(val2, Some(vec![0u8; MEMTABLE_SIZE_THRESHOLD]));
+    }
+
+    #[test]
+    fn test_read_priority_memtable_over_sstable() {
+        let path = temp_dir("lsm", "read_priority");
+        let tree = LSMTree::open(path).unwrap();
+
+        // put initial value and flush it
+        tree.put(b"key1".to_vec(), b"old_value".to_vec()).unwrap();
+        tree.put(b"filler".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
+            .unwrap();
+        assert_eq!(l0_len(&tree), 1);
+
+        // put new value in memtable
+        tree.put(b"key1".to_vec(), b"new_value".to_vec()).unwrap();
+
+        // should return new value from memtable
+        let val = tree.get(b"key1").unwrap();
+        assert_eq!(val, Some(b"new_value".to_vec()));
+    }
+
+    #[test]
+    fn test_tombstone_in_memtable_masks_sstable() {
+        let path = temp_dir("lsm", "tombstone_mask");
+        let tree = LSMTree::open(path).unwrap();
+
+        // put value and flush it
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        tree.put(b"filler".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
+            .unwrap();
+        assert_eq!(l0_len(&tree), 1);
+
+        // delete it (places tombstone in memtable)
+        tree.delete(b"key1".to_vec()).unwrap();
+
+        // get should return None because of the tombstone
+        let val = tree.get(b"key1").unwrap();
+        assert_eq!(val, None);
+    }
+
+    #[test]
+    fn test_restart_recovers_sstables() {
+        let path = temp_dir("lsm", "restart_recovery");
+
+        // create LSMT, write some data, flush
+        {
+            let tree = LSMTree::open(&path).unwrap();
+            tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+            tree.put(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+            tree.put(b"filler".to_vec(), vec![0u8; MEMTABLE_SIZE_THRESHOLD])
+                .unwrap();
+            assert_eq!(l0_len(&tree), 1);
+
+            // tree is dropped here since it goes out of scope
+        }
+
+        // re-open LSMT, should recover SSTables
+        {
+            let tree = LSMTree::open(&path).unwrap();
+            assert_eq!(l0_len(&tree), 1);
+            assert!(memtable_is_empty(&tree));
+
+            // data should be accessible
+            let val1 = tree.get(b"key1").unwrap();
+            assert_eq!(val1, Some(b"value1".to_vec()));
+            let val2 = tree.get(b"key2").unwrap();
+            assert_eq!(val2, Some(b"value2".to_vec()));
+        }
+    }
+
+    fn small_trigger_options() -> Options {
+        Options {
+            level0_file_num_compaction_trigger: 2,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_compaction_moves_l0_down() {
+        let path = temp_dir("lsm", "compaction_l0");
+        let tree = LSMTree::open_with_options(&path, small_trigger_options()).unwrap();
+
+        for i in 0..4u32 {
+            tree.put(
+                format!("key{i}").into_bytes(),
+                format!("value{i}").into_bytes(),
+            )
+            .unwrap();
+            tree.put(b"filler".to_vec(), vec![i as u8; MEMTABLE_SIZE_THRESHOLD])
+                .unwrap();
+        }
+        tree.delete(b"key0".to_vec()).unwrap();
+        wait_for_compactions(&tree);
+
+        assert!(l0_len(&tree) < 2);
+        assert!(tree.num_files_at_level(1) > 0);
+
+        assert_eq!(tree.get(b"key0").unwrap(), None);
+        assert_eq!(tree.get(b"key3").unwrap(), Some(b"value3".to_vec()));
+        assert_eq!(
+            tree.get(b"filler").unwrap(),
+            Some(vec![3u8; MEMTABLE_SIZE_THRESHOLD])
+        );
+    }
+
+    #[test]
+    fn test_restart_after_compaction() {
+        let path = temp_dir("lsm", "restart_after_compaction");
+
+        {
+            let tree = LSMTree::open_with_options(&path, small_trigger_options()).unwrap();
+            for i in 0..6u32 {
+                tree.put(
+                    format!("key{i}").into_bytes(),
+                    vec![i as u8; MEMTABLE_SIZE_THRESHOLD],
+                )
+                .unwrap();
+            }
+            wait_for_compactions(&tree);
+        }
+
+        let tree = LSMTree::open_with_options(&path, small_trigger_options()).unwrap();
+        assert!(tree.num_files_at_level(1) > 0);
+        for i in 0..6u32 {
+            let val = tree.get(format!("key{i}").as_bytes()).unwrap();
+            assert_eq!(val, Some(vec![i as u8; MEMTABLE_SIZE_THRESHOLD]));
+        }
+
+        // only live tables are left in the directory
+        let num_files = fs::read_dir(&path)
+            .unwrap()
+            .filter_map(std::result::Result::ok)
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "sst"))
+            .count();
+        let live: usize = (0..7).map(|level| tree.num_files_at_level(level)).sum();
+        assert_eq!(num_files, live);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let path = temp_dir("lsm", "invalid_options");
+        let options = Options {
+            level0_stop_writes_trigger: 1,
+            ..Options::default()
+        };
+
+        match LSMTree::open_with_options(path, options) {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("Expected invalid argument error"),
+        }
+    }
+
+    /// Pretends that `count` memtables are waiting for a flush
+    fn add_fake_immutables(tree: &LSMTree, count: usize) {
+        let mut state = tree.inner.lock_state();
+        let family = state.families.get_mut(&DEFAULT_FAMILY_ID).unwrap();
+        for _ in 0..count {
+            family.immutables.push_back(Arc::new(Memtable::new()));
+        }
+        drop(state);
+    }
+
+    #[test]
+    fn test_write_delayed_over_soft_limit() {
+        let path = temp_dir("lsm", "write_delayed");
+        let options = Options {
+            delayed_write_rate: 1000, // 1ms per byte
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(path, options).unwrap();
+
+        add_fake_immutables(&tree, tree.inner.options.memtable_slowdown_writes_trigger);
+        assert_eq!(tree.write_stall_condition(), WriteStallCondition::Delayed);
+
+        tree.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+        let stats = tree.stall_stats();
+        assert_eq!(stats.delayed_writes, 1);
+        assert_eq!(stats.delayed_time, Duration::from_millis(8));
+        assert_eq!(stats.stopped_writes, 0);
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_write_stopped_over_hard_limit() {
+        let path = temp_dir("lsm", "write_stopped");
+        let tree = LSMTree::open(path).unwrap();
+
+        add_fake_immutables(&tree, tree.inner.options.memtable_stop_writes_trigger);
+        assert_eq!(tree.write_stall_condition(), WriteStallCondition::Stopped);
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| tree.put(b"key".to_vec(), b"value".to_vec()));
+
+            thread::sleep(Duration::from_millis(50));
+            assert!(!writer.is_finished());
+
+            // simulate the flushes catching up
+            let mut state = tree.inner.lock_state();
+            let family = state.families.get_mut(&DEFAULT_FAMILY_ID).unwrap();
+            family.immutables.clear();
+            drop(state);
+            tree.inner.bg_work_done.notify_all();
+
+            writer.join().unwrap().unwrap();
+        });
+
+        let stats = tree.stall_stats();
+        assert_eq!(stats.stopped_writes, 1);
+        assert!(stats.stopped_time >= Duration::from_millis(50));
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let path = temp_dir("lsm", "concurrent_writers");
+        let tree = LSMTree::open_with_options(path, small_trigger_options()).unwrap();
+
+        thread::scope(|s| {
+            for t in 0..4u32 {
+                let tree = &tree;
+                s.spawn(move || {
+                    for i in 0..200u32 {
+                        let key = format!("t{t}-key{i:03}").into_bytes();
+                        tree.put(key, vec![t as u8; 64]).unwrap();
+                    }
+                });
+            }
+        });
+        wait_for_compactions(&tree);
+
+        for t in 0..4u32 {
+            for i in 0..200u32 {
+                let key = format!("t{t}-key{i:03}");
+                assert_eq!(tree.get(key.as_bytes()).unwrap(), Some(vec![t as u8; 64]));
+            }
+        }
+    }
+
+    fn total_entries(tree: &LSMTree) -> u32 {
+        let state = tree.inner.lock_state();
+        state
+            .default_family()
+            .version
+            .levels
+            .iter()
+            .flatten()
+            .map(|sst| sst.num_entries())
+            .sum()
+    }
+
+    #[test]
+    fn test_flush() {
+        let path = temp_dir("lsm", "flush");
+        let tree = LSMTree::open(path).unwrap();
+
+        // flushing an empty memtable is a no-op
+        tree.flush().unwrap();
+        assert_eq!(l0_len(&tree), 0);
+
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        tree.flush().unwrap();
+
+        assert_eq!(l0_len(&tree), 1);
+        assert!(memtable_is_empty(&tree));
+        assert_eq!(tree.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+    }
+
+    #[test]
+    fn test_compact_range_drops_tombstones() {
+        let path = temp_dir("lsm", "compact_range");
+        let tree = LSMTree::open(path).unwrap();
+
+        for i in 0..100u32 {
+            tree.put(format!("key{i:03}").into_bytes(), vec![0u8; 100])
+                .unwrap();
+        }
+        tree.flush().unwrap();
+
+        // overwrite some keys and delete the rest
+        for i in 0..100u32 {
+            let key = format!("key{i:03}").into_bytes();
+            if i % 10 == 0 {
+                tree.put(key, vec![1u8; 100]).unwrap();
+            } else {
+                tree.delete(key).unwrap();
+            }
+        }
+
+        tree.compact_range(b"key000", b"key099").unwrap();
+
+        assert_eq!(l0_len(&tree), 0);
+        assert_eq!(total_entries(&tree), 10);
+        for i in 0..100u32 {
+            let expected = (i % 10 == 0).then(|| vec![1u8; 100]);
+            assert_eq!(tree.get(format!("key{i:03}").as_bytes()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_compact_range_rewrites_last_level() {
+        let path = temp_dir("lsm", "compact_range_last_level");
+        let external = temp_dir("lsm", "compact_range_last_level_external");
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+
+        // nothing overlaps, so both files go straight to the last level
+        let mut files = Vec::new();
+        for name in ["default", "users"] {
+            let file = external.join(format!("{name}.sst"));
+            let mut builder = SSTableBuilder::new(file.clone()).unwrap();
+            builder.add(b"a", &Value::Some(b"1".to_vec())).unwrap();
+            builder.add(b"b", &Value::Tombstone).unwrap();
+            builder.add(b"c", &Value::Tombstone).unwrap();
+            builder.finish().unwrap();
+            files.push(file);
+        }
+        tree.ingest_external_files(&files[..1]).unwrap();
+        tree.ingest_external_files_cf(&users, &files[1..]).unwrap();
+
+        let last_level = Options::default().num_levels - 1;
+        let last_level_entries = |id: u32| -> Vec<u32> {
+            let version = Arc::clone(&tree.inner.lock_state().family(id).unwrap().version);
+            version.levels[last_level]
+                .iter()
+                .map(|sst| sst.num_entries())
+                .collect()
+        };
+        assert_eq!(tree.num_files_at_level(last_level), 1);
+        assert_eq!(last_level_entries(DEFAULT_FAMILY_ID), vec![3]);
+
+        tree.compact_range(b"a", b"z").unwrap();
+        assert_eq!(last_level_entries(DEFAULT_FAMILY_ID), vec![1]);
+        assert_eq!(total_entries(&tree), 1);
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        assert_eq!(last_level_entries(users.id()), vec![3]);
+        tree.compact_range_cf(&users, b"a", b"z").unwrap();
+        assert_eq!(last_level_entries(users.id()), vec![1]);
+        assert_eq!(tree.get_cf(&users, b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get_cf(&users, b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compact_range_outside_data() {
+        let path = temp_dir("lsm", "compact_range_outside");
+        let tree = LSMTree::open(path).unwrap();
+
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        tree.delete(b"key2".to_vec()).unwrap();
+        tree.compact_range(b"x", b"z").unwrap();
+
+        // the memtable got flushed, but nothing overlaps the range
+        assert_eq!(l0_len(&tree), 1);
+        assert_eq!(total_entries(&tree), 2);
+
+        match tree.compact_range(b"z", b"a") {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("Expected invalid argument error"),
+        }
+    }
+
+    /// Drops `session:` keys and upper-cases `upper:` values, records the contexts it saw
+    struct TestFilter {
+        contexts: Mutex<Vec<CompactionFilterContext>>,
+    }
+
+    impl CompactionFilter for TestFilter {
+        fn name(&self) -> &'static str {
+            "TestFilter"
+        }
+
+        fn filter(&self, context: &CompactionFilterContext, key: &[u8], value: &[u8]) -> Decision {
+            self.contexts.lock().unwrap().push(*context);
+
+            if key.starts_with(b"session:") {
+                Decision::Remove
+            } else if key.starts_with(b"upper:") {
+                Decision::ChangeValue(value.to_ascii_uppercase())
+            } else {
+                Decision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let path = temp_dir("lsm", "compaction_filter");
+        let filter = Arc::new(TestFilter {
+            contexts: Mutex::new(Vec::new()),
+        });
+        let options = Options {
+            compaction_filter: Some(filter.clone()),
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(path, options).unwrap();
+
+        tree.put(b"session:1".to_vec(), b"token".to_vec()).unwrap();
+        tree.put(b"upper:1".to_vec(), b"value".to_vec()).unwrap();
+        tree.put(b"plain:1".to_vec(), b"value".to_vec()).unwrap();
+        tree.compact_range(b"a", b"z").unwrap();
+
+        assert_eq!(tree.get(b"session:1").unwrap(), None);
+        assert_eq!(tree.get(b"upper:1").unwrap(), Some(b"VALUE".to_vec()));
+        assert_eq!(tree.get(b"plain:1").unwrap(), Some(b"value".to_vec()));
+
+        // bottommost, so the removed key is dropped instead of turned into a tombstone
+        assert_eq!(total_entries(&tree), 2);
+
+        let contexts = filter.contexts.lock().unwrap().clone();
+        assert_eq!(contexts.len(), 3);
+        assert!(contexts.iter().all(|c| c.is_manual && c.is_bottommost));
+        assert!(contexts.iter().all(|c| c.level == 0 && c.output_level == 1));
+    }
+
+    #[test]
+    fn test_put_with_ttl_expires() {
+        let path = temp_dir("lsm", "ttl_expires");
+        let tree = LSMTree::open(path).unwrap();
+
+        tree.put_with_ttl(
+            b"short".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(20),
+        )
+        .unwrap();
+        tree.put_with_ttl(
+            b"long".to_vec(),
+            b"value".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        assert_eq!(tree.get(b"short").unwrap(), Some(b"value".to_vec()));
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(tree.get(b"short").unwrap(), None);
+        assert_eq!(tree.get(b"long").unwrap(), Some(b"value".to_vec()));
+
+        // expiry survives a flush
+        tree.flush().unwrap();
+        assert_eq!(tree.get(b"short").unwrap(), None);
+        assert_eq!(tree.get(b"long").unwrap(), Some(b"value".to_vec()));
+
+        let keys: Vec<_> = tree
+            .scan(b"a", b"z")
+            .unwrap()
+            .into_iter()
+            .map(|(k, _v)| k)
+            .collect();
+        assert_eq!(keys, vec![b"long".to_vec()]);
+
+        // compaction drops the expired entry physically
+        tree.compact_range(b"a", b"z").unwrap();
+        assert_eq!(total_entries(&tree), 1);
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        let path = temp_dir("lsm", "default_ttl");
+        let options = Options {
+            default_ttl: Some(Duration::from_millis(20)),
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(path, options).unwrap();
+
+        tree.put(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+        assert_eq!(tree.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(tree.get(b"key1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_scan() {
+        let path = temp_dir("lsm", "scan");
+        let tree = LSMTree::open(path).unwrap();
+
+        for i in 0..10u32 {
+            tree.put(format!("key{i}").into_bytes(), b"old".to_vec())
+                .unwrap();
+        }
+        tree.flush().unwrap();
+
+        tree.put(b"key3".to_vec(), b"new".to_vec()).unwrap();
+        tree.delete(b"key4".to_vec()).unwrap();
+
+        let result = tree.scan(b"key2", b"key5").unwrap();
+        assert_eq!(
+            result,
+            vec![
+                (b"key2".to_vec(), b"old".to_vec()),
+                (b"key3".to_vec(), b"new".to_vec()),
+                (b"key5".to_vec(), b"old".to_vec()),
+            ]
+        );
+    }
+
+    fn merge_options() -> Options {
+        Options {
+            merge_operator: Some(Arc::new(crate::U64AddOperator)),
+            ..Options::default()
+        }
+    }
+
+    fn counter(value: u64) -> Vec<u8> {
+        value.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_merge_across_flushes() {
+        let path = temp_dir("lsm", "merge_flushes");
+        let tree = LSMTree::open_with_options(path, merge_options()).unwrap();
+
+        tree.put(b"counter".to_vec(), counter(10)).unwrap();
+        tree.flush().unwrap();
+        tree.merge(b"counter".to_vec(), counter(1)).unwrap();
+        tree.flush().unwrap();
+        tree.merge(b"counter".to_vec(), counter(2)).unwrap();
+
+        // base in one table, operands in another table and the memtable
+        assert_eq!(tree.get(b"counter").unwrap(), Some(counter(13)));
+
+        // merging onto a missing key starts from nothing
+        tree.merge(b"fresh".to_vec(), counter(5)).unwrap();
+        assert_eq!(tree.get(b"fresh").unwrap(), Some(counter(5)));
+
+        let scanned = tree.scan(b"a", b"z").unwrap();
+        assert_eq!(
+            scanned,
+            vec![
+                (b"counter".to_vec(), counter(13)),
+                (b"fresh".to_vec(), counter(5)),
+            ]
+        );
+
+        // compaction collapses the operands into a plain value
+        tree.compact_range(b"a", b"z").unwrap();
+        assert_eq!(total_entries(&tree), 2);
+        assert_eq!(tree.get(b"counter").unwrap(), Some(counter(13)));
+    }
+
+    #[test]
+    fn test_merge_after_delete() {
+        let path = temp_dir("lsm", "merge_after_delete");
+        let tree = LSMTree::open_with_options(path, merge_options()).unwrap();
+
+        tree.put(b"counter".to_vec(), counter(10)).unwrap();
+        tree.flush().unwrap();
+        tree.delete(b"counter".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.merge(b"counter".to_vec(), counter(1)).unwrap();
+
+        assert_eq!(tree.get(b"counter").unwrap(), Some(counter(1)));
+    }
+
+    #[test]
+    fn test_merge_without_operator() {
+        let path = temp_dir("lsm", "merge_without_operator");
+        let tree = LSMTree::open(path).unwrap();
+
+        match tree.merge(b"key".to_vec(), b"operand".to_vec()) {
+            Err(Error::InvalidArgument(_)) => {}
+            _ => panic!("Expected invalid argument error"),
+        }
+    }
+
+    #[test]
+    fn test_write_batch_is_atomic() {
+        let path = temp_dir("lsm", "write_batch");
+        let tree = LSMTree::open_with_options(&path, merge_options()).unwrap();
+        tree.put(b"bad".to_vec(), b"not a counter".to_vec())
+            .unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a".to_vec(), b"1".to_vec());
+        batch.merge(b"c".to_vec(), counter(1));
+        batch.merge(b"c".to_vec(), counter(2));
+        batch.delete(b"a".to_vec());
+        tree.write(batch).unwrap();
+
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"c").unwrap(), Some(counter(3)));
+
+        // the failing merge rejects the whole batch
+        let mut batch = WriteBatch::new();
+        batch.put(b"x".to_vec(), b"1".to_vec());
+        batch.merge(b"bad".to_vec(), counter(1));
+        assert!(tree.write(batch).is_err());
+        assert_eq!(tree.get(b"x").unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_batch_is_seen_whole() {
+        for kind in [MemtableRepKind::BTree, MemtableRepKind::SkipList] {
+            let path = temp_dir("lsm", "write_batch_whole");
+            let options = Options {
+                memtable_rep: kind,
+                write_buffer_size: 64 * 1024,
+                ..Options::default()
+            };
+            let tree = LSMTree::open_with_options(&path, options).unwrap();
+            let done = AtomicBool::new(false);
+
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    for i in 0..2000u32 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(b"a".to_vec(), i.to_be_bytes().to_vec());
+                        batch.put(b"b".to_vec(), i.to_be_bytes().to_vec());
+                        tree.write(batch).unwrap();
+                    }
+                    done.store(true, Ordering::SeqCst);
+                });
+
+                while !done.load(Ordering::SeqCst) {
+                    // a batch read in part would show `b` behind `a`
+                    let a = tree.get(b"a").unwrap();
+                    let b = tree.get(b"b").unwrap();
+                    assert!(b >= a);
+
+                    let both = tree.multi_get(&[b"a", b"b"]).unwrap();
+                    assert_eq!(both[0], both[1]);
+                    let scanned = tree.scan(b"a", b"b").unwrap();
+                    if let [(_, a), (_, b)] = scanned.as_slice() {
+                        assert_eq!(a, b);
+                    }
+                }
+            });
+        }
+    }
+
+    fn log_files(path: &Path) -> usize {
+        fs::read_dir(path)
+            .unwrap()
+            .filter(|entry| wal::log_number(&entry.as_ref().unwrap().path()).is_some())
+            .count()
+    }
+
+    #[test]
+    fn test_column_families_are_isolated() {
+        let path = temp_dir("lsm", "cf_isolated");
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+        assert!(
+            tree.create_column_family("users", Options::default())
+                .is_err()
+        );
+        assert!(
+            tree.create_column_family("../up", Options::default())
+                .is_err()
+        );
+
+        tree.put(b"key".to_vec(), b"default".to_vec()).unwrap();
+        tree.put_cf(&users, b"key".to_vec(), b"users".to_vec())
+            .unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put_cf(&users, b"a".to_vec(), b"1".to_vec());
+        batch.delete_cf(&users, b"key".to_vec());
+        batch.put(b"a".to_vec(), b"2".to_vec());
+        tree.write(batch).unwrap();
+
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(tree.get_cf(&users, b"key").unwrap(), None);
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(
+            tree.scan_cf(&users, b"a", b"z").unwrap(),
+            vec![(b"a".to_vec(), b"1".to_vec())]
+        );
+
+        let names: Vec<String> = tree
+            .column_families()
+            .iter()
+            .map(|family| family.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["default", "users"]);
+        assert_eq!(tree.column_family("users"), Some(users));
+    }
+
+    #[test]
+    fn test_drop_column_family() {
+        let path = temp_dir("lsm", "cf_drop");
+        let tree = LSMTree::open(&path).unwrap();
+
+        let default = tree.column_family(DEFAULT_COLUMN_FAMILY).unwrap();
+        assert!(tree.drop_column_family(&default).is_err());
+
+        let logs = tree
+            .create_column_family("logs", Options::default())
+            .unwrap();
+        tree.put_cf(&logs, b"key".to_vec(), b"value".to_vec())
+            .unwrap();
+        tree.flush().unwrap();
+        tree.drop_column_family(&logs).unwrap();
+
+        assert!(tree.get_cf(&logs, b"key").is_err());
+        assert!(
+            tree.put_cf(&logs, b"key".to_vec(), b"value".to_vec())
+                .is_err()
+        );
+        assert!(tree.column_family("logs").is_none());
+
+        // a new family of the same name starts empty, also after a reopen
+        let logs = tree
+            .create_column_family("logs", Options::default())
+            .unwrap();
+        assert_eq!(tree.get_cf(&logs, b"key").unwrap(), None);
+        drop(tree);
+
+        let tree = LSMTree::open(&path).unwrap();
+        let logs = tree.column_family("logs").unwrap();
+        assert_eq!(tree.get_cf(&logs, b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_wal_recovers_unflushed_writes() {
+        let path = temp_dir("lsm", "wal_recovery");
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put_cf(&users, b"b".to_vec(), b"2".to_vec());
+        batch.delete(b"a".to_vec());
+        tree.write(batch).unwrap();
+        tree.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        drop(tree);
+
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree.column_family("users").unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(tree.get_cf(&users, b"b").unwrap(), Some(b"2".to_vec()));
+
+        // replayed writes are flushed on open, only the new log is left
+        assert_eq!(log_files(&path), 1);
+
+        // sequence numbers continue after the replayed ones
+        tree.put_cf(&users, b"b".to_vec(), b"4".to_vec()).unwrap();
+        drop(tree);
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree.column_family("users").unwrap();
+        assert_eq!(tree.get_cf(&users, b"b").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_old_logs_deleted_after_flush() {
+        let path = temp_dir("lsm", "wal_cleanup");
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+
+        tree.put_cf(&users, b"a".to_vec(), b"1".to_vec()).unwrap();
+        tree.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        tree.flush().unwrap();
+        assert_eq!(log_files(&path), 1);
+
+        drop(tree);
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree.column_family("users").unwrap();
+        assert_eq!(tree.get_cf(&users, b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_custom_comparator() {
+        let path = temp_dir("lsm", "comparator");
+        let options = Options {
+            comparator: Arc::new(crate::ReverseBytewiseComparator),
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(&path, options.clone()).unwrap();
+
+        for i in 0..500u32 {
+            let key = format!("key{i:03}").into_bytes();
+            tree.put(key.clone(), key).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.compact_range(b"key499", b"key000").unwrap();
+        tree.delete(b"key250".to_vec()).unwrap();
+
+        assert_eq!(tree.num_files_at_level(0), 0);
+        assert_eq!(tree.get(b"key123").unwrap(), Some(b"key123".to_vec()));
+
+        // scans run from the first to the last key in comparator order
+        let keys: Vec<_> = tree
+            .scan(b"key252", b"key248")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                b"key252".to_vec(),
+                b"key251".to_vec(),
+                b"key249".to_vec(),
+                b"key248".to_vec(),
+            ]
+        );
+        assert!(tree.scan(b"key000", b"key001").is_err());
+        drop(tree);
+
+        // the tables can't be read with a different comparator
+        assert!(matches!(
+            LSMTree::open(&path),
+            Err(Error::InvalidArgument(_))
+        ));
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+        assert_eq!(tree.get(b"key499").unwrap(), Some(b"key499".to_vec()));
+    }
+
+    #[test]
+    fn test_shared_block_cache() {
+        let cache = Arc::new(crate::BlockCache::new(1024 * 1024));
+        let options = Options {
+            block_cache: Some(Arc::clone(&cache)),
+            ..Options::default()
+        };
+
+        let trees: Vec<_> = ["block_cache_a", "block_cache_b"]
+            .into_iter()
+            .map(|name| LSMTree::open_with_options(temp_dir("lsm", name), options.clone()).unwrap())
+            .collect();
+
+        for tree in &trees {
+            tree.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+            tree.flush().unwrap();
+        }
+
+        // the first read of each tree misses, the second one hits
+        for tree in &trees {
+            for _ in 0..2 {
+                assert_eq!(tree.get(b"key").unwrap(), Some(b"value".to_vec()));
+            }
+        }
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(cache.hits(), 2);
+        assert!(cache.usage() > 0);
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let path = temp_dir("lsm", "multi_get");
+        let options = Options {
+            target_file_size: 512,
+            ..merge_options()
+        };
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+        let key = |i: u32| format!("key{i:03}").into_bytes();
+
+        // L1 split over several tables, then L0, then the memtable
+        for i in 0..200 {
+            tree.put(key(i), counter(u64::from(i))).unwrap();
+        }
+        tree.flush().unwrap();
+        tree.compact_range(&key(0), &key(199)).unwrap();
+        assert!(tree.num_files_at_level(1) > 1);
+
+        for i in (0..200).step_by(7) {
+            tree.merge(key(i), counter(1000)).unwrap();
+        }
+        tree.flush().unwrap();
+        for i in (0..200).step_by(5) {
+            tree.delete(key(i)).unwrap();
+        }
+        tree.put(key(300), counter(300)).unwrap();
+
+        // unsorted, with duplicates and missing keys
+        let keys: Vec<Vec<u8>> = [300, 7, 5, 199, 35, 7, 250, 0, 14, 1]
+            .into_iter()
+            .map(key)
+            .collect();
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+
+        let expected: Vec<_> = keys.iter().map(|k| tree.get(k).unwrap()).collect();
+        assert_eq!(tree.multi_get(&keys).unwrap(), expected);
+        assert_eq!(expected[1], Some(counter(1007)));
+        assert_eq!(expected[2], None);
+        assert_eq!(expected[6], None);
+    }
+
+    #[test]
+    fn test_get_pinned() {
+        let path = temp_dir("lsm", "get_pinned");
+        let options = Options {
+            allow_mmap_reads: true,
+            ..merge_options()
+        };
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+
+        tree.put(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+        tree.put(b"deleted".to_vec(), b"value".to_vec()).unwrap();
+        tree.merge(b"counter".to_vec(), counter(1)).unwrap();
+        tree.flush().unwrap();
+        tree.delete(b"deleted".to_vec()).unwrap();
+        tree.put(b"active".to_vec(), b"fresh".to_vec()).unwrap();
+        tree.merge(b"counter".to_vec(), counter(2)).unwrap();
+
+        // borrowed from the mapped table
+        let value = tree.get_pinned(b"flushed").unwrap().unwrap();
+        assert_eq!(&*value, b"value");
+        assert!(value.is_pinned());
+
+        // copied from the active memtable
+        let value = tree.get_pinned(b"active").unwrap().unwrap();
+        assert_eq!(&*value, b"fresh");
+        assert!(!value.is_pinned());
+
+        let value = tree.get_pinned(b"counter").unwrap().unwrap();
+        assert_eq!(&*value, counter(3).as_slice());
+        assert!(tree.get_pinned(b"deleted").unwrap().is_none());
+        assert!(tree.get_pinned(b"missing").unwrap().is_none());
+
+        let mut buf = b"stale".to_vec();
+        assert!(tree.get_into(b"flushed", &mut buf).unwrap());
+        assert_eq!(buf, b"value");
+        assert!(!tree.get_into(b"deleted", &mut buf).unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_max_open_files() {
+        let path = temp_dir("lsm", "max_open_files");
+        let options = Options {
+            max_open_files: 2,
+            block_cache: None,
+            level0_file_num_compaction_trigger: 8,
+            ..Options::default()
+        };
+
+        {
+            let tree = LSMTree::open_with_options(&path, options.clone()).unwrap();
+            for i in 0..5u32 {
+                tree.put(i.to_be_bytes().to_vec(), b"value".to_vec())
+                    .unwrap();
+                tree.flush().unwrap();
+            }
+            assert_eq!(l0_len(&tree), 5);
+            assert_eq!(tree.inner.table_cache.open_files(), 2);
+        }
+
+        // tables are only opened when read
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+        for i in 0..5u32 {
+            assert_eq!(tree.get(&i.to_be_bytes()).unwrap(), Some(b"value".to_vec()));
+            assert!(tree.inner.table_cache.open_files() <= 2);
+        }
+        let all = tree.scan(&0u32.to_be_bytes(), &4u32.to_be_bytes()).unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(tree.inner.table_cache.open_files() <= 2);
+    }
+
+    #[test]
+    fn test_skiplist_memtable_default_buffer() {
+        let path = temp_dir("lsm", "skiplist_default_buffer");
+        let options = Options {
+            memtable_rep: MemtableRepKind::SkipList,
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+
+        // the arena is allocated ahead, a few small puts don't fill the buffer
+        for i in 0..5u8 {
+            tree.put(vec![i], b"value".to_vec()).unwrap();
+        }
+        assert_eq!(l0_len(&tree), 0);
+
+        for i in 5..100u8 {
+            tree.put(vec![i], b"value".to_vec()).unwrap();
+        }
+        assert!(l0_len(&tree) >= 1);
+        assert!(l0_len(&tree) < 10);
+    }
+
+    #[test]
+    fn test_skiplist_memtable() {
+        let path = temp_dir("lsm", "skiplist_memtable");
+        let options = Options {
+            memtable_rep: MemtableRepKind::SkipList,
+            write_buffer_size: 64 * 1024,
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+
+        thread::scope(|scope| {
+            for t in 0..4u32 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in 0..100u32 {
+                        tree.put((i * 4 + t).to_be_bytes().to_vec(), b"old".to_vec())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        tree.put(7u32.to_be_bytes().to_vec(), b"new".to_vec())
+            .unwrap();
+        tree.delete(8u32.to_be_bytes().to_vec()).unwrap();
+        assert_eq!(
+            tree.get(&7u32.to_be_bytes()).unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(tree.get(&8u32.to_be_bytes()).unwrap(), None);
+
+        // only the newest version of a key is flushed
+        tree.flush().unwrap();
+        let all = tree
+            .scan(&0u32.to_be_bytes(), &u32::MAX.to_be_bytes())
+            .unwrap();
+        assert_eq!(all.len(), 399);
+        assert_eq!(
+            tree.get(&7u32.to_be_bytes()).unwrap(),
+            Some(b"new".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_skiplist_concurrent_reads_and_writes() {
+        let path = temp_dir("lsm", "skiplist_concurrent");
+        let options = Options {
+            memtable_rep: MemtableRepKind::SkipList,
+            write_buffer_size: 16 * 1024,
+            ..merge_options()
+        };
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+
+        thread::scope(|scope| {
+            for t in 0..4u32 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in 0..200u32 {
+                        let key = (i * 4 + t).to_be_bytes();
+                        tree.put(key.to_vec(), i.to_be_bytes().to_vec()).unwrap();
+                        assert_eq!(tree.get(&key).unwrap(), Some(i.to_be_bytes().to_vec()));
+                        tree.merge(b"counter".to_vec(), counter(1)).unwrap();
+                    }
+                });
+            }
+
+            // readers see each key either not yet written or with its value
+            for _ in 0..2 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for i in 0..800u32 {
+                        if let Some(value) = tree.get(&i.to_be_bytes()).unwrap() {
+                            assert_eq!(value, (i / 4).to_be_bytes());
+                        }
+                    }
+                });
+            }
+        });
+
+        // every operand landed on top of the ones before it
+        assert_eq!(tree.get(b"counter").unwrap(), Some(counter(800)));
+        let all = tree
+            .scan(&0u32.to_be_bytes(), &u32::MAX.to_be_bytes())
+            .unwrap();
+        // and the counter
+        assert_eq!(all.len(), 801);
+    }
+
+    #[test]
+    fn test_write_buffer_manager() {
+        let manager = Arc::new(WriteBufferManager::new(64 * 1024));
+        let options = Options {
+            write_buffer_size: 1024 * 1024,
+            level0_file_num_compaction_trigger: 100,
+            level0_slowdown_writes_trigger: 100,
+            level0_stop_writes_trigger: 100,
+            write_buffer_manager: Some(Arc::clone(&manager)),
+            ..Options::default()
+        };
+        let first =
+            LSMTree::open_with_options(temp_dir("lsm", "wbm_first"), options.clone()).unwrap();
+        let second = LSMTree::open_with_options(temp_dir("lsm", "wbm_second"), options).unwrap();
+
+        for i in 0..1000u32 {
+            let value = vec![0; 100];
+            first.put(i.to_be_bytes().to_vec(), value.clone()).unwrap();
+            second.put(i.to_be_bytes().to_vec(), value).unwrap();
+            assert!(manager.memory_usage() < 96 * 1024);
+        }
+
+        // both trees flushed long before their own write buffer filled up
+        assert!(l0_len(&first) > 0);
+        assert!(l0_len(&second) > 0);
+        assert_eq!(first.get(&7u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+
+        drop(first);
+        assert!(manager.memory_usage() > 0);
+        drop(second);
+        assert_eq!(manager.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let statistics = Arc::new(Statistics::new());
+        let options = Options {
+            bloom_bits_per_key: 10,
+            statistics: Some(Arc::clone(&statistics)),
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(temp_dir("lsm", "statistics"), options).unwrap();
+
+        tree.put(b"flushed".to_vec(), b"value".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.put(b"active".to_vec(), b"value".to_vec()).unwrap();
+        tree.delete(b"deleted".to_vec()).unwrap();
+
+        assert!(tree.get(b"active").unwrap().is_some());
+        assert!(tree.get(b"flushed").unwrap().is_some());
+        assert!(tree.get(b"missing").unwrap().is_none());
+
+        assert_eq!(statistics.ticker(Ticker::MemtableHit), 1);
+        assert_eq!(statistics.ticker(Ticker::MemtableMiss), 2);
+        assert_eq!(statistics.ticker(Ticker::KeysRead), 3);
+        assert_eq!(statistics.ticker(Ticker::BytesRead), 10);
+        assert_eq!(statistics.ticker(Ticker::KeysWritten), 3);
+        assert_eq!(statistics.ticker(Ticker::WalWrites), 3);
+        assert_eq!(statistics.ticker(Ticker::WalSyncs), 0);
+        assert_eq!(statistics.ticker(Ticker::FlushCount), 1);
+        assert_eq!(statistics.level_probes(0), 2);
+        // the filter of the flushed table rules out the missing key
+        assert_eq!(statistics.ticker(Ticker::BloomFilterUseful), 1);
+
+        assert_eq!(statistics.histogram(Histogram::GetMicros).count, 3);
+        assert_eq!(statistics.histogram(Histogram::PutMicros).count, 2);
+        assert_eq!(statistics.histogram(Histogram::DeleteMicros).count, 1);
+        assert_eq!(statistics.histogram(Histogram::WriteMicros).count, 0);
+
+        assert!(
+            tree.property("stats")
+                .unwrap()
+                .contains("flush_count COUNT : 1\n")
+        );
+        assert_eq!(tree.property("num-files-at-level0").unwrap(), "1");
+        assert_eq!(tree.property("num-immutable-mem-table").unwrap(), "0");
+        assert!(tree.property("unknown").is_none());
+    }
+
+    #[test]
+    fn test_sync_writes() {
+        let path = temp_dir("lsm", "sync_writes");
+        let statistics = Arc::new(Statistics::new());
+        let options = Options {
+            sync_writes: true,
+            statistics: Some(Arc::clone(&statistics)),
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(&path, options.clone()).unwrap();
+
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        batch.delete(b"a".to_vec());
+        tree.write(batch).unwrap();
+        assert_eq!(statistics.ticker(Ticker::WalWrites), 2);
+        assert_eq!(statistics.ticker(Ticker::WalSyncs), 2);
+        drop(tree);
+
+        let tree = LSMTree::open_with_options(&path, options).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    /// Records the events it receives as text
+    #[derive(Default)]
+    struct EventRecorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventRecorder {
+        fn count(&self, prefix: &str) -> usize {
+            let events = self.events.lock().unwrap();
+            events.iter().filter(|e| e.starts_with(prefix)).count()
+        }
+
+        fn push(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    impl EventListener for EventRecorder {
+        fn on_flush_begin(&self, info: &FlushJobInfo) {
+            self.push(format!("flush_begin {}", info.column_family));
+        }
+
+        fn on_flush_completed(&self, info: &FlushJobInfo, table: &TableFileInfo) {
+            assert_eq!(info.path, table.path);
+            self.push(format!(
+                "flush_completed {} L{}",
+                info.column_family, table.level
+            ));
+        }
+
+        fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+            assert_eq!(info.inputs.len(), 2);
+            let kind = if info.is_manual { "manual" } else { "auto" };
+            self.push(format!(
+                "compaction L{}->L{} {kind}",
+                info.level, info.output_level
+            ));
+        }
+
+        fn on_table_file_created(&self, info: &TableFileInfo) {
+            assert!(info.path.exists());
+            self.push(format!("created L{}", info.level));
+        }
+
+        fn on_table_file_deleted(&self, info: &TableFileDeletionInfo) {
+            assert!(!info.path.exists());
+            self.push("deleted".to_string());
+        }
+
+        fn on_stall_conditions_changed(&self, info: &WriteStallInfo) {
+            self.push(format!("stall {:?}->{:?}", info.previous, info.current));
+        }
+    }
+
+    #[test]
+    fn test_event_listener() {
+        let recorder = Arc::new(EventRecorder::default());
+        let options = Options {
+            level0_file_num_compaction_trigger: 2,
+            level0_slowdown_writes_trigger: 2,
+            listeners: vec![Arc::clone(&recorder) as Arc<dyn EventListener>],
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(temp_dir("lsm", "event_listener"), options).unwrap();
+
+        for key in [b"a", b"b"] {
+            tree.put(key.to_vec(), b"value".to_vec()).unwrap();
+            tree.flush().unwrap();
+        }
+        wait_for_compactions(&tree);
+
+        assert_eq!(recorder.count("flush_begin default"), 2);
+        assert_eq!(recorder.count("flush_completed default L0"), 2);
+        assert_eq!(recorder.count("created L0"), 2);
+        assert_eq!(recorder.count("compaction L0->L1 auto"), 1);
+        assert_eq!(recorder.count("created L1"), 1);
+
+        // two L0 tables slow writes down until they are compacted
+        assert_eq!(recorder.count("stall Normal->Delayed"), 1);
+        assert_eq!(recorder.count("stall Delayed->Normal"), 1);
+
+        // the compaction inputs are deleted once the compaction thread lets go of them
+        drop(tree);
+        assert_eq!(recorder.count("deleted"), 2);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path = temp_dir("lsm", "checkpoint");
+        let dest = temp_dir("lsm", "checkpoint_dest");
+        fs::remove_dir(&dest).unwrap();
+
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        tree.put_cf(&users, b"c".to_vec(), b"3".to_vec()).unwrap();
+
+        tree.checkpoint(&dest).unwrap();
+        assert!(tree.checkpoint(&dest).is_err());
+
+        // later writes and compactions don't reach the checkpoint
+        tree.put(b"a".to_vec(), b"changed".to_vec()).unwrap();
+        tree.delete_cf(&users, b"c".to_vec()).unwrap();
+        tree.compact_range(b"a", b"z").unwrap();
+        drop(tree);
+
+        let checkpoint = LSMTree::open(&dest).unwrap();
+        let users = checkpoint.column_family("users").unwrap();
+        assert_eq!(checkpoint.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(checkpoint.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(
+            checkpoint.get_cf(&users, b"c").unwrap(),
+            Some(b"3".to_vec())
+        );
+        drop(checkpoint);
+
+        let tree = LSMTree::open(&path).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"changed".to_vec()));
+    }
+
+    fn build_table(path: &Path, entries: &[(&[u8], &[u8])]) {
+        let mut builder = SSTableBuilder::new(path.to_path_buf()).unwrap();
+        for (key, value) in entries {
+            builder.add(key, &Value::Some(value.to_vec())).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_ingest_external_files() {
+        let path = temp_dir("lsm", "ingest");
+        let external = temp_dir("lsm", "ingest_external");
+        let low = external.join("low.sst");
+        let high = external.join("high.sst");
+        build_table(&low, &[(b"a", b"1"), (b"b", b"new")]);
+        build_table(&high, &[(b"x", b"2"), (b"y", b"3")]);
+
+        let tree = LSMTree::open(&path).unwrap();
+        tree.put(b"b".to_vec(), b"old".to_vec()).unwrap();
+        tree.put(b"m".to_vec(), b"kept".to_vec()).unwrap();
+
+        let mut txn = tree.begin_transaction();
+        txn.put(b"b".to_vec(), b"txn".to_vec()).unwrap();
+
+        tree.ingest_external_files(&[&high, &low]).unwrap();
+        assert!(low.exists() && high.exists());
+
+        // the memtable was flushed first, `low` overlaps it and stays above it
+        assert_eq!(l0_len(&tree), 2);
+        assert_eq!(tree.num_files_at_level(6), 1);
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(tree.get(b"m").unwrap(), Some(b"kept".to_vec()));
+        assert_eq!(tree.get(b"y").unwrap(), Some(b"3".to_vec()));
+
+        // ingested keys are newer than the transaction
+        assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+
+        tree.put(b"y".to_vec(), b"4".to_vec()).unwrap();
+        drop(tree);
+
+        let tree = LSMTree::open(&path).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(tree.get(b"m").unwrap(), Some(b"kept".to_vec()));
+        assert_eq!(tree.get(b"y").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_unflushed_data_includes_writes_being_inserted() {
+        let path = temp_dir("lsm", "unflushed_pending");
+        let tree = LSMTree::open(&path).unwrap();
+        let memtable = Arc::clone(&tree.inner.lock_state().default_family().memtable);
+
+        // numbered and logged, not inserted yet
+        let write = memtable.begin_write();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                write.insert(vec![(b"a".to_vec(), Value::Some(b"1".to_vec()))], 1);
+            });
+            assert!(
+                tree.inner
+                    .lock_state()
+                    .default_family()
+                    .has_unflushed_data()
+            );
+        });
+    }
+
+    #[test]
+    fn test_ingest_rejects_overlapping_files() {
+        let path = temp_dir("lsm", "ingest_overlap");
+        let external = temp_dir("lsm", "ingest_overlap_external");
+        let first = external.join("first.sst");
+        let second = external.join("second.sst");
+        build_table(&first, &[(b"a", b"1"), (b"c", b"1")]);
+        build_table(&second, &[(b"b", b"2")]);
+
+        let tree = LSMTree::open(&path).unwrap();
+        assert!(matches!(
+            tree.ingest_external_files(&[&first, &second]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(
+            tree.ingest_external_files(&[external.join("missing.sst")])
+                .is_err()
+        );
+
+        // the copies are gone again
+        let tables = fs::read_dir(&path)
+            .unwrap()
+            .filter(|entry| manifest::sst_number(&entry.as_ref().unwrap().path()).is_some())
+            .count();
+        assert_eq!(tables, 0);
+        assert_eq!(tree.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let source = LSMTree::open(temp_dir("lsm", "export_source")).unwrap();
+        let users = source
+            .create_column_family("users", Options::default())
+            .unwrap();
+        for key in [b"a", b"b", b"c", b"d"] {
+            source.put(key.to_vec(), key.to_vec()).unwrap();
+        }
+        source.flush().unwrap();
+        source.delete(b"c".to_vec()).unwrap();
+        source.put(b"e".to_vec(), b"e".to_vec()).unwrap();
+        source
+            .put_cf(&users, b"u".to_vec(), b"user".to_vec())
+            .unwrap();
+
+        let mut dump = Vec::new();
+        assert_eq!(source.export(&mut dump).unwrap(), 4);
+        let mut range = Vec::new();
+        assert_eq!(source.export_range(b"b", b"d", &mut range).unwrap(), 2);
+        let mut users_dump = Vec::new();
+        assert_eq!(source.export_cf(&users, &mut users_dump).unwrap(), 1);
+
+        let target = LSMTree::open(temp_dir("lsm", "export_target")).unwrap();
+        target.put(b"a".to_vec(), b"old".to_vec()).unwrap();
+        target.put(b"z".to_vec(), b"kept".to_vec()).unwrap();
+        assert_eq!(target.import(dump.as_slice()).unwrap(), 4);
+        assert_eq!(
+            target.scan(b"a", b"z").unwrap(),
+            [
+                source.scan(b"a", b"e").unwrap(),
+                vec![(b"z".to_vec(), b"kept".to_vec())]
+            ]
+            .concat()
+        );
+
+        // a damaged dump leaves the tree unchanged
+        let mut damaged = range.clone();
+        let len = damaged.len();
+        damaged.truncate(len - 1);
+        assert!(matches!(
+            target.import(damaged.as_slice()),
+            Err(Error::Corruption(_))
+        ));
+        assert_eq!(target.import(range.as_slice()).unwrap(), 2);
+        assert_eq!(target.scan(b"a", b"z").unwrap().len(), 5);
+
+        let reversed = LSMTree::open_with_options(
+            temp_dir("lsm", "export_reversed"),
+            Options {
+                comparator: Arc::new(crate::ReverseBytewiseComparator),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            reversed.import(users_dump.as_slice()),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_export_import_keeps_ttl() {
+        let read_dump = |dump: &[u8]| {
+            let mut reader = DumpReader::new(dump).unwrap();
+            let mut pairs = Vec::new();
+            while let Some(pair) = reader.next_pair().unwrap() {
+                pairs.push(pair);
+            }
+            pairs
+        };
+
+        let source = LSMTree::open(temp_dir("lsm", "export_ttl_source")).unwrap();
+        source.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        source
+            .put_with_ttl(b"b".to_vec(), b"2".to_vec(), Duration::from_secs(3600))
+            .unwrap();
+        source
+            .put_with_ttl(b"c".to_vec(), b"3".to_vec(), Duration::from_millis(100))
+            .unwrap();
+
+        let mut dump = Vec::new();
+        assert_eq!(source.export(&mut dump).unwrap(), 3);
+        let exported = read_dump(&dump);
+        assert_eq!(exported[0].2, None);
+        assert!(exported[1].2.is_some());
+
+        // expires between export and import
+        thread::sleep(Duration::from_millis(150));
+        let target = LSMTree::open(temp_dir("lsm", "export_ttl_target")).unwrap();
+        assert_eq!(target.import(dump.as_slice()).unwrap(), 2);
+        assert_eq!(target.get(b"c").unwrap(), None);
+
+        let mut reexported = Vec::new();
+        assert_eq!(target.export(&mut reexported).unwrap(), 2);
+        assert_eq!(read_dump(&reexported), exported[..2]);
+    }
+
+    #[test]
+    fn test_export_is_point_in_time() {
+        let tree = LSMTree::open(temp_dir("lsm", "export_point_in_time")).unwrap();
+        let key = |i: u32| format!("k{i:06}").into_bytes();
+        tree.put(key(0), 0u32.to_be_bytes().to_vec()).unwrap();
+        tree.put(b"last".to_vec(), 0u32.to_be_bytes().to_vec())
+            .unwrap();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 1..3000u32 {
+                    tree.put(key(i), i.to_be_bytes().to_vec()).unwrap();
+                    tree.put(b"last".to_vec(), i.to_be_bytes().to_vec())
+                        .unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            });
+
+            while !done.load(Ordering::SeqCst) {
+                let mut dump = Vec::new();
+                tree.export(&mut dump).unwrap();
+
+                let mut reader = DumpReader::new(dump.as_slice()).unwrap();
+                let mut keys = 0;
+                let mut last = None;
+                while let Some((k, value, _)) = reader.next_pair().unwrap() {
+                    let value = u32::from_be_bytes(value.try_into().unwrap());
+                    if k == b"last" {
+                        last = Some(value);
+                    } else {
+                        // the keys are written in order, none may be missing
+                        assert_eq!(k, key(keys));
+                        keys += 1;
+                    }
+                }
+
+                // `last` follows its key, the dump may fall between the two
+                let last = last.unwrap();
+                assert!(last + 1 == keys || last + 2 == keys);
+            }
+        });
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let path = temp_dir("lsm", "verify_integrity");
+        let tree = LSMTree::open(&path).unwrap();
+        let users = tree
+            .create_column_family("users", Options::default())
+            .unwrap();
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        tree.put_cf(&users, b"c".to_vec(), b"3".to_vec()).unwrap();
+        tree.flush().unwrap();
+
+        let report = tree.verify_integrity();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.tables_checked, 2);
+        assert_eq!(report.entries_checked, 2);
+
+        // invalid tombstone flag of the only entry
+        let users_dir = column_family::family_dir(&path, "users");
+        let table = column_family::sst_paths(&users_dir).unwrap().remove(0);
+        let mut bytes = fs::read(&table).unwrap();
+        bytes[10] = 9;
+        fs::write(&table, bytes).unwrap();
+
+        let report = tree.verify_integrity();
+        assert!(!report.is_ok());
+        assert_eq!(report.tables_checked, 2);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].family, "users");
+        assert_eq!(report.damaged[0].path, table);
+        let problems = &report.damaged[0].problems;
+        assert!(problems[0].starts_with("Checksum mismatch in block at offset 0"));
+        assert!(problems[1].contains("Invalid tombstone flag"));
+    }
 }
@@ -0,0 +1,195 @@
+//! Manifest: persistent record of which `SSTable` belongs to which level
+//!
+//! The manifest is a small text file which is rewritten atomically (written to a
+//! temporary file, synced, then renamed) whenever the level structure changes:
+//!
+//! ```text
+//! lsm-tree-kv manifest 1
+//! sequence <sequence number>
+//! file <level> <file number>
+//! file <level> <file number>
+//! ...
+//! ```
+//!
+//! The sequence number is the one of the newest write flushed to the files, writes
+//! after it are replayed from the write-ahead log on open. Manifests written before
+//! it existed have no such line, it reads as 0.
+//!
+//! Directories written before the manifest existed have no such file, in that case
+//! every `SSTable` is treated as an L0 file.
+
+use crate::{Error, Result};
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest file inside the data directory
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Header line identifying the manifest format
+const MANIFEST_HEADER: &str = "lsm-tree-kv manifest 1";
+
+/// A live `SSTable` recorded in the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Level the file belongs to
+    pub level: usize,
+    /// Number of the file, see `sst_path`
+    pub number: usize,
+}
+
+/// Contents of a manifest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Live files
+    pub files: Vec<FileEntry>,
+    /// Sequence number of the newest write flushed to the files
+    pub flushed_sequence: u64,
+}
+
+/// Returns the path of the `SSTable` with the given number
+pub fn sst_path(dir: &Path, number: usize) -> PathBuf {
+    dir.join(format!("{number:08}.sst"))
+}
+
+/// Parses the file number out of an `SSTable` path
+pub fn sst_number(path: &Path) -> Option<usize> {
+    if path.extension().is_none_or(|ext| ext != "sst") {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Reads the manifest, returns `None` if the directory has none
+pub fn read(dir: &Path) -> Result<Option<Manifest>> {
+    let contents = match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut lines = contents.lines();
+    if lines.next() != Some(MANIFEST_HEADER) {
+        return Err(Error::Corruption("Invalid manifest header".to_string()));
+    }
+
+    let mut manifest = Manifest::default();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields.as_slice() {
+            ["sequence", sequence] => manifest.flushed_sequence = parse_field(sequence)?,
+            ["file", level, number] => manifest.files.push(FileEntry {
+                level: parse_field(level)?,
+                number: parse_field(number)?,
+            }),
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "Invalid manifest record: {line}"
+                )));
+            }
+        }
+    }
+
+    Ok(Some(manifest))
+}
+
+/// Atomically replaces the manifest
+pub fn write(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = dir.join(format!("{MANIFEST_FILE}.tmp"));
+
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(writer, "{MANIFEST_HEADER}")?;
+        writeln!(writer, "sequence {}", manifest.flushed_sequence)?;
+        for entry in &manifest.files {
+            writeln!(writer, "file {} {}", entry.level, entry.number)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+
+    fs::rename(&tmp_path, dir.join(MANIFEST_FILE))?;
+    Ok(())
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str) -> Result<T> {
+    field
+        .parse()
+        .map_err(|_| Error::Corruption(format!("Invalid manifest field: {field}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn test_missing_manifest() {
+        let dir = temp_dir("manifest", "missing");
+        assert_eq!(read(&dir).unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_read_roundtrip() {
+        let dir = temp_dir("manifest", "roundtrip");
+        let manifest = Manifest {
+            files: vec![
+                FileEntry {
+                    level: 0,
+                    number: 7,
+                },
+                FileEntry {
+                    level: 2,
+                    number: 3,
+                },
+            ],
+            flushed_sequence: 42,
+        };
+
+        write(&dir, &manifest).unwrap();
+        assert_eq!(read(&dir).unwrap(), Some(manifest));
+    }
+
+    #[test]
+    fn test_read_without_sequence() {
+        let dir = temp_dir("manifest", "without_sequence");
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            format!("{MANIFEST_HEADER}\nfile 1 5\n"),
+        )
+        .unwrap();
+
+        let manifest = read(&dir).unwrap().unwrap();
+        assert_eq!(manifest.flushed_sequence, 0);
+        assert_eq!(
+            manifest.files,
+            vec![FileEntry {
+                level: 1,
+                number: 5
+            }]
+        );
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        let dir = temp_dir("manifest", "invalid");
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            format!("{MANIFEST_HEADER}\nfile x 1\n"),
+        )
+        .unwrap();
+
+        match read(&dir) {
+            Err(Error::Corruption(_)) => {}
+            _ => panic!("Expected corruption error"),
+        }
+    }
+
+    #[test]
+    fn test_sst_number() {
+        let dir = Path::new("/tmp");
+        assert_eq!(sst_number(&sst_path(dir, 42)), Some(42));
+        assert_eq!(sst_number(Path::new("/tmp/00000001.log")), None);
+        assert_eq!(sst_number(Path::new("/tmp/foo.sst")), None);
+    }
+}
@@ -1,5 +1,15 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
-use std::collections::btree_map;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as MemoryOrdering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::comparator::{self, Comparator, OrderedKey};
+use crate::memtable_rep::{self, MemtableRep, MemtableRepKind, RepIter};
+use crate::merge_operator::{self, MergeOperator};
+use crate::{Error, Result};
 
 /// Represents a value in the memtable
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -8,87 +18,411 @@ pub enum Value {
     Some(Vec<u8>),
     /// A tombstone marking a deletion
     Tombstone,
+    /// A value which reads as deleted once its expiration time has passed
+    Expiring {
+        value: Vec<u8>,
+        /// Expiration time in milliseconds since the UNIX epoch
+        expires_at: u64,
+    },
+    /// Merge operands (oldest first) still to be applied to an older value
+    Merge(Vec<Vec<u8>>),
+}
+
+impl Value {
+    /// Get the value bytes, `None` for tombstones and values expired at `now`
+    pub fn live_value(&self, now: u64) -> Option<&[u8]> {
+        match self {
+            Self::Some(value) => Some(value),
+            Self::Expiring { value, expires_at } if *expires_at > now => Some(value),
+            Self::Expiring { .. } | Self::Tombstone | Self::Merge(_) => None,
+        }
+    }
+
+    /// Check if the value has an expiration time which is not after `now`
+    pub const fn is_expired(&self, now: u64) -> bool {
+        matches!(self, Self::Expiring { expires_at, .. } if *expires_at <= now)
+    }
+
+    /// Number of bytes the value accounts for in the memtable
+    pub(crate) fn size_bytes(&self) -> usize {
+        match self {
+            Self::Some(value) => value.len(),
+            Self::Tombstone => 0,
+            // value + expiration time
+            Self::Expiring { value, .. } => value.len() + 8,
+            // operands + their lengths
+            Self::Merge(operands) => operands.iter().map(|op| op.len() + 4).sum(),
+        }
+    }
+
+    /// Number of bytes the value owns on the heap
+    fn heap_bytes(&self) -> usize {
+        match self {
+            Self::Some(value) | Self::Expiring { value, .. } => value.capacity(),
+            Self::Tombstone => 0,
+            Self::Merge(operands) => {
+                operands.capacity() * mem::size_of::<Vec<u8>>()
+                    + operands.iter().map(Vec::capacity).sum::<usize>()
+            }
+        }
+    }
+}
+
+/// Current time in milliseconds since the UNIX epoch, the clock used for expiration
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
 }
 
-/// In-memory write buffer using a `BTreeMap` for sorted storage
+/// In-memory write buffer, sorted storage is provided by a `MemtableRep`
+///
+/// Inserts take `&self`, so any number of threads may insert and read at the
+/// same time. Writers of the tree announce their inserts with `begin_write` while
+/// holding the tree mutex and insert after releasing it.
 pub struct Memtable {
-    /// Sorted map of keys to values
-    data: BTreeMap<Vec<u8>, Value>,
+    /// Keys and their values, sorted by `comparator`
+    rep: Box<dyn MemtableRep>,
+    /// Order of the keys
+    comparator: Arc<dyn Comparator>,
     /// Approximate size in bytes
-    size_bytes: usize,
+    size_bytes: AtomicUsize,
+    /// Heap bytes owned by the stored keys and values
+    heap_bytes: AtomicUsize,
+    /// Sequence number of the newest write, stamped on values inserted by `put`,
+    /// `delete` and `merge`
+    sequence: AtomicU64,
+    /// Writes announced by `begin_write` which are not inserted yet
+    writers: AtomicUsize,
+}
+
+/// Value of a key together with the sequence number of the write that stored it
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub value: Value,
+    pub sequence: u64,
+}
+
+/// Write announced with `Memtable::begin_write`, to be inserted by `insert`
+///
+/// Dropping it without inserting withdraws the announcement.
+pub struct PendingWrite {
+    memtable: Arc<Memtable>,
+}
+
+impl PendingWrite {
+    /// Inserts values computed by `Memtable::resolve`, stamped with `sequence`
+    pub fn insert(self, entries: Vec<(Vec<u8>, Value)>, sequence: u64) {
+        self.memtable.insert_resolved(entries, sequence);
+    }
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        self.memtable.writers.fetch_sub(1, MemoryOrdering::Release);
+    }
 }
 
 impl Memtable {
-    /// Creates a new empty memtable
-    pub const fn new() -> Self {
+    /// Creates a new empty memtable with bytewise key order
+    pub fn new() -> Self {
+        Self::with_comparator(comparator::bytewise())
+    }
+
+    /// Creates a new empty memtable with keys ordered by `comparator`
+    pub fn with_comparator(comparator: Arc<dyn Comparator>) -> Self {
+        Self::with_rep(comparator, MemtableRepKind::default())
+    }
+
+    /// Creates a new empty memtable stored in a rep of the given kind
+    pub fn with_rep(comparator: Arc<dyn Comparator>, kind: MemtableRepKind) -> Self {
         Self {
-            data: BTreeMap::new(),
-            size_bytes: 0,
+            rep: memtable_rep::new_rep(kind, Arc::clone(&comparator)),
+            comparator,
+            size_bytes: AtomicUsize::new(0),
+            heap_bytes: AtomicUsize::new(0),
+            sequence: AtomicU64::new(0),
+            writers: AtomicUsize::new(0),
         }
     }
 
-    /// Insert a KV-pair
-    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        if let Some(old_value) = self.data.get(&key) {
-            match old_value {
-                Value::Some(old_value) => self.size_bytes -= old_value.len(),
-                Value::Tombstone => {}
-            }
+    /// Get the order of the keys
+    pub const fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
 
-            self.size_bytes += value.len();
-        } else {
-            // no replacement -> add full kv-pair-size
-            self.size_bytes += key.len() + value.len();
-        }
+    /// Insert a KV-pair
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.insert(key, Value::Some(value), self.largest_sequence());
+    }
 
-        self.data.insert(key, Value::Some(value));
+    /// Insert a KV-pair which expires at `expires_at` (milliseconds since the UNIX epoch)
+    pub fn put_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires_at: u64) {
+        let value = Value::Expiring { value, expires_at };
+        self.insert(key, value, self.largest_sequence());
     }
 
     /// Get value of a key
-    pub fn get(&self, key: &[u8]) -> Option<&Value> {
-        self.data.get(key)
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        self.get_with(key, Value::clone)
+    }
+
+    /// Passes the value of a key to `f` without copying it
+    pub fn get_with<R>(&self, key: &[u8], f: impl FnOnce(&Value) -> R) -> Option<R> {
+        let mut f = Some(f);
+        let mut result = None;
+        self.rep.get(key, &mut |entry| {
+            result = f.take().map(|f| f(&entry.value));
+        });
+        result
+    }
+
+    /// Get the sequence number of the last write to a key
+    pub fn sequence_of(&self, key: &[u8]) -> Option<u64> {
+        let mut sequence = None;
+        self.rep
+            .get(key, &mut |entry| sequence = Some(entry.sequence));
+        sequence
+    }
+
+    /// Keys with the sequence number of their last write, in key order
+    pub fn sequences(&self) -> impl Iterator<Item = (Vec<u8>, u64)> + '_ {
+        self.rep
+            .iter_from(None)
+            .map(|(key, entry)| (key, entry.sequence))
+    }
+
+    /// Get the sequence number of the newest write in the memtable
+    pub fn largest_sequence(&self) -> u64 {
+        self.sequence.load(MemoryOrdering::Acquire)
+    }
+
+    /// Add a merge operand for a key
+    ///
+    /// If the memtable holds the base value of the key the operand is folded onto
+    /// it right away, otherwise it is stored until the key is read or compacted.
+    pub fn merge(
+        &self,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+        operator: &dyn MergeOperator,
+    ) -> Result<()> {
+        let current = self.get(&key);
+        let value = merged_value(&key, current.as_ref(), vec![operand], Some(operator))?;
+        self.insert(key, value, self.largest_sequence());
+        Ok(())
     }
 
     /// Delete an entry by key
-    pub fn delete(&mut self, key: Vec<u8>) {
-        if let Some(old_value) = self.data.get(&key) {
-            match old_value {
-                Value::Some(old_value) => self.size_bytes -= old_value.len(),
-                Value::Tombstone => {
-                    return;
+    pub fn delete(&self, key: Vec<u8>) {
+        if self.get_with(&key, |value| *value == Value::Tombstone) == Some(true) {
+            return;
+        }
+
+        self.insert(key, Value::Tombstone, self.largest_sequence());
+    }
+
+    /// Applies writes, stamped with `sequence`
+    ///
+    /// Merge operands are resolved before anything is inserted, so a failing merge
+    /// leaves the memtable unchanged.
+    pub fn apply<'a>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a [u8], &'a Value)>,
+        sequence: u64,
+        operator: Option<&dyn MergeOperator>,
+    ) -> Result<()> {
+        let resolved = self.resolve(entries, operator)?;
+        self.insert_resolved(resolved, sequence);
+        Ok(())
+    }
+
+    /// Computes the values a group of writes stores, without changing the memtable
+    ///
+    /// Merge operands are folded onto values already in the memtable, see `merge`.
+    /// Returns the latest value per key, a group may write a key more than once.
+    pub fn resolve<'a>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a [u8], &'a Value)>,
+        operator: Option<&dyn MergeOperator>,
+    ) -> Result<Vec<(Vec<u8>, Value)>> {
+        let mut staged: BTreeMap<OrderedKey, Value> = BTreeMap::new();
+
+        for (key, value) in entries {
+            let key = self.ordered(key);
+            let value = match value {
+                Value::Merge(operands) => {
+                    let current = staged.get(&key).cloned().or_else(|| self.get(&key.key));
+                    merged_value(&key.key, current.as_ref(), operands.clone(), operator)?
                 }
-            }
-        } else {
-            self.size_bytes += key.len();
+                value => value.clone(),
+            };
+            // keys comparing equal are the same key, the latest bytes win
+            staged.remove(&key);
+            staged.insert(key, value);
+        }
+
+        Ok(staged
+            .into_iter()
+            .map(|(key, value)| (key.key, value))
+            .collect())
+    }
+
+    /// Inserts values computed by `resolve`, stamped with `sequence`
+    pub fn insert_resolved(&self, entries: Vec<(Vec<u8>, Value)>, sequence: u64) {
+        for (key, value) in entries {
+            self.insert(key, value, sequence);
+        }
+        self.sequence.fetch_max(sequence, MemoryOrdering::AcqRel);
+    }
+
+    /// Announces a write, `wait_for_writers` waits until it is inserted
+    pub fn begin_write(self: &Arc<Self>) -> PendingWrite {
+        self.writers.fetch_add(1, MemoryOrdering::Acquire);
+        PendingWrite {
+            memtable: Arc::clone(self),
+        }
+    }
+
+    /// Waits until every write announced by `begin_write` is inserted
+    ///
+    /// Called with the tree mutex held, which keeps new writes from being announced.
+    pub fn wait_for_writers(&self) {
+        while self.writers.load(MemoryOrdering::Acquire) > 0 {
+            thread::yield_now();
         }
+    }
 
-        self.data.insert(key, Value::Tombstone);
+    /// Insert a value, shadowing any value of the key with a lower sequence number
+    fn insert(&self, key: Vec<u8>, value: Value, sequence: u64) {
+        self.size_bytes
+            .fetch_add(key.len() + value.size_bytes(), MemoryOrdering::Relaxed);
+        self.heap_bytes
+            .fetch_add(key.capacity() + value.heap_bytes(), MemoryOrdering::Relaxed);
+
+        // reps which keep shadowed entries keep accounting for them
+        if let Some((old_key, old)) = self.rep.insert(key, Entry { value, sequence }) {
+            self.size_bytes.fetch_sub(
+                old_key.len() + old.value.size_bytes(),
+                MemoryOrdering::Relaxed,
+            );
+            self.heap_bytes.fetch_sub(
+                old_key.capacity() + old.value.heap_bytes(),
+                MemoryOrdering::Relaxed,
+            );
+        }
+    }
+
+    /// Wraps a key for lookups in staged writes
+    fn ordered(&self, key: &[u8]) -> OrderedKey {
+        OrderedKey::new(key.to_vec(), Arc::clone(&self.comparator))
     }
 
     /// Returns iterator over the memtalbe
-    pub fn iter(&self) -> btree_map::Iter<Vec<u8>, Value> {
-        self.data.iter()
+    pub fn iter(&self) -> MemtableIter<'_> {
+        MemtableIter {
+            inner: self.rep.iter_from(None),
+        }
+    }
+
+    /// Returns iterator over the entries with keys in `[start, end]`
+    pub fn range<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl Iterator<Item = (Vec<u8>, Value)> + 'a {
+        // an inverted range is empty
+        let entries: RepIter<'a> = if self.comparator.compare(start, end) == Ordering::Greater {
+            Box::new(std::iter::empty())
+        } else {
+            let end = end.to_vec();
+            Box::new(
+                self.rep
+                    .iter_from(Some(start))
+                    .take_while(move |(key, _)| self.comparator.compare(key, &end).is_le()),
+            )
+        };
+
+        entries.map(|(key, entry)| (key, entry.value))
     }
 
     /// Get number of entries
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.rep.len()
     }
 
     /// Check if memtable is empty
     pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+        self.rep.len() == 0
     }
 
     /// Get the number of bytes
-    pub const fn size_bytes(&self) -> usize {
-        self.size_bytes
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes.load(MemoryOrdering::Relaxed)
+    }
+
+    /// Get the number of bytes allocated for the memtable and its entries
+    ///
+    /// Unlike `size_bytes` this includes the storage structure, unused capacity
+    /// and, for skiplist memtables, the whole arena. Keys and values count with
+    /// the capacity of their buffers. The nodes of a `BTree` memtable are
+    /// estimated, see `MemtableRepKind::BTree`, only skiplist memtables are
+    /// measured exactly.
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.rep.memory_usage()
+            + self.heap_bytes.load(MemoryOrdering::Relaxed)
+    }
+
+    /// Get the number of bytes taken by the entries, what the flush trigger
+    /// compares against `Options::write_buffer_size`
+    ///
+    /// Like `memory_usage` without the space allocated ahead for entries still to
+    /// come, the first arena chunk of a skiplist alone would fill a small buffer.
+    pub fn used_memory(&self) -> usize {
+        self.memory_usage() - self.rep.reserved_bytes()
+    }
+}
+
+/// Computes the value stored when merge operands are added on top of `current`
+fn merged_value(
+    key: &[u8],
+    current: Option<&Value>,
+    mut operands: Vec<Vec<u8>>,
+    operator: Option<&dyn MergeOperator>,
+) -> Result<Value> {
+    match current {
+        None => Ok(Value::Merge(operands)),
+        Some(Value::Merge(older)) => {
+            let mut all = older.clone();
+            all.append(&mut operands);
+            Ok(Value::Merge(all))
+        }
+        Some(base) => {
+            let operator = operator.ok_or_else(|| {
+                Error::InvalidArgument("merge requires a merge operator".to_string())
+            })?;
+            merge_operator::fold(operator, key, Some(base), &operands, now_millis())
+        }
+    }
+}
+
+/// Iterator over the entries of a memtable in key order
+pub struct MemtableIter<'a> {
+    inner: RepIter<'a>,
+}
+
+impl Iterator for MemtableIter<'_> {
+    type Item = (Vec<u8>, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, entry)| (key, entry.value))
     }
 }
 
 impl<'a> IntoIterator for &'a Memtable {
-    type Item = (&'a Vec<u8>, &'a Value);
-    type IntoIter = btree_map::Iter<'a, Vec<u8>, Value>;
+    type Item = (Vec<u8>, Value);
+    type IntoIter = MemtableIter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.iter()
@@ -107,14 +441,14 @@ mod tests {
 
     #[test]
     fn test_put_get() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
         let key = b"key1".to_vec();
         let value = b"value1".to_vec();
 
         memtable.put(key.clone(), value.clone());
         let result = memtable.get(&key);
 
-        assert_eq!(result, Some(&Value::Some(value)));
+        assert_eq!(result, Some(Value::Some(value)));
     }
 
     #[test]
@@ -129,7 +463,7 @@ mod tests {
 
     #[test]
     fn test_delete_creates_tombstone() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
         let key = b"key1".to_vec();
         let value = b"value1".to_vec();
 
@@ -138,12 +472,12 @@ mod tests {
         memtable.delete(key.clone());
 
         // should be a tombstone internally
-        assert_eq!(memtable.get(&key), Some(&Value::Tombstone));
+        assert_eq!(memtable.get(&key), Some(Value::Tombstone));
     }
 
     #[test]
     fn test_get_after_delete() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
         let key = b"key1".to_vec();
         let value = b"value1".to_vec();
 
@@ -153,12 +487,12 @@ mod tests {
 
         // should return tombstone
         let result = memtable.get(&key);
-        assert_eq!(result, Some(&Value::Tombstone));
+        assert_eq!(result, Some(Value::Tombstone));
     }
 
     #[test]
     fn test_overwrite() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
         let key = b"key1".to_vec();
         let value1 = b"value1".to_vec();
         let value2 = b"value2".to_vec();
@@ -169,7 +503,7 @@ mod tests {
 
         // should be the second value
         let result = memtable.get(&key);
-        assert_eq!(result, Some(&Value::Some(value2)));
+        assert_eq!(result, Some(Value::Some(value2)));
 
         // should be exactly 1 entry
         assert_eq!(memtable.len(), 1);
@@ -177,7 +511,7 @@ mod tests {
 
     #[test]
     fn test_ordering() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
 
         // insert unsorted keys
         memtable.put(b"key3".to_vec(), b"value3".to_vec());
@@ -185,7 +519,7 @@ mod tests {
         memtable.put(b"key2".to_vec(), b"value2".to_vec());
 
         // should be sorted internally
-        let keys: Vec<_> = memtable.iter().map(|(k, _v)| k.clone()).collect();
+        let keys: Vec<_> = memtable.iter().map(|(k, _v)| k).collect();
 
         assert_eq!(
             keys,
@@ -195,7 +529,7 @@ mod tests {
 
     #[test]
     fn test_size_bytes() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
 
         // should be initially empty
         assert_eq!(memtable.size_bytes(), 0);
@@ -229,7 +563,7 @@ mod tests {
 
     #[test]
     fn test_len_and_is_empty() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
 
         assert!(memtable.is_empty());
         assert_eq!(memtable.len(), 0);
@@ -248,19 +582,19 @@ mod tests {
 
     #[test]
     fn test_delete_nonexistent_key() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
         let key = b"nonexistent".to_vec();
 
         memtable.delete(key.clone());
 
         // deleting a nonexistent key should create a tombstone
-        assert_eq!(memtable.get(&key), Some(&Value::Tombstone));
+        assert_eq!(memtable.get(&key), Some(Value::Tombstone));
         assert_eq!(memtable.len(), 1);
     }
 
     #[test]
     fn test_delete_twice() {
-        let mut memtable = Memtable::new();
+        let memtable = Memtable::new();
         let key = b"key1".to_vec();
 
         memtable.put(key.clone(), b"value1".to_vec());
@@ -269,6 +603,185 @@ mod tests {
         memtable.delete(key.clone());
 
         // should still be a tombstone
-        assert_eq!(memtable.get(&key), Some(&Value::Tombstone));
+        assert_eq!(memtable.get(&key), Some(Value::Tombstone));
+    }
+
+    #[test]
+    fn test_put_with_expiry() {
+        let memtable = Memtable::new();
+        let key = b"key1".to_vec();
+        let value = b"value1".to_vec();
+
+        memtable.put_with_expiry(key.clone(), value.clone(), 1000);
+
+        let result = memtable.get(&key).unwrap();
+        assert_eq!(result.live_value(999), Some(value.as_slice()));
+        assert_eq!(result.live_value(1000), None);
+        assert!(result.is_expired(1000));
+
+        // expiration time is accounted for
+        assert_eq!(memtable.size_bytes(), key.len() + value.len() + 8);
+    }
+
+    #[test]
+    fn test_range() {
+        let memtable = Memtable::new();
+        for key in [b"a", b"b", b"c", b"d"] {
+            memtable.put(key.to_vec(), b"value".to_vec());
+        }
+
+        let keys: Vec<_> = memtable.range(b"b", b"c").map(|(k, _v)| k).collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let operator = crate::AppendOperator::new(b",");
+        let memtable = Memtable::new();
+
+        // no base value, operands are stored
+        memtable
+            .merge(b"key1".to_vec(), b"a".to_vec(), &operator)
+            .unwrap();
+        memtable
+            .merge(b"key1".to_vec(), b"b".to_vec(), &operator)
+            .unwrap();
+        assert_eq!(
+            memtable.get(b"key1"),
+            Some(Value::Merge(vec![b"a".to_vec(), b"b".to_vec()]))
+        );
+        assert_eq!(memtable.size_bytes(), 4 + (1 + 4) * 2);
+
+        // base value, operand is folded onto it
+        memtable.put(b"key2".to_vec(), b"x".to_vec());
+        memtable
+            .merge(b"key2".to_vec(), b"y".to_vec(), &operator)
+            .unwrap();
+        assert_eq!(memtable.get(b"key2"), Some(Value::Some(b"x,y".to_vec())));
+    }
+
+    /// Orders keys ignoring ASCII case
+    struct CaseInsensitive;
+
+    impl Comparator for CaseInsensitive {
+        fn name(&self) -> &'static str {
+            "test.CaseInsensitive"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
+
+    #[test]
+    fn test_custom_comparator() {
+        let memtable = Memtable::with_comparator(Arc::new(CaseInsensitive));
+        memtable.put(b"b".to_vec(), b"1".to_vec());
+        memtable.put(b"C".to_vec(), b"2".to_vec());
+        memtable.put(b"a".to_vec(), b"3".to_vec());
+
+        // keys comparing equal are the same key
+        memtable.put(b"B".to_vec(), b"4".to_vec());
+        assert_eq!(memtable.len(), 3);
+        assert_eq!(memtable.get(b"b"), Some(Value::Some(b"4".to_vec())));
+        assert_eq!(memtable.size_bytes(), 6);
+
+        let keys: Vec<_> = memtable.iter().map(|(k, _v)| k).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"B".to_vec(), b"C".to_vec()]);
+
+        let range: Vec<_> = memtable.range(b"A", b"b").map(|(k, _v)| k).collect();
+        assert_eq!(range, vec![b"a".to_vec(), b"B".to_vec()]);
+        assert_eq!(memtable.range(b"c", b"a").count(), 0);
+    }
+
+    #[test]
+    fn test_skiplist_rep() {
+        let memtable = Memtable::with_rep(comparator::bytewise(), MemtableRepKind::SkipList);
+        memtable.put(b"key2".to_vec(), b"value2".to_vec());
+        memtable.put(b"key1".to_vec(), b"value1".to_vec());
+        memtable.put(b"key1".to_vec(), b"new".to_vec());
+        memtable.delete(b"key2".to_vec());
+
+        assert_eq!(memtable.get(b"key1"), Some(Value::Some(b"new".to_vec())));
+        assert_eq!(memtable.get(b"key2"), Some(Value::Tombstone));
+        assert_eq!(memtable.len(), 2);
+
+        // shadowed versions stay in the skiplist until the memtable is dropped
+        assert_eq!(memtable.size_bytes(), 4 * 4 + 6 + 6 + 3);
+
+        let entries: Vec<_> = memtable.iter().collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"key1".to_vec(), Value::Some(b"new".to_vec())),
+                (b"key2".to_vec(), Value::Tombstone),
+            ]
+        );
+        assert_eq!(memtable.range(b"key0", b"key1").count(), 1);
+        assert_eq!(memtable.range(b"key2", b"key1").count(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_skiplist_writes() {
+        let memtable = Arc::new(Memtable::with_rep(
+            comparator::bytewise(),
+            MemtableRepKind::SkipList,
+        ));
+
+        thread::scope(|scope| {
+            for t in 0..4u64 {
+                let memtable = &memtable;
+                scope.spawn(move || {
+                    for i in 0..500u64 {
+                        let sequence = i * 4 + t + 1;
+                        let value = Value::Some(sequence.to_be_bytes().to_vec());
+                        let entries = vec![(b"shared".to_vec(), value.clone())];
+                        memtable.begin_write().insert(entries, sequence);
+                        let own = vec![(sequence.to_be_bytes().to_vec(), value.clone())];
+                        memtable.begin_write().insert(own, sequence);
+
+                        assert_eq!(memtable.get(&sequence.to_be_bytes()), Some(value));
+                        let newest = memtable.sequence_of(b"shared").unwrap();
+                        assert!(newest >= sequence);
+                    }
+                });
+            }
+        });
+
+        memtable.wait_for_writers();
+        assert_eq!(memtable.len(), 2001);
+        assert_eq!(memtable.largest_sequence(), 2000);
+
+        // the highest sequence number wins, whichever insert came last
+        assert_eq!(
+            memtable.get(b"shared"),
+            Some(Value::Some(2000u64.to_be_bytes().to_vec()))
+        );
+        let keys: Vec<_> = memtable.iter().map(|(k, _v)| k).collect();
+        assert_eq!(keys.len(), 2001);
+        assert!(keys.is_sorted());
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        for kind in [MemtableRepKind::BTree, MemtableRepKind::SkipList] {
+            let memtable = Memtable::with_rep(comparator::bytewise(), kind);
+            let empty = memtable.memory_usage();
+
+            let mut value = Vec::with_capacity(1024);
+            value.extend_from_slice(b"value");
+            memtable.put(b"key".to_vec(), value);
+
+            // unused capacity is allocated too
+            assert_eq!(memtable.size_bytes(), 8);
+            assert!(memtable.memory_usage() >= empty + 3 + 1024);
+        }
+
+        // the skiplist arena is allocated in chunks, ahead of the nodes
+        let memtable = Memtable::with_rep(comparator::bytewise(), MemtableRepKind::SkipList);
+        memtable.put(b"key".to_vec(), b"value".to_vec());
+        let usage = memtable.memory_usage();
+        memtable.put(b"key2".to_vec(), b"value".to_vec());
+        assert!(memtable.memory_usage() < usage + 64);
     }
 }
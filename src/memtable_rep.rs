@@ -0,0 +1,176 @@
+//! Sorted storage behind a `Memtable`
+//!
+//! A `MemtableRep` only stores the newest entry per key, the memtable on top of it
+//! resolves merges and tracks sizes. `Options::memtable_rep` picks the
+//! implementation of new memtables.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::comparator::{Comparator, OrderedKey};
+use crate::memtable::Entry;
+use crate::skiplist::SkipList;
+
+/// Implementations of `MemtableRep` to choose from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemtableRepKind {
+    /// A `BTreeMap` behind a read-write lock, overwritten keys free their old
+    /// entry. Its nodes are not exposed, so their memory is estimated from the
+    /// number of keys.
+    #[default]
+    BTree,
+    /// A concurrent skiplist with arena-allocated nodes, readers never block
+    /// inserts. Overwritten keys keep their old entries until the memtable is
+    /// dropped, so they count towards its size.
+    SkipList,
+}
+
+/// Entries a node of a `BTreeMap` has room for
+const BTREE_NODE_CAPACITY: usize = 11;
+
+/// Newest entry of every key in key order, copied out of the rep
+pub type RepIter<'a> = Box<dyn Iterator<Item = (Vec<u8>, Entry)> + 'a>;
+
+/// Sorted storage of the entries of a `Memtable`
+///
+/// Any number of threads may insert and read at the same time.
+pub trait MemtableRep: Send + Sync {
+    /// Stores an entry, inserts of a key may arrive out of sequence order
+    ///
+    /// Returns the key and entry which were dropped, the older of the stored entry
+    /// and `entry`, if the rep doesn't keep both.
+    fn insert(&self, key: Vec<u8>, entry: Entry) -> Option<(Vec<u8>, Entry)>;
+
+    /// Passes the newest entry of a key to `f`
+    fn get(&self, key: &[u8], f: &mut dyn FnMut(&Entry));
+
+    /// Newest entry of every key, starting at the first key >= `start` if given
+    fn iter_from(&self, start: Option<&[u8]>) -> RepIter<'_>;
+
+    /// Number of distinct keys
+    fn len(&self) -> usize;
+
+    /// Bytes allocated for the structure holding the entries
+    ///
+    /// Only the skiplist measures what it allocated, the `BTreeMap` estimates
+    /// it. Heap memory owned by keys and values is accounted by the memtable.
+    fn memory_usage(&self) -> usize;
+
+    /// Bytes of `memory_usage` allocated ahead for entries still to come
+    fn reserved_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Creates an empty rep of the given kind
+pub fn new_rep(kind: MemtableRepKind, comparator: Arc<dyn Comparator>) -> Box<dyn MemtableRep> {
+    match kind {
+        MemtableRepKind::BTree => Box::new(BTreeRep {
+            data: RwLock::new(BTreeMap::new()),
+            comparator,
+        }),
+        MemtableRepKind::SkipList => Box::new(SkipList::new(comparator)),
+    }
+}
+
+/// Inserts and reads take turns on a lock
+struct BTreeRep {
+    data: RwLock<BTreeMap<OrderedKey, Entry>>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl BTreeRep {
+    fn ordered(&self, key: &[u8]) -> OrderedKey {
+        OrderedKey::new(key.to_vec(), Arc::clone(&self.comparator))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<OrderedKey, Entry>> {
+        self.data.read().unwrap()
+    }
+}
+
+impl MemtableRep for BTreeRep {
+    #[allow(clippy::significant_drop_tightening)]
+    fn insert(&self, key: Vec<u8>, entry: Entry) -> Option<(Vec<u8>, Entry)> {
+        let key = OrderedKey::new(key, Arc::clone(&self.comparator));
+        let mut data = self.data.write().unwrap();
+
+        if data
+            .get(&key)
+            .is_some_and(|stored| stored.sequence > entry.sequence)
+        {
+            return Some((key.key, entry));
+        }
+
+        // removed so the key is stored with its latest bytes
+        let old = data.remove_entry(&key);
+        data.insert(key, entry);
+        old.map(|(key, entry)| (key.key, entry))
+    }
+
+    fn get(&self, key: &[u8], f: &mut dyn FnMut(&Entry)) {
+        if let Some(entry) = self.read().get(&self.ordered(key)) {
+            f(entry);
+        }
+    }
+
+    #[allow(clippy::significant_drop_tightening)]
+    fn iter_from(&self, start: Option<&[u8]>) -> RepIter<'_> {
+        // looks up the next key every step, so no lock is held in between
+        let mut next = start.map_or(Bound::Unbounded, |start| {
+            Bound::Included(self.ordered(start))
+        });
+        Box::new(std::iter::from_fn(move || {
+            let data = self.read();
+            let (key, entry) = data.range((next.clone(), Bound::Unbounded)).next()?;
+            next = Bound::Excluded(key.clone());
+            Some((key.key.clone(), entry.clone()))
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        // the map doesn't expose its nodes, they are taken to be two thirds full
+        // and the few internal nodes are left out
+        let nodes = self.read().len().div_ceil(BTREE_NODE_CAPACITY * 2 / 3);
+        // every node has room for its entries, a parent link, an index and a length
+        let node = BTREE_NODE_CAPACITY * mem::size_of::<(OrderedKey, Entry)>()
+            + 2 * mem::size_of::<usize>();
+        nodes * node
+    }
+}
+
+impl MemtableRep for SkipList<Entry> {
+    fn insert(&self, key: Vec<u8>, entry: Entry) -> Option<(Vec<u8>, Entry)> {
+        Self::insert(self, key, entry.sequence, entry);
+        None
+    }
+
+    fn get(&self, key: &[u8], f: &mut dyn FnMut(&Entry)) {
+        if let Some(entry) = Self::get(self, key) {
+            f(entry);
+        }
+    }
+
+    fn iter_from(&self, start: Option<&[u8]>) -> RepIter<'_> {
+        let entries = start.map_or_else(|| Self::iter(self), |start| Self::iter_from(self, start));
+        Box::new(entries.map(|(key, entry)| (key.clone(), entry.clone())))
+    }
+
+    fn len(&self) -> usize {
+        Self::len(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        Self::memory_usage(self)
+    }
+
+    fn reserved_bytes(&self) -> usize {
+        Self::reserved_bytes(self)
+    }
+}
@@ -0,0 +1,203 @@
+//! Read-modify-write without reads
+//!
+//! `LSMTree::merge` stores an operand instead of a full value. Operands pile up as
+//! `Value::Merge` entries and are folded onto the base value by the configured
+//! `MergeOperator` when the key is read, or when a compaction (or the memtable)
+//! sees the base value and all operands together.
+
+use std::fmt;
+
+use crate::memtable::Value;
+use crate::{Error, Result};
+
+/// Combines merge operands with the value they apply to
+///
+/// Called from reads and from the compaction thread, so implementations must be
+/// thread-safe.
+pub trait MergeOperator: Send + Sync {
+    /// Name of the operator, used in error messages and debug output
+    fn name(&self) -> &str;
+
+    /// Folds `operands` (oldest first) onto `existing`, which is `None` if the key
+    /// has no live value. Returns `None` if the operands can't be applied.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Option<Vec<u8>>;
+
+    /// Combines several operands (oldest first) into a single one without knowing
+    /// the base value. Returns `None` if the operator can't do that.
+    fn partial_merge(&self, _key: &[u8], _operands: &[Vec<u8>]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MergeOperator({})", self.name())
+    }
+}
+
+/// Treats values and operands as little-endian `u64` and adds them up
+///
+/// A missing value counts as 0, additions wrap on overflow.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn decode(bytes: &[u8]) -> Option<u64> {
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn sum<'a>(start: u64, operands: impl IntoIterator<Item = &'a Vec<u8>>) -> Option<u64> {
+        operands.into_iter().try_fold(start, |sum, operand| {
+            Some(sum.wrapping_add(Self::decode(operand)?))
+        })
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &'static str {
+        "U64AddOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Option<Vec<u8>> {
+        let start = existing.map_or(Some(0), Self::decode)?;
+        Self::sum(start, operands).map(|sum| sum.to_le_bytes().to_vec())
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[Vec<u8>]) -> Option<Vec<u8>> {
+        Self::sum(0, operands).map(|sum| sum.to_le_bytes().to_vec())
+    }
+}
+
+/// Appends operands to the value, separated by a delimiter
+#[derive(Debug, Clone, Default)]
+pub struct AppendOperator {
+    /// Inserted between the existing value and every operand
+    pub delimiter: Vec<u8>,
+}
+
+impl AppendOperator {
+    /// Creates an append operator with the given delimiter
+    pub fn new(delimiter: &[u8]) -> Self {
+        Self {
+            delimiter: delimiter.to_vec(),
+        }
+    }
+}
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &'static str {
+        "AppendOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Option<Vec<u8>> {
+        let mut parts: Vec<&[u8]> = existing.into_iter().collect();
+        parts.extend(operands.iter().map(Vec::as_slice));
+        Some(parts.join(self.delimiter.as_slice()))
+    }
+
+    fn partial_merge(&self, key: &[u8], operands: &[Vec<u8>]) -> Option<Vec<u8>> {
+        self.full_merge(key, None, operands)
+    }
+}
+
+/// Folds operands (oldest first) onto a base value
+///
+/// A tombstone or expired base counts as missing. A live expiring base passes its
+/// expiration time on to the result.
+pub fn fold(
+    operator: &dyn MergeOperator,
+    key: &[u8],
+    base: Option<&Value>,
+    operands: &[Vec<u8>],
+    now: u64,
+) -> Result<Value> {
+    let existing = base.and_then(|value| value.live_value(now));
+    let merged = operator
+        .full_merge(key, existing, operands)
+        .ok_or_else(|| Error::Corruption(format!("{} failed to merge", operator.name())))?;
+
+    match base {
+        Some(Value::Expiring { expires_at, .. }) if existing.is_some() => Ok(Value::Expiring {
+            value: merged,
+            expires_at: *expires_at,
+        }),
+        _ => Ok(Value::Some(merged)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u64_bytes(value: u64) -> Vec<u8> {
+        value.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_u64_add() {
+        let op = U64AddOperator;
+        let operands = vec![u64_bytes(2), u64_bytes(3)];
+
+        assert_eq!(op.full_merge(b"k", None, &operands), Some(u64_bytes(5)));
+        assert_eq!(
+            op.full_merge(b"k", Some(&u64_bytes(10)), &operands),
+            Some(u64_bytes(15))
+        );
+        assert_eq!(op.partial_merge(b"k", &operands), Some(u64_bytes(5)));
+
+        // malformed operand
+        assert_eq!(op.full_merge(b"k", None, &[b"abc".to_vec()]), None);
+    }
+
+    #[test]
+    fn test_append() {
+        let op = AppendOperator::new(b",");
+        let operands = vec![b"b".to_vec(), b"c".to_vec()];
+
+        assert_eq!(op.full_merge(b"k", None, &operands), Some(b"b,c".to_vec()));
+        assert_eq!(
+            op.full_merge(b"k", Some(b"a"), &operands),
+            Some(b"a,b,c".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_fold_keeps_expiration() {
+        let op = AppendOperator::new(b",");
+        let base = Value::Expiring {
+            value: b"a".to_vec(),
+            expires_at: 100,
+        };
+
+        let live = fold(&op, b"k", Some(&base), &[b"b".to_vec()], 50).unwrap();
+        assert_eq!(
+            live,
+            Value::Expiring {
+                value: b"a,b".to_vec(),
+                expires_at: 100
+            }
+        );
+
+        // an expired base is ignored
+        let expired = fold(&op, b"k", Some(&base), &[b"b".to_vec()], 100).unwrap();
+        assert_eq!(expired, Value::Some(b"b".to_vec()));
+
+        let deleted = fold(&op, b"k", Some(&Value::Tombstone), &[b"b".to_vec()], 0).unwrap();
+        assert_eq!(deleted, Value::Some(b"b".to_vec()));
+    }
+}
@@ -0,0 +1,132 @@
+//! Read-only memory maps of whole files
+//!
+//! Maps are created with `mmap(2)` directly, so they are only available on 64-bit
+//! unix targets. Elsewhere `Mmap::map` fails and readers fall back to positional
+//! reads. Only immutable files may be mapped: truncating a mapped file makes
+//! accesses beyond its new end fault.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::slice;
+
+/// Read-only private mapping of a file, unmapped on drop
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this value, so sharing it between
+// threads is no different from sharing a `&[u8]`
+unsafe impl Send for Mmap {}
+// SAFETY: see `Send`
+unsafe impl Sync for Mmap {}
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_PRIVATE: c_int = 2;
+
+    unsafe extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl Mmap {
+    /// Maps the whole file, empty files can't be mapped
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    pub fn map(file: &File) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty files can't be mapped",
+            ));
+        }
+
+        // SAFETY: a fresh mapping of a valid descriptor, the kernel picks the address
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        // MAP_FAILED
+        if ptr as usize == usize::MAX {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: ptr.cast_const().cast(),
+            len,
+        })
+    }
+
+    /// Memory maps are not supported on this target
+    #[cfg(not(all(unix, target_pointer_width = "64")))]
+    pub fn map(_file: &File) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory maps are not supported on this target",
+        ))
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping stays valid and readable until `self` is dropped
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly the range mapped in `map`, nothing borrows it anymore
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        unsafe {
+            sys::munmap(self.ptr.cast_mut().cast(), self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::temp_path;
+
+    #[test]
+    fn test_map_file() {
+        let path = temp_path("mmap", "map_file");
+        fs::write(&path, b"mapped bytes").unwrap();
+
+        let map = Mmap::map(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(&map[..], b"mapped bytes");
+        drop(map);
+
+        fs::write(&path, b"").unwrap();
+        assert!(Mmap::map(&File::open(&path).unwrap()).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Batched point lookups, see `LSMTree::multi_get`
+//!
+//! Keys are looked up in key order so every table is walked once: each table
+//! gets all keys still undecided at its level in a single `SSTable::multi_get`.
+//! Tables of a deeper level don't overlap, so they are read in parallel by a
+//! few threads once the batch is large enough.
+
+use std::cmp::Ordering;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use crate::Result;
+use crate::memtable::Value;
+use crate::sstable::SSTable;
+use crate::statistics::Statistics;
+use crate::version::Version;
+
+/// Fewest keys of a level read in parallel
+const PARALLEL_READ_MIN_KEYS: usize = 64;
+
+/// Most threads reading the tables of a level, the calling thread included
+const MAX_READ_THREADS: usize = 4;
+
+/// Entries found for one key, newest first
+pub struct KeyLookup<'a> {
+    pub key: &'a [u8],
+    pub values: Vec<Value>,
+    /// Set once a value older entries can't change was found
+    pub done: bool,
+}
+
+impl<'a> KeyLookup<'a> {
+    pub const fn new(key: &'a [u8]) -> Self {
+        Self {
+            key,
+            values: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Adds the next older entry, only merge operands need the entries below them
+    pub fn push(&mut self, value: Value) {
+        self.done = !matches!(value, Value::Merge(_));
+        self.values.push(value);
+    }
+}
+
+/// Adds the table entries of undecided keys, `lookups` are sorted by key
+///
+/// Every key looked up in a table counts as a probe of its level in `statistics`.
+pub fn read_tables(
+    version: &Version,
+    lookups: &mut [KeyLookup<'_>],
+    statistics: Option<&Statistics>,
+) -> Result<()> {
+    let record_probes = |level: usize, count: usize| {
+        if let Some(statistics) = statistics {
+            statistics.record_probes(level, count as u64);
+        }
+    };
+
+    // L0 tables overlap, each one is checked for all undecided keys, newest first
+    for sstable in &version.levels[0] {
+        let pending: Vec<&mut KeyLookup> = lookups.iter_mut().filter(|l| !l.done).collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        record_probes(0, pending.len());
+        read_table(sstable, pending)?;
+    }
+
+    for (n, level) in version.levels.iter().enumerate().skip(1) {
+        // at most one table per level can hold a key
+        let mut batches: Vec<(&Arc<SSTable>, Vec<&mut KeyLookup>)> = Vec::new();
+        for lookup in lookups.iter_mut().filter(|l| !l.done) {
+            let idx = level.partition_point(|sst| {
+                sst.largest_key()
+                    .is_some_and(|k| version.comparator.compare(k, lookup.key) == Ordering::Less)
+            });
+            let Some(sstable) = level.get(idx) else {
+                continue;
+            };
+
+            // keys are sorted, so are the tables they fall into
+            match batches.last_mut() {
+                Some((last, batch)) if Arc::ptr_eq(last, sstable) => batch.push(lookup),
+                _ => batches.push((sstable, vec![lookup])),
+            }
+        }
+
+        record_probes(n, batches.iter().map(|(_, batch)| batch.len()).sum());
+        read_in_parallel(batches)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the batches of different tables
+///
+/// Large enough lookups are spread over a few threads taking batches in turn,
+/// the calling thread being one of them. Smaller ones are read on the calling
+/// thread, as starting a thread costs more than a few reads.
+fn read_in_parallel(batches: Vec<(&Arc<SSTable>, Vec<&mut KeyLookup>)>) -> Result<()> {
+    // the number of CPUs is looked up once, finding it reads several files
+    static CPUS: OnceLock<usize> = OnceLock::new();
+
+    let keys: usize = batches.iter().map(|(_, batch)| batch.len()).sum();
+    let threads = if keys < PARALLEL_READ_MIN_KEYS {
+        1
+    } else {
+        let cpus =
+            *CPUS.get_or_init(|| thread::available_parallelism().map_or(1, NonZeroUsize::get));
+        cpus.min(MAX_READ_THREADS).min(batches.len())
+    };
+    if threads <= 1 {
+        return batches
+            .into_iter()
+            .try_for_each(|(sstable, batch)| read_table(sstable, batch));
+    }
+
+    let queue = Mutex::new(batches.into_iter());
+    let work = || loop {
+        let next = queue.lock().unwrap().next();
+        let Some((sstable, batch)) = next else {
+            return Ok(());
+        };
+        read_table(sstable, batch)?;
+    };
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (1..threads).map(|_| scope.spawn(work)).collect();
+        let mut result = work();
+        for handle in handles {
+            let thread_result = handle
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            result = result.and(thread_result);
+        }
+        result
+    })
+}
+
+/// Looks up a batch of sorted keys in a single table
+fn read_table(sstable: &SSTable, mut batch: Vec<&mut KeyLookup>) -> Result<()> {
+    let keys: Vec<&[u8]> = batch.iter().map(|lookup| lookup.key).collect();
+    for (lookup, value) in batch.iter_mut().zip(sstable.multi_get(&keys)?) {
+        if let Some(value) = value {
+            lookup.push(value);
+        }
+    }
+    Ok(())
+}
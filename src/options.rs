@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::block_cache::BlockCache;
+use crate::compaction_filter::CompactionFilter;
+use crate::comparator::{self, Comparator};
+use crate::event_listener::EventListener;
+use crate::memtable_rep::MemtableRepKind;
+use crate::merge_operator::MergeOperator;
+use crate::statistics::Statistics;
+use crate::write_buffer_manager::WriteBufferManager;
+use crate::{Error, Result};
+
+/// Default size at which the active memtable is flushed
+pub const MEMTABLE_SIZE_THRESHOLD: usize = 4096; // 4KB
+
+/// Default capacity of the block cache
+pub const BLOCK_CACHE_CAPACITY: usize = 8 * 1024 * 1024; // 8MB
+
+/// Tuning knobs for an `LSMTree`
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Memory in bytes, see `Memtable::used_memory`, at which the active
+    /// memtable is turned immutable and flushed
+    pub write_buffer_size: usize,
+    /// Total number of levels (L0 included)
+    pub num_levels: usize,
+    /// Number of L0 files that triggers a compaction into L1
+    pub level0_file_num_compaction_trigger: usize,
+    /// Number of L0 files at which writes start getting delayed
+    pub level0_slowdown_writes_trigger: usize,
+    /// Number of L0 files at which writes are stopped until compaction catches up
+    pub level0_stop_writes_trigger: usize,
+    /// Number of immutable memtables waiting for a flush at which writes get delayed
+    pub memtable_slowdown_writes_trigger: usize,
+    /// Number of immutable memtables waiting for a flush at which writes are stopped
+    pub memtable_stop_writes_trigger: usize,
+    /// Estimated pending compaction bytes at which writes get delayed
+    pub soft_pending_compaction_bytes_limit: u64,
+    /// Estimated pending compaction bytes at which writes are stopped
+    pub hard_pending_compaction_bytes_limit: u64,
+    /// Write throughput (bytes per second) enforced while writes are delayed
+    pub delayed_write_rate: u64,
+    /// Target size of L1, deeper levels grow by `max_bytes_for_level_multiplier`
+    pub max_bytes_for_level_base: u64,
+    /// Size ratio between two adjacent levels
+    pub max_bytes_for_level_multiplier: u64,
+    /// Target size of a single compaction output file
+    pub target_file_size: u64,
+    /// Filter deciding which values compactions keep, rewrite or drop
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Time-to-live applied to every `put`, for cache-like trees
+    pub default_ttl: Option<Duration>,
+    /// Operator resolving the operands written by `LSMTree::merge`
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// How long a pessimistic transaction waits for a key lock
+    pub lock_timeout: Duration,
+    /// Order of the keys, can't be changed once the tree holds `SSTables`
+    pub comparator: Arc<dyn Comparator>,
+    /// Approximate size of the data blocks of new `SSTables`
+    pub block_size: usize,
+    /// Bits per key spent on the bloom filter of new `SSTables`, 0 writes no filter
+    ///
+    /// Filters hash the key bytes, so only use them with comparators under which
+    /// keys are equal only if their bytes are.
+    pub bloom_bits_per_key: usize,
+    /// Cache for blocks read from `SSTables`, may be shared between trees
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Keeps the index and filter blocks of open tables in memory instead of
+    /// reading them through the block cache
+    pub pin_index_and_filter_blocks: bool,
+    /// Maximum number of `SSTable` files the tree keeps open, the least recently
+    /// used are closed and reopened on demand
+    ///
+    /// Only the options the tree is opened with are used, it applies to all
+    /// column families.
+    pub max_open_files: usize,
+    /// Serves `SSTable` reads from memory maps of their files instead of
+    /// positional reads, tables which can't be mapped fall back to the latter
+    ///
+    /// Mapped tables bypass the block cache and hold no entry in the table cache.
+    pub allow_mmap_reads: bool,
+    /// Sorted storage of new memtables
+    pub memtable_rep: MemtableRepKind,
+    /// Syncs the write-ahead log after every write batch, so the writes survive
+    /// a crash of the machine and not only one of the process
+    ///
+    /// Only the options the tree is opened with are used, the log is shared by
+    /// all column families.
+    pub sync_writes: bool,
+    /// Memory budget for the memtables of all column families, may be shared
+    /// between trees
+    ///
+    /// Only the options the tree is opened with are used.
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    /// Counters and histograms of the tree, may be shared between trees
+    ///
+    /// Only the options the tree is opened with are used, all column families
+    /// record into them.
+    pub statistics: Option<Arc<Statistics>>,
+    /// Receivers of flush, compaction, table file and write stall events
+    ///
+    /// Only the options the tree is opened with are used, they see the events of
+    /// all column families.
+    pub listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl Options {
+    /// Target size in bytes for a level >= 1
+    pub fn max_bytes_for_level(&self, level: usize) -> u64 {
+        let mut bytes = self.max_bytes_for_level_base;
+        for _ in 1..level {
+            bytes = bytes.saturating_mul(self.max_bytes_for_level_multiplier);
+        }
+        bytes
+    }
+
+    /// Checks that the options are consistent with each other
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.num_levels < 2 {
+            return Err(Error::InvalidArgument(
+                "num_levels must be at least 2".to_string(),
+            ));
+        }
+
+        if self.level0_file_num_compaction_trigger == 0
+            || self.level0_slowdown_writes_trigger < self.level0_file_num_compaction_trigger
+            || self.level0_stop_writes_trigger < self.level0_slowdown_writes_trigger
+        {
+            return Err(Error::InvalidArgument(
+                "L0 triggers must satisfy 0 < compaction <= slowdown <= stop".to_string(),
+            ));
+        }
+
+        if self.memtable_slowdown_writes_trigger == 0
+            || self.memtable_stop_writes_trigger < self.memtable_slowdown_writes_trigger
+        {
+            return Err(Error::InvalidArgument(
+                "memtable triggers must satisfy 0 < slowdown <= stop".to_string(),
+            ));
+        }
+
+        if self.hard_pending_compaction_bytes_limit < self.soft_pending_compaction_bytes_limit {
+            return Err(Error::InvalidArgument(
+                "hard pending compaction bytes limit is below the soft limit".to_string(),
+            ));
+        }
+
+        if self.block_size == 0 {
+            return Err(Error::InvalidArgument(
+                "block_size must be positive".to_string(),
+            ));
+        }
+
+        if self.max_open_files == 0 {
+            return Err(Error::InvalidArgument(
+                "max_open_files must be positive".to_string(),
+            ));
+        }
+
+        if self.delayed_write_rate == 0 || self.max_bytes_for_level_multiplier == 0 {
+            return Err(Error::InvalidArgument(
+                "delayed_write_rate and max_bytes_for_level_multiplier must be positive"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            write_buffer_size: MEMTABLE_SIZE_THRESHOLD,
+            num_levels: 7,
+            level0_file_num_compaction_trigger: 4,
+            level0_slowdown_writes_trigger: 8,
+            level0_stop_writes_trigger: 12,
+            memtable_slowdown_writes_trigger: 2,
+            memtable_stop_writes_trigger: 4,
+            soft_pending_compaction_bytes_limit: 1024 * 1024, // 1MB
+            hard_pending_compaction_bytes_limit: 4 * 1024 * 1024, // 4MB
+            delayed_write_rate: 16 * 1024 * 1024,             // 16MB/s
+            max_bytes_for_level_base: 64 * 1024,              // 64KB
+            max_bytes_for_level_multiplier: 10,
+            target_file_size: 16 * 1024, // 16KB
+            compaction_filter: None,
+            default_ttl: None,
+            merge_operator: None,
+            lock_timeout: Duration::from_secs(1),
+            comparator: comparator::bytewise(),
+            block_size: 4096, // 4KB
+            bloom_bits_per_key: 0,
+            block_cache: Some(Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY))),
+            pin_index_and_filter_blocks: true,
+            max_open_files: 1000,
+            allow_mmap_reads: false,
+            memtable_rep: MemtableRepKind::BTree,
+            sync_writes: false,
+            write_buffer_manager: None,
+            statistics: None,
+            listeners: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Values returned without copying them out of where they are stored
+//!
+//! `LSMTree::get_pinned` returns a `PinnedValue` which keeps the immutable
+//! memtable, block or memory map holding the value alive while it is borrowed.
+//! The active memtable changes while it is read, so its values are copied, as
+//! are values computed from merge operands.
+
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use crate::memtable::Memtable;
+use crate::sstable::Block;
+
+/// Value borrowed from the memtable or block it is stored in
+pub struct PinnedValue {
+    source: Source,
+}
+
+enum Source {
+    Owned(Vec<u8>),
+    Memtable(MemtableValue),
+    /// Block and the range of the value in it
+    Block(Block, Range<usize>),
+}
+
+/// Bytes of a value stored in an immutable memtable
+struct MemtableValue {
+    /// Keeps the bytes alive, only accessed through `ptr`
+    _memtable: Arc<Memtable>,
+    ptr: *const u8,
+    len: usize,
+}
+
+// SAFETY: `ptr` only refers to bytes owned by the memtable, which no longer
+// changes once it was switched out, see `Family::switch_memtable`, and is kept
+// alive by the `Arc`
+unsafe impl Send for MemtableValue {}
+// SAFETY: see `Send`
+unsafe impl Sync for MemtableValue {}
+
+impl PinnedValue {
+    pub(crate) const fn owned(value: Vec<u8>) -> Self {
+        Self {
+            source: Source::Owned(value),
+        }
+    }
+
+    /// Pins the value of a key in an immutable memtable, `None` unless it is live at `now`
+    pub(crate) fn memtable(memtable: &Arc<Memtable>, key: &[u8], now: u64) -> Option<Self> {
+        let (ptr, len) = memtable
+            .get_with(key, |value| {
+                value
+                    .live_value(now)
+                    .map(|value| (value.as_ptr(), value.len()))
+            })
+            .flatten()?;
+        Some(Self {
+            source: Source::Memtable(MemtableValue {
+                _memtable: Arc::clone(memtable),
+                ptr,
+                len,
+            }),
+        })
+    }
+
+    pub(crate) const fn block(block: Block, range: Range<usize>) -> Self {
+        Self {
+            source: Source::Block(block, range),
+        }
+    }
+
+    /// Check whether the value is borrowed rather than copied
+    pub const fn is_pinned(&self) -> bool {
+        !matches!(self.source, Source::Owned(_))
+    }
+}
+
+impl Deref for PinnedValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.source {
+            Source::Owned(value) => value,
+            // SAFETY: the bytes belong to a value of the memtable, which can't
+            // change or be dropped while the `Arc` is held
+            Source::Memtable(value) => unsafe { std::slice::from_raw_parts(value.ptr, value.len) },
+            Source::Block(block, range) => &block[range.clone()],
+        }
+    }
+}
+
+impl AsRef<[u8]> for PinnedValue {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq<[u8]> for PinnedValue {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl fmt::Debug for PinnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinnedValue")
+            .field("value", &&**self)
+            .field("pinned", &self.is_pinned())
+            .finish()
+    }
+}
+
+/// Newest entry of a key in a single memtable or table
+pub enum Lookup {
+    /// The value, `None` if it was deleted or has expired
+    Value(Option<PinnedValue>),
+    /// Merge operands, the value has to be computed from older entries
+    Merge,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_memtable_value() {
+        let memtable = Memtable::new();
+        memtable.put(b"key".to_vec(), b"value".to_vec());
+        memtable.put_with_expiry(b"expired".to_vec(), b"value".to_vec(), 10);
+        let memtable = Arc::new(memtable);
+
+        assert!(PinnedValue::memtable(&memtable, b"expired", 20).is_none());
+
+        // the value outlives the last other reference to the memtable
+        let value = PinnedValue::memtable(&memtable, b"key", 20).unwrap();
+        drop(memtable);
+        assert_eq!(&*value, b"value");
+        assert!(value.is_pinned());
+    }
+}
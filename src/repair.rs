@@ -0,0 +1,381 @@
+//! Repair of a tree whose files are damaged, see `LSMTree::repair`
+//!
+//! Every table of every column family is read end to end. Intact tables are
+//! kept. A damaged table is replaced by a new table holding the entries which
+//! can still be read, see `sstable::salvage`, and moved to the `lost` directory
+//! of its family for inspection.
+//!
+//! The manifest of each family is written anew. If the old one could be read,
+//! tables keep their level and tables it doesn't list are left to be deleted on
+//! open. Otherwise every table goes to L0. L0 tables are renumbered so that
+//! tables holding newer writes are newer files, unless the old manifest could be
+//! read and no L0 table was salvaged. Write-ahead logs are left as they are and
+//! replayed on the next open.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::column_family::{self, DEFAULT_COLUMN_FAMILY, FAMILIES_DIR};
+use crate::manifest::{self, FileEntry, MANIFEST_FILE, Manifest};
+use crate::options::Options;
+use crate::sstable::{self, SSTable, SSTableBuilder};
+use crate::wal;
+use crate::{Error, Result};
+
+/// Directory inside a family directory the damaged files are moved to
+pub const LOST_DIR: &str = "lost";
+
+/// What `LSMTree::repair` found and did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Tables which were readable end to end
+    pub tables_intact: usize,
+    /// Damaged tables replaced by a table of their readable entries
+    pub tables_salvaged: usize,
+    /// Entries read out of damaged tables
+    pub entries_salvaged: u64,
+    /// Entries of damaged tables which couldn't be read, only counted for
+    /// tables whose footer is intact
+    pub entries_lost: u64,
+    /// Damaged tables, now in the `lost` directory of their family
+    pub quarantined: Vec<PathBuf>,
+    /// Tables listed in a manifest but missing on disk
+    pub missing: Vec<PathBuf>,
+    /// Column families whose manifest couldn't be read, their tables are in L0
+    pub manifests_rebuilt: Vec<String>,
+}
+
+/// A table going into the new manifest
+struct Table {
+    level: usize,
+    number: usize,
+    largest_sequence: u64,
+}
+
+/// Repairs the tree stored in `data_dir`, which must not be open
+pub fn repair(data_dir: &Path, options: &Options) -> Result<RepairReport> {
+    options.validate()?;
+    if !data_dir.is_dir() {
+        return Err(Error::InvalidArgument(format!(
+            "Directory {data_dir:?} does not exist"
+        )));
+    }
+
+    let mut families = vec![(DEFAULT_COLUMN_FAMILY.to_string(), data_dir.to_path_buf())];
+    let families_dir = data_dir.join(FAMILIES_DIR);
+    if families_dir.exists() {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(&families_dir)?
+            .filter_map(std::result::Result::ok)
+            .map(|entry| entry.path())
+            // others are leftovers of an interrupted create or drop
+            .filter(|dir| dir.join(MANIFEST_FILE).exists())
+            .collect();
+        dirs.sort();
+
+        for dir in dirs {
+            let name = dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    Error::Corruption(format!("Invalid column family directory {dir:?}"))
+                })?;
+            families.push((name, dir));
+        }
+    }
+
+    // new files get numbers above every table and log
+    let mut max_number = fs::read_dir(data_dir)?
+        .filter_map(std::result::Result::ok)
+        .filter_map(|entry| wal::log_number(&entry.path()))
+        .max()
+        .unwrap_or(0);
+    for (_, dir) in &families {
+        for path in column_family::sst_paths(dir)? {
+            max_number = max_number.max(manifest::sst_number(&path).unwrap_or(0));
+        }
+    }
+    let mut next_number = max_number + 1;
+
+    let mut report = RepairReport::default();
+    for (name, dir) in families {
+        repair_family(&name, &dir, options, &mut next_number, &mut report)?;
+    }
+    Ok(report)
+}
+
+fn repair_family(
+    name: &str,
+    dir: &Path,
+    options: &Options,
+    next_number: &mut usize,
+    report: &mut RepairReport,
+) -> Result<()> {
+    let old_manifest = match manifest::read(dir) {
+        Ok(manifest) => manifest,
+        Err(Error::Corruption(_)) => {
+            report.manifests_rebuilt.push(name.to_string());
+            None
+        }
+        Err(err) => return Err(err),
+    };
+
+    let candidates: Vec<(usize, PathBuf)> = if let Some(manifest) = &old_manifest {
+        manifest
+            .files
+            .iter()
+            .map(|entry| (entry.level, manifest::sst_path(dir, entry.number)))
+            .collect()
+    } else {
+        let mut paths = column_family::sst_paths(dir)?;
+        paths.sort();
+        paths.into_iter().map(|path| (0, path)).collect()
+    };
+
+    let mut tables = Vec::new();
+    let mut damaged = Vec::new();
+    let mut salvaged_l0 = false;
+    for (level, path) in candidates {
+        let Some(number) = manifest::sst_number(&path) else {
+            continue;
+        };
+        if !path.exists() {
+            report.missing.push(path);
+            continue;
+        }
+
+        if let Some(largest_sequence) = check_table(&path, options)? {
+            report.tables_intact += 1;
+            tables.push(Table {
+                level,
+                number,
+                largest_sequence,
+            });
+            continue;
+        }
+
+        let salvaged = sstable::salvage(&path, options.comparator.as_ref())?;
+        let recovered = salvaged.entries.len() as u64;
+        if let Some(num_entries) = salvaged.num_entries {
+            report.entries_lost += u64::from(num_entries).saturating_sub(recovered);
+        }
+        if recovered > 0 {
+            let number = take_number(next_number);
+            let mut builder =
+                SSTableBuilder::with_options(manifest::sst_path(dir, number), options)?;
+            builder.set_largest_sequence(salvaged.largest_sequence);
+            for (key, value) in &salvaged.entries {
+                builder.add(key, value)?;
+            }
+            builder.finish()?;
+
+            report.tables_salvaged += 1;
+            report.entries_salvaged += recovered;
+            salvaged_l0 |= level == 0;
+            tables.push(Table {
+                level,
+                number,
+                largest_sequence: salvaged.largest_sequence,
+            });
+        }
+        damaged.push(path);
+    }
+
+    // the order of the L0 files decides which writes are newer, a salvaged table
+    // got the highest number and would shadow newer ones
+    if old_manifest.is_none() || salvaged_l0 {
+        tables.sort_by_key(|table| (table.level, table.largest_sequence, table.number));
+        for table in tables.iter_mut().filter(|table| table.level == 0) {
+            let number = take_number(next_number);
+            fs::rename(
+                manifest::sst_path(dir, table.number),
+                manifest::sst_path(dir, number),
+            )?;
+            table.number = number;
+        }
+    }
+
+    let manifest = Manifest {
+        files: tables
+            .iter()
+            .map(|table| FileEntry {
+                level: table.level,
+                number: table.number,
+            })
+            .collect(),
+        flushed_sequence: old_manifest.map_or(0, |manifest| manifest.flushed_sequence),
+    };
+    manifest::write(dir, &manifest)?;
+
+    // only moved once the manifest no longer refers to them
+    if !damaged.is_empty() {
+        let lost_dir = dir.join(LOST_DIR);
+        fs::create_dir_all(&lost_dir)?;
+        for path in damaged {
+            let dest = lost_dir.join(path.file_name().unwrap());
+            fs::rename(&path, &dest)?;
+            report.quarantined.push(dest);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a table end to end, returns its largest sequence number if it is
+/// intact and `None` if it is damaged
+fn check_table(path: &Path, options: &Options) -> Result<Option<u64>> {
+    let table = match SSTable::open_with_options(path.to_path_buf(), options) {
+        Ok(table) => table,
+        Err(err) if is_damage(&err) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut count = 0u64;
+    let mut previous: Option<Vec<u8>> = None;
+    for entry in &table {
+        let key = match entry {
+            Ok((key, _)) => key,
+            Err(err) if is_damage(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if let Some(previous) = &previous {
+            if options.comparator.compare(previous, &key) != Ordering::Less {
+                return Ok(None);
+            }
+        }
+        previous = Some(key);
+        count += 1;
+    }
+
+    Ok((count == u64::from(table.num_entries())).then(|| table.largest_sequence()))
+}
+
+/// Check whether an error stems from the contents of a file rather than from
+/// accessing it
+fn is_damage(err: &Error) -> bool {
+    match err {
+        Error::Corruption(_) => true,
+        Error::Io(err) => err.kind() == ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+fn take_number(next_number: &mut usize) -> usize {
+    let number = *next_number;
+    *next_number += 1;
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LSMTree;
+    use crate::test_util::temp_dir;
+    use std::io::{Seek, SeekFrom, Write};
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{i:03}").into_bytes()
+    }
+
+    fn single_table(dir: &Path) -> PathBuf {
+        let paths = column_family::sst_paths(dir).unwrap();
+        assert_eq!(paths.len(), 1);
+        paths[0].clone()
+    }
+
+    #[test]
+    fn test_repair_salvages_damaged_table() {
+        let dir = temp_dir("repair", "salvage");
+        let tree = LSMTree::open(&dir).unwrap();
+        for i in 0..100 {
+            tree.put(key(i), b"value".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // every entry takes 20 bytes, break the key length of the 51st
+        let path = single_table(&dir);
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(50 * 20)).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+
+        let report = LSMTree::repair(&dir).unwrap();
+        assert_eq!(report.tables_intact, 0);
+        assert_eq!(report.tables_salvaged, 1);
+        assert_eq!(report.entries_salvaged, 50);
+        assert_eq!(report.entries_lost, 50);
+        assert_eq!(
+            report.quarantined,
+            vec![dir.join(LOST_DIR).join(path.file_name().unwrap())]
+        );
+        assert!(report.quarantined[0].exists());
+        assert!(!path.exists());
+
+        let tree = LSMTree::open(&dir).unwrap();
+        assert_eq!(tree.get(&key(49)).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(tree.get(&key(50)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_repair_keeps_newer_l0_table_ahead() {
+        let dir = temp_dir("repair", "l0_order");
+        let options = Options {
+            write_buffer_size: 1024 * 1024,
+            ..Options::default()
+        };
+        let tree = LSMTree::open_with_options(&dir, options).unwrap();
+        for i in 0..100 {
+            tree.put(key(i), b"value".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        let older = single_table(&dir);
+        for i in 0..100 {
+            tree.put(key(i), b"fresh".to_vec()).unwrap();
+        }
+        tree.flush().unwrap();
+        assert_eq!(tree.num_files_at_level(0), 2);
+        drop(tree);
+
+        // break the 51st entry of the older table, see `test_repair_salvages_damaged_table`
+        let mut file = fs::OpenOptions::new().write(true).open(&older).unwrap();
+        file.seek(SeekFrom::Start(50 * 20)).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+
+        let report = LSMTree::repair(&dir).unwrap();
+        assert_eq!(report.tables_intact, 1);
+        assert_eq!(report.tables_salvaged, 1);
+
+        // the salvaged table holds the older writes and stays below the newer one
+        let tree = LSMTree::open(&dir).unwrap();
+        assert_eq!(tree.get(&key(10)).unwrap(), Some(b"fresh".to_vec()));
+        assert_eq!(tree.get(&key(90)).unwrap(), Some(b"fresh".to_vec()));
+    }
+
+    #[test]
+    fn test_repair_rebuilds_manifest() {
+        let dir = temp_dir("repair", "manifest");
+        let tree = LSMTree::open(&dir).unwrap();
+        tree.put(b"a".to_vec(), b"old".to_vec()).unwrap();
+        tree.put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        tree.flush().unwrap();
+        tree.put(b"a".to_vec(), b"new".to_vec()).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+
+        fs::write(dir.join(MANIFEST_FILE), b"garbage").unwrap();
+        assert!(LSMTree::open(&dir).is_err());
+
+        let report = LSMTree::repair(&dir).unwrap();
+        assert_eq!(report.manifests_rebuilt, vec![DEFAULT_COLUMN_FAMILY]);
+        assert_eq!(report.tables_intact, 2);
+        assert!(report.quarantined.is_empty());
+
+        let tree = LSMTree::open(&dir).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"1".to_vec()));
+    }
+}
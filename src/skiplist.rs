@@ -0,0 +1,365 @@
+//! Concurrent skiplist over arena-allocated nodes
+//!
+//! Inserts link new nodes with compare-and-swap, so any number of threads can
+//! insert and read at the same time without taking a lock. Nodes are never
+//! changed or removed once linked: inserting a key again adds a node sorting
+//! before those with a lower sequence number, and lookups see the node with the
+//! highest one, whichever thread got to link first. Nodes live in an
+//! append-only arena and are addressed by their index, so references to them
+//! stay valid as long as the list is alive.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering as MemoryOrdering};
+use std::sync::{Arc, OnceLock};
+
+use crate::comparator::Comparator;
+
+/// Maximum number of levels of a node, enough for billions of nodes
+const MAX_HEIGHT: usize = 12;
+
+/// Index marking the end of a level
+const NIL: u32 = u32::MAX;
+
+/// Version sorting before every node of a key
+const FIRST: (u64, u32) = (u64::MAX, NIL);
+
+/// Number of slots of the first arena chunk, every further chunk doubles in size
+const FIRST_CHUNK: usize = 64;
+
+/// Number of arena chunks, together they hold more than `NIL` slots
+const ARENA_CHUNKS: usize = 27;
+
+/// Append-only storage whose elements never move
+struct Arena<T> {
+    chunks: [OnceLock<Box<[OnceLock<T>]>>; ARENA_CHUNKS],
+    len: AtomicUsize,
+    /// Bytes of the allocated chunks
+    allocated: AtomicUsize,
+}
+
+impl<T> Arena<T> {
+    fn new() -> Self {
+        Self {
+            chunks: std::array::from_fn(|_| OnceLock::new()),
+            len: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// (chunk, slot in the chunk) of an index
+    const fn locate(idx: usize) -> (usize, usize) {
+        // chunk k starts at index FIRST_CHUNK * (2^k - 1)
+        let chunk = (idx / FIRST_CHUNK + 1).ilog2() as usize;
+        (chunk, idx - FIRST_CHUNK * ((1 << chunk) - 1))
+    }
+
+    /// Stores a value, returns its index
+    fn push(&self, value: T) -> u32 {
+        let idx = self.len.fetch_add(1, MemoryOrdering::Relaxed);
+        assert!(idx < NIL as usize, "skiplist arena is full");
+
+        let (chunk, slot) = Self::locate(idx);
+        let slots = self.chunks[chunk].get_or_init(|| {
+            let slots = FIRST_CHUNK << chunk;
+            self.allocated.fetch_add(
+                slots * mem::size_of::<OnceLock<T>>(),
+                MemoryOrdering::Relaxed,
+            );
+            (0..slots).map(|_| OnceLock::new()).collect()
+        });
+        if slots[slot].set(value).is_err() {
+            unreachable!("arena slot {idx} was handed out twice");
+        }
+
+        idx as u32
+    }
+
+    /// Get a stored value, `idx` must have been returned by `push`
+    fn get(&self, idx: u32) -> &T {
+        let (chunk, slot) = Self::locate(idx as usize);
+        self.chunks[chunk]
+            .get()
+            .and_then(|slots| slots[slot].get())
+            .expect("arena index was never stored")
+    }
+
+    /// Get the number of bytes allocated for chunks, used or not
+    fn allocated_bytes(&self) -> usize {
+        self.allocated.load(MemoryOrdering::Relaxed)
+    }
+
+    /// Get the number of bytes of slots allocated but not handed out yet
+    fn reserved_bytes(&self) -> usize {
+        let used = self.len.load(MemoryOrdering::Relaxed) * mem::size_of::<OnceLock<T>>();
+        self.allocated_bytes().saturating_sub(used)
+    }
+}
+
+struct Node<V> {
+    key: Vec<u8>,
+    /// Orders the nodes of a key, highest first
+    sequence: u64,
+    value: V,
+    /// Index of the next node per level, the height of the node is its length
+    next: Box<[AtomicU32]>,
+}
+
+/// Sorted map from keys to values supporting concurrent inserts and reads
+pub struct SkipList<V> {
+    arena: Arena<Node<V>>,
+    /// First node per level
+    head: [AtomicU32; MAX_HEIGHT],
+    comparator: Arc<dyn Comparator>,
+    /// Number of distinct keys
+    len: AtomicUsize,
+    /// Drives the choice of node heights
+    inserts: AtomicU64,
+    /// Bytes of the per-node link arrays, allocated outside the arena
+    link_bytes: AtomicUsize,
+}
+
+impl<V> SkipList<V> {
+    pub fn new(comparator: Arc<dyn Comparator>) -> Self {
+        Self {
+            arena: Arena::new(),
+            head: std::array::from_fn(|_| AtomicU32::new(NIL)),
+            comparator,
+            len: AtomicUsize::new(0),
+            inserts: AtomicU64::new(0),
+            link_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Inserts a value, shadowing values of the same key with a lower `sequence`
+    pub fn insert(&self, key: Vec<u8>, sequence: u64, value: V) {
+        let height = self.random_height();
+        self.link_bytes.fetch_add(
+            height * mem::size_of::<AtomicU32>(),
+            MemoryOrdering::Relaxed,
+        );
+        let idx = self.arena.push(Node {
+            key,
+            sequence,
+            value,
+            next: (0..height).map(|_| AtomicU32::new(NIL)).collect(),
+        });
+        let node = self.arena.get(idx);
+
+        // link bottom-up, the node is visible to readers once linked at level 0
+        for level in 0..height {
+            loop {
+                let (prev, next) = self.find_splice(&node.key, (sequence, idx), level);
+                node.next[level].store(next, MemoryOrdering::Relaxed);
+
+                let linked = self
+                    .link(prev, level)
+                    .compare_exchange(next, idx, MemoryOrdering::Release, MemoryOrdering::Relaxed)
+                    .is_ok();
+                if !linked {
+                    continue;
+                }
+
+                // versions of a key are adjacent, the first one counts
+                if level == 0
+                    && !self.has_key(prev, &node.key)
+                    && !self.has_key(Some(next), &node.key)
+                {
+                    self.len.fetch_add(1, MemoryOrdering::Relaxed);
+                }
+                break;
+            }
+        }
+    }
+
+    /// Newest value of a key
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let (_, next) = self.find_splice(key, FIRST, 0);
+        self.has_key(Some(next), key)
+            .then(|| &self.arena.get(next).value)
+    }
+
+    /// Newest value of every key in key order
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter {
+            list: self,
+            next: self.head[0].load(MemoryOrdering::Acquire),
+            last_key: None,
+        }
+    }
+
+    /// Like `iter`, starting at the first key >= `start`
+    pub fn iter_from(&self, start: &[u8]) -> Iter<'_, V> {
+        let (_, next) = self.find_splice(start, FIRST, 0);
+        Iter {
+            list: self,
+            next,
+            last_key: None,
+        }
+    }
+
+    /// Get the number of distinct keys
+    pub fn len(&self) -> usize {
+        self.len.load(MemoryOrdering::Relaxed)
+    }
+
+    /// Get the number of bytes allocated for nodes and their links
+    ///
+    /// Heap memory owned by keys and values is not included.
+    pub fn memory_usage(&self) -> usize {
+        self.arena.allocated_bytes() + self.link_bytes.load(MemoryOrdering::Relaxed)
+    }
+
+    /// Get the number of bytes of `memory_usage` allocated ahead for nodes still
+    /// to come
+    pub fn reserved_bytes(&self) -> usize {
+        self.arena.reserved_bytes()
+    }
+
+    /// Neighbours at `level` of the position of `key` with `version`
+    ///
+    /// Nodes of equal keys are ordered by (sequence, index), highest first,
+    /// `FIRST` finds the position before all nodes of `key`. Returns the last node
+    /// before the position, `None` for the head, and the first node after it.
+    fn find_splice(&self, key: &[u8], version: (u64, u32), level: usize) -> (Option<u32>, u32) {
+        let mut prev = None;
+        let mut l = MAX_HEIGHT - 1;
+        loop {
+            let next = self.link(prev, l).load(MemoryOrdering::Acquire);
+            if next != NIL && self.is_before(next, key, version) {
+                prev = Some(next);
+            } else if l == level {
+                return (prev, next);
+            } else {
+                l -= 1;
+            }
+        }
+    }
+
+    /// Check whether node `node` sorts before `key` with `version`
+    fn is_before(&self, node: u32, key: &[u8], version: (u64, u32)) -> bool {
+        let stored = self.arena.get(node);
+        match self.comparator.compare(&stored.key, key) {
+            Ordering::Less => true,
+            Ordering::Equal => (stored.sequence, node) > version,
+            Ordering::Greater => false,
+        }
+    }
+
+    /// Check whether `node` exists and holds `key`
+    fn has_key(&self, node: Option<u32>, key: &[u8]) -> bool {
+        node.filter(|&node| node != NIL).is_some_and(|node| {
+            self.comparator.compare(&self.arena.get(node).key, key) == Ordering::Equal
+        })
+    }
+
+    /// Pointer to the next node at `level`, of the head for `None`
+    fn link(&self, node: Option<u32>, level: usize) -> &AtomicU32 {
+        node.map_or_else(
+            || &self.head[level],
+            |node| &self.arena.get(node).next[level],
+        )
+    }
+
+    /// Height of a new node, each level is a quarter as likely as the one below
+    fn random_height(&self) -> usize {
+        let n = self.inserts.fetch_add(1, MemoryOrdering::Relaxed);
+
+        // splitmix64 finalizer, spreads the counter over all bits
+        let mut x = n.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+
+        (1 + x.trailing_zeros() as usize / 2).min(MAX_HEIGHT)
+    }
+}
+
+impl<V> fmt::Debug for SkipList<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipList")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Iterator over the newest value of every key
+pub struct Iter<'a, V> {
+    list: &'a SkipList<V>,
+    next: u32,
+    /// Key of the last returned node, older versions of it are skipped
+    last_key: Option<&'a [u8]>,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a Vec<u8>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next != NIL {
+            let node = self.list.arena.get(self.next);
+            self.next = node.next[0].load(MemoryOrdering::Acquire);
+
+            let comparator = &self.list.comparator;
+            if self
+                .last_key
+                .is_some_and(|last| comparator.compare(last, &node.key) == Ordering::Equal)
+            {
+                continue;
+            }
+
+            self.last_key = Some(&node.key);
+            return Some((&node.key, &node.value));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::comparator;
+
+    #[test]
+    fn test_newest_value_wins() {
+        let list = SkipList::new(comparator::bytewise());
+        list.insert(b"b".to_vec(), 1, 1);
+        list.insert(b"a".to_vec(), 2, 2);
+        list.insert(b"b".to_vec(), 3, 3);
+        // linked last, but older
+        list.insert(b"b".to_vec(), 2, 4);
+
+        assert_eq!(list.get(b"b"), Some(&3));
+        assert_eq!(list.get(b"c"), None);
+        assert_eq!(list.len(), 2);
+
+        let entries: Vec<_> = list.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        assert_eq!(entries, vec![(b"a".to_vec(), 2), (b"b".to_vec(), 3)]);
+        assert_eq!(list.iter_from(b"aa").count(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let list = SkipList::new(comparator::bytewise());
+
+        thread::scope(|scope| {
+            for t in 0..4u32 {
+                let list = &list;
+                scope.spawn(move || {
+                    for i in 0..1000u32 {
+                        list.insert((i * 4 + t).to_be_bytes().to_vec(), u64::from(i), t);
+                        // readers never block or see a partially linked node
+                        assert!(list.get(&(i * 4 + t).to_be_bytes()).is_some());
+                    }
+                });
+            }
+        });
+
+        assert_eq!(list.len(), 4000);
+        let keys: Vec<_> = list.iter().map(|(k, _)| k.clone()).collect();
+        assert_eq!(keys.len(), 4000);
+        assert!(keys.is_sorted());
+    }
+}
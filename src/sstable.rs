@@ -2,24 +2,34 @@
 //!
 //! # File Format Specification
 //!
-//! An `SSTable` file consists of three main sections:
+//! An `SSTable` file consists of five main sections:
 //!
 //! ```text
 //! ┌─────────────────────────────────────────┐
-//! │          Data Block                     │
+//! │          Data Blocks                    │
 //! │  (variable length, sorted entries)      │
 //! ├─────────────────────────────────────────┤
+//! │          Filter Block                   │
+//! │  (bloom filter over the keys, optional) │
+//! ├─────────────────────────────────────────┤
 //! │          Index Block                    │
-//! │  (sparse index: key → offset)           │
+//! │  (sparse index: key → data block)       │
+//! ├─────────────────────────────────────────┤
+//! │          Properties Block               │
+//! │  (name → value, e.g. the comparator)    │
 //! ├─────────────────────────────────────────┤
 //! │          Footer                         │
-//! │  (metadata, 32 bytes fixed)             │
+//! │  (metadata, 48 bytes fixed)             │
 //! └─────────────────────────────────────────┘
 //! ```
 //!
+//! Entries are sorted by the comparator the table was written with.
+//!
 //! ## Data Block Format
 //!
-//! The data block contains sorted key-value pairs:
+//! Entries are split into data blocks of about `Options::block_size` bytes,
+//! which are stored back to back. Lookups read and cache whole blocks. Each
+//! block contains sorted key-value pairs:
 //!
 //! ```text
 //! For each entry:
@@ -27,286 +37,1618 @@
 //!   key:        [u8; key_len]
 //!   value_len:  u32 (4 bytes)
 //!   value:      [u8; value_len]
-//!   tombstone:  u8 (1 byte)    // 0 = value, 1 = tombstone
+//!   tombstone:  u8 (1 byte)    // 0 = value, 1 = tombstone, 2 = expiring value,
+//!                              // 3 = merge operands
+//!   expires_at: u64 (8 bytes)  // only for expiring values, milliseconds since the UNIX epoch
 //! ```
 //!
+//! The value of a merge entry holds its operands, oldest first:
+//!
+//! ```text
+//! For each operand:
+//!   operand_len: u32 (4 bytes)
+//!   operand:     [u8; operand_len]
+//! ```
+//!
+//! ## Filter Block Format
+//!
+//! A bloom filter over all keys, see `bloom`. Empty if the table was written
+//! without a filter.
+//!
 //! ## Index Block Format
 //!
-//! The index block contains a sparse index mapping keys to offsets:
+//! The index block holds one entry per data block:
 //!
 //! ```text
-//! For each index entry:
+//! For each data block:
 //!   key_len:    u32 (4 bytes)
-//!   key:        [u8; key_len]
-//!   offset:     u64 (8 bytes)  // offset into data block
+//!   key:        [u8; key_len]  // last key of the block
+//!   offset:     u64 (8 bytes)  // offset of the block
+//!   len:        u32 (4 bytes)  // length of the block
+//!   checksum:   u32 (4 bytes)  // CRC-32 of the block
 //! ```
 //!
-//! ## Footer Format (32 bytes fixed)
+//! ## Properties Block Format
 //!
 //! ```text
+//! For each property:
+//!   name_len:   u32 (4 bytes)
+//!   name:       [u8; name_len]   // UTF-8
+//!   value_len:  u32 (4 bytes)
+//!   value:      [u8; value_len]  // UTF-8
+//! ```
+//!
+//! The block ends where the footer starts. `comparator` holds the name of the
+//! comparator.
+//!
+//! ## Footer Format (48 bytes fixed)
+//!
+//! ```text
+//! filter_offset:  u64 (8 bytes)  // offset to filter block (= end of the data blocks)
+//! props_offset:   u64 (8 bytes)  // offset to properties block
 //! index_offset:   u64 (8 bytes)  // offset to index block
 //! index_len:      u32 (4 bytes)  // length of index block
 //! num_entries:    u32 (4 bytes)  // total number of entries
-//! magic_number:   u64 (8 bytes)  // 0x5353544142454c31 ("SSTABLE1")
-//! _reserved:      u64 (8 bytes)  // reserved for future use
+//! magic_number:   u64 (8 bytes)  // 0x5353544142454c34 ("SSTABLE4")
+//! largest_seq:    u64 (8 bytes)  // sequence number of the newest write in the table
 //! ```
-
+//!
+//! Files of older formats are still read. "SSTABLE3" files have no checksums
+//! in their index, their blocks are not verified. "SSTABLE2" files have no filter block
+//! and no `filter_offset`, their index holds the offset of every single entry
+//! (`key_len`, `key`, `offset`). "SSTABLE1" files additionally have no
+//! properties and no `props_offset`, their keys are sorted bytewise.
+
+use crate::block_cache::BlockCache;
+use crate::bloom::{self, FilterBuilder};
+use crate::comparator::{self, Comparator};
+use crate::crc32;
+use crate::event_listener::{EventListener, TableFileDeletionInfo};
+use crate::mmap::Mmap;
+use crate::options::Options;
+use crate::pinned::{Lookup, PinnedValue};
+use crate::statistics::{Statistics, Ticker};
+use crate::table_cache::TableCache;
 use crate::{Error, Result, Value};
+use std::borrow::Borrow;
+use std::cmp;
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Magic number for `SSTable` files: "SSTABLE4" in ASCII
+const MAGIC_NUMBER: u64 = 0x5353_5441_4245_4c34;
+
+/// Magic number of the format without block checksums: "SSTABLE3" in ASCII
+const MAGIC_NUMBER_V3: u64 = 0x5353_5441_4245_4c33;
+
+/// Magic number of the format with a dense index: "SSTABLE2" in ASCII
+const MAGIC_NUMBER_V2: u64 = 0x5353_5441_4245_4c32;
 
-/// Magic number for `SSTable` files: "SSTABLE1" in ASCII
-const MAGIC_NUMBER: u64 = 0x5353_5441_4245_4c31;
+/// Magic number of the format without properties: "SSTABLE1" in ASCII
+const MAGIC_NUMBER_V1: u64 = 0x5353_5441_4245_4c31;
 
 /// Size of the footer in bytes
-const FOOTER_SIZE: u64 = 32;
+const FOOTER_SIZE: u64 = 48;
+
+/// Size of the footer of the "SSTABLE2" format, the trailing part of `FOOTER_SIZE`
+const FOOTER_SIZE_V2: u64 = 40;
+
+/// Size of the footer of the "SSTABLE1" format, the trailing part of `FOOTER_SIZE_V2`
+const FOOTER_SIZE_V1: u64 = 32;
+
+/// Property holding the name of the comparator
+pub const COMPARATOR_PROPERTY: &str = "comparator";
+
+/// Source of the ids which tell the blocks of different tables apart in a cache
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Location of a data block
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockHandle {
+    /// Last key of the block
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u64,
+    /// CRC-32 of the block, `None` for files written before checksums existed
+    checksum: Option<u32>,
+}
 
 /// `SSTable` builder class
 pub struct SSTableBuilder {
     /// Buffered writer
     writer: BufWriter<File>,
-    /// In-memory index: key → offset in data block
-    index: Vec<(Vec<u8>, u64)>,
+    /// Handles of the finished data blocks
+    index: Vec<BlockHandle>,
+    /// Last key added
+    last_key: Vec<u8>,
+    /// Offset of the data block being written
+    block_start: u64,
     /// Current offset in the data block
     current_offset: u64,
+    /// CRC-32 of the data block written so far
+    block_checksum: u32,
+    /// Encoding of the entry being added, reused across entries
+    entry_buf: Vec<u8>,
     /// Number of entries written
     num_entries: u32,
+    /// Sequence number of the newest write added
+    largest_sequence: u64,
+    /// Order the keys are added in
+    comparator: Arc<dyn Comparator>,
+    /// Size at which a data block is finished
+    block_size: u64,
+    /// Bloom filter over the added keys, if enabled
+    filter: Option<FilterBuilder>,
 }
 
 impl SSTableBuilder {
-    /// Instantiates new  `SSTable` builder
+    /// Instantiates new  `SSTable` builder with the default options
     pub fn new(path: PathBuf) -> Result<Self> {
-        let file = File::create(&path).expect("Error creating file");
+        Self::with_options(path, &Options::default())
+    }
+
+    /// Instantiates new `SSTable` builder using the comparator, block size and
+    /// bloom filter settings of `options`
+    pub fn with_options(path: PathBuf, options: &Options) -> Result<Self> {
+        let file = File::create(&path)?;
         let writer = BufWriter::new(file);
 
         Ok(Self {
             writer,
             index: Vec::new(),
+            last_key: Vec::new(),
+            block_start: 0,
             current_offset: 0,
+            block_checksum: 0,
+            entry_buf: Vec::new(),
             num_entries: 0,
+            largest_sequence: 0,
+            comparator: Arc::clone(&options.comparator),
+            block_size: options.block_size as u64,
+            filter: (options.bloom_bits_per_key > 0)
+                .then(|| FilterBuilder::new(options.bloom_bits_per_key)),
         })
     }
 
     /// Add a key-value pair to the `SSTable`
-    //  !! must be added in sorted order
+    ///
+    /// Keys must be added in strictly increasing order of the comparator, a key
+    /// out of order fails with `Error::InvalidArgument`.
     pub fn add(&mut self, key: &[u8], value: &Value) -> Result<()> {
-        let offset = self.current_offset;
-
-        // write key
-        let key_len = key.len() as u32;
-        self.writer.write_all(&key_len.to_le_bytes())?;
-        self.current_offset += 4;
-
-        self.writer.write_all(key)?;
-        self.current_offset += key.len() as u64;
-
-        // write value
-        match value {
-            // write actual value
-            Value::Some(val) => {
-                let value_len = val.len() as u32;
-                self.writer.write_all(&value_len.to_le_bytes())?;
-                self.current_offset += 4;
-
-                self.writer.write_all(val)?;
-                self.current_offset += val.len() as u64;
-
-                // tombstone flag (0 = not a tombstone)
-                self.writer.write_all(&[0u8])?;
-                self.current_offset += 1;
-            }
-            // write tombstone
-            Value::Tombstone => {
-                // valu e length is 0 for tombstones
-                self.writer.write_all(&0u32.to_le_bytes())?;
-                self.current_offset += 4;
-
-                // tombstone flag (1 = tombstone)
-                self.writer.write_all(&[1u8])?;
-                self.current_offset += 1;
-            }
+        if self.num_entries > 0
+            && self.comparator.compare(&self.last_key, key) != cmp::Ordering::Less
+        {
+            return Err(Error::InvalidArgument(format!(
+                "Key {:?} added after {:?}, keys must be added in sorted order",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(&self.last_key)
+            )));
         }
 
-        // add entry to index
-        self.index.push((key.to_vec(), offset));
+        self.entry_buf.clear();
+        self.current_offset += write_entry(&mut self.entry_buf, key, value)?;
+        self.writer.write_all(&self.entry_buf)?;
+        self.block_checksum = crc32::update(self.block_checksum, &self.entry_buf);
         self.num_entries += 1;
 
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        if let Some(filter) = &mut self.filter {
+            filter.add(key);
+        }
+
+        if self.current_offset - self.block_start >= self.block_size {
+            self.finish_block();
+        }
+
         Ok(())
     }
 
+    /// Adds the data block being written to the index
+    fn finish_block(&mut self) {
+        if self.current_offset > self.block_start {
+            self.index.push(BlockHandle {
+                last_key: self.last_key.clone(),
+                offset: self.block_start,
+                len: self.current_offset - self.block_start,
+                checksum: Some(self.block_checksum),
+            });
+            self.block_start = self.current_offset;
+            self.block_checksum = 0;
+        }
+    }
+
+    /// Get the number of bytes written to the data block so far
+    pub const fn data_size(&self) -> u64 {
+        self.current_offset
+    }
+
+    /// Get the number of entries added so far
+    pub const fn num_entries(&self) -> u32 {
+        self.num_entries
+    }
+
+    /// Set the sequence number of the newest write in the table
+    pub const fn set_largest_sequence(&mut self, sequence: u64) {
+        self.largest_sequence = sequence;
+    }
+
     /// Finish writing the `SSTable` and flush to disk
     pub fn finish(mut self) -> Result<()> {
-        let index_offset = self.current_offset;
+        self.finish_block();
+
+        // write filter block
+        let filter_offset = self.current_offset;
+        let filter = self.filter.as_ref().map(FilterBuilder::finish);
+        let filter = filter.unwrap_or_default();
+        self.writer.write_all(&filter)?;
 
         // writee index block
+        let index_offset = filter_offset + filter.len() as u64;
         let mut index_len = 0u64;
-        for (key, offset) in &self.index {
-            let key_len = key.len() as u32;
+        for block in &self.index {
+            let key_len = block.last_key.len() as u32;
             self.writer.write_all(&key_len.to_le_bytes())?;
-            index_len += 4;
-
-            self.writer.write_all(key)?;
-            index_len += key.len() as u64;
+            self.writer.write_all(&block.last_key)?;
+            self.writer.write_all(&block.offset.to_le_bytes())?;
+            self.writer.write_all(&(block.len as u32).to_le_bytes())?;
+            self.writer
+                .write_all(&block.checksum.unwrap_or_default().to_le_bytes())?;
+            index_len += 4 + block.last_key.len() as u64 + 8 + 4 + 4;
+        }
 
-            self.writer.write_all(&offset.to_le_bytes())?;
-            index_len += 8;
+        // write properties block
+        let props_offset = index_offset + index_len;
+        let properties = [(COMPARATOR_PROPERTY, self.comparator.name())];
+        for (name, value) in properties {
+            for bytes in [name.as_bytes(), value.as_bytes()] {
+                self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                self.writer.write_all(bytes)?;
+            }
         }
 
         // write the footer
+        self.writer.write_all(&filter_offset.to_le_bytes())?;
+        self.writer.write_all(&props_offset.to_le_bytes())?;
         self.writer.write_all(&index_offset.to_le_bytes())?;
         self.writer.write_all(&(index_len as u32).to_le_bytes())?;
         self.writer.write_all(&self.num_entries.to_le_bytes())?;
         self.writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
-        self.writer.write_all(&0u64.to_le_bytes())?; // reserved
+        self.writer
+            .write_all(&self.largest_sequence.to_le_bytes())?;
 
         // flush to disk
         self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
 
         Ok(())
     }
-}
+}
+
+/// Decoded footer of any format version
+struct Footer {
+    magic: u64,
+    filter_offset: u64,
+    props_offset: u64,
+    /// End of the properties block (= start of the footer)
+    props_end: u64,
+    index_offset: u64,
+    index_len: u64,
+    num_entries: u32,
+    largest_sequence: u64,
+    file_size: u64,
+}
+
+impl Footer {
+    /// Check whether the index holds an entry per data block rather than per entry
+    const fn has_block_index(&self) -> bool {
+        matches!(self.magic, MAGIC_NUMBER | MAGIC_NUMBER_V3)
+    }
+
+    /// Check whether the index holds a checksum per data block
+    const fn has_checksums(&self) -> bool {
+        self.magic == MAGIC_NUMBER
+    }
+
+    /// Reads and validates the footer, older files have no filter and no properties
+    fn read(file: &File) -> Result<Self> {
+        let file_size = file.metadata()?.len();
+
+        if file_size < FOOTER_SIZE_V1 {
+            return Err(Error::Corruption(format!(
+                "File too small for footer: {file_size} bytes"
+            )));
+        }
+
+        // the last 32 bytes have the same layout in all versions
+        let footer_buf = read_exact_at(file, file_size - FOOTER_SIZE_V1, FOOTER_SIZE_V1)?;
+
+        let index_offset = u64::from_le_bytes(footer_buf[0..8].try_into().unwrap());
+        let index_len = u32::from_le_bytes(footer_buf[8..12].try_into().unwrap());
+        let num_entries = u32::from_le_bytes(footer_buf[12..16].try_into().unwrap());
+        let magic = u64::from_le_bytes(footer_buf[16..24].try_into().unwrap());
+        let largest_sequence = u64::from_le_bytes(footer_buf[24..32].try_into().unwrap());
+
+        // validate magic number
+        let (filter_offset, props_offset, props_end) = match magic {
+            MAGIC_NUMBER | MAGIC_NUMBER_V3 if file_size >= FOOTER_SIZE => {
+                let buf = read_exact_at(file, file_size - FOOTER_SIZE, 16)?;
+                let filter_offset = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+                let props_offset = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+                (filter_offset, props_offset, file_size - FOOTER_SIZE)
+            }
+            MAGIC_NUMBER_V2 if file_size >= FOOTER_SIZE_V2 => {
+                let buf = read_exact_at(file, file_size - FOOTER_SIZE_V2, 8)?;
+                let props_offset = u64::from_le_bytes(buf.try_into().unwrap());
+                (index_offset, props_offset, file_size - FOOTER_SIZE_V2)
+            }
+            MAGIC_NUMBER_V1 => {
+                let props_end = file_size - FOOTER_SIZE_V1;
+                (index_offset, props_end, props_end)
+            }
+            _ => {
+                return Err(Error::Corruption(format!(
+                    "Invalid magic number: expected 0x{MAGIC_NUMBER:x}, got 0x{magic:x}"
+                )));
+            }
+        };
+
+        if filter_offset > index_offset
+            || index_offset
+                .checked_add(u64::from(index_len))
+                .is_none_or(|end| end > props_offset)
+            || props_offset > props_end
+        {
+            return Err(Error::Corruption(
+                "Index block extends into the footer".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            magic,
+            filter_offset,
+            props_offset,
+            props_end,
+            index_offset,
+            index_len: u64::from(index_len),
+            num_entries,
+            largest_sequence,
+            file_size,
+        })
+    }
+}
+
+/// `SSTable` reader
+pub struct SSTable {
+    /// File path
+    path: PathBuf,
+    /// File handle, only accessed through positional reads
+    file: TableFile,
+    /// Identifies the blocks of the table in the block cache
+    id: u64,
+    /// Index of the data blocks, `None` if it is read through the block cache
+    index: Option<Arc<Vec<BlockHandle>>>,
+    /// (offset, length) of the index block
+    index_block: (u64, u64),
+    /// Whether the index holds a checksum per data block
+    checksums: bool,
+    /// Bloom filter, `None` if there is none or it is read through the block cache
+    filter: Option<Arc<Vec<u8>>>,
+    /// (offset, length) of the filter block, the length is 0 without a filter
+    filter_block: (u64, u64),
+    /// Cache for the blocks read from the file
+    block_cache: Option<Arc<BlockCache>>,
+    /// Counts the lookups the bloom filter saved or let through in vain
+    statistics: Option<Arc<Statistics>>,
+    /// Told when the file is deleted
+    listeners: Vec<Arc<dyn EventListener>>,
+    /// Order of the keys
+    comparator: Arc<dyn Comparator>,
+    /// Properties stored with the table
+    properties: BTreeMap<String, String>,
+    /// Smallest and largest key, `None` for empty tables
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    /// Number of entries in the `SSTable`
+    num_entries: u32,
+    /// End of the data blocks
+    data_end: u64,
+    /// Total size of the file in bytes
+    file_size: u64,
+    /// Sequence number of the newest write in the table, 0 for older files
+    largest_sequence: u64,
+    /// Set once the table is no longer referenced by the tree, the file is removed on drop
+    obsolete: AtomicBool,
+}
+
+impl SSTable {
+    /// Open an existing `SSTable` with the default options
+    pub fn open(path: PathBuf) -> Result<Self> {
+        Self::open_with_options(path, &Options::default())
+    }
+
+    /// Open an existing `SSTable` using the comparator and block cache settings
+    /// of `options`
+    ///
+    /// Fails if the table was written with a comparator of a different name.
+    pub fn open_with_options(path: PathBuf, options: &Options) -> Result<Self> {
+        Self::load(path, options, None)
+    }
+
+    /// Like `open_with_options`, but the file is only held open while it is in
+    /// `table_cache`
+    pub(crate) fn open_cached(
+        path: PathBuf,
+        options: &Options,
+        table_cache: &Arc<TableCache>,
+    ) -> Result<Self> {
+        Self::load(path, options, Some(table_cache))
+    }
+
+    /// Reads the metadata of a table, keeping its file open or handing it to
+    /// `table_cache`
+    fn load(
+        path: PathBuf,
+        options: &Options,
+        table_cache: Option<&Arc<TableCache>>,
+    ) -> Result<Self> {
+        let file = File::open(&path)?;
+        let footer = Footer::read(&file)?;
+
+        // read properties block
+        let props_buf = read_exact_at(
+            &file,
+            footer.props_offset,
+            footer.props_end - footer.props_offset,
+        )?;
+        let properties = decode_properties(&props_buf)?;
+
+        let comparator = Arc::clone(&options.comparator);
+        let table_comparator = properties
+            .get(COMPARATOR_PROPERTY)
+            .map_or(comparator::BytewiseComparator.name(), String::as_str);
+        if table_comparator != comparator.name() {
+            return Err(Error::InvalidArgument(format!(
+                "{path:?} is sorted by comparator {table_comparator:?}, not {:?}",
+                comparator.name()
+            )));
+        }
+
+        // read index block, older files index every entry
+        let index_buf = read_exact_at(&file, footer.index_offset, footer.index_len)?;
+        let index = if footer.has_block_index() {
+            decode_index(&index_buf, footer.has_checksums())?
+        } else {
+            group_into_blocks(
+                decode_entry_index(&index_buf)?,
+                footer.index_offset,
+                options.block_size,
+            )
+        };
+
+        let key_range = match index.last() {
+            Some(last) => {
+                let (first_key, _) = read_entry(&mut FileReader::new(&file, 0))?;
+                Some((first_key, last.last_key.clone()))
+            }
+            None => None,
+        };
+
+        // the index of older files is not stored as blocks, so it has to stay in memory
+        let filter_block = (
+            footer.filter_offset,
+            footer.index_offset - footer.filter_offset,
+        );
+        let pinned = options.pin_index_and_filter_blocks || !footer.has_block_index();
+        let filter = if pinned && filter_block.1 > 0 {
+            Some(Arc::new(read_exact_at(
+                &file,
+                filter_block.0,
+                filter_block.1,
+            )?))
+        } else {
+            None
+        };
+
+        // a mapping outlives the file handle, so mapped tables don't use the table cache
+        let id = NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed);
+        let mapped = options.allow_mmap_reads.then(|| Mmap::map(&file).ok());
+        let file = match (mapped.flatten(), table_cache) {
+            (Some(map), _) => TableFile::Mapped(Arc::new(map)),
+            (None, Some(table_cache)) => {
+                table_cache.insert(id, Arc::new(file));
+                TableFile::Cached(Arc::clone(table_cache))
+            }
+            (None, None) => TableFile::Pinned(Arc::new(file)),
+        };
+
+        Ok(Self {
+            path,
+            file,
+            id,
+            index: pinned.then(|| Arc::new(index)),
+            index_block: (footer.index_offset, footer.index_len),
+            checksums: footer.has_checksums(),
+            filter,
+            filter_block,
+            block_cache: options.block_cache.clone(),
+            statistics: options.statistics.clone(),
+            listeners: options.listeners.clone(),
+            comparator,
+            properties,
+            key_range,
+            num_entries: footer.num_entries,
+            data_end: footer.filter_offset,
+            file_size: footer.file_size,
+            largest_sequence: footer.largest_sequence,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Get a value by key
+    pub fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        self.find(key, |block, entry| entry.to_value(block))
+    }
+
+    /// Looks up a key like `get`, but borrows the value from the block holding it
+    pub(crate) fn get_pinned(&self, key: &[u8], now: u64) -> Result<Option<Lookup>> {
+        self.find(key, |block, entry| {
+            Ok(match entry.flag {
+                FLAG_MERGE => Lookup::Merge,
+                _ if entry.is_live(now) => {
+                    Lookup::Value(Some(PinnedValue::block(block.clone(), entry.value.clone())))
+                }
+                _ => Lookup::Value(None),
+            })
+        })
+    }
+
+    /// Looks up distinct keys sorted by the comparator of the table, returning
+    /// their values in the same order
+    ///
+    /// Every block holding some of the keys is read once, in file order.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Value>>> {
+        let mut values = vec![None; keys.len()];
+
+        // (block, key) of the keys the filter doesn't rule out, sorted by block
+        let index = self.index()?;
+        let mut candidates = Vec::new();
+        let mut passed = 0;
+        for (i, key) in keys.iter().enumerate() {
+            if !self.may_contain(key)? {
+                self.record(Ticker::BloomFilterUseful, 1);
+                continue;
+            }
+            passed += 1;
+            let idx = index.partition_point(|block| {
+                self.comparator.compare(&block.last_key, key) == cmp::Ordering::Less
+            });
+            if idx < index.len() {
+                candidates.push((idx, i));
+            }
+        }
+
+        for run in candidates.chunk_by(|a, b| a.0 == b.0) {
+            let handle = &index[run[0].0];
+            let block = self.read_block(handle.offset, handle.len)?;
+
+            // merge the keys of the block with its entries
+            let mut pending = run.iter().map(|&(_, i)| i).peekable();
+            let mut pos = 0;
+            while pos < block.len() && pending.peek().is_some() {
+                let entry = BlockEntry::decode(&block, &mut pos)?;
+                while let Some(&i) = pending.peek() {
+                    match self.comparator.compare(keys[i], entry.key) {
+                        cmp::Ordering::Less => {}
+                        cmp::Ordering::Equal => values[i] = Some(entry.to_value(&block)?),
+                        cmp::Ordering::Greater => break,
+                    }
+                    pending.next();
+                }
+            }
+        }
+
+        if self.has_filter() {
+            let found = values.iter().filter(|value| value.is_some()).count();
+            self.record(Ticker::BloomFilterFalsePositive, (passed - found) as u64);
+        }
+        Ok(values)
+    }
+
+    /// Finds the entry of a key and maps it with `f`, which also gets the block
+    /// holding it
+    fn find<T>(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(&Block, &BlockEntry<'_>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        if !self.may_contain(key)? {
+            self.record(Ticker::BloomFilterUseful, 1);
+            return Ok(None);
+        }
+
+        let found = self.search(key, f)?;
+        if found.is_none() && self.has_filter() {
+            self.record(Ticker::BloomFilterFalsePositive, 1);
+        }
+        Ok(found)
+    }
+
+    /// Searches the data block which may hold a key, see `find`
+    fn search<T>(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(&Block, &BlockEntry<'_>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        // binary search the index for the only block which may hold the key
+        let index = self.index()?;
+        let idx = index.partition_point(|block| {
+            self.comparator.compare(&block.last_key, key) == cmp::Ordering::Less
+        });
+        let Some(handle) = index.get(idx) else {
+            return Ok(None);
+        };
+
+        // scan the data block without copying the entries passed over
+        let block = self.read_block(handle.offset, handle.len)?;
+        let mut pos = 0;
+        while pos < block.len() {
+            let entry = BlockEntry::decode(&block, &mut pos)?;
+            match self.comparator.compare(entry.key, key) {
+                cmp::Ordering::Less => {}
+                cmp::Ordering::Equal => return f(&block, &entry).map(Some),
+                cmp::Ordering::Greater => break,
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns an iterator over all entries in key order
+    pub fn iter(&self) -> SSTableIter<'_> {
+        self.iter_at(0)
+    }
+
+    /// Returns an iterator over the entries with keys >= `start`
+    pub fn iter_from(&self, start: &[u8]) -> SSTableIter<'_> {
+        let index = match self.index() {
+            Ok(index) => index,
+            Err(err) => {
+                let mut iter = self.iter_at(self.data_end);
+                iter.error = Some(err);
+                return iter;
+            }
+        };
+
+        // the first block which may hold keys >= start
+        let idx = index.partition_point(|block| {
+            self.comparator.compare(&block.last_key, start) == cmp::Ordering::Less
+        });
+        let offset = index.get(idx).map_or(self.data_end, |block| block.offset);
+
+        let mut iter = self.iter_at(offset);
+        iter.skip_before = Some(start.to_vec());
+        iter
+    }
+
+    fn iter_at(&self, offset: u64) -> SSTableIter<'_> {
+        let (reader, error) = match self.reader(offset) {
+            Ok(reader) => (Some(reader), None),
+            Err(err) => (None, Some(err)),
+        };
+
+        SSTableIter {
+            reader,
+            pos: offset,
+            end: self.data_end,
+            comparator: self.comparator.as_ref(),
+            skip_before: None,
+            error,
+        }
+    }
+
+    /// Sequential reader over the data blocks starting at `offset`
+    fn reader(&self, offset: u64) -> Result<EntryReader> {
+        let file = match &self.file {
+            TableFile::Mapped(map) => {
+                let mut cursor = io::Cursor::new(Block::mapped(map, 0, self.data_end)?);
+                cursor.set_position(offset);
+                return Ok(EntryReader::Mapped(cursor));
+            }
+            TableFile::Pinned(file) => Arc::clone(file),
+            TableFile::Cached(table_cache) => table_cache.file(self.id, &self.path)?,
+        };
+        Ok(EntryReader::File(BufReader::new(FileReader::new(
+            file, offset,
+        ))))
+    }
+
+    /// Reads `len` bytes at `offset`, mapped tables borrow them from the mapping
+    ///
+    /// The file is reopened through the table cache if it was evicted.
+    fn read_range(&self, offset: u64, len: u64) -> Result<Block> {
+        let file = match &self.file {
+            TableFile::Mapped(map) => return Block::mapped(map, offset, len),
+            TableFile::Pinned(file) => Arc::clone(file),
+            TableFile::Cached(table_cache) => table_cache.file(self.id, &self.path)?,
+        };
+        Ok(Block::Owned(Arc::new(read_exact_at(&file, offset, len)?)))
+    }
+
+    /// Check whether reads are served from a memory map of the file
+    pub const fn is_mapped(&self) -> bool {
+        matches!(self.file, TableFile::Mapped(_))
+    }
+
+    /// Index of the data blocks, read through the block cache unless pinned
+    fn index(&self) -> Result<Arc<Vec<BlockHandle>>> {
+        if let Some(index) = &self.index {
+            return Ok(Arc::clone(index));
+        }
+
+        let (offset, len) = self.index_block;
+        let block = self.read_block(offset, len)?;
+        Ok(Arc::new(decode_index(&block, self.checksums)?))
+    }
+
+    /// Checks the bloom filter, `true` if the table may contain the key
+    fn may_contain(&self, key: &[u8]) -> Result<bool> {
+        if !self.has_filter() {
+            return Ok(true);
+        }
+        let (offset, len) = self.filter_block;
+
+        let filter = match &self.filter {
+            Some(filter) => Block::Owned(Arc::clone(filter)),
+            None => self.read_block(offset, len)?,
+        };
+        Ok(bloom::may_contain(&filter, key))
+    }
+
+    /// Check whether the table was written with a bloom filter
+    const fn has_filter(&self) -> bool {
+        self.filter_block.1 > 0
+    }
+
+    fn record(&self, ticker: Ticker, count: u64) {
+        if let Some(statistics) = &self.statistics {
+            statistics.record(ticker, count);
+        }
+    }
+
+    /// Reads a block through the block cache, mapped blocks are never cached
+    fn read_block(&self, offset: u64, len: u64) -> Result<Block> {
+        let cache = match (&self.file, &self.block_cache) {
+            (TableFile::Mapped(_), _) | (_, None) => return self.read_range(offset, len),
+            (_, Some(cache)) => cache,
+        };
+
+        if let Some(block) = cache.get(self.id, offset) {
+            return Ok(Block::Owned(block));
+        }
+
+        let block = self.read_range(offset, len)?;
+        if let Block::Owned(bytes) = &block {
+            cache.insert(self.id, offset, Arc::clone(bytes));
+        }
+        Ok(block)
+    }
+
+    /// Get the number of entries in the `SSTable`
+    pub const fn num_entries(&self) -> u32 {
+        self.num_entries
+    }
+
+    /// Get the file path
+    pub const fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Get the size of the file in bytes
+    pub const fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Get the sequence number of the newest write in the table
+    pub const fn largest_sequence(&self) -> u64 {
+        self.largest_sequence
+    }
+
+    /// Check whether the table stores an entry for a key
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Smallest key stored in the table
+    pub fn smallest_key(&self) -> Option<&[u8]> {
+        self.key_range
+            .as_ref()
+            .map(|(smallest, _)| smallest.as_slice())
+    }
+
+    /// Largest key stored in the table
+    pub fn largest_key(&self) -> Option<&[u8]> {
+        self.key_range
+            .as_ref()
+            .map(|(_, largest)| largest.as_slice())
+    }
+
+    /// Check whether the key range of the table intersects `[start, end]`
+    pub fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        match (self.smallest_key(), self.largest_key()) {
+            (Some(smallest), Some(largest)) => {
+                self.comparator.compare(smallest, end) != cmp::Ordering::Greater
+                    && self.comparator.compare(largest, start) != cmp::Ordering::Less
+            }
+            _ => false,
+        }
+    }
+
+    /// Get the order of the keys
+    pub const fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
+
+    /// Get the properties stored with the table
+    pub const fn properties(&self) -> &BTreeMap<String, String> {
+        &self.properties
+    }
+
+    /// Marks the table as obsolete so its file is deleted once the last reference is dropped
+    pub(crate) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        if let TableFile::Cached(table_cache) = &self.file {
+            table_cache.evict(self.id);
+        }
+
+        if self.obsolete.load(Ordering::Acquire) {
+            // best effort, a leftover file is ignored on the next open
+            if fs::remove_file(&self.path).is_ok() {
+                let info = TableFileDeletionInfo {
+                    path: self.path.clone(),
+                    file_size: self.file_size,
+                };
+                for listener in &self.listeners {
+                    listener.on_table_file_deleted(&info);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a SSTable {
+    type Item = Result<(Vec<u8>, Value)>;
+    type IntoIter = SSTableIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Sequential iterator over the data blocks of an `SSTable`
+///
+/// Reads the file directly, so scans and compactions don't evict cached blocks.
+pub struct SSTableIter<'a> {
+    /// `None` if the file couldn't be opened, `error` says why
+    reader: Option<EntryReader>,
+    /// Offset of the next entry
+    pos: u64,
+    /// End of the data blocks
+    end: u64,
+    comparator: &'a dyn Comparator,
+    /// Entries with smaller keys are skipped, see `SSTable::iter_from`
+    skip_before: Option<Vec<u8>>,
+    /// Error hit before the first entry, returned on the first call
+    error: Option<Error>,
+}
+
+impl Iterator for SSTableIter<'_> {
+    type Item = Result<(Vec<u8>, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(err) = self.error.take() {
+                self.pos = self.end;
+                return Some(Err(err));
+            }
+
+            if self.pos >= self.end {
+                return None;
+            }
+            let reader = self.reader.as_mut()?;
+
+            match read_entry(reader) {
+                Ok((key, value)) => {
+                    self.pos += encoded_entry_len(&key, &value);
+
+                    let comparator = self.comparator;
+                    if self
+                        .skip_before
+                        .as_ref()
+                        .is_some_and(|start| comparator.compare(&key, start) == cmp::Ordering::Less)
+                    {
+                        continue;
+                    }
+                    self.skip_before = None;
+
+                    return Some(Ok((key, value)));
+                }
+                Err(err) => {
+                    // stop after the first error
+                    self.pos = self.end;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Where an `SSTable` gets its file handle from
+enum TableFile {
+    /// Held open for the lifetime of the table
+    Pinned(Arc<File>),
+    /// Opened on demand through a cache bounding the number of open files
+    Cached(Arc<TableCache>),
+    /// Mapped into memory, reads need no syscalls
+    Mapped(Arc<Mmap>),
+}
+
+/// Bytes of a block, read into memory or borrowed from a mapped file
+#[derive(Clone)]
+pub enum Block {
+    Owned(Arc<Vec<u8>>),
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+impl Block {
+    /// Borrows `len` bytes at `offset` of a mapping
+    fn mapped(map: &Arc<Mmap>, offset: u64, len: u64) -> Result<Self> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        let end = start.saturating_add(usize::try_from(len).unwrap_or(usize::MAX));
+        if end > map.len() {
+            return Err(Error::Corruption(format!(
+                "Block at {offset} of {len} bytes exceeds the file"
+            )));
+        }
+        Ok(Self::Mapped(Arc::clone(map), start..end))
+    }
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(bytes) => bytes,
+            Self::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+impl AsRef<[u8]> for Block {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Source of the entries read by an `SSTableIter`
+enum EntryReader {
+    File(BufReader<FileReader>),
+    Mapped(io::Cursor<Block>),
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::File(reader) => reader.read(buf),
+            Self::Mapped(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+/// `Read` adapter issuing positional reads, so a shared `File` is never seeked
+struct FileReader<F = Arc<File>> {
+    file: F,
+    pos: u64,
+}
+
+impl<F: Borrow<File>> FileReader<F> {
+    const fn new(file: F, pos: u64) -> Self {
+        Self { file, pos }
+    }
+}
+
+impl<F: Borrow<File>> Read for FileReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_at(self.file.borrow(), buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Reads `len` bytes at `offset`
+fn read_exact_at(file: &File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    FileReader::new(file, offset).read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Overwrites the sequence number of the newest write recorded in the footer of
+/// a table file
+///
+/// The field sits at the same place in every format version.
+pub fn write_largest_sequence(path: &Path, sequence: u64) -> Result<()> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let footer = Footer::read(&file)?;
+
+    file.seek(SeekFrom::Start(footer.file_size - 8))?;
+    file.write_all(&sequence.to_le_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Outcome of checking a table file end to end, see `verify_table`
+pub struct TableCheck {
+    /// Entries found in the data blocks
+    pub entries: u64,
+    /// Damage found, empty if the table is intact
+    pub problems: Vec<String>,
+}
+
+/// Reads a table file end to end and checks its structure
+///
+/// Checks the footer and properties, the checksum of every data block, that
+/// the index blocks tile the data blocks and name the key each block ends with,
+/// that every entry decodes, that the keys are sorted by `comparator` and found
+/// by the bloom filter, and that the number of entries matches the footer.
+/// Blocks of "SSTABLE3" and older files have no checksums, damage leaving their
+/// structure intact goes unnoticed.
+pub fn verify_table(path: &Path, comparator: &dyn Comparator) -> TableCheck {
+    let mut check = TableCheck {
+        entries: 0,
+        problems: Vec::new(),
+    };
+    if let Err(err) = verify_file(path, comparator, &mut check) {
+        check.problems.push(err.to_string());
+    }
+    check
+}
+
+/// Checks of `verify_table`, an error ends them early
+fn verify_file(path: &Path, comparator: &dyn Comparator, check: &mut TableCheck) -> Result<()> {
+    let file = File::open(path)?;
+    let footer = Footer::read(&file)?;
+
+    check_comparator(&file, &footer, comparator)?;
+    let index = read_index_blocks(&file, &footer)?;
+    let filter = read_exact_at(
+        &file,
+        footer.filter_offset,
+        footer.index_offset - footer.filter_offset,
+    )?;
+
+    // blocks are read one at a time, the previous key is kept across them
+    let mut block_start = 0;
+    let mut previous: Option<Vec<u8>> = None;
+    let mut out_of_order = false;
+    let mut missing_from_filter = false;
+    for handle in &index {
+        if handle.offset != block_start {
+            return Err(Error::Corruption(format!(
+                "Block at offset {} doesn't follow the previous block ending at {block_start}",
+                handle.offset
+            )));
+        }
+        let block_end = handle
+            .offset
+            .checked_add(handle.len)
+            .filter(|&end| end <= footer.filter_offset)
+            .ok_or_else(|| {
+                Error::Corruption(format!(
+                    "Block at offset {} extends past the data blocks",
+                    handle.offset
+                ))
+            })?;
+        let block = read_exact_at(&file, handle.offset, handle.len)?;
+        let block = block.as_slice();
+        if handle
+            .checksum
+            .is_some_and(|checksum| checksum != crc32::crc32(block))
+        {
+            check.problems.push(format!(
+                "Checksum mismatch in block at offset {}",
+                handle.offset
+            ));
+        }
+
+        let mut pos = 0;
+        let mut last_key = None;
+        while pos < block.len() {
+            let entry_offset = handle.offset + pos as u64;
+            let entry = BlockEntry::decode(block, &mut pos).map_err(|err| {
+                Error::Corruption(format!("Entry at offset {entry_offset}: {err}"))
+            })?;
+            entry.to_value(block)?;
+
+            if !out_of_order
+                && last_key.or(previous.as_deref()).is_some_and(|previous| {
+                    comparator.compare(previous, entry.key) != cmp::Ordering::Less
+                })
+            {
+                out_of_order = true;
+                check
+                    .problems
+                    .push(format!("Key at offset {entry_offset} is out of order"));
+            }
+            if !missing_from_filter && !filter.is_empty() && !bloom::may_contain(&filter, entry.key)
+            {
+                missing_from_filter = true;
+                check.problems.push(format!(
+                    "Key at offset {entry_offset} is missing from the bloom filter"
+                ));
+            }
+
+            check.entries += 1;
+            last_key = Some(entry.key);
+        }
+
+        if last_key != Some(handle.last_key.as_slice()) {
+            check.problems.push(format!(
+                "Key mismatch at indexed offset {}: the index names another key than the block holds",
+                handle.offset
+            ));
+        }
+        if let Some(key) = last_key {
+            previous = Some(key.to_vec());
+        }
+        block_start = block_end;
+    }
+
+    if block_start != footer.filter_offset {
+        check.problems.push(format!(
+            "Index covers {block_start} bytes of data blocks, the footer {}",
+            footer.filter_offset
+        ));
+    }
+    if check.entries != u64::from(footer.num_entries) {
+        check.problems.push(format!(
+            "Table holds {} entries, the footer says {}",
+            check.entries, footer.num_entries
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the properties of a table name `comparator` as its order
+fn check_comparator(file: &File, footer: &Footer, comparator: &dyn Comparator) -> Result<()> {
+    let props_buf = read_exact_at(
+        file,
+        footer.props_offset,
+        footer.props_end - footer.props_offset,
+    )?;
+    let properties = decode_properties(&props_buf)?;
+    let table_comparator = properties
+        .get(COMPARATOR_PROPERTY)
+        .map_or(comparator::BytewiseComparator.name(), String::as_str);
+    if table_comparator != comparator.name() {
+        return Err(Error::InvalidArgument(format!(
+            "Table is sorted by comparator {table_comparator:?}, not {:?}",
+            comparator.name()
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the index of a table as stored, without grouping the entries of older
+/// files into blocks
+///
+/// Older files index every entry, which makes each entry a block of its own.
+fn read_index_blocks(file: &File, footer: &Footer) -> Result<Vec<BlockHandle>> {
+    let index_buf = read_exact_at(file, footer.index_offset, footer.index_len)?;
+    if footer.has_block_index() {
+        return decode_index(&index_buf, footer.has_checksums());
+    }
 
-/// `SSTable` reader
-pub struct SSTable {
-    /// File path
-    path: PathBuf,
-    /// File handle
-    file: File,
-    /// In-memory index: key → offset in data block
-    index: BTreeMap<Vec<u8>, u64>,
-    /// Number of entries in the `SSTable`
-    num_entries: u32,
+    let entries = decode_entry_index(&index_buf)?;
+    let ends = entries.iter().skip(1).map(|(_, offset)| *offset);
+    Ok(entries
+        .iter()
+        .zip(ends.chain(std::iter::once(footer.filter_offset)))
+        .map(|((key, offset), end)| BlockHandle {
+            last_key: key.clone(),
+            offset: *offset,
+            len: end.saturating_sub(*offset),
+            checksum: None,
+        })
+        .collect())
 }
 
-impl SSTable {
-    /// Open an existing `SSTable`
-    pub fn open(path: PathBuf) -> Result<Self> {
-        let mut file = File::open(&path)?;
+/// Entries read out of a damaged table, see `salvage`
+pub struct Salvaged {
+    /// Readable entries in key order
+    pub entries: Vec<(Vec<u8>, Value)>,
+    /// Number of entries recorded in the footer, `None` if the footer is damaged
+    pub num_entries: Option<u32>,
+    /// Sequence number of the newest write, 0 if the footer is damaged
+    pub largest_sequence: u64,
+}
 
-        // read footer
-        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+/// Reads the data blocks of a damaged table front to back
+///
+/// Stops at the end of the data blocks, at the first entry which can't be
+/// decoded, or at the first key out of order. Without a valid footer the end of
+/// the data blocks is unknown, the blocks following them are told apart by
+/// their keys not continuing the order.
+pub fn salvage(path: &Path, comparator: &dyn Comparator) -> Result<Salvaged> {
+    let mut data = fs::read(path)?;
+    let footer = Footer::read(&File::open(path)?).ok();
+    if let Some(footer) = &footer {
+        data.truncate(usize::try_from(footer.filter_offset).unwrap_or(usize::MAX));
+    }
 
-        let mut footer_buf = [0u8; FOOTER_SIZE as usize];
-        file.read_exact(&mut footer_buf)?;
+    let mut entries: Vec<(Vec<u8>, Value)> = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let Ok(entry) = BlockEntry::decode(&data, &mut pos) else {
+            break;
+        };
+        if let Some((previous, _)) = entries.last() {
+            if comparator.compare(previous, entry.key) != cmp::Ordering::Less {
+                break;
+            }
+        }
+        let Ok(value) = entry.to_value(&data) else {
+            break;
+        };
+        entries.push((entry.key.to_vec(), value));
+    }
 
-        // parse footer
-        let index_offset = u64::from_le_bytes(footer_buf[0..8].try_into().unwrap());
-        let index_len = u32::from_le_bytes(footer_buf[8..12].try_into().unwrap());
-        let num_entries = u32::from_le_bytes(footer_buf[12..16].try_into().unwrap());
-        let magic = u64::from_le_bytes(footer_buf[16..24].try_into().unwrap());
+    Ok(Salvaged {
+        entries,
+        num_entries: footer.as_ref().map(|footer| footer.num_entries),
+        largest_sequence: footer.map_or(0, |footer| footer.largest_sequence),
+    })
+}
 
-        // validate magic number
-        if magic != MAGIC_NUMBER {
-            return Err(Error::Corruption(format!(
-                "Invalid magic number: expected 0x{MAGIC_NUMBER:x}, got 0x{magic:x}"
-            )));
+/// Writes a data block entry, returns the number of bytes written
+///
+/// Also used for the records of the write-ahead log.
+pub fn write_entry<W: Write>(writer: &mut W, key: &[u8], value: &Value) -> Result<u64> {
+    let mut written = 0;
+
+    // write key
+    let key_len = key.len() as u32;
+    writer.write_all(&key_len.to_le_bytes())?;
+    written += 4;
+
+    writer.write_all(key)?;
+    written += key.len() as u64;
+
+    // write value
+    match value {
+        // write actual value
+        Value::Some(val) => {
+            let value_len = val.len() as u32;
+            writer.write_all(&value_len.to_le_bytes())?;
+            written += 4;
+
+            writer.write_all(val)?;
+            written += val.len() as u64;
+
+            // tombstone flag (0 = not a tombstone)
+            writer.write_all(&[0u8])?;
+            written += 1;
+        }
+        // write tombstone
+        Value::Tombstone => {
+            // valu e length is 0 for tombstones
+            writer.write_all(&0u32.to_le_bytes())?;
+            written += 4;
+
+            // tombstone flag (1 = tombstone)
+            writer.write_all(&[1u8])?;
+            written += 1;
         }
+        // write value followed by its expiration time
+        Value::Expiring { value, expires_at } => {
+            let value_len = value.len() as u32;
+            writer.write_all(&value_len.to_le_bytes())?;
+            written += 4;
 
-        // read index block
-        file.seek(SeekFrom::Start(index_offset))?;
-        let mut index_buf = vec![0u8; index_len as usize];
-        file.read_exact(&mut index_buf)?;
+            writer.write_all(value)?;
+            written += value.len() as u64;
 
-        // pars eindex
-        let mut index = BTreeMap::new();
-        let mut pos = 0;
+            // tombstone flag (2 = expiring value)
+            writer.write_all(&[2u8])?;
+            written += 1;
 
-        while pos < index_len as usize {
-            let key_len = u32::from_le_bytes(index_buf[pos..pos + 4].try_into().unwrap()) as usize;
-            pos += 4;
+            writer.write_all(&expires_at.to_le_bytes())?;
+            written += 8;
+        }
+        // write encoded operand list
+        Value::Merge(operands) => {
+            let encoded = encode_operands(operands);
+            let value_len = encoded.len() as u32;
+            writer.write_all(&value_len.to_le_bytes())?;
+            written += 4;
+
+            writer.write_all(&encoded)?;
+            written += encoded.len() as u64;
+
+            // tombstone flag (3 = merge operands)
+            writer.write_all(&[3u8])?;
+            written += 1;
+        }
+    }
 
-            let key = index_buf[pos..pos + key_len].to_vec();
-            pos += key_len;
+    Ok(written)
+}
 
-            let offset = u64::from_le_bytes(index_buf[pos..pos + 8].try_into().unwrap());
-            pos += 8;
+/// Tombstone flag of entries holding merge operands
+const FLAG_MERGE: u8 = 3;
+
+/// Data block entry decoded in place
+struct BlockEntry<'a> {
+    key: &'a [u8],
+    /// Range of the value bytes in the block, the encoded operands of merge entries
+    value: Range<usize>,
+    /// Tombstone flag, see `write_entry`
+    flag: u8,
+    /// Expiration time of expiring values, 0 otherwise
+    expires_at: u64,
+}
 
-            index.insert(key, offset);
-        }
+impl<'a> BlockEntry<'a> {
+    /// Decodes the entry at `pos` of a block, advancing `pos` past it
+    fn decode(block: &'a [u8], pos: &mut usize) -> Result<Self> {
+        let mut buf = &block[*pos..];
+        let key = take_bytes(&mut buf)?;
+        let value_len = u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap()) as usize;
+        let value_start = block.len() - buf.len();
+        take(&mut buf, value_len)?;
+
+        let flag = take(&mut buf, 1)?[0];
+        let expires_at = match flag {
+            0 | 1 | FLAG_MERGE => 0,
+            2 => u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap()),
+            flag => return Err(Error::Corruption(format!("Invalid tombstone flag: {flag}"))),
+        };
 
+        *pos = block.len() - buf.len();
         Ok(Self {
-            path,
-            file,
-            index,
-            num_entries,
+            key,
+            value: value_start..value_start + value_len,
+            flag,
+            expires_at,
         })
     }
 
-    /// Get a value by key
-    pub fn get(&mut self, key: &[u8]) -> Result<Option<Value>> {
-        // binary search the index
-        let offset = match self.index.get(key) {
-            Some(offset) => *offset,
-            None => return Ok(None),
-        };
-
-        // seek to the data block entry
-        self.file.seek(SeekFrom::Start(offset))?;
-
-        // read key_len
-        let mut key_len_buf = [0u8; 4];
-        self.file.read_exact(&mut key_len_buf)?;
-        let key_len = u32::from_le_bytes(key_len_buf) as usize;
+    /// Check whether the entry holds a value which hasn't expired at `now`
+    const fn is_live(&self, now: u64) -> bool {
+        match self.flag {
+            0 => true,
+            2 => self.expires_at > now,
+            _ => false,
+        }
+    }
 
-        // read key
-        let mut key_buf = vec![0u8; key_len];
-        self.file.read_exact(&mut key_buf)?;
+    /// Copies the entry out of its block
+    fn to_value(&self, block: &[u8]) -> Result<Value> {
+        let value = &block[self.value.clone()];
+        Ok(match self.flag {
+            0 => Value::Some(value.to_vec()),
+            2 => Value::Expiring {
+                value: value.to_vec(),
+                expires_at: self.expires_at,
+            },
+            FLAG_MERGE => Value::Merge(decode_operands(value)?),
+            _ => Value::Tombstone,
+        })
+    }
+}
 
-        if key_buf != key {
-            return Err(Error::Corruption(
-                "Key mismatch at indexed offset".to_string(),
-            ));
+/// Decodes a single data block entry
+pub fn read_entry<R: Read>(reader: &mut R) -> Result<(Vec<u8>, Value)> {
+    // read key_len
+    let mut key_len_buf = [0u8; 4];
+    reader.read_exact(&mut key_len_buf)?;
+    let key_len = u32::from_le_bytes(key_len_buf) as usize;
+
+    // read key
+    let mut key_buf = vec![0u8; key_len];
+    reader.read_exact(&mut key_buf)?;
+
+    // read value_len
+    let mut value_len_buf = [0u8; 4];
+    reader.read_exact(&mut value_len_buf)?;
+    let value_len = u32::from_le_bytes(value_len_buf) as usize;
+
+    // read value if not a tombstone
+    let value = if value_len > 0 {
+        let mut value_buf = vec![0u8; value_len];
+        reader.read_exact(&mut value_buf)?;
+        value_buf
+    } else {
+        Vec::new() // TODO: save memory by not instantiating vector if tombstone
+    };
+
+    // read tombstone flag
+    let mut tombstone_buf = [0u8; 1];
+    reader.read_exact(&mut tombstone_buf)?;
+
+    match tombstone_buf[0] {
+        0 => Ok((key_buf, Value::Some(value))),
+        1 => Ok((key_buf, Value::Tombstone)),
+        2 => {
+            let mut expires_at_buf = [0u8; 8];
+            reader.read_exact(&mut expires_at_buf)?;
+            let expires_at = u64::from_le_bytes(expires_at_buf);
+            Ok((key_buf, Value::Expiring { value, expires_at }))
         }
+        3 => Ok((key_buf, Value::Merge(decode_operands(&value)?))),
+        flag => Err(Error::Corruption(format!("Invalid tombstone flag: {flag}"))),
+    }
+}
 
-        // read value_len
-        let mut value_len_buf = [0u8; 4];
-        self.file.read_exact(&mut value_len_buf)?;
-        let value_len = u32::from_le_bytes(value_len_buf) as usize;
+/// Size of an entry in the data block
+fn encoded_entry_len(key: &[u8], value: &Value) -> u64 {
+    let value_len = match value {
+        Value::Some(val) => val.len(),
+        Value::Tombstone => 0,
+        // value + expiration time
+        Value::Expiring { value, .. } => value.len() + 8,
+        Value::Merge(operands) => operands.iter().map(|op| 4 + op.len()).sum(),
+    };
+    (4 + key.len() + 4 + value_len + 1) as u64
+}
 
-        // read value if not a tombstone
-        let value = if value_len > 0 {
-            let mut value_buf = vec![0u8; value_len];
-            self.file.read_exact(&mut value_buf)?;
-            value_buf
+/// Decodes the index block, `checksums` tells whether it holds a checksum per block
+fn decode_index(mut buf: &[u8], checksums: bool) -> Result<Vec<BlockHandle>> {
+    let mut index = Vec::new();
+    while !buf.is_empty() {
+        let last_key = take_bytes(&mut buf)?.to_vec();
+        let offset = u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap());
+        let len = u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap());
+        let checksum = if checksums {
+            Some(u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap()))
         } else {
-            Vec::new() // TODO: save memory by not instantiating vector if tombstone
+            None
         };
+        index.push(BlockHandle {
+            last_key,
+            offset,
+            len: u64::from(len),
+            checksum,
+        });
+    }
+    Ok(index)
+}
 
-        // read tombstone flag
-        let mut tombstone_buf = [0u8; 1];
-        self.file.read_exact(&mut tombstone_buf)?;
-        let is_tombstone = tombstone_buf[0] == 1;
+/// Decodes the index of older files, which holds (key, offset) of every entry
+fn decode_entry_index(mut buf: &[u8]) -> Result<Vec<(Vec<u8>, u64)>> {
+    let mut index = Vec::new();
+    while !buf.is_empty() {
+        let key = take_bytes(&mut buf)?.to_vec();
+        let offset = u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap());
+        index.push((key, offset));
+    }
+    Ok(index)
+}
 
-        if is_tombstone {
-            Ok(Some(Value::Tombstone))
-        } else {
-            Ok(Some(Value::Some(value)))
+/// Groups consecutive entries into blocks of about `block_size` bytes
+fn group_into_blocks(
+    entries: Vec<(Vec<u8>, u64)>,
+    data_end: u64,
+    block_size: usize,
+) -> Vec<BlockHandle> {
+    let mut blocks = Vec::new();
+    let mut block_start = 0;
+    let mut entries = entries.into_iter().peekable();
+
+    while let Some((key, _)) = entries.next() {
+        let next_offset = entries.peek().map_or(data_end, |(_, offset)| *offset);
+        if next_offset - block_start >= block_size as u64 || next_offset == data_end {
+            blocks.push(BlockHandle {
+                last_key: key,
+                offset: block_start,
+                len: next_offset - block_start,
+                checksum: None,
+            });
+            block_start = next_offset;
         }
     }
 
-    /// Get the number of entries in the `SSTable`
-    pub const fn num_entries(&self) -> u32 {
-        self.num_entries
+    blocks
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    let bytes = buf
+        .get(..len)
+        .ok_or_else(|| Error::Corruption("Truncated block".to_string()))?;
+    *buf = &buf[len..];
+    Ok(bytes)
+}
+
+/// Takes a length-prefixed byte string
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = u32::from_le_bytes(take(buf, 4)?.try_into().unwrap()) as usize;
+    take(buf, len)
+}
+
+/// Decodes the properties block
+fn decode_properties(mut buf: &[u8]) -> Result<BTreeMap<String, String>> {
+    let mut properties = BTreeMap::new();
+
+    while !buf.is_empty() {
+        let name = take_bytes(&mut buf)?;
+        let value = take_bytes(&mut buf)?;
+        let utf8 = |bytes: &[u8]| {
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| Error::Corruption("Property is not UTF-8".to_string()))
+        };
+        properties.insert(utf8(name)?, utf8(value)?);
     }
 
-    /// Get the file path
-    pub const fn path(&self) -> &PathBuf {
-        &self.path
+    Ok(properties)
+}
+
+/// Encodes merge operands into the value of a data block entry
+fn encode_operands(operands: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for operand in operands {
+        buf.extend_from_slice(&(operand.len() as u32).to_le_bytes());
+        buf.extend_from_slice(operand);
     }
+    buf
+}
+
+/// Decodes the merge operands stored in the value of a data block entry
+fn decode_operands(mut buf: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut operands = Vec::new();
+
+    while !buf.is_empty() {
+        let corrupted = || Error::Corruption("Truncated merge operand".to_string());
+
+        let len_bytes = buf.get(..4).ok_or_else(corrupted)?;
+        let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+        let operand = buf.get(4..4 + len).ok_or_else(corrupted)?;
+
+        operands.push(operand.to_vec());
+        buf = &buf[4 + len..];
+    }
+
+    Ok(operands)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::temp_path;
     use std::fs;
+    use std::io::{Seek, SeekFrom};
 
     /// Creates test path
     fn test_path(name: &str) -> PathBuf {
-        let dir = std::env::temp_dir().join("lsm-tree-kv-test");
-        fs::create_dir_all(&dir).unwrap();
-        dir.join(name)
+        temp_path("sstable", name)
     }
 
     #[test]
@@ -326,7 +1668,7 @@ mod tests {
 
         // Read
         {
-            let mut sst = SSTable::open(path.clone()).unwrap();
+            let sst = SSTable::open(path.clone()).unwrap();
             let value = sst.get(b"key1").unwrap();
             assert_eq!(value, Some(Value::Some(b"value1".to_vec())));
             assert_eq!(sst.num_entries(), 1);
@@ -355,7 +1697,7 @@ mod tests {
 
         // Read
         {
-            let mut sst = SSTable::open(path.clone()).unwrap();
+            let sst = SSTable::open(path.clone()).unwrap();
             assert_eq!(sst.num_entries(), 100);
 
             for i in 0..100 {
@@ -385,7 +1727,7 @@ mod tests {
 
         // Read
         {
-            let mut sst = SSTable::open(path.clone()).unwrap();
+            let sst = SSTable::open(path.clone()).unwrap();
             let value = sst.get(b"nonexistent").unwrap();
             assert_eq!(value, None);
         }
@@ -410,7 +1752,7 @@ mod tests {
 
         // Read
         {
-            let mut sst = SSTable::open(path.clone()).unwrap();
+            let sst = SSTable::open(path.clone()).unwrap();
             let value1 = sst.get(b"key1").unwrap();
             assert_eq!(value1, Some(Value::Tombstone));
 
@@ -440,14 +1782,14 @@ mod tests {
 
         // Read, close, and reopen
         {
-            let mut sst = SSTable::open(path.clone()).unwrap();
+            let sst = SSTable::open(path.clone()).unwrap();
             let value = sst.get(b"key1").unwrap();
             assert_eq!(value, Some(Value::Some(b"value1".to_vec())));
         } // sst is dropped here
 
         // Reopen
         {
-            let mut sst = SSTable::open(path.clone()).unwrap();
+            let sst = SSTable::open(path.clone()).unwrap();
             let value = sst.get(b"key2").unwrap();
             assert_eq!(value, Some(Value::Some(b"value2".to_vec())));
         }
@@ -488,6 +1830,41 @@ mod tests {
         fs::remove_file(&path).unwrap();
     }
 
+    #[test]
+    fn test_overflowing_index_offset() {
+        let path = test_path("overflowing_index.sst");
+        let _ = fs::remove_file(&path);
+
+        let mut builder = SSTableBuilder::new(path.clone()).unwrap();
+        builder
+            .add(b"key1", &Value::Some(b"value1".to_vec()))
+            .unwrap();
+        builder.finish().unwrap();
+
+        // index offset plus length overflows u64
+        {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::End(-32)).unwrap();
+            file.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        }
+
+        assert!(matches!(
+            SSTable::open(path.clone()),
+            Err(Error::Corruption(_))
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_builder_reports_create_error() {
+        let path = test_path("missing_dir").join("table.sst");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        assert!(matches!(SSTableBuilder::new(path), Err(Error::Io(_))));
+    }
+
     #[test]
     fn test_empty_sstable() {
         let path = test_path("empty.sst");
@@ -507,4 +1884,424 @@ mod tests {
 
         fs::remove_file(&path).unwrap();
     }
+
+    #[test]
+    fn test_add_out_of_order() {
+        let path = test_path("out_of_order.sst");
+        let _ = fs::remove_file(&path);
+
+        let mut builder = SSTableBuilder::new(path.clone()).unwrap();
+        builder.add(b"b", &Value::Some(b"1".to_vec())).unwrap();
+        for key in [b"a", b"b"] {
+            match builder.add(key, &Value::Some(b"2".to_vec())) {
+                Err(Error::InvalidArgument(_)) => {}
+                _ => panic!("Expected invalid argument error"),
+            }
+        }
+        builder.add(b"c", &Value::Some(b"3".to_vec())).unwrap();
+        builder.finish().unwrap();
+
+        let sst = SSTable::open(path.clone()).unwrap();
+        assert_eq!(sst.num_entries(), 2);
+        assert_eq!(sst.get(b"c").unwrap(), Some(Value::Some(b"3".to_vec())));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_table() {
+        let path = test_path("verify.sst");
+        let _ = fs::remove_file(&path);
+
+        let options = Options {
+            block_size: 64,
+            bloom_bits_per_key: 10,
+            ..Options::default()
+        };
+        let mut builder = SSTableBuilder::with_options(path.clone(), &options).unwrap();
+        for i in 0..100 {
+            let key = format!("key{i:03}");
+            builder
+                .add(key.as_bytes(), &Value::Some(b"value".to_vec()))
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let check = verify_table(&path, &comparator::BytewiseComparator);
+        assert_eq!(check.entries, 100);
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+
+        // a flipped value byte leaves the structure intact
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4 + 6 + 4] ^= 1;
+        let flipped = test_path("verify_flipped.sst");
+        fs::write(&flipped, bytes).unwrap();
+        let check = verify_table(&flipped, &comparator::BytewiseComparator);
+        assert_eq!(
+            check.problems,
+            vec!["Checksum mismatch in block at offset 0".to_string()]
+        );
+        fs::remove_file(&flipped).unwrap();
+
+        // the first key of the second block sorts before the previous block
+        let file = File::open(&path).unwrap();
+        let index = read_index_blocks(&file, &Footer::read(&file).unwrap()).unwrap();
+        let second = index[1].offset;
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[second as usize + 4] = b'K';
+        let unordered = test_path("verify_unordered.sst");
+        fs::write(&unordered, bytes).unwrap();
+        let check = verify_table(&unordered, &comparator::BytewiseComparator);
+        assert!(
+            check
+                .problems
+                .contains(&format!("Key at offset {second} is out of order")),
+            "{:?}",
+            check.problems
+        );
+        fs::remove_file(&unordered).unwrap();
+
+        // change the first key named by the index and the entry count
+        let file_size = fs::metadata(&path).unwrap().len();
+        let footer = read_exact_at(&File::open(&path).unwrap(), file_size - 32, 16).unwrap();
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(index_offset + 4)).unwrap();
+        file.write_all(b"K").unwrap();
+        file.seek(SeekFrom::Start(file_size - 20)).unwrap();
+        file.write_all(&99u32.to_le_bytes()).unwrap();
+        drop(file);
+
+        let check = verify_table(&path, &comparator::BytewiseComparator);
+        assert_eq!(check.entries, 100);
+        assert_eq!(check.problems.len(), 2, "{:?}", check.problems);
+        assert!(check.problems[0].starts_with("Key mismatch at indexed offset 0"));
+        assert!(check.problems[1].contains("footer says 99"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_salvage_truncated_table() {
+        let path = test_path("salvage.sst");
+        let _ = fs::remove_file(&path);
+
+        let mut builder = SSTableBuilder::new(path.clone()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            builder.add(key, &Value::Some(b"value".to_vec())).unwrap();
+        }
+        builder.set_largest_sequence(9);
+        builder.finish().unwrap();
+
+        let salvaged = salvage(&path, &comparator::BytewiseComparator).unwrap();
+        assert_eq!(salvaged.entries.len(), 3);
+        assert_eq!(salvaged.num_entries, Some(3));
+        assert_eq!(salvaged.largest_sequence, 9);
+
+        // cut off within the second entry, the footer is gone
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(20).unwrap();
+        drop(file);
+
+        assert!(SSTable::open(path.clone()).is_err());
+        let salvaged = salvage(&path, &comparator::BytewiseComparator).unwrap();
+        assert_eq!(
+            salvaged.entries,
+            vec![(b"a".to_vec(), Value::Some(b"value".to_vec()))]
+        );
+        assert_eq!(salvaged.num_entries, None);
+        assert_eq!(salvaged.largest_sequence, 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_expiring_value_persistence() {
+        let path = test_path("expiring.sst");
+        let _ = fs::remove_file(&path);
+
+        let expiring = Value::Expiring {
+            value: b"value1".to_vec(),
+            expires_at: 1_700_000_000_000,
+        };
+
+        // Write
+        {
+            let mut builder = SSTableBuilder::new(path.clone()).unwrap();
+            builder.add(b"key1", &expiring).unwrap();
+            builder
+                .add(b"key2", &Value::Some(b"value2".to_vec()))
+                .unwrap();
+            builder.finish().unwrap();
+        }
+
+        // Read
+        {
+            let sst = SSTable::open(path.clone()).unwrap();
+            assert_eq!(sst.get(b"key1").unwrap(), Some(expiring));
+
+            // sequential reads must skip the expiration time as well
+            let entries: Vec<_> = sst.iter().map(Result::unwrap).collect();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].1, Value::Some(b"value2".to_vec()));
+
+            let from: Vec<_> = sst.iter_from(b"key2").map(Result::unwrap).collect();
+            assert_eq!(from.len(), 1);
+            assert_eq!(from[0].0, b"key2".to_vec());
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_operands_persistence() {
+        let path = test_path("merge_operands.sst");
+        let _ = fs::remove_file(&path);
+
+        let merge = Value::Merge(vec![b"a".to_vec(), Vec::new(), b"ccc".to_vec()]);
+
+        // Write
+        {
+            let mut builder = SSTableBuilder::new(path.clone()).unwrap();
+            builder.add(b"key1", &merge).unwrap();
+            builder.add(b"key2", &Value::Tombstone).unwrap();
+            builder.finish().unwrap();
+        }
+
+        // Read
+        {
+            let sst = SSTable::open(path.clone()).unwrap();
+            assert_eq!(sst.get(b"key1").unwrap(), Some(merge));
+
+            let entries: Vec<_> = sst.iter().map(Result::unwrap).collect();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].1, Value::Tombstone);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_comparator_is_persisted() {
+        let path = test_path("reverse_comparator.sst");
+        let _ = fs::remove_file(&path);
+        let reverse: Arc<dyn Comparator> = Arc::new(comparator::ReverseBytewiseComparator);
+
+        // Write
+        {
+            let options = Options {
+                comparator: Arc::clone(&reverse),
+                ..Options::default()
+            };
+            let mut builder = SSTableBuilder::with_options(path.clone(), &options).unwrap();
+            for key in [b"c", b"b", b"a"] {
+                builder.add(key, &Value::Some(key.to_vec())).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        // Read
+        {
+            let options = Options {
+                comparator: reverse,
+                ..Options::default()
+            };
+            let sst = SSTable::open_with_options(path.clone(), &options).unwrap();
+            assert_eq!(sst.get(b"b").unwrap(), Some(Value::Some(b"b".to_vec())));
+            assert_eq!(sst.smallest_key(), Some(b"c".as_slice()));
+            assert!(sst.overlaps(b"b", b"a"));
+            assert!(!sst.overlaps(b"e", b"d"));
+            assert_eq!(
+                sst.properties()
+                    .get(COMPARATOR_PROPERTY)
+                    .map(String::as_str),
+                Some("lsm-tree-kv.ReverseBytewiseComparator")
+            );
+
+            let from: Vec<_> = sst.iter_from(b"bb").map(|e| e.unwrap().0).collect();
+            assert_eq!(from, vec![b"b".to_vec(), b"a".to_vec()]);
+        }
+
+        // the default comparator would read the table in the wrong order
+        assert!(matches!(
+            SSTable::open(path.clone()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_previous_format() {
+        let path = test_path("previous_format.sst");
+
+        // data block, index block and the 32 byte footer without properties
+        let mut data = Vec::new();
+        let data_len = write_entry(&mut data, b"key1", &Value::Some(b"v".to_vec())).unwrap();
+        data.extend_from_slice(&4u32.to_le_bytes());
+        data.extend_from_slice(b"key1");
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&data_len.to_le_bytes());
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&MAGIC_NUMBER_V1.to_le_bytes());
+        data.extend_from_slice(&7u64.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let sst = SSTable::open(path.clone()).unwrap();
+        assert_eq!(sst.get(b"key1").unwrap(), Some(Value::Some(b"v".to_vec())));
+        assert_eq!(sst.largest_sequence(), 7);
+        assert!(sst.properties().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_format_without_checksums() {
+        let path = test_path("without_checksums.sst");
+        let _ = fs::remove_file(&path);
+
+        let mut builder = SSTableBuilder::new(path.clone()).unwrap();
+        builder.add(b"key1", &Value::Some(b"v".to_vec())).unwrap();
+        builder.finish().unwrap();
+
+        // drop the checksum of the only index entry and rewrite the footer as "SSTABLE3"
+        let mut bytes = fs::read(&path).unwrap();
+        let footer_start = bytes.len() - FOOTER_SIZE as usize;
+        let field =
+            |bytes: &[u8], at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let props_offset = field(&bytes, footer_start + 8);
+        let index_len = u32::from_le_bytes(
+            bytes[footer_start + 24..footer_start + 28]
+                .try_into()
+                .unwrap(),
+        );
+        let checksum_at = props_offset as usize - 4;
+        bytes.drain(checksum_at..checksum_at + 4);
+        let footer_start = footer_start - 4;
+        bytes[footer_start + 8..footer_start + 16]
+            .copy_from_slice(&(props_offset - 4).to_le_bytes());
+        bytes[footer_start + 24..footer_start + 28].copy_from_slice(&(index_len - 4).to_le_bytes());
+        bytes[footer_start + 32..footer_start + 40].copy_from_slice(&MAGIC_NUMBER_V3.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let sst = SSTable::open(path.clone()).unwrap();
+        assert_eq!(sst.get(b"key1").unwrap(), Some(Value::Some(b"v".to_vec())));
+        let check = verify_table(&path, &comparator::BytewiseComparator);
+        assert_eq!(check.entries, 1);
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_blocks_through_cache() {
+        let path = test_path("block_cache.sst");
+        let _ = fs::remove_file(&path);
+
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = Options {
+            block_size: 64,
+            bloom_bits_per_key: 10,
+            block_cache: Some(Arc::clone(&cache)),
+            pin_index_and_filter_blocks: false,
+            ..Options::default()
+        };
+
+        // Write
+        {
+            let mut builder = SSTableBuilder::with_options(path.clone(), &options).unwrap();
+            for i in 0..100 {
+                let key = format!("key{:03}", i * 2);
+                builder
+                    .add(key.as_bytes(), &Value::Some(key.as_bytes().to_vec()))
+                    .unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        // Read
+        {
+            let sst = SSTable::open_with_options(path.clone(), &options).unwrap();
+            assert!(sst.index.is_none());
+            assert_eq!(sst.smallest_key(), Some(b"key000".as_slice()));
+            assert_eq!(sst.largest_key(), Some(b"key198".as_slice()));
+
+            for i in 0..100 {
+                let key = format!("key{:03}", i * 2);
+                let value = sst.get(key.as_bytes()).unwrap();
+                assert_eq!(value, Some(Value::Some(key.into_bytes())));
+            }
+            assert_eq!(sst.get(b"key001").unwrap(), None);
+            assert_eq!(sst.get(b"key999").unwrap(), None);
+
+            // filter, index and data blocks are read once each
+            let misses = cache.misses();
+            assert!(cache.hits() > misses);
+            sst.get(b"key100").unwrap();
+            assert_eq!(cache.misses(), misses);
+
+            // starts in the middle of a block
+            let from: Vec<_> = sst.iter_from(b"key151").map(|e| e.unwrap().0).collect();
+            assert_eq!(from.len(), 24);
+            assert_eq!(from[0], b"key152".to_vec());
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_reads() {
+        let path = test_path("mmap_reads.sst");
+        let _ = fs::remove_file(&path);
+
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = Options {
+            block_size: 64,
+            block_cache: Some(Arc::clone(&cache)),
+            pin_index_and_filter_blocks: false,
+            allow_mmap_reads: true,
+            ..Options::default()
+        };
+
+        // Write
+        {
+            let mut builder = SSTableBuilder::with_options(path.clone(), &options).unwrap();
+            for i in 0..100 {
+                let key = format!("key{:03}", i * 2);
+                builder
+                    .add(key.as_bytes(), &Value::Some(key.as_bytes().to_vec()))
+                    .unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        // Read
+        {
+            let sst = SSTable::open_with_options(path.clone(), &options).unwrap();
+            assert_eq!(
+                sst.is_mapped(),
+                cfg!(all(unix, target_pointer_width = "64"))
+            );
+
+            for i in 0..100 {
+                let key = format!("key{:03}", i * 2);
+                let value = sst.get(key.as_bytes()).unwrap();
+                assert_eq!(value, Some(Value::Some(key.into_bytes())));
+            }
+            assert_eq!(sst.get(b"key001").unwrap(), None);
+
+            let from: Vec<_> = sst.iter_from(b"key151").map(|e| e.unwrap().0).collect();
+            assert_eq!(from.len(), 24);
+            assert_eq!(from[0], b"key152".to_vec());
+            assert_eq!(sst.iter().count(), 100);
+
+            // mapped blocks bypass the block cache
+            if sst.is_mapped() {
+                assert_eq!(cache.hits() + cache.misses(), 0);
+            }
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,439 @@
+//! Counters and latency histograms of a tree
+//!
+//! A `Statistics` object is shared through `Options::statistics` and updated
+//! with relaxed atomics, so recording never blocks readers or writers. It can be
+//! read as structured values, as the text of `LSMTree::property("stats")` or in
+//! the Prometheus text exposition format.
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Number of levels with their own probe counter, deeper levels count at the last
+pub const MAX_TRACKED_LEVELS: usize = 16;
+
+/// Number of histogram buckets, bucket `i > 0` holds values in `[2^(i-1), 2^i)`
+const BUCKETS: usize = 65;
+
+/// Event counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ticker {
+    /// Point lookups answered by a memtable
+    MemtableHit,
+    /// Point lookups which had to check the tables
+    MemtableMiss,
+    /// Table lookups skipped because the bloom filter ruled the key out
+    BloomFilterUseful,
+    /// Table lookups the bloom filter let through for keys the table doesn't hold
+    BloomFilterFalsePositive,
+    /// Keys looked up by point lookups
+    KeysRead,
+    /// Bytes of the values returned by point lookups
+    BytesRead,
+    /// Keys written, deletions and merge operands included
+    KeysWritten,
+    /// Bytes of the keys and values written
+    BytesWritten,
+    /// Memtables flushed to L0
+    FlushCount,
+    /// Bytes of the tables written by flushes
+    FlushBytes,
+    /// Compactions run
+    CompactionCount,
+    /// Bytes of the tables read by compactions
+    CompactionBytesRead,
+    /// Bytes of the tables written by compactions
+    CompactionBytesWritten,
+    /// Microseconds writes spent delayed or stopped
+    StallMicros,
+    /// Records appended to the write-ahead log, handed to the OS
+    WalWrites,
+    /// Bytes appended to the write-ahead log
+    WalBytes,
+    /// Syncs of the write-ahead log, see `Options::sync_writes`
+    WalSyncs,
+}
+
+impl Ticker {
+    /// All tickers, in the order they are reported
+    pub const ALL: [Self; 17] = [
+        Self::MemtableHit,
+        Self::MemtableMiss,
+        Self::BloomFilterUseful,
+        Self::BloomFilterFalsePositive,
+        Self::KeysRead,
+        Self::BytesRead,
+        Self::KeysWritten,
+        Self::BytesWritten,
+        Self::FlushCount,
+        Self::FlushBytes,
+        Self::CompactionCount,
+        Self::CompactionBytesRead,
+        Self::CompactionBytesWritten,
+        Self::StallMicros,
+        Self::WalWrites,
+        Self::WalBytes,
+        Self::WalSyncs,
+    ];
+
+    /// Get the name of the ticker in reports
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::MemtableHit => "memtable_hit",
+            Self::MemtableMiss => "memtable_miss",
+            Self::BloomFilterUseful => "bloom_filter_useful",
+            Self::BloomFilterFalsePositive => "bloom_filter_false_positive",
+            Self::KeysRead => "keys_read",
+            Self::BytesRead => "bytes_read",
+            Self::KeysWritten => "keys_written",
+            Self::BytesWritten => "bytes_written",
+            Self::FlushCount => "flush_count",
+            Self::FlushBytes => "flush_bytes",
+            Self::CompactionCount => "compaction_count",
+            Self::CompactionBytesRead => "compaction_bytes_read",
+            Self::CompactionBytesWritten => "compaction_bytes_written",
+            Self::StallMicros => "stall_micros",
+            Self::WalWrites => "wal_writes",
+            Self::WalBytes => "wal_bytes",
+            Self::WalSyncs => "wal_syncs",
+        }
+    }
+}
+
+/// Latency distributions, all in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Histogram {
+    /// Single key lookups
+    GetMicros,
+    /// Batched lookups
+    MultiGetMicros,
+    /// Single key puts
+    PutMicros,
+    /// Single key deletions
+    DeleteMicros,
+    /// Write batches and merges
+    WriteMicros,
+    /// Flushes of a memtable
+    FlushMicros,
+    /// Compactions
+    CompactionMicros,
+}
+
+impl Histogram {
+    /// All histograms, in the order they are reported
+    pub const ALL: [Self; 7] = [
+        Self::GetMicros,
+        Self::MultiGetMicros,
+        Self::PutMicros,
+        Self::DeleteMicros,
+        Self::WriteMicros,
+        Self::FlushMicros,
+        Self::CompactionMicros,
+    ];
+
+    /// Get the name of the histogram in reports
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::GetMicros => "get_micros",
+            Self::MultiGetMicros => "multi_get_micros",
+            Self::PutMicros => "put_micros",
+            Self::DeleteMicros => "delete_micros",
+            Self::WriteMicros => "write_micros",
+            Self::FlushMicros => "flush_micros",
+            Self::CompactionMicros => "compaction_micros",
+        }
+    }
+}
+
+/// Summary of a histogram
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HistogramData {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    pub average: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+/// Lock-free histogram over exponentially growing buckets
+struct HistogramImpl {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl HistogramImpl {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn data(&self) -> HistogramData {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return HistogramData::default();
+        }
+
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let sum = self.sum.load(Ordering::Relaxed);
+        let min = self.min.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+
+        // interpolates within the bucket holding the percentile
+        let percentile = |p: f64| {
+            let target = p / 100.0 * count as f64;
+            let mut seen = 0.0;
+            for (i, &n) in buckets.iter().enumerate() {
+                if n == 0 {
+                    continue;
+                }
+                if seen + n as f64 >= target {
+                    let low = if i == 0 {
+                        0.0
+                    } else {
+                        (1u128 << (i - 1)) as f64
+                    };
+                    let high = (1u128 << i) as f64;
+                    let value = low + (high - low) * (target - seen) / n as f64;
+                    return value.clamp(min as f64, max as f64);
+                }
+                seen += n as f64;
+            }
+            max as f64
+        };
+
+        HistogramData {
+            count,
+            sum,
+            min,
+            max,
+            average: sum as f64 / count as f64,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+        }
+    }
+}
+
+/// Counters and histograms of one or more trees
+pub struct Statistics {
+    tickers: [AtomicU64; Ticker::ALL.len()],
+    histograms: [HistogramImpl; Histogram::ALL.len()],
+    /// Table lookups per level
+    level_probes: [AtomicU64; MAX_TRACKED_LEVELS],
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            tickers: std::array::from_fn(|_| AtomicU64::new(0)),
+            histograms: std::array::from_fn(|_| HistogramImpl::new()),
+            level_probes: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Get the value of a counter
+    pub fn ticker(&self, ticker: Ticker) -> u64 {
+        self.tickers[ticker as usize].load(Ordering::Relaxed)
+    }
+
+    /// Get a summary of a histogram
+    pub fn histogram(&self, histogram: Histogram) -> HistogramData {
+        self.histograms[histogram as usize].data()
+    }
+
+    /// Get the number of table lookups at a level
+    pub fn level_probes(&self, level: usize) -> u64 {
+        self.level_probes[level.min(MAX_TRACKED_LEVELS - 1)].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, ticker: Ticker, count: u64) {
+        self.tickers[ticker as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe(&self, histogram: Histogram, value: u64) {
+        self.histograms[histogram as usize].record(value);
+    }
+
+    pub(crate) fn record_probes(&self, level: usize, count: u64) {
+        self.level_probes[level.min(MAX_TRACKED_LEVELS - 1)].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Levels up to the deepest one probed so far
+    fn probed_levels(&self) -> usize {
+        (0..MAX_TRACKED_LEVELS)
+            .rev()
+            .find(|&level| self.level_probes(level) > 0)
+            .map_or(0, |level| level + 1)
+    }
+
+    /// Formats the statistics in the Prometheus text exposition format
+    ///
+    /// Every metric name starts with `prefix`, followed by an underscore.
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut out = String::new();
+        for ticker in Ticker::ALL {
+            let name = format!("{prefix}_{}_total", ticker.name());
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", self.ticker(ticker));
+        }
+
+        let name = format!("{prefix}_level_probes_total");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for level in 0..self.probed_levels() {
+            let probes = self.level_probes(level);
+            let _ = writeln!(out, "{name}{{level=\"{level}\"}} {probes}");
+        }
+
+        for histogram in Histogram::ALL {
+            let name = format!("{prefix}_{}", histogram.name());
+            let data = self.histogram(histogram);
+            let _ = writeln!(out, "# TYPE {name} summary");
+            for (quantile, value) in [("0.5", data.p50), ("0.95", data.p95), ("0.99", data.p99)] {
+                let _ = writeln!(out, "{name}{{quantile=\"{quantile}\"}} {value}");
+            }
+            let _ = writeln!(out, "{name}_sum {}", data.sum);
+            let _ = writeln!(out, "{name}_count {}", data.count);
+        }
+        out
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One line per counter and histogram, the text of `LSMTree::property("stats")`
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ticker in Ticker::ALL {
+            writeln!(f, "{} COUNT : {}", ticker.name(), self.ticker(ticker))?;
+        }
+        for level in 0..self.probed_levels() {
+            writeln!(
+                f,
+                "level{level}_probes COUNT : {}",
+                self.level_probes(level)
+            )?;
+        }
+        for histogram in Histogram::ALL {
+            let data = self.histogram(histogram);
+            writeln!(
+                f,
+                "{} P50 : {:.1} P95 : {:.1} P99 : {:.1} MAX : {} COUNT : {} SUM : {}",
+                histogram.name(),
+                data.p50,
+                data.p95,
+                data.p99,
+                data.max,
+                data.count,
+                data.sum
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Statistics").finish_non_exhaustive()
+    }
+}
+
+/// Records the time until it is dropped into a histogram
+pub struct StopWatch<'a> {
+    statistics: Option<&'a Statistics>,
+    histogram: Histogram,
+    start: Instant,
+}
+
+impl<'a> StopWatch<'a> {
+    /// Starts timing, nothing is recorded without statistics
+    pub fn start(statistics: Option<&'a Statistics>, histogram: Histogram) -> Self {
+        Self {
+            statistics,
+            histogram,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for StopWatch<'_> {
+    fn drop(&mut self) {
+        if let Some(statistics) = self.statistics {
+            let micros = u64::try_from(self.start.elapsed().as_micros()).unwrap_or(u64::MAX);
+            statistics.observe(self.histogram, micros);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let statistics = Statistics::new();
+        for value in 1..=100 {
+            statistics.observe(Histogram::GetMicros, value);
+        }
+
+        let data = statistics.histogram(Histogram::GetMicros);
+        assert_eq!(
+            (data.count, data.sum, data.min, data.max),
+            (100, 5050, 1, 100)
+        );
+        assert!((data.average - 50.5).abs() < f64::EPSILON);
+        // buckets double in size, percentiles are within a bucket of the truth
+        assert!((32.0..=64.0).contains(&data.p50));
+        assert!((64.0..=100.0).contains(&data.p99));
+
+        assert_eq!(
+            statistics.histogram(Histogram::PutMicros),
+            HistogramData::default()
+        );
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let statistics = Statistics::new();
+        statistics.record(Ticker::BytesRead, 42);
+        statistics.record_probes(1, 3);
+        statistics.observe(Histogram::FlushMicros, 10);
+
+        let text = statistics.to_prometheus("lsm");
+        assert!(text.contains("# TYPE lsm_bytes_read_total counter\nlsm_bytes_read_total 42\n"));
+        assert!(text.contains("lsm_level_probes_total{level=\"0\"} 0\n"));
+        assert!(text.contains("lsm_level_probes_total{level=\"1\"} 3\n"));
+        assert!(text.contains("# TYPE lsm_flush_micros summary\n"));
+        assert!(text.contains("lsm_flush_micros_count 1\n"));
+
+        assert!(statistics.to_string().contains("bytes_read COUNT : 42\n"));
+    }
+}
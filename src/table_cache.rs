@@ -0,0 +1,108 @@
+//! Cache of open `SSTable` files
+//!
+//! Tables keep their metadata (key range, file size, pinned index and filter) in
+//! memory, but only hold a file handle while it is in this cache. Handles are
+//! opened lazily on the first read and the least recently used ones are closed
+//! once more than `Options::max_open_files` are open.
+
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::Result;
+use crate::lru::LruCache;
+
+/// LRU cache of file handles, keyed by table id
+pub struct TableCache {
+    /// Open files, each charged 1 against `max_open_files`
+    files: Mutex<LruCache<u64, Arc<File>>>,
+}
+
+impl TableCache {
+    /// Creates a cache keeping at most `max_open_files` files open
+    pub fn new(max_open_files: usize) -> Self {
+        Self {
+            files: Mutex::new(LruCache::new(max_open_files)),
+        }
+    }
+
+    /// Get the open file of a table, opening `path` if it isn't cached
+    ///
+    /// Readers holding a returned handle keep it open after eviction.
+    pub fn file(&self, table_id: u64, path: &Path) -> Result<Arc<File>> {
+        let cached = self.files.lock().unwrap().get(&table_id);
+        if let Some(file) = cached {
+            return Ok(file);
+        }
+
+        // open outside the lock, a concurrent open of the same table is dropped
+        let file = Arc::new(File::open(path)?);
+        self.insert(table_id, Arc::clone(&file));
+        Ok(file)
+    }
+
+    /// Adds the open file of a table
+    pub fn insert(&self, table_id: u64, file: Arc<File>) {
+        self.files.lock().unwrap().insert(table_id, file, 1);
+    }
+
+    /// Closes the file of a table, if open
+    pub fn evict(&self, table_id: u64) {
+        self.files.lock().unwrap().remove(&table_id);
+    }
+
+    /// Get the number of files currently held open
+    pub fn open_files(&self) -> usize {
+        self.files.lock().unwrap().usage()
+    }
+}
+
+impl fmt::Debug for TableCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TableCache")
+            .field("open_files", &self.open_files())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::test_util::temp_path;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = temp_path("table_cache", name);
+        fs::write(&path, b"table").unwrap();
+        path
+    }
+
+    #[test]
+    fn test_bounds_open_files() {
+        let paths: Vec<PathBuf> = (0..3).map(|i| temp_file(&i.to_string())).collect();
+        let cache = TableCache::new(2);
+
+        for (id, path) in (0..).zip(&paths) {
+            cache.file(id, path).unwrap();
+        }
+        assert_eq!(cache.open_files(), 2);
+
+        // evicted handles are reopened on demand
+        cache.file(0, &paths[0]).unwrap();
+        assert_eq!(cache.open_files(), 2);
+
+        cache.evict(0);
+        assert_eq!(cache.open_files(), 1);
+
+        // a missing file fails without being cached
+        assert!(cache.file(3, Path::new("/nonexistent/table")).is_err());
+        assert_eq!(cache.open_files(), 1);
+
+        for path in paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
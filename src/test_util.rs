@@ -0,0 +1,32 @@
+//! Helpers shared by the tests of all modules
+//!
+//! Tests write below `lsm-tree-kv-test` in the temporary directory of the system,
+//! each module in a directory of its own.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Directory holding the files of all tests
+fn root() -> PathBuf {
+    std::env::temp_dir().join("lsm-tree-kv-test")
+}
+
+/// Creates an empty directory for the test `name` of `module`
+pub fn temp_dir(module: &str, name: &str) -> PathBuf {
+    let dir = root().join(module).join(name);
+
+    // clean up what an earlier run left
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Path of the file `name` in the directory of `module`, which is created
+pub fn temp_path(module: &str, name: &str) -> PathBuf {
+    let dir = root().join(module);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
@@ -0,0 +1,390 @@
+//! Multi-key transactions
+//!
+//! A `Transaction` buffers its writes in memory until `commit` applies them as one
+//! batch. Optimistic transactions remember the sequence number of the last write
+//! when they started and fail on commit if a key they read or wrote was written by
+//! someone else in the meantime. Pessimistic transactions lock every key they
+//! write or read for update instead, other transactions wait for the lock.
+//!
+//! Locks are only taken by transactions, plain writes to the tree bypass them.
+//!
+//! Tables only record the sequence number of their newest write. While
+//! optimistic transactions are open, the keys leaving the memtables of the
+//! default family are remembered with their own sequence numbers in
+//! `FlushedWrites`, so unrelated writes flushed along with them don't conflict.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::Result;
+use crate::comparator::{Comparator, OrderedKey};
+use crate::lsm::LSMTree;
+use crate::memtable::Memtable;
+use crate::sstable::SSTable;
+use crate::write_batch::WriteBatch;
+
+/// Buffered writes on top of an `LSMTree`, see `LSMTree::begin_transaction`
+///
+/// Dropping a transaction without committing discards its writes.
+pub struct Transaction<'a> {
+    tree: &'a LSMTree,
+    /// Sequence number of the last write visible when the transaction started
+    snapshot: u64,
+    /// Latest buffered write per key, `None` for deletions
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Keys read from the tree, checked for conflicts on commit
+    reads: BTreeSet<Vec<u8>>,
+    /// Id in the lock manager, only set for pessimistic transactions
+    lock_id: Option<u64>,
+    /// Keys locked by a pessimistic transaction, released when it ends
+    locked: BTreeSet<Vec<u8>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) const fn new(tree: &'a LSMTree, snapshot: u64, lock_id: Option<u64>) -> Self {
+        Self {
+            tree,
+            snapshot,
+            writes: BTreeMap::new(),
+            reads: BTreeSet::new(),
+            lock_id,
+            locked: BTreeSet::new(),
+        }
+    }
+
+    /// Check whether the transaction locks keys instead of checking for conflicts
+    pub const fn is_pessimistic(&self) -> bool {
+        self.lock_id.is_some()
+    }
+
+    /// Retrieves a value, seeing the transaction's own writes
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+
+        self.reads.insert(key.to_vec());
+        self.tree.get(key)
+    }
+
+    /// Retrieves a value the transaction is going to update
+    ///
+    /// A pessimistic transaction locks the key first, so nobody else can change it
+    /// before the transaction ends. Fails with `Error::TimedOut` if the lock isn't
+    /// available within `Options::lock_timeout` and with `Error::Busy` if waiting
+    /// for it would deadlock.
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lock(key)?;
+        self.get(key)
+    }
+
+    /// Buffers the insertion of a key-value pair, locking the key if pessimistic
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Buffers the deletion of a key, locking the key if pessimistic
+    pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Locks a key for the rest of the transaction, no-op for optimistic ones
+    fn lock(&mut self, key: &[u8]) -> Result<()> {
+        let Some(id) = self.lock_id else {
+            return Ok(());
+        };
+        if self.locked.contains(key) {
+            return Ok(());
+        }
+
+        let timeout = self.tree.options().lock_timeout;
+        self.tree.lock_manager().lock(id, key, timeout)?;
+        self.locked.insert(key.to_vec());
+        Ok(())
+    }
+
+    /// Applies the buffered writes atomically and releases the locks
+    ///
+    /// An optimistic transaction fails with `Error::Conflict` if a key it read or
+    /// wrote was written after it started, nothing is applied then and the caller
+    /// can retry.
+    pub fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::new();
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.put(key.clone(), value.clone()),
+                None => batch.delete(key.clone()),
+            }
+        }
+
+        // the locks already keep other transactions away
+        if self.is_pessimistic() {
+            return self.tree.write(batch);
+        }
+
+        let keys: BTreeSet<&[u8]> = self
+            .reads
+            .iter()
+            .chain(self.writes.keys())
+            .map(Vec::as_slice)
+            .collect();
+
+        self.tree
+            .commit_transaction(batch, self.snapshot, keys.into_iter())
+    }
+
+    /// Discards the buffered writes and releases the locks
+    pub fn rollback(self) {}
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        match self.lock_id {
+            Some(id) => self.tree.lock_manager().unlock(id, &self.locked),
+            None => self.tree.release_snapshot(self.snapshot),
+        }
+    }
+}
+
+/// Writes which reached the tables of the default family after an open
+/// optimistic transaction started, kept until no open transaction is older
+#[derive(Default)]
+pub struct FlushedWrites {
+    /// Snapshots of the open optimistic transactions, with their number
+    snapshots: BTreeMap<u64, usize>,
+    /// Flushed keys with the sequence number of their last write
+    keys: BTreeMap<OrderedKey, u64>,
+    /// Ingested tables with the sequence number they were added at
+    tables: Vec<(Arc<SSTable>, u64)>,
+}
+
+impl FlushedWrites {
+    /// Starts keeping the writes newer than `snapshot`
+    pub fn open_snapshot(&mut self, snapshot: u64) {
+        *self.snapshots.entry(snapshot).or_default() += 1;
+    }
+
+    /// Drops the writes no open transaction can conflict with anymore
+    pub fn release_snapshot(&mut self, snapshot: u64) {
+        if let Some(count) = self.snapshots.get_mut(&snapshot) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&snapshot);
+            }
+        }
+
+        let oldest = self.oldest().unwrap_or(u64::MAX);
+        self.keys.retain(|_, sequence| *sequence > oldest);
+        self.tables.retain(|(_, sequence)| *sequence > oldest);
+    }
+
+    /// Remembers the keys of a flushed memtable written after the oldest snapshot
+    pub fn add_memtable(&mut self, memtable: &Memtable) {
+        let Some(oldest) = self.oldest() else {
+            return;
+        };
+
+        for (key, sequence) in memtable.sequences() {
+            if sequence > oldest {
+                let key = OrderedKey::new(key, Arc::clone(memtable.comparator()));
+                let newest = self.keys.entry(key).or_default();
+                *newest = (*newest).max(sequence);
+            }
+        }
+    }
+
+    /// Remembers an ingested table, all its keys were written at `sequence`
+    pub fn add_table(&mut self, table: Arc<SSTable>, sequence: u64) {
+        if self.oldest().is_some_and(|oldest| sequence > oldest) {
+            self.tables.push((table, sequence));
+        }
+    }
+
+    /// Sequence number of the newest write to a key which left the memtables
+    /// after the oldest open snapshot
+    pub fn key_sequence(
+        &self,
+        key: &[u8],
+        comparator: &Arc<dyn Comparator>,
+    ) -> Result<Option<u64>> {
+        let mut newest = self
+            .keys
+            .get(&OrderedKey::new(key.to_vec(), Arc::clone(comparator)))
+            .copied();
+        for (table, sequence) in &self.tables {
+            if newest < Some(*sequence) && table.contains_key(key)? {
+                newest = Some(*sequence);
+            }
+        }
+        Ok(newest)
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        self.snapshots.keys().next().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn test_commit_and_read_own_writes() {
+        let tree = LSMTree::open(temp_dir("transaction", "commit")).unwrap();
+        tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut txn = tree.begin_transaction();
+        txn.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        txn.delete(b"a".to_vec()).unwrap();
+        assert_eq!(txn.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(txn.get(b"a").unwrap(), None);
+
+        // nothing visible before the commit
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get(b"b").unwrap(), None);
+
+        txn.commit().unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let tree = LSMTree::open(temp_dir("transaction", "rollback")).unwrap();
+
+        let mut txn = tree.begin_transaction();
+        txn.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        txn.rollback();
+
+        assert_eq!(tree.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_conflict_on_read_key() {
+        let tree = LSMTree::open(temp_dir("transaction", "conflict_read")).unwrap();
+        tree.put(b"counter".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut txn = tree.begin_transaction();
+        assert_eq!(txn.get(b"counter").unwrap(), Some(b"1".to_vec()));
+        txn.put(b"other".to_vec(), b"x".to_vec()).unwrap();
+
+        // concurrent write to the key read by the transaction
+        tree.put(b"counter".to_vec(), b"2".to_vec()).unwrap();
+
+        assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+        assert_eq!(tree.get(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn test_conflict_on_written_key_after_flush() {
+        let tree = LSMTree::open(temp_dir("transaction", "conflict_flush")).unwrap();
+
+        let mut txn = tree.begin_transaction();
+        txn.put(b"key".to_vec(), b"txn".to_vec()).unwrap();
+
+        // the concurrent write is only found in an SSTable
+        tree.put(b"key".to_vec(), b"other".to_vec()).unwrap();
+        tree.flush().unwrap();
+
+        assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"other".to_vec()));
+    }
+
+    #[test]
+    fn test_unrelated_write_flushed_with_key_does_not_conflict() {
+        let tree = LSMTree::open(temp_dir("transaction", "no_conflict_flush")).unwrap();
+        tree.put(b"k".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut txn = tree.begin_transaction();
+        assert_eq!(txn.get(b"k").unwrap(), Some(b"1".to_vec()));
+        txn.put(b"k".to_vec(), b"2".to_vec()).unwrap();
+
+        // the table holding `k` also holds a newer, unrelated write
+        tree.put(b"other".to_vec(), b"x".to_vec()).unwrap();
+        tree.flush().unwrap();
+
+        txn.commit().unwrap();
+        assert_eq!(tree.get(b"k").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_unrelated_writes_do_not_conflict() {
+        let path = temp_dir("transaction", "no_conflict");
+        {
+            let tree = LSMTree::open(&path).unwrap();
+            tree.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+            tree.flush().unwrap();
+        }
+
+        // sequence numbers continue after a restart
+        let tree = LSMTree::open(&path).unwrap();
+        let mut txn = tree.begin_transaction();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"1".to_vec()));
+        txn.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+
+        tree.put(b"b".to_vec(), b"x".to_vec()).unwrap();
+
+        txn.commit().unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_pessimistic_counter() {
+        let tree = LSMTree::open(temp_dir("transaction", "pessimistic_counter")).unwrap();
+        tree.put(b"counter".to_vec(), 0u64.to_le_bytes().to_vec())
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        let mut txn = tree.begin_pessimistic_transaction();
+                        let value = txn.get_for_update(b"counter").unwrap().unwrap();
+                        let count = u64::from_le_bytes(value.try_into().unwrap());
+                        txn.put(b"counter".to_vec(), (count + 1).to_le_bytes().to_vec())
+                            .unwrap();
+                        txn.commit().unwrap();
+                    }
+                });
+            }
+        });
+
+        let value = tree.get(b"counter").unwrap().unwrap();
+        assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 100);
+    }
+
+    #[test]
+    fn test_pessimistic_lock_timeout() {
+        let options = crate::Options {
+            lock_timeout: std::time::Duration::from_millis(20),
+            ..crate::Options::default()
+        };
+        let tree =
+            LSMTree::open_with_options(temp_dir("transaction", "lock_timeout"), options).unwrap();
+
+        let mut first = tree.begin_pessimistic_transaction();
+        first.put(b"key".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut second = tree.begin_pessimistic_transaction();
+        assert!(matches!(
+            second.get_for_update(b"key"),
+            Err(Error::TimedOut(_))
+        ));
+
+        // rolling back releases the lock
+        first.rollback();
+        second.put(b"key".to_vec(), b"2".to_vec()).unwrap();
+        second.commit().unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"2".to_vec()));
+    }
+}
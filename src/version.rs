@@ -0,0 +1,151 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::Result;
+use crate::compaction::EntryIter;
+use crate::comparator::Comparator;
+use crate::manifest::{self, FileEntry};
+use crate::memtable::Value;
+use crate::sstable::SSTable;
+use crate::statistics::Statistics;
+
+/// Immutable snapshot of the `SSTables` making up the tree
+///
+/// Every flush or compaction installs a new `Version`, readers hold on to the one
+/// they started with so files are not deleted underneath them.
+#[derive(Clone)]
+pub struct Version {
+    /// `levels[0]` holds overlapping tables ordered newest first,
+    /// deeper levels hold non-overlapping tables sorted by smallest key
+    pub levels: Vec<Vec<Arc<SSTable>>>,
+    /// Order of the keys in all tables
+    pub comparator: Arc<dyn Comparator>,
+}
+
+impl Version {
+    /// Creates an empty version with the given number of levels
+    pub fn new(num_levels: usize, comparator: Arc<dyn Comparator>) -> Self {
+        Self {
+            levels: vec![Vec::new(); num_levels],
+            comparator,
+        }
+    }
+
+    /// Values stored for a key, from the newest to the oldest table
+    ///
+    /// Tables are only read as far as the iterator is advanced, each read counts
+    /// as a probe of its level in `statistics`.
+    pub fn values<'a>(
+        &'a self,
+        key: &'a [u8],
+        statistics: Option<&'a Statistics>,
+    ) -> impl Iterator<Item = Result<Value>> + 'a {
+        self.tables_for(key).filter_map(move |(level, sstable)| {
+            if let Some(statistics) = statistics {
+                statistics.record_probes(level, 1);
+            }
+            sstable.get(key).transpose()
+        })
+    }
+
+    /// (level, table) of the tables which may contain a key, from the newest to
+    /// the oldest
+    pub fn tables_for<'a>(
+        &'a self,
+        key: &'a [u8],
+    ) -> impl Iterator<Item = (usize, &'a Arc<SSTable>)> + 'a {
+        // L0 tables may overlap, check all of them newest first
+        let level0 = self.levels[0].iter().map(|sst| (0, sst));
+
+        // at most one table per deeper level can contain the key
+        let deeper = self
+            .levels
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter_map(move |(n, level)| {
+                let idx = level.partition_point(|sst| {
+                    sst.largest_key()
+                        .is_some_and(|k| self.comparator.compare(k, key) == Ordering::Less)
+                });
+                level.get(idx).map(|sst| (n, sst))
+            });
+
+        level0.chain(deeper)
+    }
+
+    /// Sequence number of the newest write in any table
+    pub fn largest_sequence(&self) -> u64 {
+        self.levels
+            .iter()
+            .flatten()
+            .map(|sst| sst.largest_sequence())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Sorted streams over the entries with keys >= `start`, or over all entries
+    /// without a start, newest first
+    pub fn range_sources<'a>(&'a self, start: Option<&'a [u8]>) -> Vec<EntryIter<'a>> {
+        let iter_from = move |sst: &'a Arc<SSTable>| {
+            start.map_or_else(|| sst.iter(), |start| sst.iter_from(start))
+        };
+
+        let mut sources: Vec<EntryIter<'a>> = self.levels[0]
+            .iter()
+            .map(|sst| Box::new(iter_from(sst)) as EntryIter)
+            .collect();
+
+        // tables of a deeper level form a single sorted run
+        for level in &self.levels[1..] {
+            let tables = level.iter().filter(move |sst| {
+                start.is_none_or(|start| {
+                    sst.largest_key()
+                        .is_some_and(|k| self.comparator.compare(k, start) != Ordering::Less)
+                })
+            });
+            sources.push(Box::new(tables.flat_map(iter_from)));
+        }
+
+        sources
+    }
+
+    /// Total size of all files in a level
+    pub fn level_bytes(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|sst| sst.file_size()).sum()
+    }
+
+    /// Files of a level whose key range intersects `[start, end]`
+    pub fn overlapping_files(&self, level: usize, start: &[u8], end: &[u8]) -> Vec<Arc<SSTable>> {
+        self.levels[level]
+            .iter()
+            .filter(|sst| sst.overlaps(start, end))
+            .cloned()
+            .collect()
+    }
+
+    /// Sorts the files of each level into their canonical order
+    pub fn sort_levels(&mut self) {
+        // newer L0 files have higher numbers
+        self.levels[0].sort_by_key(|sst| std::cmp::Reverse(manifest::sst_number(sst.path())));
+        for level in &mut self.levels[1..] {
+            level.sort_by(|a, b| match (a.smallest_key(), b.smallest_key()) {
+                (Some(a), Some(b)) => self.comparator.compare(a, b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            });
+        }
+    }
+
+    /// Lists the files of the version as manifest records
+    pub fn manifest_entries(&self) -> Vec<FileEntry> {
+        self.levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, files)| {
+                files.iter().filter_map(move |sst| {
+                    manifest::sst_number(sst.path()).map(|number| FileEntry { level, number })
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,241 @@
+//! Write-ahead log shared by all column families
+//!
+//! Every write batch is appended to the current log before it is applied to the
+//! memtables, so writes survive a crash before their memtable is flushed. A new
+//! log is started whenever a memtable is turned immutable, old logs are deleted
+//! once every family has flushed the writes they hold. Records are handed to the
+//! OS right away and synced to disk only with `Options::sync_writes`.
+//!
+//! # File Format
+//!
+//! A log is a sequence of records:
+//!
+//! ```text
+//! payload_len: u32 (4 bytes)
+//! checksum:    u32 (4 bytes)  // CRC-32 of the payload
+//! payload:     [u8; payload_len]
+//! ```
+//!
+//! The payload holds a write batch:
+//!
+//! ```text
+//! record_type: u8 (1 byte)    // 1 = write batch
+//! sequence:    u64 (8 bytes)
+//! num_entries: u32 (4 bytes)
+//! for each entry:
+//!   family_len: u32 (4 bytes)
+//!   family:     [u8; family_len]
+//!   entry       // encoded like an `SSTable` data block entry
+//! ```
+//!
+//! A record with a bad length or checksum ends the log, it is the remainder of a
+//! write interrupted by a crash.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+use crate::crc32::crc32;
+use crate::memtable::Value;
+use crate::sstable;
+
+const BATCH_RECORD: u8 = 1;
+
+/// A decoded write batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub sequence: u64,
+    /// Writes as (family, key, value)
+    pub entries: Vec<(String, Vec<u8>, Value)>,
+}
+
+/// Returns the path of the log with the given number
+pub fn log_path(dir: &Path, number: usize) -> PathBuf {
+    dir.join(format!("{number:08}.log"))
+}
+
+/// Parses the file number out of a log path
+pub fn log_number(path: &Path) -> Option<usize> {
+    if path.extension().is_none_or(|ext| ext != "log") {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Appends records to a log file
+pub struct Wal {
+    writer: BufWriter<File>,
+    /// Number of the log file, see `log_path`
+    number: usize,
+}
+
+impl Wal {
+    /// Creates a new empty log
+    pub fn create(dir: &Path, number: usize) -> Result<Self> {
+        let file = File::create(log_path(dir, number))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            number,
+        })
+    }
+
+    /// Get the number of the log file
+    pub const fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Appends a write batch, entries are (family, key, value)
+    ///
+    /// Returns the number of bytes appended to the log.
+    pub fn add_batch<'a>(
+        &mut self,
+        sequence: u64,
+        entries: impl ExactSizeIterator<Item = (&'a str, &'a [u8], &'a Value)>,
+    ) -> Result<usize> {
+        let mut payload = vec![BATCH_RECORD];
+        payload.extend_from_slice(&sequence.to_le_bytes());
+        payload.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+
+        for (family, key, value) in entries {
+            put_bytes(&mut payload, family.as_bytes());
+            sstable::write_entry(&mut payload, key, value)?;
+        }
+
+        self.add_record(&payload)
+    }
+
+    /// Syncs the records appended so far to disk
+    pub fn sync(&self) -> Result<()> {
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Writes a record and hands it to the OS, the file is not synced, see `sync`
+    ///
+    /// Returns the size of the record with its header.
+    fn add_record(&mut self, payload: &[u8]) -> Result<usize> {
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&crc32(payload).to_le_bytes())?;
+        self.writer.write_all(payload)?;
+        self.writer.flush()?;
+        Ok(8 + payload.len())
+    }
+}
+
+/// Reads all intact records of a log
+pub fn read(path: &Path) -> Result<Vec<Record>> {
+    let data = fs::read(path)?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+
+    while let Some(header) = data.get(pos..pos + 8) {
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        let Some(payload) = data.get(pos + 8..pos + 8 + len) else {
+            break;
+        };
+        if crc32(payload) != checksum {
+            break;
+        }
+
+        match decode_record(payload) {
+            Some(record) => records.push(record),
+            None => break,
+        }
+        pos += 8 + len;
+    }
+
+    Ok(records)
+}
+
+fn decode_record(payload: &[u8]) -> Option<Record> {
+    let (&kind, mut rest) = payload.split_first()?;
+    if kind != BATCH_RECORD {
+        return None;
+    }
+
+    let sequence = u64::from_le_bytes(take(&mut rest, 8)?.try_into().ok()?);
+    let count = u32::from_le_bytes(take(&mut rest, 4)?.try_into().ok()?);
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let family = take_string(&mut rest)?;
+        let (key, value) = sstable::read_entry(&mut rest).ok()?;
+        entries.push((family, key, value));
+    }
+
+    Some(Record { sequence, entries })
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let bytes = buf.get(..len)?;
+    *buf = &buf[len..];
+    Some(bytes)
+}
+
+fn take_string(buf: &mut &[u8]) -> Option<String> {
+    let len = u32::from_le_bytes(take(buf, 4)?.try_into().ok()?) as usize;
+    String::from_utf8(take(buf, len)?.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn test_roundtrip_and_torn_tail() {
+        let dir = temp_dir("wal", "roundtrip");
+        let value = Value::Some(b"v".to_vec());
+        let merge = Value::Merge(vec![b"m".to_vec()]);
+
+        let mut wal = Wal::create(&dir, 7).unwrap();
+        wal.add_batch(
+            1,
+            [
+                ("default", b"a".as_slice(), &value),
+                ("other", b"b".as_slice(), &merge),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+        wal.add_batch(
+            2,
+            std::iter::once(("default", b"c".as_slice(), &Value::Tombstone)),
+        )
+        .unwrap();
+        drop(wal);
+
+        // simulate a crash in the middle of a record
+        let path = log_path(&dir, 7);
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&[100, 0, 0, 0, 1, 2]);
+        fs::write(&path, data).unwrap();
+
+        assert_eq!(log_number(&path), Some(7));
+        assert_eq!(
+            read(&path).unwrap(),
+            vec![
+                Record {
+                    sequence: 1,
+                    entries: vec![
+                        ("default".to_string(), b"a".to_vec(), value),
+                        ("other".to_string(), b"b".to_vec(), merge),
+                    ],
+                },
+                Record {
+                    sequence: 2,
+                    entries: vec![("default".to_string(), b"c".to_vec(), Value::Tombstone)],
+                },
+            ]
+        );
+    }
+}
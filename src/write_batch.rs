@@ -0,0 +1,168 @@
+//! Atomic groups of writes
+//!
+//! All writes of a `WriteBatch` are logged as one record and applied to the
+//! memtables under one sequence number, readers see either none or all of them.
+//! A batch may span several column families.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::column_family::{ColumnFamily, DEFAULT_FAMILY_ID};
+use crate::memtable::{Value, now_millis};
+
+/// Collects puts, deletes and merges to be written together by `LSMTree::write`
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    /// Writes as (family id, key, value) in the order they were added, merges
+    /// hold a single operand
+    entries: Vec<(u32, Vec<u8>, Value)>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds a KV-pair
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.entries
+            .push((DEFAULT_FAMILY_ID, key, Value::Some(value)));
+    }
+
+    /// Adds a KV-pair to a column family
+    pub fn put_cf(&mut self, family: &ColumnFamily, key: Vec<u8>, value: Vec<u8>) {
+        self.entries.push((family.id(), key, Value::Some(value)));
+    }
+
+    /// Adds a KV-pair which reads as deleted once `ttl` has passed
+    ///
+    /// The expiration time is computed when the pair is added to the batch.
+    pub fn put_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        let value = Value::Expiring {
+            value,
+            expires_at: expires_at(ttl),
+        };
+        self.entries.push((DEFAULT_FAMILY_ID, key, value));
+    }
+
+    /// Adds a deletion of a key
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.entries
+            .push((DEFAULT_FAMILY_ID, key, Value::Tombstone));
+    }
+
+    /// Adds a deletion of a key in a column family
+    pub fn delete_cf(&mut self, family: &ColumnFamily, key: Vec<u8>) {
+        self.entries.push((family.id(), key, Value::Tombstone));
+    }
+
+    /// Adds a merge operand for a key
+    pub fn merge(&mut self, key: Vec<u8>, operand: Vec<u8>) {
+        self.entries
+            .push((DEFAULT_FAMILY_ID, key, Value::Merge(vec![operand])));
+    }
+
+    /// Adds a merge operand for a key in a column family
+    pub fn merge_cf(&mut self, family: &ColumnFamily, key: Vec<u8>, operand: Vec<u8>) {
+        self.entries
+            .push((family.id(), key, Value::Merge(vec![operand])));
+    }
+
+    /// Get the number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the batch holds no writes
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all writes from the batch
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Writes as (family id, key, value) in the order they were added
+    pub(crate) fn entries(&self) -> &[(u32, Vec<u8>, Value)] {
+        &self.entries
+    }
+
+    /// Ids of the column families the batch writes to
+    pub(crate) fn families(&self) -> BTreeSet<u32> {
+        self.entries.iter().map(|(family, _, _)| *family).collect()
+    }
+
+    /// Writes to one column family, in the order they were added
+    pub(crate) fn family_entries(&self, family: u32) -> impl Iterator<Item = (&[u8], &Value)> {
+        self.entries
+            .iter()
+            .filter(move |(id, _, _)| *id == family)
+            .map(|(_, key, value)| (key.as_slice(), value))
+    }
+
+    /// Check if the batch holds merge operands for a column family
+    pub(crate) fn has_merges(&self, family: u32) -> bool {
+        self.family_entries(family)
+            .any(|(_, value)| matches!(value, Value::Merge(_)))
+    }
+
+    /// Approximate number of bytes the batch adds to the memtables
+    pub(crate) fn size_bytes(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(_, key, value)| key.len() + value.size_bytes())
+            .sum()
+    }
+
+    /// Turns plain puts to a column family into puts expiring after `ttl`
+    pub(crate) fn expire_puts(&mut self, family: u32, ttl: Duration) {
+        let expires_at = expires_at(ttl);
+        for (_, _, value) in self.entries.iter_mut().filter(|(id, _, _)| *id == family) {
+            if let Value::Some(bytes) = value {
+                *value = Value::Expiring {
+                    value: std::mem::take(bytes),
+                    expires_at,
+                };
+            }
+        }
+    }
+}
+
+/// Expiration time of a value written now with the given TTL
+pub fn expires_at(ttl: Duration) -> u64 {
+    let ttl_millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire_puts() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"a".to_vec(), b"1".to_vec());
+        batch.delete(b"b".to_vec());
+        batch.merge(b"c".to_vec(), b"2".to_vec());
+        let other = ColumnFamily::new(1, "other".to_string());
+        batch.put_cf(&other, b"d".to_vec(), b"3".to_vec());
+        assert_eq!(batch.len(), 4);
+        assert!(batch.has_merges(DEFAULT_FAMILY_ID));
+        assert!(!batch.has_merges(other.id()));
+        assert_eq!(batch.families(), BTreeSet::from([0, 1]));
+
+        batch.expire_puts(DEFAULT_FAMILY_ID, Duration::from_secs(60));
+        let entries = batch.entries();
+        assert!(matches!(entries[0].2, Value::Expiring { .. }));
+        assert_eq!(entries[1].2, Value::Tombstone);
+        assert_eq!(entries[2].2, Value::Merge(vec![b"2".to_vec()]));
+        assert_eq!(entries[3].2, Value::Some(b"3".to_vec()));
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+}
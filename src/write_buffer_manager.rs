@@ -0,0 +1,115 @@
+//! Memory budget for memtables shared across trees
+//!
+//! Every tree opened with the same manager through `Options::write_buffer_manager`
+//! reports the memory allocated by its memtables. Once the active memtables take
+//! most of the budget, or all memtables together exceed it, the next write to a
+//! tree flushes that tree's largest active memtable.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory allocated by the memtables of a tree, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// All memtables, including those waiting for a flush
+    pub total: usize,
+    /// Active memtables, which still take writes
+    pub mutable: usize,
+}
+
+/// Caps the memory of the memtables of all trees sharing it
+#[derive(Debug)]
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    memory_used: AtomicUsize,
+    mutable_used: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    /// Creates a manager allowing `buffer_size` bytes of memtables
+    pub const fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            memory_used: AtomicUsize::new(0),
+            mutable_used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the memory budget in bytes
+    pub const fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Get the bytes allocated by all memtables
+    pub fn memory_usage(&self) -> usize {
+        self.memory_used.load(Ordering::Relaxed)
+    }
+
+    /// Get the bytes allocated by active memtables
+    pub fn mutable_memory_usage(&self) -> usize {
+        self.mutable_used.load(Ordering::Relaxed)
+    }
+
+    /// Check whether a memtable should be flushed to free memory
+    ///
+    /// Memtables already waiting for a flush free their memory soon, so they only
+    /// count once the active memtables hold at least half of the budget.
+    pub fn should_flush(&self) -> bool {
+        let mutable = self.mutable_memory_usage();
+        mutable > self.buffer_size / 8 * 7
+            || (self.memory_usage() >= self.buffer_size && mutable >= self.buffer_size / 2)
+    }
+
+    /// Replaces the usage a tree reported before with its current usage
+    pub(crate) fn update(&self, old: MemoryUsage, new: MemoryUsage) {
+        adjust(&self.memory_used, old.total, new.total);
+        adjust(&self.mutable_used, old.mutable, new.mutable);
+    }
+}
+
+fn adjust(counter: &AtomicUsize, old: usize, new: usize) {
+    if new >= old {
+        counter.fetch_add(new - old, Ordering::Relaxed);
+    } else {
+        counter.fetch_sub(old - new, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_flush() {
+        let manager = WriteBufferManager::new(800);
+        let mut usage = MemoryUsage::default();
+
+        let next = MemoryUsage {
+            total: 600,
+            mutable: 600,
+        };
+        manager.update(usage, next);
+        usage = next;
+        assert!(!manager.should_flush());
+
+        // a flush in progress doesn't trigger another one
+        let next = MemoryUsage {
+            total: 900,
+            mutable: 300,
+        };
+        manager.update(usage, next);
+        usage = next;
+        assert!(!manager.should_flush());
+
+        let next = MemoryUsage {
+            total: 1000,
+            mutable: 400,
+        };
+        manager.update(usage, next);
+        usage = next;
+        assert!(manager.should_flush());
+
+        manager.update(usage, MemoryUsage::default());
+        assert_eq!(manager.memory_usage(), 0);
+        assert_eq!(manager.mutable_memory_usage(), 0);
+    }
+}
@@ -0,0 +1,109 @@
+//! Write backpressure
+//!
+//! Writes are throttled once flushes or compactions fall behind: crossing a soft
+//! limit delays every write to `delayed_write_rate`, crossing a hard limit blocks
+//! writes until background work brings the tree back under the limit.
+
+use std::time::Duration;
+
+use crate::compaction;
+use crate::options::Options;
+use crate::version::Version;
+
+/// Throttling state of the write path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WriteStallCondition {
+    /// Writes proceed at full speed
+    Normal,
+    /// Writes are slowed down to `delayed_write_rate`
+    Delayed,
+    /// Writes block until background work catches up
+    Stopped,
+}
+
+/// Cumulative time writes spent stalled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StallStats {
+    /// Number of writes that were delayed
+    pub delayed_writes: u64,
+    /// Total time spent in write delays
+    pub delayed_time: Duration,
+    /// Number of writes that were stopped
+    pub stopped_writes: u64,
+    /// Total time writes spent blocked
+    pub stopped_time: Duration,
+}
+
+impl StallStats {
+    /// Total time writes spent stalled
+    pub fn total_stall_time(&self) -> Duration {
+        self.delayed_time + self.stopped_time
+    }
+}
+
+/// Computes the stall condition for the given tree shape
+pub fn stall_condition(
+    options: &Options,
+    num_immutables: usize,
+    version: &Version,
+) -> WriteStallCondition {
+    let num_l0_files = version.levels[0].len();
+    let pending_bytes = compaction::pending_compaction_bytes(version, options);
+
+    if num_immutables >= options.memtable_stop_writes_trigger
+        || num_l0_files >= options.level0_stop_writes_trigger
+        || pending_bytes >= options.hard_pending_compaction_bytes_limit
+    {
+        WriteStallCondition::Stopped
+    } else if num_immutables >= options.memtable_slowdown_writes_trigger
+        || num_l0_files >= options.level0_slowdown_writes_trigger
+        || pending_bytes >= options.soft_pending_compaction_bytes_limit
+    {
+        WriteStallCondition::Delayed
+    } else {
+        WriteStallCondition::Normal
+    }
+}
+
+/// Time a delayed write of `bytes` has to wait to stay under `delayed_write_rate`
+pub fn write_delay(options: &Options, bytes: usize) -> Duration {
+    let micros = (bytes as u128 * 1_000_000) / u128::from(options.delayed_write_rate);
+    Duration::from_micros(micros.try_into().unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_immutable_memtable_triggers() {
+        let options = Options::default();
+        let version = Version::new(options.num_levels, Arc::clone(&options.comparator));
+
+        assert_eq!(
+            stall_condition(&options, 0, &version),
+            WriteStallCondition::Normal
+        );
+        assert_eq!(
+            stall_condition(&options, options.memtable_slowdown_writes_trigger, &version),
+            WriteStallCondition::Delayed
+        );
+        assert_eq!(
+            stall_condition(&options, options.memtable_stop_writes_trigger, &version),
+            WriteStallCondition::Stopped
+        );
+    }
+
+    #[test]
+    fn test_write_delay() {
+        let options = Options {
+            delayed_write_rate: 1000,
+            ..Options::default()
+        };
+
+        assert_eq!(write_delay(&options, 1000), Duration::from_secs(1));
+        assert_eq!(write_delay(&options, 10), Duration::from_millis(10));
+        assert_eq!(write_delay(&options, 0), Duration::ZERO);
+    }
+}